@@ -0,0 +1,115 @@
+#![feature(test)]
+
+extern crate svm;
+extern crate test;
+
+use test::Bencher;
+
+use svm::{Engine, FlatMemory, Memory, MemoryBackend, VirtualMachine};
+use svm::asm::Assembler;
+
+/// Size of the memory used by the `_flat` benchmarks, which is plenty for the
+/// examples and their stacks.
+const FLAT_MEMORY_SIZE: usize = 1 << 16;
+
+fn run<M: MemoryBackend + 'static>(b: &mut Bencher, path: &str, memory: M, engine: Engine, decode_cache: bool) {
+    let program = Assembler::new().file(path).assemble().unwrap().bytes();
+
+    let mut vm = VirtualMachine::with_memory(memory, program).unwrap();
+    vm.engine = engine;
+    vm.decode_cache = decode_cache;
+
+    // Reuse the same machine so that the cache persists between runs, as it
+    // would in a longer running program
+    b.iter(|| {
+        vm.registers = [0; 32];
+        vm.reset();
+
+        assert_eq!(vm.run(), Ok(0));
+        vm.registers[3]
+    });
+}
+
+#[bench]
+fn fibonacci(b: &mut Bencher) {
+    run(b, "examples/fibonacci.sasm", Memory::new(), Engine::Interpreter, false);
+}
+
+#[bench]
+fn fibonacci_flat(b: &mut Bencher) {
+    run(b, "examples/fibonacci.sasm", FlatMemory::new(FLAT_MEMORY_SIZE), Engine::Interpreter, false);
+}
+
+#[bench]
+fn fibonacci_cached(b: &mut Bencher) {
+    run(b, "examples/fibonacci.sasm", Memory::new(), Engine::Interpreter, true);
+}
+
+#[bench]
+fn fibonacci_flat_cached(b: &mut Bencher) {
+    run(b, "examples/fibonacci.sasm", FlatMemory::new(FLAT_MEMORY_SIZE), Engine::Interpreter, true);
+}
+
+#[bench]
+fn fibonacci_blocks(b: &mut Bencher) {
+    run(b, "examples/fibonacci.sasm", Memory::new(), Engine::Blocks, true);
+}
+
+#[bench]
+fn fibonacci_flat_blocks(b: &mut Bencher) {
+    run(b, "examples/fibonacci.sasm", FlatMemory::new(FLAT_MEMORY_SIZE), Engine::Blocks, true);
+}
+
+#[cfg(feature = "jit")]
+#[bench]
+fn fibonacci_jit(b: &mut Bencher) {
+    run(b, "examples/fibonacci.sasm", Memory::new(), Engine::Jit, true);
+}
+
+#[cfg(feature = "jit")]
+#[bench]
+fn fibonacci_flat_jit(b: &mut Bencher) {
+    run(b, "examples/fibonacci.sasm", FlatMemory::new(FLAT_MEMORY_SIZE), Engine::Jit, true);
+}
+
+#[bench]
+fn linear_search(b: &mut Bencher) {
+    run(b, "examples/linear_search.sasm", Memory::new(), Engine::Interpreter, false);
+}
+
+#[bench]
+fn linear_search_flat(b: &mut Bencher) {
+    run(b, "examples/linear_search.sasm", FlatMemory::new(FLAT_MEMORY_SIZE), Engine::Interpreter, false);
+}
+
+#[bench]
+fn linear_search_cached(b: &mut Bencher) {
+    run(b, "examples/linear_search.sasm", Memory::new(), Engine::Interpreter, true);
+}
+
+#[bench]
+fn linear_search_flat_cached(b: &mut Bencher) {
+    run(b, "examples/linear_search.sasm", FlatMemory::new(FLAT_MEMORY_SIZE), Engine::Interpreter, true);
+}
+
+#[bench]
+fn linear_search_blocks(b: &mut Bencher) {
+    run(b, "examples/linear_search.sasm", Memory::new(), Engine::Blocks, true);
+}
+
+#[bench]
+fn linear_search_flat_blocks(b: &mut Bencher) {
+    run(b, "examples/linear_search.sasm", FlatMemory::new(FLAT_MEMORY_SIZE), Engine::Blocks, true);
+}
+
+#[cfg(feature = "jit")]
+#[bench]
+fn linear_search_jit(b: &mut Bencher) {
+    run(b, "examples/linear_search.sasm", Memory::new(), Engine::Jit, true);
+}
+
+#[cfg(feature = "jit")]
+#[bench]
+fn linear_search_flat_jit(b: &mut Bencher) {
+    run(b, "examples/linear_search.sasm", FlatMemory::new(FLAT_MEMORY_SIZE), Engine::Jit, true);
+}
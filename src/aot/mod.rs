@@ -0,0 +1,438 @@
+//! Ahead-of-time translation of SVM programs into C, as used by `svm2c`.
+//!
+//! The control flow of the program is recovered statically, starting from
+//! the entry point and following branches, fallthrough and any constant
+//! loaded into a register which could be the address of code (such as the
+//! return address in `li r2, %exit`). Each instruction found is translated
+//! into a labelled C statement, with jumps to constant addresses becoming
+//! `goto`s.
+//!
+//! Other writes to the program counter, such as `mv r0, r2`, jump through a
+//! dispatch on the address. Addresses with no translated code are run by an
+//! interpreter in the runtime until it reaches translated code again, so
+//! every program runs, but self-modifying code is not supported as stores to
+//! translated code aren't detected.
+//!
+//! The runtime passes the host program's arguments and environment on as
+//! `svm` would, but only provides syscalls 0 to 7. Programs calling any other
+//! syscall in the specification are refused when translated, or stopped with
+//! an error if the call is only found once it's run.
+//!
+//! ```
+//! use svm::aot;
+//!
+//! // c.li r4, 0; c.call 0
+//! let mut source = Vec::new();
+//! aot::translate(&[0x31, 0x01, 0x3d, 0x00], &mut source).unwrap();
+//!
+//! assert!(String::from_utf8(source).unwrap().contains("L_00000000:"));
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+
+use {Instruction, Memory, OpCode};
+use block::operation;
+
+/// Runtime providing memory, syscalls and the fallback interpreter, which the
+/// translated program is appended to.
+const RUNTIME: &'static str = include_str!("runtime.c");
+
+/// Number of bytes of the program image on each line of the output.
+const BYTES_PER_LINE: usize = 12;
+
+/// First and last syscalls in the specification which the runtime doesn't
+/// provide. Calls to syscalls outside of the specification are left for the
+/// runtime to report, as `svm` does.
+const UNSUPPORTED_SYSCALLS: (u16, u16) = (8, 28);
+
+/// Instructions reachable from the entry point of a program, by address.
+pub type ControlFlow = BTreeMap<u32, Instruction>;
+
+/// Returns the register `instr` writes to, if any.
+fn destination(instr: Instruction) -> Option<usize> {
+    use Instruction::*;
+    use OpCode::*;
+
+    match instr {
+        Register { dst, .. } | Upper { dst, .. } => Some(dst),
+        Immediate { op: BEZ, .. } | Immediate { op: C_BEZ, .. } | Immediate { op: BNZ, .. } |
+        Immediate { op: C_BNZ, .. } | Immediate { op: CALL, .. } | Immediate { op: C_CALL, .. } |
+        Immediate { op: BREAK, .. } | Immediate { op: C_BREAK, .. } => None,
+        Immediate { dst, .. } => Some(dst),
+        Store { .. } => None
+    }
+}
+
+/// Returns the value `instr` writes to its destination if it doesn't depend
+/// on any register other than the program counter, whose value is `next`.
+fn constant(instr: Instruction, next: u32) -> Option<u32> {
+    use Instruction::*;
+    use OpCode::*;
+
+    match instr {
+        Register { op: MV, src2: 0, .. } => Some(next),
+        Register { op: MV, .. } => None,
+        Register { op, src1: 0, src2: 0, .. } => operation(op).map(|f| f(next, next)),
+        Immediate { op: LI, imm, .. } | Immediate { op: C_LI, imm, .. } => Some(imm),
+        Immediate { op, src1: 0, imm, .. } => operation(op).map(|f| f(next, imm)),
+        Upper { imm, .. } => Some(imm),
+        _ => None
+    }
+}
+
+/// Returns the address `instr` branches to if its condition holds, if it's a
+/// branch.
+fn branch_target(instr: Instruction, next: u32) -> Option<u32> {
+    use Instruction::*;
+    use OpCode::*;
+
+    match instr {
+        Immediate { op: BEZ, imm, .. } | Immediate { op: C_BEZ, imm, .. } |
+        Immediate { op: BNZ, imm, .. } | Immediate { op: C_BNZ, imm, .. } => Some(next.wrapping_add(imm)),
+        Store { op: STORE, .. } | Store { op: C_STORE, .. } => None,
+        Store { imm, .. } => Some(next.wrapping_add(imm)),
+        _ => None
+    }
+}
+
+/// Returns whether execution can continue with the instruction after `instr`.
+fn falls_through(instr: Instruction) -> bool {
+    use Instruction::*;
+    use OpCode::*;
+
+    match instr {
+        Immediate { op: CALL, imm: 0, .. } | Immediate { op: C_CALL, imm: 0, .. } => false,
+        _ => destination(instr) != Some(0)
+    }
+}
+
+/// Recovers the control flow of `image`, loaded at address 0 with its entry
+/// point at the start.
+pub fn recover(image: &[u8]) -> ControlFlow {
+    let mut memory = Memory::new();
+    memory.write(0, image).expect("memory has no page limit");
+
+    let mut instrs = ControlFlow::new();
+    let mut pending = vec![0];
+
+    while let Some(addr) = pending.pop() {
+        if addr as u64 >= image.len() as u64 || instrs.contains_key(&addr) {
+            continue;
+        }
+
+        // Invalid instructions are left to the interpreter to report
+        let instr = match memory.fetch(addr) {
+            Ok(instr) => instr,
+            Err(_) => continue
+        };
+        let next = addr.wrapping_add(instr.size());
+
+        instrs.insert(addr, instr);
+
+        // Follow constants in case they're the address of code, such as a
+        // return address or a jump table entry. Odd constants are much more
+        // likely to be data, and code they do reach is interpreted
+        if let Some(value) = constant(instr, next) {
+            if value & 1 == 0 {
+                pending.push(value);
+            }
+        }
+
+        if let Some(target) = branch_target(instr, next) {
+            pending.push(target);
+        }
+
+        if falls_through(instr) {
+            pending.push(next);
+        }
+    }
+
+    instrs
+}
+
+/// Returns an error if any instruction in `instrs` which is certainly code
+/// calls a syscall the runtime doesn't provide. Instructions only reached by
+/// following a constant which isn't jumped to may well be data, so calls
+/// among them are left for the runtime to report if they're ever run.
+fn check_syscalls(instrs: &ControlFlow) -> Result<(), io::Error> {
+    use Instruction::*;
+    use OpCode::*;
+
+    let (first, last) = UNSUPPORTED_SYSCALLS;
+    let mut visited = BTreeSet::new();
+    let mut pending = vec![0];
+
+    while let Some(addr) = pending.pop() {
+        let instr = match instrs.get(&addr) {
+            Some(&instr) if visited.insert(addr) => instr,
+            _ => continue
+        };
+        let next = addr.wrapping_add(instr.size());
+
+        let call = match instr {
+            Immediate { op: CALL, imm, .. } | Immediate { op: C_CALL, imm, .. } => Some(imm as u16),
+            _ => None
+        };
+
+        if let Some(call) = call {
+            if first <= call && call <= last {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("syscall {} at 0x{:08x} isn't supported by translated programs",
+                                                  call, addr)));
+            }
+        }
+
+        if destination(instr) == Some(0) {
+            if let Some(value) = constant(instr, next) {
+                pending.push(value);
+            }
+        }
+
+        if let Some(target) = branch_target(instr, next) {
+            pending.push(target);
+        }
+
+        if falls_through(instr) {
+            pending.push(next);
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns an error if `image` can't be translated, as it calls a syscall
+/// which the runtime doesn't provide.
+pub fn check(image: &[u8]) -> Result<(), io::Error> {
+    check_syscalls(&recover(image))
+}
+
+/// Returns the C expression for reading register `reg`, which is the constant
+/// `next` for the program counter.
+fn register(reg: usize, next: u32) -> String {
+    match reg {
+        0 => format!("0x{:08x}u", next),
+        _ => format!("r[{}]", reg)
+    }
+}
+
+/// Returns the C expression for the operation performed by `op` on `a` and
+/// `b`, as in `block::operation`.
+fn expression(op: OpCode, a: &str, b: &str) -> Option<String> {
+    use OpCode::*;
+
+    Some(match op {
+        ADD | C_ADD | ADDI | C_ADDI => format!("{} + {}", a, b),
+        SUB | C_SUB => format!("{} - {}", a, b),
+        AND | C_AND | ANDI | C_ANDI => format!("{} & {}", a, b),
+        OR  | C_OR  | ORI  | C_ORI  => format!("{} | {}", a, b),
+        XOR | C_XOR | XORI | C_XORI => format!("{} ^ {}", a, b),
+        SLL | C_SLL | SLLI | C_SLLI => format!("{} << ({} & 31)", a, b),
+        SRL | C_SRL | SRLI | C_SRLI => format!("{} >> ({} & 31)", a, b),
+        SRA | C_SRA | SRAI | C_SRAI => format!("SVM_SRA({}, {})", a, b),
+        MV | LI | C_LI | LUI | C_LUI => b.to_owned(),
+        _ => return None
+    })
+}
+
+/// Returns the C statement jumping to `target`, through the dispatch if it
+/// wasn't translated.
+fn jump(instrs: &ControlFlow, target: u32) -> String {
+    if instrs.contains_key(&target) {
+        format!("goto L_{:08x};", target)
+    } else {
+        format!("r[0] = 0x{:08x}u; goto dispatch;", target)
+    }
+}
+
+/// Writes the C statements for `instr`, at `addr`.
+fn write_instr<W: Write>(writer: &mut W, instrs: &ControlFlow, addr: u32, instr: Instruction)
+    -> Result<(), io::Error> {
+    use Instruction::*;
+    use OpCode::*;
+
+    let next = addr.wrapping_add(instr.size());
+
+    writeln!(writer, "L_{:08x}: /* {:?} */", addr, instr)?;
+
+    let value = match instr {
+        Register { op, src1, src2, .. } => expression(op, &register(src1, next), &register(src2, next)),
+        Immediate { op: BEZ, src1, .. } | Immediate { op: C_BEZ, src1, .. } => {
+            let target = jump(instrs, branch_target(instr, next).unwrap());
+            return writeln!(writer, "    if ({} == 0) {{ {} }}", register(src1, next), target);
+        },
+        Immediate { op: BNZ, src1, .. } | Immediate { op: C_BNZ, src1, .. } => {
+            let target = jump(instrs, branch_target(instr, next).unwrap());
+            return writeln!(writer, "    if ({} != 0) {{ {} }}", register(src1, next), target);
+        },
+        Immediate { op: LOAD, src1, imm, .. } | Immediate { op: C_LOAD, src1, imm, .. } => {
+            Some(format!("svm_read_u32(vm, {} + 0x{:08x}u)", register(src1, next), imm))
+        },
+        Immediate { op: CALL, imm, .. } | Immediate { op: C_CALL, imm, .. } => {
+            return match imm as u16 {
+                0 => writeln!(writer, "    return (int32_t) r[4];"),
+                // The program counter is only kept up to date for errors
+                call => writeln!(writer, "    r[0] = 0x{:08x}u; svm_syscall(vm, {});", addr, call)
+            };
+        },
+        Immediate { op: BREAK, .. } | Immediate { op: C_BREAK, .. } => {
+            return writeln!(writer, "    /* Breakpoints are ignored */");
+        },
+        Immediate { op, src1, imm, .. } => expression(op, &register(src1, next), &format!("0x{:08x}u", imm)),
+        Store { op: STORE, src1, src2, imm } | Store { op: C_STORE, src1, src2, imm } => {
+            return writeln!(writer, "    svm_write_u32(vm, {} + 0x{:08x}u, {});",
+                            register(src1, next), imm, register(src2, next));
+        },
+        Store { op, src1, src2, .. } => {
+            let (a, b) = (register(src1, next), register(src2, next));
+            let condition = match op {
+                BEQ => format!("{} == {}", a, b),
+                BNE => format!("{} != {}", a, b),
+                BLT => format!("(int32_t) {} < (int32_t) {}", a, b),
+                BGE => format!("(int32_t) {} >= (int32_t) {}", a, b),
+                BLT_U => format!("{} < {}", a, b),
+                BGE_U => format!("{} >= {}", a, b),
+                _ => unreachable!("{:?}", op)
+            };
+
+            return writeln!(writer, "    if ({}) {{ {} }}", condition, jump(instrs, branch_target(instr, next).unwrap()));
+        },
+        Upper { imm, .. } => Some(format!("0x{:08x}u", imm))
+    };
+
+    let value = value.unwrap_or_else(|| unreachable!("{:?}", instr));
+
+    match (destination(instr), constant(instr, next)) {
+        (Some(0), Some(target)) => writeln!(writer, "    {}", jump(instrs, target)),
+        (Some(0), None) => writeln!(writer, "    r[0] = {}; goto dispatch;", value),
+        (Some(dst), _) => writeln!(writer, "    r[{}] = {};", dst, value),
+        (None, _) => unreachable!("{:?}", instr)
+    }
+}
+
+/// Translates `image` into a C program, which behaves like running the image
+/// with `svm` once compiled. Fails without writing anything if the image
+/// can't be translated, see `check`.
+pub fn translate<W: Write>(image: &[u8], writer: &mut W) -> Result<(), io::Error> {
+    let instrs = recover(image);
+    check_syscalls(&instrs)?;
+
+    writeln!(writer, "/* Translated by svm2c */\n")?;
+    writer.write_all(RUNTIME.as_bytes())?;
+
+    // An empty array isn't valid C, so always write at least one byte
+    writeln!(writer, "\nstatic const uint8_t svm_image[] = {{")?;
+
+    let padding = [0];
+    let bytes = if image.is_empty() { &padding[..] } else { image };
+
+    for line in bytes.chunks(BYTES_PER_LINE) {
+        let line: Vec<String> = line.iter().map(|b| format!("0x{:02x}", b)).collect();
+        writeln!(writer, "    {},", line.join(", "))?;
+    }
+
+    writeln!(writer, "}};\n")?;
+    writeln!(writer, "static void svm_load(struct svm *vm) {{")?;
+    writeln!(writer, "    svm_write(vm, 0, svm_image, {}u);", image.len())?;
+    writeln!(writer, "}}\n")?;
+
+    writeln!(writer, "static int svm_compiled(uint32_t addr) {{")?;
+    writeln!(writer, "    switch (addr) {{")?;
+
+    for addr in instrs.keys() {
+        writeln!(writer, "    case 0x{:08x}u:", addr)?;
+    }
+
+    writeln!(writer, "        return 1;")?;
+    writeln!(writer, "    default:")?;
+    writeln!(writer, "        return 0;")?;
+    writeln!(writer, "    }}")?;
+    writeln!(writer, "}}\n")?;
+
+    writeln!(writer, "static int32_t svm_run(struct svm *vm) {{")?;
+    writeln!(writer, "    uint32_t *r = vm->r;\n")?;
+    writeln!(writer, "dispatch:")?;
+    writeln!(writer, "    switch (r[0]) {{")?;
+
+    for addr in instrs.keys() {
+        writeln!(writer, "    case 0x{:08x}u: goto L_{:08x};", addr, addr)?;
+    }
+
+    writeln!(writer, "    }}\n")?;
+    writeln!(writer, "    if (svm_interpret(vm)) {{")?;
+    writeln!(writer, "        return (int32_t) r[4];")?;
+    writeln!(writer, "    }}\n")?;
+    writeln!(writer, "    goto dispatch;\n")?;
+
+    let mut iter = instrs.iter().peekable();
+
+    while let Some((&addr, &instr)) = iter.next() {
+        write_instr(writer, &instrs, addr, instr)?;
+
+        let next = addr.wrapping_add(instr.size());
+
+        // Instructions may not be contiguous, such as when code is followed
+        // by data, or overlap when an address is reached which is part way
+        // through another instruction
+        if falls_through(instr) && iter.peek().map(|&(&addr, _)| addr) != Some(next) {
+            writeln!(writer, "    {}", jump(&instrs, next))?;
+        }
+    }
+
+    writeln!(writer, "}}")
+}
+
+#[cfg(test)]
+mod test {
+    use Instruction::*;
+    use OpCode::*;
+
+    use super::{check, recover, translate};
+
+    #[test]
+    fn recover_control_flow() {
+        // li r2, 16; c.addi r0, 6; (invalid) c.li r4, 1; c.call 0; c.li r3, 1;
+        // mv r0, r2; c.li r4, 0; c.call 0
+        let image = [0xb0, 0x00, 0x10, 0x00, 0x13, 0x0c, 0x00, 0x00, 0x31, 0x03, 0x3d, 0x00,
+                     0xf1, 0x02, 0x39, 0x10, 0x31, 0x01, 0x3d, 0x00];
+        let instrs = recover(&image);
+
+        assert_eq!(instrs.keys().cloned().collect::<Vec<u32>>(), [0, 4, 12, 14, 16, 18]);
+        assert_eq!(instrs[&4], Immediate { op: C_ADDI, dst: 0, src1: 0, imm: 6 });
+        assert_eq!(instrs[&14], Register { op: MV, dst: 0, src1: 0, src2: 2 });
+    }
+
+    #[test]
+    fn translate_jumps() {
+        // As above
+        let image = [0xb0, 0x00, 0x10, 0x00, 0x13, 0x0c, 0x00, 0x00, 0x31, 0x03, 0x3d, 0x00,
+                     0xf1, 0x02, 0x39, 0x10, 0x31, 0x01, 0x3d, 0x00];
+        let mut source = Vec::new();
+        translate(&image, &mut source).unwrap();
+
+        let source = String::from_utf8(source).unwrap();
+
+        assert!(source.contains("    case 0x0000000cu: goto L_0000000c;\n"));
+        assert!(source.contains("    r[2] = 0x00000010u;\n"));
+        assert!(source.contains("    goto L_0000000c;\n"));
+        assert!(source.contains("    r[0] = r[2]; goto dispatch;\n"));
+        assert!(source.contains("    return (int32_t) r[4];\n"));
+    }
+
+    #[test]
+    fn unsupported_syscall() {
+        // c.li r4, 0; call 15; c.call 0
+        let image = [0x31, 0x01, 0x3c, 0x00, 0x0f, 0x00, 0x3d, 0x00];
+        let mut source = Vec::new();
+
+        assert_eq!(translate(&image, &mut source).unwrap_err().to_string(),
+                   "syscall 15 at 0x00000002 isn't supported by translated programs");
+        assert!(source.is_empty());
+
+        // Syscalls outside of the specification are left to the runtime
+        assert!(check(&[0x31, 0x01, 0x3c, 0x00, 0x63, 0x00, 0x3d, 0x00]).is_ok());
+
+        // As are calls in what's only loaded as an address: li r4, 6; c.call 0; call 15
+        assert!(check(&[0x30, 0x01, 0x06, 0x00, 0x3d, 0x00, 0x3c, 0x00, 0x0f, 0x00]).is_ok());
+    }
+}
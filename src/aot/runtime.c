@@ -0,0 +1,555 @@
+/*
+ * Runtime for programs translated by svm2c.
+ *
+ * Provides paged memory, program arguments and the file, argument and
+ * environment syscalls of the virtual machine, along with an interpreter for
+ * code which wasn't found when the program was translated, such as code
+ * reached through a computed jump to an address which was never loaded into a
+ * register as a constant.
+ *
+ * The translated program follows this runtime and defines `svm_load`, which
+ * writes the program image into memory, `svm_compiled`, which returns whether
+ * there is translated code for an address, and `svm_run`.
+ */
+
+#include <errno.h>
+#include <fcntl.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <unistd.h>
+
+extern char **environ;
+
+#define SVM_PAGE_SIZE 4096u
+#define SVM_PAGE_COUNT (0x100000000ull / SVM_PAGE_SIZE)
+
+/* Arithmetic right shift, which C leaves implementation defined for negative
+ * values */
+#define SVM_SRA(a, b) (((a) >> ((b) & 31)) | ((a) & 0x80000000u ? ~(0xffffffffu >> ((b) & 31)) : 0))
+
+struct svm {
+    uint32_t r[32];
+    /* Pages are allocated when first written, and read as zeroes until then */
+    uint8_t *pages[SVM_PAGE_COUNT];
+    /* Host file descriptors of open files, indexed by their handle minus 3,
+     * or -1 if the handle has been closed */
+    int *files;
+    uint32_t file_count;
+    /* Arguments and environment variables of the host program, which are
+     * passed on to the translated program */
+    int argc;
+    char **argv;
+    char **envp;
+};
+
+static void svm_load(struct svm *vm);
+static int svm_compiled(uint32_t addr);
+static int32_t svm_run(struct svm *vm);
+
+static void svm_error(struct svm *vm, const char *message) {
+    fflush(stdout);
+    fprintf(stderr, "svm: Virtual Machine error: %s at 0x%08x\n", message, vm->r[0]);
+    exit(1);
+}
+
+/* Allocates `size` zeroed bytes, exiting if there isn't enough memory */
+static void *svm_alloc(size_t size) {
+    void *ptr = calloc(size, 1);
+
+    if (!ptr) {
+        fprintf(stderr, "svm: out of memory\n");
+        exit(1);
+    }
+
+    return ptr;
+}
+
+static uint8_t *svm_page(struct svm *vm, uint32_t addr) {
+    uint8_t **page = &vm->pages[addr / SVM_PAGE_SIZE];
+
+    if (!*page) {
+        *page = svm_alloc(SVM_PAGE_SIZE);
+    }
+
+    return *page;
+}
+
+static void svm_read(struct svm *vm, uint32_t addr, uint8_t *buf, uint32_t len) {
+    while (len > 0) {
+        uint32_t offset = addr % SVM_PAGE_SIZE;
+        uint32_t count = SVM_PAGE_SIZE - offset < len ? SVM_PAGE_SIZE - offset : len;
+        const uint8_t *page = vm->pages[addr / SVM_PAGE_SIZE];
+
+        if (page) {
+            memcpy(buf, page + offset, count);
+        } else {
+            memset(buf, 0, count);
+        }
+
+        addr += count;
+        buf += count;
+        len -= count;
+    }
+}
+
+static void svm_write(struct svm *vm, uint32_t addr, const uint8_t *buf, uint32_t len) {
+    while (len > 0) {
+        uint32_t offset = addr % SVM_PAGE_SIZE;
+        uint32_t count = SVM_PAGE_SIZE - offset < len ? SVM_PAGE_SIZE - offset : len;
+
+        memcpy(svm_page(vm, addr) + offset, buf, count);
+
+        addr += count;
+        buf += count;
+        len -= count;
+    }
+}
+
+static uint32_t svm_read_u32(struct svm *vm, uint32_t addr) {
+    uint8_t buf[4];
+    svm_read(vm, addr, buf, 4);
+
+    return (uint32_t) buf[0] | (uint32_t) buf[1] << 8 | (uint32_t) buf[2] << 16 | (uint32_t) buf[3] << 24;
+}
+
+static void svm_write_u32(struct svm *vm, uint32_t addr, uint32_t value) {
+    uint8_t buf[4];
+
+    buf[0] = value;
+    buf[1] = value >> 8;
+    buf[2] = value >> 16;
+    buf[3] = value >> 24;
+
+    svm_write(vm, addr, buf, 4);
+}
+
+/* Reads `len` bytes of guest memory into a new buffer, with a null byte after
+ * them so that it can be used as a path */
+static char *svm_read_buffer(struct svm *vm, uint32_t addr, uint32_t len) {
+    char *buf = svm_alloc((size_t) len + 1);
+
+    svm_read(vm, addr, (uint8_t *) buf, len);
+    buf[len] = 0;
+
+    return buf;
+}
+
+/* Returns the value syscalls return on failure with the host error `error`,
+ * which is its SVM error code negated. The codes are the host's own numbers
+ * below 35, which Unix systems share, and any others are reported as EIO */
+static uint32_t svm_host_error(int error) {
+    switch (error) {
+    case EPERM: case ENOENT: case EINTR: case EIO: case EBADF: case EAGAIN: case ENOMEM:
+    case EACCES: case EFAULT: case EBUSY: case EEXIST: case EXDEV: case ENOTDIR: case EISDIR:
+    case EINVAL: case EMFILE: case EFBIG: case ENOSPC: case ESPIPE: case EROFS: case EPIPE:
+        return (uint32_t) -error;
+    default:
+        return (uint32_t) -EIO;
+    }
+}
+
+/* Returns the host file descriptor of `handle`, or -1 if it isn't open */
+static int svm_file(struct svm *vm, uint32_t handle) {
+    if (handle < 3 || handle - 3 >= vm->file_count) {
+        return -1;
+    }
+
+    return vm->files[handle - 3];
+}
+
+static uint32_t svm_sys_read(struct svm *vm) {
+    uint32_t handle = vm->r[4], ptr = vm->r[5], len = vm->r[6];
+    int fd = handle == 0 ? 0 : svm_file(vm, handle);
+    uint8_t *buf;
+    ssize_t count;
+
+    if (fd < 0) {
+        return svm_host_error(EBADF);
+    }
+
+    buf = svm_alloc((size_t) len + 1);
+    count = read(fd, buf, len);
+
+    if (count < 0) {
+        free(buf);
+        return svm_host_error(errno);
+    }
+
+    svm_write(vm, ptr, buf, (uint32_t) count);
+    free(buf);
+
+    return (uint32_t) count;
+}
+
+static uint32_t svm_sys_write(struct svm *vm) {
+    uint32_t handle = vm->r[4], ptr = vm->r[5], len = vm->r[6];
+    int fd = handle == 1 || handle == 2 ? (int) handle : svm_file(vm, handle);
+    char *buf;
+    ssize_t count;
+
+    if (fd < 0) {
+        return svm_host_error(EBADF);
+    }
+
+    buf = svm_read_buffer(vm, ptr, len);
+    count = write(fd, buf, len);
+    free(buf);
+
+    return count < 0 ? svm_host_error(errno) : (uint32_t) count;
+}
+
+/* Opens a file with the flags of sys_open, which are checked in the same way
+ * as Rust's `OpenOptions` */
+static uint32_t svm_open(struct svm *vm, uint32_t ptr, uint32_t len, uint32_t flags) {
+    int readable = flags & 1, writable = flags & 2, create = flags & 4, exclusive = flags & 8;
+    int truncate = flags & 16, append = flags & 32;
+    int mode, fd;
+    uint32_t handle;
+    char *path;
+
+    if (append) {
+        mode = readable ? O_RDWR | O_APPEND : O_WRONLY | O_APPEND;
+    } else if (writable) {
+        mode = readable ? O_RDWR : O_WRONLY;
+    } else if (readable) {
+        mode = O_RDONLY;
+    } else {
+        return svm_host_error(EINVAL);
+    }
+
+    if ((!writable && !append && (truncate || create || exclusive)) || (append && truncate && !exclusive)) {
+        return svm_host_error(EINVAL);
+    }
+
+    if (exclusive) {
+        mode |= O_CREAT | O_EXCL;
+    } else {
+        mode |= (create ? O_CREAT : 0) | (truncate ? O_TRUNC : 0);
+    }
+
+    path = svm_read_buffer(vm, ptr, len);
+    fd = open(path, mode | O_CLOEXEC, 0666);
+    free(path);
+
+    if (fd < 0) {
+        return svm_host_error(errno);
+    }
+
+    /* Use the lowest free handle */
+    for (handle = 0; handle < vm->file_count && vm->files[handle] >= 0; handle++) {}
+
+    if (handle == vm->file_count) {
+        int *files = svm_alloc((handle + 1) * sizeof(int));
+
+        if (vm->files) {
+            memcpy(files, vm->files, handle * sizeof(int));
+            free(vm->files);
+        }
+
+        vm->files = files;
+        vm->file_count++;
+    }
+
+    vm->files[handle] = fd;
+
+    return handle + 3;
+}
+
+static uint32_t svm_sys_close(struct svm *vm) {
+    uint32_t handle = vm->r[4];
+    int fd = svm_file(vm, handle);
+    int error = 0;
+
+    if (fd < 0) {
+        return svm_host_error(EBADF);
+    }
+
+    vm->files[handle - 3] = -1;
+
+    if (fsync(fd) < 0) {
+        error = errno;
+    }
+
+    close(fd);
+
+    return error ? svm_host_error(error) : 0;
+}
+
+/* Writes a pointer to each string of the null terminated `table` at `addr`,
+ * followed by 0, copying the strings to `*strings` and advancing it past
+ * them. Returns the address after the table */
+static uint32_t svm_push_table(struct svm *vm, char **table, uint32_t addr, uint32_t *strings) {
+    for (; *table; table++) {
+        uint32_t len = (uint32_t) strlen(*table) + 1;
+
+        svm_write(vm, *strings, (const uint8_t *) *table, len);
+        svm_write_u32(vm, addr, *strings);
+
+        *strings += len;
+        addr += 4;
+    }
+
+    svm_write_u32(vm, addr, 0);
+
+    return addr + 4;
+}
+
+/* Places the arguments and environment on the stack as `svm` does, moving
+ * the stack pointer down to the argument count and setting `r4` to `r6` */
+static void svm_push_args(struct svm *vm) {
+    uint64_t strings_len = 0, table_len, top = vm->r[1];
+    uint32_t count = 0, strings, sp;
+    char **str;
+
+    for (str = vm->argv; *str; str++, count++) {
+        strings_len += strlen(*str) + 1;
+    }
+
+    for (str = vm->envp; *str; str++, count++) {
+        strings_len += strlen(*str) + 1;
+    }
+
+    /* argc, argv and envp with their null terminators, then the strings */
+    table_len = 4 * ((uint64_t) count + 3);
+
+    if (table_len + strings_len + 3 > top) {
+        fprintf(stderr, "svm: out of memory\n");
+        exit(1);
+    }
+
+    strings = (uint32_t) (top - strings_len) & ~3u;
+    sp = strings - (uint32_t) table_len;
+
+    svm_write_u32(vm, sp, (uint32_t) vm->argc);
+    svm_push_table(vm, vm->envp, svm_push_table(vm, vm->argv, sp + 4, &strings), &strings);
+
+    vm->r[1] = sp;
+    vm->r[4] = (uint32_t) vm->argc;
+    vm->r[5] = sp + 4;
+    vm->r[6] = sp + 4 * ((uint32_t) vm->argc + 2);
+}
+
+static uint32_t svm_sys_args(struct svm *vm) {
+    uint32_t index = vm->r[4], ptr = vm->r[5], len = vm->r[6];
+    uint32_t arg_len;
+
+    if (index >= (uint32_t) vm->argc) {
+        return svm_host_error(EINVAL);
+    }
+
+    arg_len = (uint32_t) strlen(vm->argv[index]);
+    svm_write(vm, ptr, (const uint8_t *) vm->argv[index], arg_len < len ? arg_len : len);
+
+    return arg_len;
+}
+
+static uint32_t svm_sys_getenv(struct svm *vm) {
+    uint32_t name_len = vm->r[5], ptr = vm->r[6], len = vm->r[7];
+    char *name = svm_read_buffer(vm, vm->r[4], name_len);
+    char **var;
+
+    /* A name containing a null byte can't match any variable */
+    if (strlen(name) == name_len) {
+        for (var = vm->envp; *var; var++) {
+            if (strncmp(*var, name, name_len) == 0 && (*var)[name_len] == '=') {
+                const char *value = *var + name_len + 1;
+                uint32_t value_len = (uint32_t) strlen(value);
+
+                svm_write(vm, ptr, (const uint8_t *) value, value_len < len ? value_len : len);
+                free(name);
+
+                return value_len;
+            }
+        }
+    }
+
+    free(name);
+
+    return svm_host_error(ENOENT);
+}
+
+/* Executes syscall `call`, returning 1 if the program exited */
+static int svm_syscall(struct svm *vm, uint32_t call) {
+    uint32_t *r = vm->r;
+
+    switch (call) {
+    case 0: /* sys_exit */
+        return 1;
+    case 1: /* sys_read */
+        r[3] = svm_sys_read(vm);
+        break;
+    case 2: /* sys_write */
+        r[3] = svm_sys_write(vm);
+        break;
+    case 3: /* sys_open */
+        r[3] = svm_open(vm, r[4], r[5], r[6]);
+        break;
+    case 4: /* sys_close */
+        r[3] = svm_sys_close(vm);
+        break;
+    case 5: /* sys_create */
+        r[3] = svm_open(vm, r[4], r[5], 2 | 4 | 16);
+        break;
+    case 6: /* sys_args */
+        r[3] = svm_sys_args(vm);
+        break;
+    case 7: /* sys_getenv */
+        r[3] = svm_sys_getenv(vm);
+        break;
+    default: {
+        char message[64];
+
+        if (call <= 28)
+            sprintf(message, "syscall %u isn't supported by translated programs", (unsigned) call);
+        else
+            sprintf(message, "invalid syscall encountered (0x%04x)", call);
+        svm_error(vm, message);
+    }
+    }
+
+    return 0;
+}
+
+static int svm_valid_op(uint32_t op) {
+    /* 0x00, 0x01, 0x38, 0x3a, 0x3b and the odd branches are reserved */
+    switch (op) {
+    case 0x00: case 0x01: case 0x25: case 0x27: case 0x29: case 0x2b: case 0x2d: case 0x2f:
+    case 0x38: case 0x3a: case 0x3b:
+        return 0;
+    default:
+        return 1;
+    }
+}
+
+/* Interprets instructions from the program counter until it reaches
+ * translated code, returning 1 if the program exited */
+static int svm_interpret(struct svm *vm) {
+    uint32_t *r = vm->r;
+
+    do {
+        uint32_t pc = r[0];
+        uint32_t instr = svm_read_u32(vm, pc);
+        uint32_t op = instr & 0x3f;
+        /* Fields of the 4 byte formats, and of compressed register
+         * instructions, whose first operand is also their destination */
+        uint32_t dst = (instr >> 6) & 31, src1 = (instr >> 11) & 31, src2 = (instr >> 16) & 31;
+        uint32_t imm = (uint32_t) ((int32_t) (instr & 0xffff0000u) >> 16);
+        uint32_t store_imm = ((instr >> 6) & 31) | (uint32_t) ((int32_t) (instr & 0xffe00000u) >> 16);
+        /* Fields of the compressed immediate, load and store formats */
+        uint32_t c_dst = (instr >> 6) & 7, c_reg1 = (instr >> 6) & 3, c_reg2 = (instr >> 11) & 3;
+        uint32_t c_imm = (uint32_t) ((int32_t) ((instr & 0xfe00u) << 16) >> 25);
+        uint32_t c_store_imm = ((instr & 0x700u) >> 7) | (uint32_t) ((int32_t) ((instr & 0xe000u) << 16) >> 25);
+
+        if (!svm_valid_op(op)) {
+            char message[64];
+            int i;
+
+            strcpy(message, "invalid opcode encountered (0b");
+
+            for (i = 5; i >= 0; i--) {
+                strcat(message, op & (1u << i) ? "1" : "0");
+            }
+
+            strcat(message, ")");
+            svm_error(vm, message);
+        }
+
+        r[0] += op & 1 ? 2 : 4;
+
+        switch (op) {
+        case 0x02: r[dst] = r[src1] + r[src2]; break;
+        case 0x04: r[dst] = r[src1] - r[src2]; break;
+        case 0x06: r[dst] = r[src1] & r[src2]; break;
+        case 0x08: r[dst] = r[src1] | r[src2]; break;
+        case 0x0a: r[dst] = r[src1] ^ r[src2]; break;
+        case 0x0c: r[dst] = r[src1] << (r[src2] & 31); break;
+        case 0x0e: r[dst] = r[src1] >> (r[src2] & 31); break;
+        case 0x10: r[dst] = SVM_SRA(r[src1], r[src2]); break;
+
+        case 0x12: r[dst] = r[src1] + imm; break;
+        case 0x14: r[dst] = r[src1] & imm; break;
+        case 0x16: r[dst] = r[src1] | imm; break;
+        case 0x18: r[dst] = r[src1] ^ imm; break;
+        case 0x1a: r[dst] = r[src1] << (imm & 31); break;
+        case 0x1c: r[dst] = r[src1] >> (imm & 31); break;
+        case 0x1e: r[dst] = SVM_SRA(r[src1], imm); break;
+
+        case 0x20: if (r[src1] == 0) r[0] += imm; break;
+        case 0x22: if (r[src1] != 0) r[0] += imm; break;
+        case 0x24: if (r[src1] == r[src2]) r[0] += store_imm; break;
+        case 0x26: if (r[src1] != r[src2]) r[0] += store_imm; break;
+        case 0x28: if ((int32_t) r[src1] < (int32_t) r[src2]) r[0] += store_imm; break;
+        case 0x2a: if ((int32_t) r[src1] >= (int32_t) r[src2]) r[0] += store_imm; break;
+        case 0x2c: if (r[src1] < r[src2]) r[0] += store_imm; break;
+        case 0x2e: if (r[src1] >= r[src2]) r[0] += store_imm; break;
+
+        case 0x30: r[dst] = imm; break;
+        case 0x32: r[dst] = instr & 0xffff0000u; break;
+        case 0x34: r[dst] = svm_read_u32(vm, r[src1] + imm); break;
+        case 0x36: svm_write_u32(vm, r[src1] + store_imm, r[src2]); break;
+
+        case 0x03: r[dst] += r[src1]; break;
+        case 0x05: r[dst] -= r[src1]; break;
+        case 0x07: r[dst] &= r[src1]; break;
+        case 0x09: r[dst] |= r[src1]; break;
+        case 0x0b: r[dst] ^= r[src1]; break;
+        case 0x0d: r[dst] = r[dst] << (r[src1] & 31); break;
+        case 0x0f: r[dst] = r[dst] >> (r[src1] & 31); break;
+        case 0x11: r[dst] = SVM_SRA(r[dst], r[src1]); break;
+        case 0x39: r[dst] = r[src1]; break;
+
+        case 0x13: r[c_dst] += c_imm; break;
+        case 0x15: r[c_dst] &= c_imm; break;
+        case 0x17: r[c_dst] |= c_imm; break;
+        case 0x19: r[c_dst] ^= c_imm; break;
+        case 0x1b: r[c_dst] = r[c_dst] << (c_imm & 31); break;
+        case 0x1d: r[c_dst] = r[c_dst] >> (c_imm & 31); break;
+        case 0x1f: r[c_dst] = SVM_SRA(r[c_dst], c_imm); break;
+
+        case 0x21: if (r[c_dst] == 0) r[0] += c_imm; break;
+        case 0x23: if (r[c_dst] != 0) r[0] += c_imm; break;
+
+        case 0x31: r[c_dst] = c_imm; break;
+        case 0x33: r[c_dst] = (uint32_t) ((int32_t) ((instr & 0xfe00u) << 16) >> 9); break;
+        case 0x35: r[c_reg1] = svm_read_u32(vm, r[c_reg2] + c_store_imm); break;
+        case 0x37: svm_write_u32(vm, r[c_reg1] + c_store_imm, r[c_reg2]); break;
+
+        case 0x3c:
+        case 0x3d: {
+            uint32_t call = (op == 0x3c ? imm : c_imm) & 0xffff;
+            r[0] = pc;
+
+            if (svm_syscall(vm, call)) {
+                return 1;
+            }
+
+            r[0] += op & 1 ? 2 : 4;
+            break;
+        }
+        case 0x3e:
+        case 0x3f:
+            /* Breakpoints are ignored */
+            break;
+        }
+    } while (!svm_compiled(r[0]));
+
+    return 0;
+}
+
+int main(int argc, char **argv) {
+    /* Too large for the stack */
+    static struct svm vm;
+    static char *no_env[] = { NULL };
+
+    vm.r[1] = 0xfffffffcu;
+    vm.argc = argc;
+    vm.argv = argv;
+    vm.envp = environ ? environ : no_env;
+
+    svm_load(&vm);
+    svm_push_args(&vm);
+
+    return svm_run(&vm);
+}
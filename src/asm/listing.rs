@@ -0,0 +1,126 @@
+use std::io::{self, Write};
+
+use super::Program;
+
+/// Number of bytes shown on each line of a listing. Longer strings and
+/// `.word` lists continue on the following lines.
+const BYTES_PER_LINE: usize = 4;
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<String>>().join(" ")
+}
+
+impl Program {
+    /// Returns the `size` bytes of the program starting at `addr`, or nothing
+    /// if they lie outside of it (as with `.bss`).
+    fn bytes_at(&self, addr: u32, size: u32) -> &[u8] {
+        let mut bytes = self.segments.iter().filter_map(|segment| {
+            let start = addr.wrapping_sub(segment.addr) as usize;
+            segment.data.get(start..start + size as usize)
+        });
+
+        bytes.next().unwrap_or(&[])
+    }
+
+    /// Writes a listing of the program to `writer`.
+    ///
+    /// Each line of source is shown with its line number, address and the
+    /// bytes it produced. Lines expanded from a macro are numbered after the
+    /// invocation and marked with a `+`. The listing ends with a table of every
+    /// label, its address and the lines which refer to it.
+    pub fn write_listing<W: Write>(&self, writer: &mut W) -> Result<(), io::Error> {
+        let mut file = None;
+
+        for mapping in &self.source_map {
+            let (origin, line) = mapping.location.origin();
+
+            if file != Some(origin) {
+                if file.is_some() {
+                    writeln!(writer, "")?;
+                }
+
+                writeln!(writer, "{}:", origin)?;
+                file = Some(origin);
+            }
+
+            let marker = if mapping.location.expansions.is_empty() { ' ' } else { '+' };
+            let mut chunks = self.bytes_at(mapping.address, mapping.size).chunks(BYTES_PER_LINE);
+
+            let text = if mapping.text.trim().is_empty() {
+                format!("{:5}", line)
+            } else {
+                format!("{:5}{} {:08x}  {:<11}  {}", line, marker, mapping.address,
+                        hex(chunks.next().unwrap_or(&[])), mapping.text)
+            };
+
+            writeln!(writer, "{}", text.trim_right())?;
+
+            for (i, chunk) in chunks.enumerate() {
+                let addr = mapping.address.wrapping_add(((i + 1) * BYTES_PER_LINE) as u32);
+                writeln!(writer, "{:6} {:08x}  {}", "", addr, hex(chunk))?;
+            }
+        }
+
+        let mut symbols = self.symbols.clone();
+        symbols.sort_by(|&(ref a, a_addr), &(ref b, b_addr)| (a_addr, a).cmp(&(b_addr, b)));
+
+        let width = symbols.iter().map(|&(ref name, _)| name.len()).max().unwrap_or(0);
+
+        writeln!(writer, "\nSymbols:\n")?;
+
+        for (name, addr) in symbols {
+            let mut references: Vec<String> = Vec::new();
+
+            for mapping in self.source_map.iter().filter(|m| m.labels.contains(&name)) {
+                let (file, line) = mapping.location.origin();
+                let reference = format!("{}:{}", file, line);
+
+                if !references.contains(&reference) {
+                    references.push(reference);
+                }
+            }
+
+            let text = format!("{:08x}  {:<width$}  {}", addr, name, references.join(", "), width = width);
+            writeln!(writer, "{}", text.trim_right())?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::Assembler;
+
+    #[test]
+    fn listing() {
+        let source = "# Count down from 3\nstart:\n    c.li r4, 3\nloop:  c.addi r4, -1  # Next\n    \
+                      c.bnz r4, $loop\n    addi r5, r0, %message\n    c.addi r0, $start\n.data\nmessage: bytes \"Hello\"\n.word 1, 2";
+        let program = Assembler::new().source("test.sasm", source).assemble().unwrap();
+
+        let mut listing = Vec::new();
+        program.write_listing(&mut listing).unwrap();
+
+        assert_eq!(String::from_utf8(listing).unwrap(), "\
+test.sasm:
+    1  00000000               # Count down from 3
+    2  00000000               start:
+    3  00000000  31 07            c.li r4, 3
+    4  00000002  13 ff        loop:  c.addi r4, -1  # Next
+    5  00000004  23 f9            c.bnz r4, $loop
+    6  00000006  52 01 0c 00      addi r5, r0, %message
+    7  0000000a  13 e8            c.addi r0, $start
+    8  0000000c               .data
+    9  0000000c  48 65 6c 6c  message: bytes \"Hello\"
+       00000010  6f
+   10  00000011  01 00 00 00  .word 1, 2
+       00000015  02 00 00 00
+
+Symbols:
+
+00000000  start    test.sasm:7
+00000002  loop     test.sasm:5
+0000000c  message  test.sasm:6
+");
+    }
+}
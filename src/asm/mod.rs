@@ -0,0 +1,856 @@
+//! Assembler for SVM programs, as used by `sasm`.
+//!
+//! ```
+//! use svm::asm::Assembler;
+//!
+//! let program = Assembler::new().source("add.sasm", "start:\n    add r3, r4, r5").assemble().unwrap();
+//!
+//! assert_eq!(program.bytes(), vec![0xc2, 0x20, 0x05, 0x00]);
+//! assert_eq!(program.symbol("start"), Some(0));
+//! ```
+
+mod listing;
+mod parser;
+mod preprocessor;
+
+use std::cmp;
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use {flatten_segments, flattened_size, Binding, Linker, Object, Relocation, RelocationKind, Section, Segment, Symbol,
+     Target, MAX_RAW_SIZE, ZERO_SECTION};
+
+use self::parser::{Base, ImmediatePlaceholder, InstructionPlaceholder, Relocatable};
+use self::preprocessor::{Preprocessor, SourceLine};
+
+pub use self::preprocessor::Location;
+
+/// An error found while assembling, along with where it was found.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Diagnostic {
+    /// Line of source the error relates to, if any.
+    pub location: Option<Location>,
+    pub message: String
+}
+
+impl Diagnostic {
+    pub fn new<S: Into<String>>(location: Option<Location>, message: S) -> Self {
+        Self {
+            location: location,
+            message: message.into()
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.location {
+            Some(ref location) => write!(f, "{}: {}", location, self.message),
+            None => write!(f, "{}", self.message)
+        }
+    }
+}
+
+/// Every error found while assembling a program.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Diagnostics {
+    pub errors: Vec<Diagnostic>
+}
+
+impl From<Diagnostic> for Diagnostics {
+    fn from(diagnostic: Diagnostic) -> Self {
+        Self { errors: vec![diagnostic] }
+    }
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, diagnostic) in self.errors.iter().enumerate() {
+            if i > 0 {
+                f.write_str("\n")?;
+            }
+
+            write!(f, "{}", diagnostic)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl error::Error for Diagnostics {
+    fn description(&self) -> &str {
+        "assembly failed"
+    }
+}
+
+/// A line of source and the bytes produced from it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SourceMapping {
+    /// Address of the line's bytes, or the address of the next byte in its
+    /// section if it produces none.
+    pub address: u32,
+    pub size: u32,
+    pub location: Location,
+    /// The line as written, including any comment.
+    pub text: String,
+    /// Names of the labels and constants the line refers to.
+    pub labels: Vec<String>
+}
+
+/// An assembled and linked program.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Program {
+    /// Lowest address of any stored section, which `bytes` starts at.
+    pub address: u32,
+    /// Contents of each stored section at the address it was placed, as in
+    /// `Image::segments`.
+    pub segments: Vec<Segment>,
+    /// Name and address of every label in the program.
+    pub symbols: Vec<(String, u32)>,
+    /// Name, start and end address of each section, as in `Image::sections`.
+    pub sections: Vec<(String, u32, u32)>,
+    /// Every line of source after macro expansion, in order, along with the
+    /// bytes it produced.
+    pub source_map: Vec<SourceMapping>
+}
+
+impl Program {
+    /// Returns the program as a raw binary loaded at `address`, with the gaps
+    /// between sections filled with zeros.
+    pub fn bytes(&self) -> Vec<u8> {
+        flatten_segments(self.address, &self.segments)
+    }
+
+    /// Returns the length of the raw binary `bytes` would return.
+    pub fn raw_size(&self) -> u64 {
+        flattened_size(self.address, &self.segments)
+    }
+
+    /// Returns the address of the label `name`, if there is one.
+    pub fn symbol(&self, name: &str) -> Option<u32> {
+        self.symbols.iter().find(|&&(ref n, _)| n == name).map(|&(_, addr)| addr)
+    }
+
+    /// Returns the start address of the section `name`, if there is one.
+    pub fn section(&self, name: &str) -> Option<u32> {
+        self.sections.iter().find(|&&(ref n, _, _)| n == name).map(|&(_, start, _)| start)
+    }
+
+    /// Returns the location of the source line that produced the byte at
+    /// `addr`, if any.
+    pub fn location(&self, addr: u32) -> Option<&Location> {
+        self.source_map.iter()
+                       .find(|m| addr >= m.address && addr - m.address < m.size)
+                       .map(|m| &m.location)
+    }
+}
+
+enum Source {
+    File(PathBuf),
+    /// Source held in memory, as its name and text.
+    Text(String, String)
+}
+
+/// Builder which assembles source into a program or object file.
+pub struct Assembler {
+    sources: Vec<Source>,
+    include_paths: Vec<PathBuf>,
+    section_addresses: Vec<(String, u32)>
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Assembler {
+    /// Constructs a new `Assembler` with no sources.
+    pub fn new() -> Self {
+        Self {
+            sources: Vec::new(),
+            include_paths: Vec::new(),
+            section_addresses: Vec::new()
+        }
+    }
+
+    /// Adds the source `text`, which diagnostics refer to as the file `name`.
+    pub fn source(mut self, name: &str, text: &str) -> Self {
+        self.sources.push(Source::Text(name.to_owned(), text.to_owned()));
+        self
+    }
+
+    /// Adds the source file at `path`, which is read when assembling.
+    pub fn file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.sources.push(Source::File(path.as_ref().to_owned()));
+        self
+    }
+
+    /// Adds a directory to search for files included with `.include`. Files
+    /// are searched for relative to the including file first, then in each
+    /// include path in the order they were added.
+    pub fn include_path<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.include_paths.push(path.as_ref().to_owned());
+        self
+    }
+
+    /// Places the section `name` at `addr` when linking the program.
+    pub fn section_address(mut self, name: &str, addr: u32) -> Self {
+        self.section_addresses.push((name.to_owned(), addr));
+        self
+    }
+
+    fn preprocess(&self) -> Result<Vec<SourceLine>, Diagnostics> {
+        let mut preprocessor = Preprocessor::new(self.include_paths.clone());
+
+        for source in &self.sources {
+            match *source {
+                Source::File(ref path) => preprocessor.process_file(path)?,
+                Source::Text(ref name, ref text) => preprocessor.process_source(name, None, text)?
+            }
+        }
+
+        Ok(preprocessor.into_lines())
+    }
+
+    /// Assembles the sources, in the order they were added, into a
+    /// relocatable object.
+    pub fn assemble_object(&self) -> Result<Object, Diagnostics> {
+        let lines = self.preprocess()?;
+        assemble(&lines).map(|(object, _)| object)
+    }
+
+    /// Assembles the sources, in the order they were added, and links them
+    /// into a program. The sources must not refer to any external symbols.
+    pub fn assemble(&self) -> Result<Program, Diagnostics> {
+        let lines = self.preprocess()?;
+        let (object, mappings) = assemble(&lines)?;
+
+        let mut linker = Linker::new();
+
+        for &(ref name, addr) in &self.section_addresses {
+            linker.set_section_address(name, addr);
+        }
+
+        linker.add_object(object);
+
+        let image = linker.link().map_err(|e| Diagnostic::new(None, e.to_string()))?;
+        let source_map = lines.iter().zip(mappings).map(|(line, (section, offset, size, labels))| {
+            SourceMapping {
+                address: image.section_addresses[0][section].wrapping_add(offset),
+                size: size,
+                location: line.location.clone(),
+                text: line.text.clone(),
+                labels: labels.into_iter().map(|l| l.to_owned()).collect()
+            }
+        }).collect();
+
+        Ok(Program {
+            address: image.address,
+            segments: image.segments,
+            symbols: image.symbols,
+            sections: image.sections,
+            source_map: source_map
+        })
+    }
+}
+
+/// Parses an address given on the command line, in decimal or in hex with a
+/// `0x` prefix, as taken by `svm`, `sasm` and `slink`.
+pub fn parse_address(addr: &str) -> Option<u32> {
+    if addr.starts_with("0x") || addr.starts_with("0X") {
+        u32::from_str_radix(&addr[2..], 16).ok()
+    } else {
+        addr.parse().ok()
+    }
+}
+
+fn error<S: Into<String>>(location: &Location, message: S) -> Diagnostics {
+    Diagnostic::new(Some(location.clone()), message).into()
+}
+
+/// Returns `length`, the new length of the section `name`, unless it
+/// overflowed or the section is stored and would be larger than `MAX_RAW_SIZE`.
+fn check_length(name: &str, length: Option<u32>) -> Result<u32, String> {
+    match length {
+        Some(length) if name == ZERO_SECTION || length as u64 <= MAX_RAW_SIZE => Ok(length),
+        _ => Err("Section is too large".to_owned())
+    }
+}
+
+/// Returns a relocation of `kind` at `offset` in the section at index
+/// `section`, for the address of `base` plus `addend`.
+fn object_relocation(section: usize, offset: u32, kind: RelocationKind, base: Base, addend: u32) -> Relocation {
+    Relocation {
+        section: section as u32,
+        offset: offset,
+        kind: kind,
+        target: match base {
+            Base::Section(i) => Target::Section(i as u32),
+            Base::External(name) => Target::Symbol(name.to_owned())
+        },
+        addend: addend
+    }
+}
+
+/// Definition of a constant by `.equ` or `.set`, as its location, name and
+/// value.
+type Constant<'a> = (&'a Location, &'a str, ImmediatePlaceholder<'a>);
+
+/// Returns `symbols` along with the value of each constant at `position`, the
+/// number of definitions in `constants` which come before it.
+///
+/// A constant has the value of its latest definition before `position`, or of
+/// its first definition if it is only defined later. Constants whose visible
+/// definition has no value in `values` are left out.
+fn scope<'a>(symbols: &HashMap<&'a str, Relocatable<'a>>, constants: &[Constant<'a>],
+             values: &[Option<Relocatable<'a>>], position: usize) -> HashMap<&'a str, Relocatable<'a>> {
+    let mut visible = HashMap::new();
+
+    for (i, &(_, name, _)) in constants.iter().enumerate() {
+        if i < position || !visible.contains_key(name) {
+            visible.insert(name, i);
+        }
+    }
+
+    let mut scope = symbols.clone();
+
+    for (name, i) in visible {
+        if let Some(ref value) = values[i] {
+            scope.insert(name, value.clone());
+        }
+    }
+
+    scope
+}
+
+/// Evaluates the `.equ` and `.set` constants in `constants`, returning the
+/// value of each definition along with the errors for any that couldn't be
+/// evaluated. Each definition sees the constants in scope at its own
+/// position, so `.set N, N + 1` refers to the previous value of `N`.
+///
+/// Constants may refer to labels and to each other in any order, so we
+/// repeatedly evaluate whatever we can until no further progress is made.
+fn resolve_constants<'a>(symbols: &HashMap<&'a str, Relocatable<'a>>, constants: &[Constant<'a>])
+                         -> (Vec<Option<Relocatable<'a>>>, Vec<Diagnostic>) {
+    let mut values = vec![None; constants.len()];
+    let mut unresolved = constants.len();
+    let mut errors = Vec::new();
+
+    while unresolved > 0 {
+        let count = unresolved;
+        errors.clear();
+
+        for (i, &(location, _, ref value)) in constants.iter().enumerate() {
+            if values[i].is_some() {
+                continue;
+            }
+
+            match value.evaluate(&scope(symbols, constants, &values, i), None) {
+                Ok(value) => {
+                    values[i] = Some(value);
+                    unresolved -= 1;
+                },
+                Err(e) => errors.push(Diagnostic::new(Some(location.clone()), e))
+            }
+        }
+
+        if unresolved == count {
+            break;
+        }
+    }
+
+    (values, errors)
+}
+
+/// Evaluates `value`, which must be constant, using the labels in `symbols`
+/// and whichever of `constants` can be resolved so far.
+///
+/// Used by directives which change the layout of a section, as these must be
+/// known before the whole program has been seen.
+fn evaluate_constant<'a>(value: &ImmediatePlaceholder<'a>,
+                         symbols: &HashMap<&'a str, Relocatable<'a>>,
+                         constants: &[Constant<'a>]) -> Result<u32, String> {
+    // Constants which can't be resolved yet are only an error if `value`
+    // refers to them, which evaluating it will catch
+    let (values, _) = resolve_constants(symbols, constants);
+
+    let value = value.evaluate(&scope(symbols, constants, &values, constants.len()), None)?;
+
+    if value.is_constant() {
+        Ok(value.offset)
+    } else {
+        Err("Expression must be constant".to_owned())
+    }
+}
+
+/// Section index, offset and size of the bytes produced by each line, along
+/// with the labels it refers to.
+type Mappings<'a> = Vec<(usize, u32, u32, Vec<&'a str>)>;
+
+fn assemble(lines: &[SourceLine]) -> Result<(Object, Mappings), Diagnostics> {
+    let mut symbols = HashMap::new();
+    let mut labels: Vec<(&str, usize, u32)> = Vec::new();
+    let mut globals = Vec::new();
+    let mut externs = Vec::new();
+    let mut constants: Vec<Constant> = Vec::new();
+    let mut redefinable_constants = Vec::new();
+    let mut instrs = Vec::new();
+    let mut mappings = Vec::with_capacity(lines.len());
+    // Name, length and alignment of each section, in the order they're first used
+    let mut sections: Vec<(&str, u32, u32)> = vec![(".text", 0, 1)];
+    let mut current = 0;
+    // Syntax errors don't affect the rest of the program, so we report all
+    // of them at once
+    let mut errors = Vec::new();
+
+    for &SourceLine { ref text, ref location } in lines {
+        mappings.push((current, sections[current].1, 0, Vec::new()));
+
+        if text.trim().len() == 0 {
+            continue;
+        }
+
+        let (label, instr) = match parser::parse_line(text).to_full_result() {
+            Ok(result) => result,
+            Err(_) => {
+                errors.push(Diagnostic::new(Some(location.clone()), "Syntax error"));
+                continue;
+            }
+        };
+
+        if let Some(ref instr) = instr {
+            instr.labels(&mut mappings.last_mut().unwrap().3);
+        }
+
+        if let Some(label) = label {
+            if labels.iter().any(|&(l, _, _)| l == label) {
+                return Err(error(location, format!("Label redefined: {}", label)));
+            }
+
+            let length = sections[current].1;
+            symbols.insert(label, Relocatable::section(current, length));
+            labels.push((label, current, length));
+        }
+
+        match instr {
+            Some(InstructionPlaceholder::Constant { name, value, redefinable }) => {
+                let index = constants.iter().position(|&(_, n, _)| n == name);
+
+                match index {
+                    Some(_) if redefinable && redefinable_constants.contains(&name) =>
+                        constants.push((location, name, value)),
+                    Some(_) => return Err(error(location, format!("Constant redefined: {}", name))),
+                    None => constants.push((location, name, value))
+                }
+
+                if redefinable {
+                    redefinable_constants.push(name);
+                }
+            },
+            Some(InstructionPlaceholder::Global(name)) => globals.push((location, name)),
+            Some(InstructionPlaceholder::Extern(name)) => externs.push((location, name)),
+            Some(InstructionPlaceholder::SwitchSection(name)) => {
+                let index = sections.iter().position(|&(n, _, _)| n == name);
+
+                current = match index {
+                    Some(i) => i,
+                    None => {
+                        sections.push((name, 0, 1));
+                        sections.len() - 1
+                    }
+                };
+
+                // Lines which switch section belong to the new one
+                *mappings.last_mut().unwrap() = (current, sections[current].1, 0, Vec::new());
+            },
+            Some(InstructionPlaceholder::Org(value)) => {
+                let offset = evaluate_constant(&value, &symbols, &constants).map_err(|e| error(location, e))?;
+
+                if offset < sections[current].1 {
+                    return Err(error(location, ".org cannot move backwards"));
+                }
+
+                sections[current].1 = check_length(sections[current].0, Some(offset)).map_err(|e| error(location, e))?;
+            },
+            Some(InstructionPlaceholder::Space(value)) => {
+                let size = evaluate_constant(&value, &symbols, &constants).map_err(|e| error(location, e))?;
+                let length = sections[current].1.checked_add(size);
+                sections[current].1 = check_length(sections[current].0, length).map_err(|e| error(location, e))?;
+            },
+            Some(InstructionPlaceholder::Align(value)) => {
+                let align = evaluate_constant(&value, &symbols, &constants).map_err(|e| error(location, e))?;
+
+                if !align.is_power_of_two() {
+                    return Err(error(location, "Alignment must be a power of two"));
+                }
+
+                let length = sections[current].1.checked_add(align - 1).map(|length| length & !(align - 1));
+                sections[current].1 = check_length(sections[current].0, length).map_err(|e| error(location, e))?;
+                sections[current].2 = cmp::max(sections[current].2, align);
+            },
+            Some(instr) => {
+                if sections[current].0 == ZERO_SECTION {
+                    return Err(error(location, format!("Cannot emit data in {}", ZERO_SECTION)));
+                }
+
+                let length = sections[current].1;
+                sections[current].1 += instr.size();
+                mappings.last_mut().unwrap().2 = instr.size();
+                instrs.push((location, current, length, constants.len(), instr));
+            },
+            None => {}
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(Diagnostics { errors: errors });
+    }
+
+    for &(location, name, _) in &constants {
+        if symbols.contains_key(name) {
+            return Err(error(location, format!("Constant has the same name as a label: {}", name)));
+        }
+    }
+
+    for &(location, name) in &externs {
+        if symbols.contains_key(name) || constants.iter().any(|&(_, n, _)| n == name) {
+            return Err(error(location, format!("External symbol defined locally: {}", name)));
+        }
+
+        symbols.insert(name, Relocatable::external(name));
+    }
+
+    let (values, unresolved) = resolve_constants(&symbols, &constants);
+
+    if !unresolved.is_empty() {
+        return Err(Diagnostics { errors: unresolved });
+    }
+
+    // Symbols as seen by the instructions since the last constant definition
+    let mut visible = (0, scope(&symbols, &constants, &values, 0));
+
+    let mut object = Object::default();
+    object.sections = sections.iter().map(|&(name, _, _)| Section::new(name)).collect();
+
+    for (location, index, offset, position, instr) in instrs {
+        if position != visible.0 {
+            visible = (position, scope(&symbols, &constants, &values, position));
+        }
+
+        let symbols = &visible.1;
+        let size = instr.size();
+        let data = &mut object.sections[index].data;
+
+        // Fill any gap left by `.org`, `.space` or `.align`
+        data.resize(offset as usize, 0);
+
+        match instr {
+            InstructionPlaceholder::StringLiteral(string) => data.extend(string.bytes()),
+            InstructionPlaceholder::Words(words) => {
+                for word in words {
+                    let word_offset = data.len() as u32;
+                    let value = match word.evaluate(symbols, None).and_then(|value| value.into_immediate(index)) {
+                        Ok((value, None)) => value,
+                        // Addresses are stored whole, so only absolute ones can be relocated
+                        Ok((value, Some((RelocationKind::Absolute, base, addend)))) => {
+                            object.relocations.push(object_relocation(index, word_offset, RelocationKind::Word, base, addend));
+                            value
+                        },
+                        Ok(_) => {
+                            errors.push(Diagnostic::new(Some(location.clone()), "Expression cannot be relocated"));
+                            0
+                        },
+                        Err(e) => {
+                            errors.push(Diagnostic::new(Some(location.clone()), e));
+                            0
+                        }
+                    };
+
+                    data.write_u32::<LittleEndian>(value).unwrap();
+                }
+            },
+            _ => {
+                let (instr, relocation) = match instr.into_instr(symbols, index, offset + size) {
+                    Ok(result) => result,
+                    Err(e) => {
+                        // Keep the offsets of later instructions correct
+                        data.resize((offset + size) as usize, 0);
+                        errors.push(Diagnostic::new(Some(location.clone()), e));
+                        continue;
+                    }
+                };
+
+                if let Some((kind, base, addend)) = relocation {
+                    object.relocations.push(object_relocation(index, offset, kind, base, addend));
+                }
+
+                instr.write_bytes(data).map_err(|e| error(location, format!("{:?}", e)))?;
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(Diagnostics { errors: errors });
+    }
+
+    for (section, &(_, length, align)) in object.sections.iter_mut().zip(&sections) {
+        section.size = length;
+        section.align = align;
+
+        if !section.is_zero() {
+            section.data.resize(length as usize, 0);
+        }
+    }
+
+    for &(location, name) in &globals {
+        if !labels.iter().any(|&(l, _, _)| l == name) {
+            return Err(error(location, format!("Global symbol not defined: {}", name)));
+        }
+    }
+
+    for (name, section, value) in labels {
+        let global = globals.iter().any(|&(_, g)| g == name);
+
+        object.symbols.push(Symbol {
+            name: name.to_owned(),
+            binding: if global { Binding::Global } else { Binding::Local },
+            section: section as u32,
+            value: value
+        });
+    }
+
+    for (_, name) in externs {
+        object.symbols.push(Symbol { name: name.to_owned(), binding: Binding::Extern, section: 0, value: 0 });
+    }
+
+    Ok((object, mappings))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    use {Binding, Object, Relocation, RelocationKind, Section, Symbol, Target};
+
+    use super::{parse_address, Assembler, Diagnostic, Diagnostics, Location, SourceMapping};
+
+    fn assemble_object(source: &str) -> Result<Object, String> {
+        Assembler::new().source("test.sasm", source).assemble_object().map_err(|e| e.to_string())
+    }
+
+    fn assemble(source: &str) -> Result<Vec<u8>, String> {
+        Assembler::new().source("test.sasm", source).assemble().map(|p| p.bytes()).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn address() {
+        assert_eq!(parse_address("4096"), Some(4096));
+        assert_eq!(parse_address("0x1000"), Some(4096));
+        assert_eq!(parse_address("0XfFfF"), Some(0xffff));
+        assert_eq!(parse_address("0x"), None);
+        assert_eq!(parse_address("0x100000000"), None);
+        assert_eq!(parse_address("-1"), None);
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(assemble("add r0, r0, r1"), Ok(vec![0x02, 0x00, 0x01, 0x00]));
+        assert_eq!(assemble("addi r0, r0, 4"), Ok(vec![0x12, 0x00, 0x04, 0x00]));
+
+        assert_eq!(assemble("label:\n add r2, r2, r3\n addi r0, r0, $label"),
+            Ok(vec![0x82, 0x10, 0x03, 0x00, 0x12, 0x00, 0xf8, 0xff]));
+        
+        assert_eq!(assemble("load r0, r2, %label\n label:"),
+            Ok(vec![0x34, 0x10, 0x04, 0x00]));
+
+        assert_eq!(assemble(".equ SIZE, HALF * 2\n .equ HALF, 4\n addi r4, r0, SIZE"),
+            Ok(vec![0x12, 0x01, 0x08, 0x00]));
+
+        assert_eq!(assemble("lui r4, hi(%label)\n addi r4, r4, lo(%label)\n label:"),
+            Ok(vec![0x32, 0x01, 0x00, 0x00, 0x12, 0x21, 0x08, 0x00]));
+
+        assert_eq!(assemble(".set A, 1\n .set A, 2\n addi r4, r0, A"),
+            Ok(vec![0x12, 0x01, 0x02, 0x00]));
+        assert_eq!(assemble(".set A, 1\n addi r4, r0, A\n .set A, 2\n addi r5, r0, A"),
+            Ok(vec![0x12, 0x01, 0x01, 0x00, 0x52, 0x01, 0x02, 0x00]));
+        assert_eq!(assemble(".set N, 1\n .set N, N + 1\n addi r4, r0, N\n .set N, N + 1\n .space N"),
+            Ok(vec![0x12, 0x01, 0x02, 0x00, 0x00, 0x00, 0x00]));
+        assert_eq!(assemble(".set N, N + 1"), Err("test.sasm:1: Label not found: N".to_owned()));
+        assert_eq!(assemble("value:\n.equ value, 1"),
+            Err("test.sasm:2: Constant has the same name as a label: value".to_owned()));
+        assert_eq!(assemble(".equ A, 1\n .equ A, 2"),
+            Err("test.sasm:2: Constant redefined: A".to_owned()));
+
+        assert_eq!(assemble(".macro li reg, value\n addi \\reg, r0, \\value\n.endm\n li r4, 8"),
+            Ok(vec![0x12, 0x01, 0x08, 0x00]));
+
+        assert_eq!(assemble(".macro bad\n addi r4, r0, %missing\n.endm\n bad"),
+            Err("test.sasm:2 in macro `bad` invoked at test.sasm:4: Label not found: missing".to_owned()));
+    }
+
+    #[test]
+    fn object() {
+        let source = ".global start\n.extern print\nstart:\n lui r4, hi(%print)\n addi r4, r4, lo(%print)\n \
+                      c.addi r0, $print\nloop:\n c.addi r0, $loop";
+
+        let print = || Target::Symbol("print".to_owned());
+
+        assert_eq!(assemble_object(source), Ok(Object {
+            sections: vec![Section {
+                name: ".text".to_owned(),
+                data: vec![0x32, 0x01, 0x00, 0x00, 0x12, 0x21, 0x00, 0x00, 0x13, 0xec, 0x13, 0xfc],
+                size: 12,
+                align: 1
+            }],
+            symbols: vec![
+                Symbol { name: "start".to_owned(), binding: Binding::Global, section: 0, value: 0 },
+                Symbol { name: "loop".to_owned(), binding: Binding::Local, section: 0, value: 10 },
+                Symbol { name: "print".to_owned(), binding: Binding::Extern, section: 0, value: 0 }
+            ],
+            relocations: vec![
+                Relocation { section: 0, offset: 0, kind: RelocationKind::High, target: print(), addend: 0 },
+                Relocation { section: 0, offset: 4, kind: RelocationKind::Low, target: print(), addend: 0 },
+                Relocation { section: 0, offset: 8, kind: RelocationKind::Relative, target: print(),
+                             addend: -10i32 as u32 }
+            ]
+        }));
+
+        assert_eq!(assemble(".extern print\n c.addi r0, $print"),
+            Err("Link error: undefined symbol (print)".to_owned()));
+        assert_eq!(assemble_object(".global missing"),
+            Err("test.sasm:1: Global symbol not defined: missing".to_owned()));
+    }
+
+    #[test]
+    fn sections() {
+        let source = ".bss\nbuffer: .space 16\n.data\nvalue:\n bytes \"hi\"\n.text\n addi r4, r0, %buffer\n \
+                      addi r5, r0, %value\n.org 12\n c.addi r0, $value";
+
+        assert_eq!(assemble(source), Ok(vec![
+            0x12, 0x01, 0x14, 0x00, 0x52, 0x01, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x13, 0x04, 0x00, 0x00, 0x68, 0x69
+        ]));
+
+        let object = assemble_object(source).unwrap();
+        let names: Vec<&str> = object.sections.iter().map(|s| &s.name[..]).collect();
+        assert_eq!(names, vec![".text", ".bss", ".data"]);
+        assert_eq!((object.sections[1].size, object.sections[1].data.len()), (16, 0));
+
+        let program = Assembler::new().source("test.sasm", source).section_address(".data", 0x40).assemble().unwrap();
+        assert_eq!((program.address, program.bytes().len()), (0, 0x42));
+        assert_eq!(program.symbol("buffer"), Some(0x44));
+        assert_eq!((program.section(".text"), program.section(".data"), program.section(".rodata")),
+                   (Some(0), Some(0x40), None));
+        assert_eq!(program.segments.iter().map(|s| (s.addr, s.data.len())).collect::<Vec<_>>(), vec![(0, 14), (0x40, 2)]);
+
+        assert_eq!(assemble(".bss\n bytes \"hi\""), Err("test.sasm:2: Cannot emit data in .bss".to_owned()));
+        assert_eq!(assemble(" c.addi r0, 0\n.org 1"), Err("test.sasm:2: .org cannot move backwards".to_owned()));
+        assert_eq!(assemble(".align 3"), Err("test.sasm:1: Alignment must be a power of two".to_owned()));
+        assert_eq!(assemble(".space 0x4000001"), Err("test.sasm:1: Section is too large".to_owned()));
+        assert_eq!(assemble(".data\n.org 0x7fffffff"), Err("test.sasm:2: Section is too large".to_owned()));
+        assert_eq!(assemble(".bss\n.space 0x7fffffff\n.align 0x10000000"), Ok(vec![]));
+        assert_eq!(assemble(".bss\n.space 0x7fffffff\n.space 0x7fffffff\n.space 2"),
+            Err("test.sasm:4: Section is too large".to_owned()));
+        assert_eq!(assemble(".bss\n.space 0x7fffffff\n.space 0x7fffffff\n.align 0x10"),
+            Err("test.sasm:4: Section is too large".to_owned()));
+        assert_eq!(assemble(".space SIZE\n.equ SIZE, 4"), Err("test.sasm:1: Label not found: SIZE".to_owned()));
+
+        assert_eq!(assemble(" c.li r4, 1\n.align 4\n.word 0x12345678, end - start\nstart:\nend:"),
+            Ok(vec![0x31, 0x03, 0x00, 0x00, 0x78, 0x56, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00]));
+        assert_eq!(assemble(".word start, %start + 2\nstart:"), Ok(vec![0x08, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00]));
+        assert_eq!(assemble(".word hi(%start)\nstart:"), Err("test.sasm:1: Expression cannot be relocated".to_owned()));
+
+        let object = assemble_object(".extern handler\n.data\n.word 0, %handler").unwrap();
+        assert_eq!(object.relocations, vec![Relocation {
+            section: 1,
+            offset: 4,
+            kind: RelocationKind::Word,
+            target: Target::Symbol("handler".to_owned()),
+            addend: 0
+        }]);
+
+        // The section is placed so that the alignment holds for the address
+        let source = " c.li r4, 1\n.data\n.align 16\nvalue:\n bytes \"hi\"";
+        let program = Assembler::new().source("test.sasm", source).assemble().unwrap();
+        assert_eq!(program.symbol("value"), Some(16));
+    }
+
+    #[test]
+    fn include() {
+        fs::create_dir_all(".include_test_dir").unwrap();
+        File::create(".include_test.sasm").unwrap()
+            .write_all(b".include \"inc.sasm\"\n addi r4, r0, VALUE").unwrap();
+        File::create(".include_test_dir/inc.sasm").unwrap()
+            .write_all(b".equ VALUE, 8").unwrap();
+
+        let assembler = Assembler::new().file(".include_test.sasm");
+
+        assert_eq!(assembler.include_path(".include_test_dir").assemble().map(|p| p.bytes()),
+            Ok(vec![0x12, 0x01, 0x08, 0x00]));
+        assert_eq!(Assembler::new().file(".include_test.sasm").assemble().map_err(|e| e.to_string()),
+            Err(".include_test.sasm:1: Included file not found: \"inc.sasm\"".to_owned()));
+
+        fs::remove_file(".include_test.sasm").unwrap();
+        fs::remove_dir_all(".include_test_dir").unwrap();
+    }
+
+    #[test]
+    fn include_cycle() {
+        File::create(".include_cycle_test.sasm").unwrap()
+            .write_all(b".include \".include_cycle_test.sasm\"").unwrap();
+
+        assert_eq!(Assembler::new().file(".include_cycle_test.sasm").assemble().map_err(|e| e.to_string()),
+            Err(".include_cycle_test.sasm:1: .include_cycle_test.sasm: Include cycle detected".to_owned()));
+
+        fs::remove_file(".include_cycle_test.sasm").unwrap();
+    }
+
+    #[test]
+    fn program() {
+        let program = Assembler::new().source("main.sasm", "start:\n c.li r4, 1\n\n bytes \"ab\"\n.data\nvalue:\n c.li r5, 2")
+                                      .source("end.sasm", ".text\nend:")
+                                      .assemble()
+                                      .unwrap();
+
+        let location = |file: &str, line| Location { file: file.to_owned(), line: line, expansions: Vec::new() };
+
+        assert_eq!(program.bytes(), vec![0x31, 0x03, 0x61, 0x62, 0x71, 0x05]);
+        assert_eq!(program.symbols, vec![("start".to_owned(), 0), ("value".to_owned(), 4), ("end".to_owned(), 4)]);
+        assert_eq!(program.source_map.len(), 9);
+        assert_eq!(program.source_map[3], SourceMapping {
+            address: 2,
+            size: 2,
+            location: location("main.sasm", 4),
+            text: " bytes \"ab\"".to_owned(),
+            labels: Vec::new()
+        });
+
+        let mapped: Vec<(u32, u32, usize)> = program.source_map.iter()
+                                                               .filter(|m| m.size > 0)
+                                                               .map(|m| (m.address, m.size, m.location.line))
+                                                               .collect();
+        assert_eq!(mapped, vec![(0, 2, 2), (2, 2, 4), (4, 2, 7)]);
+
+        assert_eq!(program.location(3), Some(&location("main.sasm", 4)));
+        assert_eq!(program.location(6), None);
+        assert_eq!(program.symbol("missing"), None);
+    }
+
+    #[test]
+    fn diagnostics() {
+        let result = Assembler::new().source("test.sasm", "bad\n c.li r4, 1\n add r4\n c.li r5, %missing").assemble();
+
+        assert_eq!(result, Err(Diagnostics { errors: vec![
+            Diagnostic::new(Some(Location { file: "test.sasm".to_owned(), line: 1, expansions: Vec::new() }), "Syntax error"),
+            Diagnostic::new(Some(Location { file: "test.sasm".to_owned(), line: 3, expansions: Vec::new() }), "Syntax error")
+        ]}));
+
+        assert_eq!(assemble(" c.li r4, %a\n c.li r5, %b"),
+            Err("test.sasm:1: Label not found: a\ntest.sasm:2: Label not found: b".to_owned()));
+    }
+}
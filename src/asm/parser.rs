@@ -0,0 +1,902 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use nom::{ErrorKind, IResult, alpha, alphanumeric, digit, not_line_ending};
+
+use {Instruction, OpCode, RelocationKind};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum UnaryOperator {
+    Negate,
+    Not,
+    /// Upper half of a value, adjusted so that adding the sign extended
+    /// `Low` half yields the original value again. The pair is meant for
+    /// `LUI` followed by `ADDI`; `ORI` only gives back the value when its bit
+    /// 15 is clear, so `Low` is an error there otherwise.
+    High,
+    Low
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BinaryOperator {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Shl,
+    Shr,
+    And,
+    Or,
+    Xor
+}
+
+/// Binary operators grouped by precedence, from loosest to tightest binding.
+const BINARY_OPERATORS: &'static [&'static [(&'static str, BinaryOperator)]] = &[
+    &[("|", BinaryOperator::Or)],
+    &[("^", BinaryOperator::Xor)],
+    &[("&", BinaryOperator::And)],
+    &[("<<", BinaryOperator::Shl), (">>", BinaryOperator::Shr)],
+    &[("+", BinaryOperator::Add), ("-", BinaryOperator::Sub)],
+    &[("*", BinaryOperator::Mul), ("/", BinaryOperator::Div), ("%", BinaryOperator::Rem)]
+];
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ImmediatePlaceholder<'a> {
+    Value(u32),
+    LabelAbsolute(&'a str),
+    LabelRelative(&'a str),
+    Unary(UnaryOperator, Box<ImmediatePlaceholder<'a>>),
+    Binary(BinaryOperator, Box<ImmediatePlaceholder<'a>>, Box<ImmediatePlaceholder<'a>>)
+}
+
+/// Base address that the value of a `Relocatable` is relative to.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Base<'a> {
+    /// Start of the section at this index in the program being assembled.
+    Section(usize),
+    /// Symbol declared with `.extern`, defined in another object.
+    External(&'a str)
+}
+
+/// Value of an expression which may depend on addresses that are only known
+/// once the program is linked.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Relocatable<'a> {
+    pub offset: u32,
+    /// Bases added to `offset`, along with their coefficients.
+    bases: Vec<(Base<'a>, i32)>,
+    /// Set once `hi()` or `lo()` is applied. Values that aren't constant allow
+    /// no further arithmetic, while constants keep it until the next operator.
+    split: Option<UnaryOperator>
+}
+
+impl<'a> Relocatable<'a> {
+    pub fn constant(value: u32) -> Self {
+        Relocatable { offset: value, bases: Vec::new(), split: None }
+    }
+
+    pub fn section(index: usize, offset: u32) -> Self {
+        Relocatable { offset: offset, bases: vec![(Base::Section(index), 1)], split: None }
+    }
+
+    pub fn external(name: &'a str) -> Self {
+        Relocatable { offset: 0, bases: vec![(Base::External(name), 1)], split: None }
+    }
+
+    pub fn is_constant(&self) -> bool {
+        self.bases.is_empty()
+    }
+
+    /// Returns the sum of this value and `other` multiplied by `factor`.
+    fn add(mut self, other: Relocatable<'a>, factor: i32) -> Self {
+        self.offset = self.offset.wrapping_add(other.offset.wrapping_mul(factor as u32));
+
+        for (base, coefficient) in other.bases {
+            let index = self.bases.iter().position(|&(b, _)| b == base);
+
+            match index {
+                Some(i) => self.bases[i].1 += coefficient * factor,
+                None => self.bases.push((base, coefficient * factor))
+            }
+        }
+
+        self.bases.retain(|&(_, coefficient)| coefficient != 0);
+        self
+    }
+
+    /// Converts this value into an immediate, along with the relocation needed
+    /// to fix it up at link time, if any.
+    ///
+    /// The immediate is the value the expression would have if every section
+    /// were placed at address 0. Relative values must be relative to the start
+    /// of `section`, the section containing the instruction.
+    pub fn into_immediate(self, section: usize) -> Result<(u32, RelocationPlaceholder<'a>), String> {
+        let mut target = None;
+        let mut relative = false;
+
+        for &(base, coefficient) in &self.bases {
+            match (base, coefficient) {
+                (Base::Section(index), -1) if index == section && !relative => relative = true,
+                (_, 1) if target.is_none() => target = Some(base),
+                _ => return Err("Expression cannot be relocated".to_owned())
+            }
+        }
+
+        let target = match target {
+            Some(target) => target,
+            None if !relative => return Ok((self.offset, None)),
+            None => return Err("Expression cannot be relocated".to_owned())
+        };
+
+        let kind = match (relative, self.split) {
+            (false, None) => RelocationKind::Absolute,
+            (true, None) => RelocationKind::Relative,
+            (false, Some(UnaryOperator::High)) => RelocationKind::High,
+            (false, Some(UnaryOperator::Low)) => RelocationKind::Low,
+            _ => return Err("Expression cannot be relocated".to_owned())
+        };
+
+        Ok((kind.apply(self.offset), Some((kind, target, self.offset))))
+    }
+}
+
+impl<'a> ImmediatePlaceholder<'a> {
+    /// Appends the name of every label this expression refers to to `labels`.
+    pub fn labels(&self, labels: &mut Vec<&'a str>) {
+        use self::ImmediatePlaceholder::*;
+
+        match *self {
+            Value(_) => {},
+            LabelAbsolute(label) | LabelRelative(label) => labels.push(label),
+            Unary(_, ref operand) => operand.labels(labels),
+            Binary(_, ref lhs, ref rhs) => {
+                lhs.labels(labels);
+                rhs.labels(labels);
+            }
+        }
+    }
+
+    /// Evaluates this expression using the values in `symbols`.
+    ///
+    /// Relative labels are resolved against `pos`, the section and offset of
+    /// the end of the current instruction, and are an error if there is none.
+    pub fn evaluate(&self, symbols: &HashMap<&'a str, Relocatable<'a>>, pos: Option<(usize, u32)>)
+                -> Result<Relocatable<'a>, String> {
+        use self::ImmediatePlaceholder::*;
+
+        match *self {
+            Value(value) => Ok(Relocatable::constant(value)),
+            LabelAbsolute(label) => symbols.get(label).cloned().ok_or(format!("Label not found: {}", label)),
+            LabelRelative(label) => {
+                let (section, offset) = pos.ok_or(format!("Relative label not allowed here: ${}", label))?;
+
+                symbols.get(label)
+                       .map(|value| value.clone().add(Relocatable::section(section, offset), -1))
+                       .ok_or(format!("Label not found: {}", label))
+            },
+            Unary(op, ref operand) => {
+                let mut value = operand.evaluate(symbols, pos)?;
+
+                if value.split.is_some() && !value.is_constant() {
+                    return Err("Result of hi() or lo() cannot be used in an expression".to_owned());
+                }
+
+                match op {
+                    UnaryOperator::Negate => Ok(Relocatable::constant(0).add(value, -1)),
+                    UnaryOperator::Not if value.is_constant() => Ok(Relocatable::constant(!value.offset)),
+                    UnaryOperator::High | UnaryOperator::Low if value.is_constant() => {
+                        let kind = if op == UnaryOperator::High { RelocationKind::High } else { RelocationKind::Low };
+                        Ok(Relocatable { split: Some(op), ..Relocatable::constant(kind.apply(value.offset)) })
+                    },
+                    UnaryOperator::High | UnaryOperator::Low => {
+                        value.split = Some(op);
+                        Ok(value)
+                    },
+                    _ => Err("Expression cannot be relocated".to_owned())
+                }
+            },
+            Binary(op, ref lhs, ref rhs) => {
+                let lhs = lhs.evaluate(symbols, pos)?;
+                let rhs = rhs.evaluate(symbols, pos)?;
+
+                if (lhs.split.is_some() && !lhs.is_constant()) || (rhs.split.is_some() && !rhs.is_constant()) {
+                    return Err("Result of hi() or lo() cannot be used in an expression".to_owned());
+                }
+
+                match op {
+                    BinaryOperator::Add => return Ok(Relocatable { split: None, ..lhs.add(rhs, 1) }),
+                    BinaryOperator::Sub => return Ok(Relocatable { split: None, ..lhs.add(rhs, -1) }),
+                    _ if !lhs.is_constant() || !rhs.is_constant() => return Err("Expression cannot be relocated".to_owned()),
+                    _ => {}
+                }
+
+                let (lhs, rhs) = (lhs.offset, rhs.offset);
+
+                Ok(Relocatable::constant(match op {
+                    BinaryOperator::Mul => lhs.wrapping_mul(rhs),
+                    BinaryOperator::Div | BinaryOperator::Rem if rhs == 0 => return Err("Division by zero".to_owned()),
+                    BinaryOperator::Div => (lhs as i32).wrapping_div(rhs as i32) as u32,
+                    BinaryOperator::Rem => (lhs as i32).wrapping_rem(rhs as i32) as u32,
+                    BinaryOperator::Shl => lhs.wrapping_shl(rhs),
+                    BinaryOperator::Shr => lhs.wrapping_shr(rhs),
+                    BinaryOperator::And => lhs & rhs,
+                    BinaryOperator::Or => lhs | rhs,
+                    BinaryOperator::Xor => lhs ^ rhs,
+                    BinaryOperator::Add | BinaryOperator::Sub => unreachable!()
+                }))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum InstructionPlaceholder<'a> {
+    Register { op: OpCode, dst: usize, src1: usize, src2: usize },
+    Immediate { op: OpCode, dst: usize, src1: usize, imm: ImmediatePlaceholder<'a> },
+    Store { op: OpCode, src1: usize, src2: usize, imm: ImmediatePlaceholder<'a> },
+    Upper { op: OpCode, dst: usize, imm: ImmediatePlaceholder<'a> },
+    StringLiteral(String),
+    /// `.word` directive, emitting 32 bit values, which may be addresses.
+    Words(Vec<ImmediatePlaceholder<'a>>),
+    /// `.equ` and `.set` directives. Only constants defined by `.set` may be
+    /// redefined, in which case each use sees the latest definition before it.
+    Constant { name: &'a str, value: ImmediatePlaceholder<'a>, redefinable: bool },
+    /// `.global` directive, exporting a label to other objects.
+    Global(&'a str),
+    /// `.extern` directive, declaring a symbol defined in another object.
+    Extern(&'a str),
+    /// `.text`, `.data`, `.rodata`, `.bss` and `.section` directives, switching
+    /// to the named section.
+    SwitchSection(&'a str),
+    /// `.org` directive, moving to an offset from the start of the section.
+    Org(ImmediatePlaceholder<'a>),
+    /// `.space` directive, reserving a number of zero bytes.
+    Space(ImmediatePlaceholder<'a>),
+    /// `.align` directive, padding to a multiple of a power of two.
+    Align(ImmediatePlaceholder<'a>)
+}
+
+/// Relocation needed by an instruction, as its kind, target and addend.
+pub type RelocationPlaceholder<'a> = Option<(RelocationKind, Base<'a>, u32)>;
+
+impl<'a> InstructionPlaceholder<'a> {
+    /// Consumes this placeholder, returning the finalised `Instruction` and
+    /// the relocation needed for its immediate, if any. `pos` is the offset of
+    /// the end of the instruction within the section at index `section`.
+    pub fn into_instr(self, symbols: &HashMap<&'a str, Relocatable<'a>>, section: usize, pos: u32)
+                  -> Result<(Instruction, RelocationPlaceholder<'a>), String> {
+        // `ORI` sign extends its immediate too, so a lower half with bit 15
+        // set would also set every bit of the upper half. The value is checked
+        // rather than the syntax, as it may come from a constant. Relocated
+        // values are checked by the linker instead.
+        match self {
+            InstructionPlaceholder::Immediate { op: OpCode::ORI, ref imm, .. } |
+            InstructionPlaceholder::Immediate { op: OpCode::C_ORI, ref imm, .. } => {
+                let value = imm.evaluate(symbols, Some((section, pos)))?;
+
+                if value.is_constant() && value.split == Some(UnaryOperator::Low) && value.offset & 0x8000 != 0 {
+                    return Err("lo() cannot be used with ORI when bit 15 is set, use ADDI instead".to_owned());
+                }
+            },
+            _ => {}
+        }
+
+        macro_rules! replace_labels {
+            ($($instr:ident { $($field:ident),+ $(@$imm:ident)* }),*) => {
+                match self {
+                    $(InstructionPlaceholder::$instr { $($field,)+ $($imm)* } =>
+                        replace_labels!(__impl $instr { $($field),+ $(@$imm)* }),)*
+                    _ => unreachable!()
+                }
+            };
+            (__impl $instr:ident { $($field:ident),+ $(@$imm:ident)+ }) => {
+                $($imm)+.evaluate(symbols, Some((section, pos)))
+                        .and_then(|value| value.into_immediate(section))
+                        .map(|(imm, relocation)| (Instruction::$instr { $($field,)* imm }, relocation))
+            };
+            (__impl $instr:ident { $($field:ident),* }) => {
+                Ok((Instruction::$instr { $($field),* }, None))
+            }
+        }
+
+        replace_labels!(
+            Register { op, dst, src1, src2 },
+            Immediate { op, dst, src1  @imm },
+            Store { op, src1, src2  @imm },
+            Upper { op, dst  @imm }
+        )
+    }
+
+    /// Appends the name of every label this instruction or directive refers
+    /// to to `labels`.
+    pub fn labels(&self, labels: &mut Vec<&'a str>) {
+        use self::InstructionPlaceholder::*;
+
+        match *self {
+            Immediate { ref imm, .. } | Store { ref imm, .. } | Upper { ref imm, .. } => imm.labels(labels),
+            Words(ref words) => for word in words {
+                word.labels(labels);
+            },
+            Constant { ref value, .. } | Org(ref value) | Space(ref value) | Align(ref value) => value.labels(labels),
+            Register { .. } | StringLiteral(_) | Global(_) | Extern(_) | SwitchSection(_) => {}
+        }
+    }
+
+    /// Returns the size of this instruction in bytes. Directives which affect
+    /// the layout of a section are handled while assembling, so have no size.
+    pub fn size(&self) -> u32 {
+        use self::InstructionPlaceholder::*;
+
+        match *self {
+            Register { op, .. } | Immediate { op, .. } | Store { op, .. } | Upper { op, .. } => {
+                if (op as u32) & 1 == 0 { 4 } else { 2 }
+            },
+            StringLiteral(ref string) => string.len() as u32,
+            Words(ref words) => 4 * words.len() as u32,
+            Constant { .. } | Global(_) | Extern(_) | SwitchSection(_) | Org(_) | Space(_) | Align(_) => 0
+        }
+    }
+}
+
+fn comment(input: &str) -> IResult<&str, ()> {
+    ws!(input, map!(preceded!(char!('#'), not_line_ending), |_| ()))
+}
+
+fn identifier(input: &str) -> IResult<&str, &str> {
+    // `recognize!` allows us to include the inital character in the result
+    recognize!(input, preceded!(alpha, many0!(alt!(alphanumeric | tag!("_")))))
+}
+
+fn label(input: &str) -> IResult<&str, &str> {
+    ws!(input, terminated!(identifier, char!(':')))
+}
+
+fn register(input: &str) -> IResult<&str, usize> {
+    ws!(input, preceded!(tag_no_case!("r"), map_res!(recognize!(many1!(digit)), FromStr::from_str)))
+}
+
+#[allow(unused_variables)]
+fn number_sign(input: &str) -> IResult<&str, i32> {
+    // nom has some bugs / compiler ambiguities surrounding `alt!`, `opt!` etc.
+    // so we have to use this workaround
+    switch!(input, alt_complete!(tag!("-") | tag!("+") | tag!("")),
+        "-" => value!(-1) |
+        _   => value!(1)
+    )
+}
+
+#[allow(unused_variables)]
+fn number_radix(input: &str) -> IResult<&str, u32> {
+    // nom has some bugs / compiler ambiguities surrounding `alt!`, `opt!` etc.
+    // so we have to use this workaround
+    switch!(input, alt_complete!(tag_no_case!("0b") | tag_no_case!("0o") | tag_no_case!("0x") | tag!("")),
+        // `switch!` doesn't accept `|` in patterns, so we can't write
+        // `"0b" | "0B" => ...` like we can in `match`
+        "0b" => value!(2) |
+        "0B" => value!(2) |
+        "0o" => value!(8) |
+        "0O" => value!(8) |
+        "0x" => value!(16) |
+        "0X" => value!(16) |
+        _    => value!(10)
+    )
+}
+
+fn number(input: &str) -> IResult<&str, u32> {
+    do_parse!(input,
+        sign: number_sign >>
+        radix: number_radix >>
+        value: map_res!(
+            recognize!(many1!(alphanumeric)), |s| i32::from_str_radix(s, radix).map(|i| (i * sign) as u32)
+        ) >>
+        (value)
+    )
+}
+
+fn unary(op: UnaryOperator, operand: ImmediatePlaceholder) -> ImmediatePlaceholder {
+    ImmediatePlaceholder::Unary(op, Box::new(operand))
+}
+
+fn expression_unary(input: &str) -> IResult<&str, ImmediatePlaceholder> {
+    // `number` must come first so that negative literals are parsed as values
+    // rather than negated expressions
+    ws!(input, alt_complete!(
+        map!(number, |n| ImmediatePlaceholder::Value(n)) |
+        map!(preceded!(char!('-'), expression_unary), |e| unary(UnaryOperator::Negate, e)) |
+        map!(preceded!(char!('~'), expression_unary), |e| unary(UnaryOperator::Not, e)) |
+        preceded!(char!('+'), expression_unary) |
+        delimited!(char!('('), expression, char!(')')) |
+        map!(delimited!(pair!(tag_no_case!("hi"), char!('(')), expression, char!(')')),
+            |e| unary(UnaryOperator::High, e)) |
+        map!(delimited!(pair!(tag_no_case!("lo"), char!('(')), expression, char!(')')),
+            |e| unary(UnaryOperator::Low, e)) |
+        map!(preceded!(char!('%'), identifier), |l| ImmediatePlaceholder::LabelAbsolute(l)) |
+        map!(preceded!(char!('$'), identifier), |l| ImmediatePlaceholder::LabelRelative(l)) |
+        map!(identifier, |l| ImmediatePlaceholder::LabelAbsolute(l))
+    ))
+}
+
+fn expression_binary(input: &str, precedence: usize) -> IResult<&str, ImmediatePlaceholder> {
+    if precedence == BINARY_OPERATORS.len() {
+        return expression_unary(input);
+    }
+
+    let (mut input, mut lhs) = try_parse!(input, call!(expression_binary, precedence + 1));
+
+    // Operators of the same precedence are left associative, so we fold the
+    // operands as we go rather than recursing
+    loop {
+        let rest = input.trim_left();
+
+        match BINARY_OPERATORS[precedence].iter().find(|&&(token, _)| rest.starts_with(token)) {
+            Some(&(token, op)) => {
+                let (rest, rhs) = try_parse!(&rest[token.len()..], call!(expression_binary, precedence + 1));
+
+                lhs = ImmediatePlaceholder::Binary(op, Box::new(lhs), Box::new(rhs));
+                input = rest;
+            },
+            None => return IResult::Done(input, lhs)
+        }
+    }
+}
+
+fn expression(input: &str) -> IResult<&str, ImmediatePlaceholder> {
+    expression_binary(input, 0)
+}
+
+fn immediate(input: &str) -> IResult<&str, ImmediatePlaceholder> {
+    ws!(input, expression)
+}
+
+fn mnemonic(input: &str) -> IResult<&str, &str> {
+    ws!(input, recognize!(
+        delimited!(
+            opt!(complete!(tag_no_case!("c."))),
+            many1!(alpha),
+            opt!(complete!(tag_no_case!(".u")))
+        )
+    ))
+}
+
+fn string_literal(input: &str) -> IResult<&str, InstructionPlaceholder> {
+    ws!(input, delimited!(
+        char!('"'),
+        map!(
+            recognize!(opt!(is_not!("\""))),
+            |s: &str| InstructionPlaceholder::StringLiteral(s.replace("\\\\", "\\")
+                                                             .replace("\\0", "\0")
+                                                             .replace("\\n", "\n")
+                                                             .replace("\\r", "\r")
+                                                             .replace("\\t", "\t")
+                                                             .replace("\\'", "\'")
+                                                             .replace("\\\"", "\""))
+        ),
+        char!('"')
+    ))
+}
+
+fn section_name(input: &str) -> IResult<&str, &str> {
+    ws!(input, recognize!(pair!(opt!(char!('.')), identifier)))
+}
+
+fn directive(input: &str) -> IResult<&str, InstructionPlaceholder> {
+    ws!(input, alt_complete!(
+        do_parse!(
+            tag_no_case!(".equ") >>
+            name: terminated!(identifier, char!(',')) >>
+            value: immediate >>
+            (InstructionPlaceholder::Constant { name, value, redefinable: false })
+        ) |
+        do_parse!(
+            tag_no_case!(".set") >>
+            name: terminated!(identifier, char!(',')) >>
+            value: immediate >>
+            (InstructionPlaceholder::Constant { name, value, redefinable: true })
+        ) |
+        do_parse!(
+            alt_complete!(tag_no_case!(".global") | tag_no_case!(".globl")) >>
+            name: identifier >>
+            (InstructionPlaceholder::Global(name))
+        ) |
+        do_parse!(
+            tag_no_case!(".extern") >>
+            name: identifier >>
+            (InstructionPlaceholder::Extern(name))
+        ) |
+        map!(preceded!(tag_no_case!(".section"), section_name), |name| InstructionPlaceholder::SwitchSection(name)) |
+        map!(tag_no_case!(".text"), |_| InstructionPlaceholder::SwitchSection(".text")) |
+        map!(tag_no_case!(".data"), |_| InstructionPlaceholder::SwitchSection(".data")) |
+        map!(tag_no_case!(".rodata"), |_| InstructionPlaceholder::SwitchSection(".rodata")) |
+        map!(tag_no_case!(".bss"), |_| InstructionPlaceholder::SwitchSection(".bss")) |
+        map!(preceded!(tag_no_case!(".org"), immediate), |value| InstructionPlaceholder::Org(value)) |
+        map!(preceded!(tag_no_case!(".space"), immediate), |value| InstructionPlaceholder::Space(value)) |
+        map!(preceded!(tag_no_case!(".align"), immediate), |value| InstructionPlaceholder::Align(value)) |
+        do_parse!(
+            tag_no_case!(".word") >>
+            words: separated_nonempty_list!(char!(','), immediate) >>
+            (InstructionPlaceholder::Words(words))
+        )
+    ))
+}
+
+fn instruction_r(input: &str, op: OpCode) -> IResult<&str, InstructionPlaceholder> {
+    do_parse!(input,
+        dst: terminated!(register, char!(',')) >>
+        src1: terminated!(register, char!(',')) >>
+        src2: register >>
+        (InstructionPlaceholder::Register { op, dst, src1, src2 })
+    )
+}
+
+fn instruction_cr(input: &str, op: OpCode) -> IResult<&str, InstructionPlaceholder> {
+    do_parse!(input,
+        dst: terminated!(register, char!(',')) >>
+        src2: register >>
+        (InstructionPlaceholder::Register { op, dst, src1: dst, src2 })
+    )
+}
+
+fn instruction_i(input: &str, op: OpCode) -> IResult<&str, InstructionPlaceholder> {
+    do_parse!(input,
+        dst: terminated!(register, char!(',')) >>
+        src1: terminated!(register, char!(',')) >>
+        imm: immediate >>
+        (InstructionPlaceholder::Immediate { op, dst, src1, imm })
+    )
+}
+
+fn instruction_ci(input: &str, op: OpCode) -> IResult<&str, InstructionPlaceholder> {
+    do_parse!(input,
+        dst: terminated!(register, char!(',')) >>
+        imm: immediate >>
+        (InstructionPlaceholder::Immediate { op, dst, src1: dst, imm })
+    )
+}
+
+fn instruction_call(input: &str, op: OpCode) -> IResult<&str, InstructionPlaceholder> {
+    do_parse!(input,
+        imm: immediate >>
+        (InstructionPlaceholder::Immediate { op, dst: 0, src1 : 0, imm })
+    )
+}
+
+fn instruction_break(input: &str, op: OpCode) -> IResult<&str, InstructionPlaceholder> {
+    value!(input, InstructionPlaceholder::Immediate { op, dst: 0, src1: 0, imm: ImmediatePlaceholder::Value(0) })
+}
+
+fn instruction_s(input: &str, op: OpCode) -> IResult<&str, InstructionPlaceholder> {
+    do_parse!(input,
+        src1: terminated!(register, char!(',')) >>
+        src2: terminated!(register, char!(',')) >>
+        imm: immediate >>
+        (InstructionPlaceholder::Store { op, src1, src2, imm })
+    )
+}
+
+fn instruction_u(input: &str, op: OpCode) -> IResult<&str, InstructionPlaceholder> {
+    do_parse!(input,
+        dst: terminated!(register, char!(',')) >>
+        imm: immediate >>
+        (InstructionPlaceholder::Upper { op, dst, imm })
+    )
+}
+
+fn instruction(input: &str) -> IResult<&str, InstructionPlaceholder> {
+    use OpCode::*;
+
+    // Can't use `switch!()` as it doesn't accept `|` in patterns,
+    // so we have to manually expand it
+    match map!(input, mnemonic, |s: &str| s.replace(".", "_").to_uppercase()) {
+        IResult::Error(error) => IResult::Error(error),
+        IResult::Incomplete(needed) => IResult::Incomplete(needed),
+        IResult::Done(input, output) => match output.as_ref() {
+            "BYTES" => ws!(input, string_literal),
+            _ => match OpCode::from_str(&output) {
+                Err(_) => IResult::Error(error_position!(ErrorKind::MapRes, input)),
+                Ok(op) => match op {
+                    ADD | SUB | AND | OR | XOR | SLL | SRL | SRA =>
+                        instruction_r(input, op),
+                    C_ADD | C_SUB | C_AND | C_OR | C_XOR | C_SLL | C_SRL | C_SRA | MV =>
+                        instruction_cr(input, op),
+                    ADDI | ANDI | ORI | XORI | SLLI | SRLI | SRAI | LOAD | C_LOAD =>
+                        instruction_i(input, op),
+                    LI | BEZ | BNZ |
+                    C_ADDI | C_ANDI | C_ORI | C_XORI | C_SLLI | C_SRLI | C_SRAI | C_LI | C_BEZ | C_BNZ =>
+                        instruction_ci(input, op),
+                    CALL | C_CALL =>
+                        instruction_call(input, op),
+                    BREAK | C_BREAK =>
+                        instruction_break(input, op),
+                    STORE | C_STORE | BEQ | BNE | BLT | BGE | BLT_U | BGE_U =>
+                        instruction_s(input, op),
+                    LUI | C_LUI =>
+                        instruction_u(input, op)
+                }
+            }
+        }
+    }
+}
+
+pub fn parse_line(input: &str) -> IResult<&str, (Option<&str>, Option<InstructionPlaceholder>)> {
+    ws!(input, terminated!(
+        terminated!(
+            alt_complete!(
+                map!(comment, |_| (None, None)) |
+                map!(directive, |d| (None, Some(d))) |
+                map!(pair!(label, directive), |(l, d)| (Some(l), Some(d))) |
+                map!(pair!(label, instruction), |(l, i)| (Some(l), Some(i))) |
+                map!(instruction, |i| (None, Some(i))) |
+                map!(label, |l| (Some(l), None))
+            ),
+            opt!(complete!(comment))
+        ),
+        eof!()
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use nom::IResult::Done;
+
+    use std::collections::HashMap;
+
+    use OpCode::*;
+    use RelocationKind;
+
+    use super::{Base, BinaryOperator, ImmediatePlaceholder, InstructionPlaceholder, Relocatable, UnaryOperator};
+
+    #[test]
+    fn comment() {
+        assert_eq!(super::comment("# This is a comment..."), Done("", ()));
+    }
+
+    #[test]
+    fn identifier() {
+        assert_eq!(super::identifier("Label_1"), Done("", "Label_1"));
+    }
+
+    #[test]
+    fn label() {
+        assert_eq!(super::label("Label_1:"), Done("", "Label_1"));
+    }
+
+    #[test]
+    fn register() {
+        assert_eq!(super::register("r31"), Done("", 31));
+    }
+
+    #[test]
+    fn number_sign() {
+        assert_eq!(super::number_sign(""), Done("", 1));
+        assert_eq!(super::number_sign("+"), Done("", 1));
+        assert_eq!(super::number_sign("-"), Done("", -1));
+    }
+
+    #[test]
+    fn number_radix() {
+        assert_eq!(super::number_radix(""), Done("", 10));
+        assert_eq!(super::number_radix("0b"), Done("", 2));
+        assert_eq!(super::number_radix("0o"), Done("", 8));
+        assert_eq!(super::number_radix("0x"), Done("", 16));
+    }
+
+    #[test]
+    fn number() {
+        assert_eq!(super::number("10"), Done("", 10));
+        assert_eq!(super::number("0x1f"), Done("", 0x1f));
+        assert_eq!(super::number("-4"), Done("", -4i32 as u32));
+        assert_eq!(super::number("-0b101"), Done("", -0b101i32 as u32));
+    }
+
+    #[test]
+    fn immediate() {
+        assert_eq!(super::immediate("-2"), Done("", ImmediatePlaceholder::Value(-2i32 as u32)));
+        assert_eq!(super::immediate("%label1"), Done("", ImmediatePlaceholder::LabelAbsolute("label1")));
+        assert_eq!(super::immediate("$label1"), Done("", ImmediatePlaceholder::LabelRelative("label1")));
+    }
+
+    #[test]
+    fn expression() {
+        use super::ImmediatePlaceholder::*;
+
+        assert_eq!(super::expression("%array + 8"),
+            Done("", Binary(BinaryOperator::Add, Box::new(LabelAbsolute("array")), Box::new(Value(8)))));
+
+        assert_eq!(super::expression("1 + 2 * 3"),
+            Done("", Binary(BinaryOperator::Add, Box::new(Value(1)),
+                Box::new(Binary(BinaryOperator::Mul, Box::new(Value(2)), Box::new(Value(3)))))));
+
+        assert_eq!(super::expression("end - start"),
+            Done("", Binary(BinaryOperator::Sub, Box::new(LabelAbsolute("end")), Box::new(LabelAbsolute("start")))));
+
+        assert_eq!(super::expression("lo(~$label)"),
+            Done("", Unary(UnaryOperator::Low, Box::new(Unary(UnaryOperator::Not, Box::new(LabelRelative("label")))))));
+    }
+
+    #[test]
+    fn evaluate() {
+        let mut symbols = HashMap::new();
+        symbols.insert("start", Relocatable::section(0, 0x10));
+        symbols.insert("end", Relocatable::section(0, 0x12348765));
+
+        let eval = |s: &'static str| super::expression(s).unwrap().1
+                                                         .evaluate(&symbols, Some((0, 0x14)))
+                                                         .and_then(|value| value.into_immediate(0))
+                                                         .map(|(imm, _)| imm);
+
+        assert_eq!(eval("(1 << 4) | 3"), Ok(19));
+        assert_eq!(eval("-7 / 2 + 10 % 4 - (0xf0 >> 4 ^ 0b11 & 6)"), Ok(-14i32 as u32));
+        assert_eq!(eval("end - start"), Ok(0x12348755));
+        assert_eq!(eval("$start"), Ok(-4i32 as u32));
+        assert_eq!(eval("hi(end)"), Ok(0x12350000));
+        assert_eq!(eval("lo(end)"), Ok(0x8765));
+        assert_eq!(eval("hi(0x12348765) + (lo(0x12348765) ^ 0x8000) - 0x8000"), Ok(0x12348765));
+        assert_eq!(eval("1 / 0"), Err("Division by zero".to_owned()));
+        assert_eq!(eval("%missing"), Err("Label not found: missing".to_owned()));
+        assert_eq!(eval("end * 2"), Err("Expression cannot be relocated".to_owned()));
+        assert_eq!(eval("hi(end) + 1"), Err("Result of hi() or lo() cannot be used in an expression".to_owned()));
+    }
+
+    #[test]
+    fn into_immediate() {
+        let mut symbols = HashMap::new();
+        symbols.insert("label", Relocatable::section(0, 8));
+        symbols.insert("buffer", Relocatable::section(1, 16));
+        symbols.insert("print", Relocatable::external("print"));
+
+        let eval = |s: &'static str| super::expression(s).unwrap().1
+                                                         .evaluate(&symbols, Some((0, 4)))
+                                                         .and_then(|value| value.into_immediate(0));
+
+        assert_eq!(eval("%label + 4"), Ok((12, Some((RelocationKind::Absolute, Base::Section(0), 12)))));
+        assert_eq!(eval("$label"), Ok((4, None)));
+        assert_eq!(eval("%print"), Ok((0, Some((RelocationKind::Absolute, Base::External("print"), 0)))));
+        assert_eq!(eval("$print"),
+            Ok((-4i32 as u32, Some((RelocationKind::Relative, Base::External("print"), -4i32 as u32)))));
+        assert_eq!(eval("$buffer"), Ok((12, Some((RelocationKind::Relative, Base::Section(1), 12)))));
+        assert_eq!(eval("hi(print + 0x8000)"),
+            Ok((0x10000, Some((RelocationKind::High, Base::External("print"), 0x8000)))));
+        assert_eq!(eval("lo(label)"), Ok((8, Some((RelocationKind::Low, Base::Section(0), 8)))));
+        assert_eq!(eval("print + label"), Err("Expression cannot be relocated".to_owned()));
+        assert_eq!(eval("buffer - label"), Ok((8, Some((RelocationKind::Relative, Base::Section(1), 8)))));
+        assert_eq!(eval("buffer - print"), Err("Expression cannot be relocated".to_owned()));
+    }
+
+    #[test]
+    fn directive() {
+        assert_eq!(super::directive(".equ SIZE, 4 * 2"),
+            Done("", InstructionPlaceholder::Constant {
+                name: "SIZE", redefinable: false,
+                value: ImmediatePlaceholder::Binary(BinaryOperator::Mul,
+                    Box::new(ImmediatePlaceholder::Value(4)), Box::new(ImmediatePlaceholder::Value(2))) }));
+
+        assert_eq!(super::directive(".set count, 1"),
+            Done("", InstructionPlaceholder::Constant {
+                name: "count", value: ImmediatePlaceholder::Value(1), redefinable: true }));
+
+        assert_eq!(super::directive(".global start"), Done("", InstructionPlaceholder::Global("start")));
+        assert_eq!(super::parse_line(".global start"), Done("", (None, Some(InstructionPlaceholder::Global("start")))));
+        assert_eq!(super::directive(".bss"), Done("", InstructionPlaceholder::SwitchSection(".bss")));
+        assert_eq!(super::directive(".section .vectors"), Done("", InstructionPlaceholder::SwitchSection(".vectors")));
+        assert_eq!(super::directive(".space 64"), Done("", InstructionPlaceholder::Space(ImmediatePlaceholder::Value(64))));
+        assert_eq!(super::directive(".word 1, -2"),
+            Done("", InstructionPlaceholder::Words(vec![ImmediatePlaceholder::Value(1), ImmediatePlaceholder::Value(-2i32 as u32)])));
+    }
+
+    #[test]
+    fn mnemonic() {
+        assert_eq!(super::mnemonic("addi"), Done("", "addi"));
+        assert_eq!(super::mnemonic("c.addi"), Done("", "c.addi"));
+    }
+
+    #[test]
+    fn instruction_r() {
+        assert_eq!(super::instruction_r("r0, r0, r1", ADD),
+            Done("", InstructionPlaceholder::Register { op: ADD, dst: 0, src1: 0, src2: 1 }));
+    }
+
+    #[test]
+    fn instruction_i() {
+        assert_eq!(super::instruction_i("r0, r0, $label", ADDI),
+            Done("", InstructionPlaceholder::Immediate {
+                op: ADDI, dst: 0, src1: 0, imm: ImmediatePlaceholder::LabelRelative("label")}));
+    }
+
+    #[test]
+    fn instruction_s() {
+        assert_eq!(super::instruction_s("r0, r1, -4", STORE),
+            Done("", InstructionPlaceholder::Store {
+                op: STORE, src1: 0, src2: 1, imm: ImmediatePlaceholder::Value(-4i32 as u32) }));
+    }
+
+    #[test]
+    fn instruction_u() {
+        assert_eq!(super::instruction_u("r1, 0x1fa800c5", LUI),
+            Done("", InstructionPlaceholder::Upper { op: LUI, dst: 1, imm: ImmediatePlaceholder::Value(0x1fa800c5) }));
+    }
+
+    #[test]
+    fn instruction_cr() {
+        assert_eq!(super::instruction_cr("r3, r4", C_ADD),
+            Done("", InstructionPlaceholder::Register { op: C_ADD, dst: 3, src1: 3, src2: 4 }));
+    }
+
+    #[test]
+    fn instruction_ci() {
+        assert_eq!(super::instruction_ci("r0, %label", C_ADDI),
+            Done("", InstructionPlaceholder::Immediate {
+                op: C_ADDI, dst: 0, src1: 0, imm: ImmediatePlaceholder::LabelAbsolute("label") }));
+    }
+
+    #[test]
+    fn instruction_cl() {
+        assert_eq!(super::instruction_i("r3, r1, 0", C_LOAD),
+            Done("", InstructionPlaceholder::Immediate {
+                op: C_LOAD, dst: 3, src1: 1, imm: ImmediatePlaceholder::Value(0) }));
+    }
+
+    #[test]
+    fn instruction_cs() {
+        assert_eq!(super::instruction_s("r1, r3, -4", C_STORE),
+            Done("", InstructionPlaceholder::Store {
+                op: C_STORE, src1: 1, src2: 3, imm: ImmediatePlaceholder::Value(-4i32 as u32) }));
+    }
+
+    #[test]
+    fn instruction_cu() {
+        assert_eq!(super::instruction_u("r2, %label", C_LUI),
+            Done("", InstructionPlaceholder::Upper {
+                op: C_LUI, dst: 2, imm: ImmediatePlaceholder::LabelAbsolute("label") }));
+    }
+
+    #[test]
+    fn instruction() {
+        assert_eq!(super::instruction("add r0, r0, r1"),
+            Done("", InstructionPlaceholder::Register { op: ADD, dst: 0, src1: 0, src2: 1 }));
+        
+        assert_eq!(super::instruction("addi r0, r0, 0xff"),
+            Done("", InstructionPlaceholder::Immediate {
+                op: ADDI, dst: 0, src1: 0, imm: ImmediatePlaceholder::Value(0xff) }));
+
+        assert_eq!(super::instruction("store r0, r1, -4"),
+            Done("", InstructionPlaceholder::Store {
+                op: STORE, src1: 0, src2: 1, imm: ImmediatePlaceholder::Value(-4i32 as u32) }));
+        
+        assert_eq!(super::instruction("lui r1, %label"),
+            Done("", InstructionPlaceholder::Upper {
+                op: LUI, dst: 1, imm: ImmediatePlaceholder::LabelAbsolute("label") }));
+        
+        assert_eq!(super::instruction("c.add r0, r1"),
+            Done("", InstructionPlaceholder::Register { op: C_ADD, dst: 0, src1: 0, src2: 1 }));
+        
+        assert_eq!(super::instruction("c.addi r0, 0xff"),
+            Done("", InstructionPlaceholder::Immediate {
+                op: C_ADDI, dst: 0, src1: 0, imm: ImmediatePlaceholder::Value(0xff) }));
+        
+        assert_eq!(super::instruction("c.load r3, r1, 0"),
+            Done("", InstructionPlaceholder::Immediate {
+            op: C_LOAD, dst: 3, src1: 1, imm: ImmediatePlaceholder::Value(0) }));
+        
+        assert_eq!(super::instruction("c.store r1, r3, -4"),
+            Done("", InstructionPlaceholder::Store {
+                op: C_STORE, src1: 1, src2: 3, imm: ImmediatePlaceholder::Value(-4i32 as u32) }));
+        
+        assert_eq!(super::instruction("c.lui r2, $label"),
+            Done("", InstructionPlaceholder::Upper {
+                op: C_LUI, dst: 2, imm: ImmediatePlaceholder::LabelRelative("label") }));
+    }
+
+    #[test]
+    fn parse_line() {
+        assert_eq!(super::parse_line(" # add r0, r0, r1 "), Done("", (None, None)));
+
+        assert_eq!(super::parse_line(" add r0, r0, r1   # ignore me"),
+            Done("", (None, Some(InstructionPlaceholder::Register { op: ADD, dst: 0, src1: 0, src2: 1 }))));
+
+        assert_eq!(super::parse_line("label:"), Done("", (Some("label"), None)));
+
+        assert_eq!(super::parse_line(" label :\tadd r0,r0,r1 "),
+            Done("", (Some("label"), Some(InstructionPlaceholder::Register { op: ADD, dst: 0, src1: 0, src2: 1 }))));
+    }
+}
@@ -0,0 +1,338 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use super::Diagnostic;
+
+/// Maximum depth of nested macro invocations, to catch runaway recursion.
+const MAX_MACRO_DEPTH: usize = 64;
+
+/// Position of a line of source, following it back through any macro
+/// expansions it came from.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Location {
+    /// Name of the file containing the line.
+    pub file: String,
+    /// Line number, counting from 1. For lines expanded from a macro this is
+    /// the line within the macro's definition.
+    pub line: usize,
+    /// Macro invocations the line was expanded through, innermost first, as
+    /// tuples of macro name, file name and line number of the invocation.
+    pub expansions: Vec<(String, String, usize)>
+}
+
+impl Location {
+    /// Returns the file and line number the line ultimately came from: the
+    /// outermost macro invocation it was expanded through, if any.
+    pub fn origin(&self) -> (&str, usize) {
+        match self.expansions.last() {
+            Some(&(_, ref file, line)) => (file, line),
+            None => (&self.file, self.line)
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)?;
+
+        for &(ref name, ref file, line) in &self.expansions {
+            write!(f, " in macro `{}` invoked at {}:{}", name, file, line)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A line of source after macro expansion.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SourceLine {
+    pub text: String,
+    pub location: Location
+}
+
+#[derive(Debug)]
+struct Macro {
+    /// Name of the file the macro was defined in.
+    file: String,
+    params: Vec<String>,
+    /// Lines of the macro's body, along with their line numbers.
+    body: Vec<(usize, String)>
+}
+
+/// Returns `line` without any trailing comment, ignoring `#`s inside string
+/// literals.
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+
+    line
+}
+
+/// Splits a leading `label:` off `line`, returning the label (including the
+/// colon) and the remainder of the line.
+fn split_label(line: &str) -> (Option<&str>, &str) {
+    let trimmed = line.trim_left();
+
+    match trimmed.find(':') {
+        Some(i) if i > 0 && trimmed[..i].trim_right().chars().all(|c| c.is_alphanumeric() || c == '_') =>
+            (Some(&trimmed[..i + 1]), &trimmed[i + 1..]),
+        _ => (None, line)
+    }
+}
+
+/// Replaces `\param` with the corresponding argument in `line` and `\@` with
+/// `id`, leaving any other escapes (such as `\n` in strings) intact.
+fn substitute(line: &str, params: &[String], args: &[&str], id: usize) -> String {
+    let mut result = String::with_capacity(line.len());
+    let mut rest = line;
+
+    while let Some(i) = rest.find('\\') {
+        result.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+
+        if rest.starts_with('\\') {
+            result.push_str("\\\\");
+            rest = &rest[1..];
+            continue;
+        }
+
+        if rest.starts_with('@') {
+            result.push_str(&id.to_string());
+            rest = &rest[1..];
+            continue;
+        }
+
+        let len = rest.find(|c: char| !c.is_alphanumeric() && c != '_').unwrap_or(rest.len());
+
+        match params.iter().position(|p| *p == rest[..len]) {
+            Some(p) => {
+                result.push_str(args[p]);
+                rest = &rest[len..];
+            },
+            None => result.push('\\')
+        }
+    }
+
+    result.push_str(rest);
+    result
+}
+
+/// Front end of the assembler, which reads source files, follows `.include`
+/// directives and expands macro invocations.
+pub struct Preprocessor {
+    include_paths: Vec<PathBuf>,
+    /// Files currently being processed, used to detect include cycles.
+    include_stack: Vec<PathBuf>,
+    macros: HashMap<String, Macro>,
+    /// Number of expansions performed so far, used to generate unique `\@` labels.
+    expansions: usize,
+    lines: Vec<SourceLine>
+}
+
+impl Preprocessor {
+    /// Constructs a new `Preprocessor` which searches `include_paths`, in
+    /// order, for included files not found relative to the including file.
+    pub fn new(include_paths: Vec<PathBuf>) -> Self {
+        Self {
+            include_paths: include_paths,
+            include_stack: Vec::new(),
+            macros: HashMap::new(),
+            expansions: 0,
+            lines: Vec::new()
+        }
+    }
+
+    /// Reads and processes the source file at `path`.
+    pub fn process_file(&mut self, path: &Path) -> Result<(), Diagnostic> {
+        let canonical = path.canonicalize().map_err(|e| Diagnostic::new(None, format!("{}: {}", path.display(), e)))?;
+
+        if self.include_stack.contains(&canonical) {
+            return Err(Diagnostic::new(None, format!("{}: Include cycle detected", path.display())));
+        }
+
+        let mut buf = String::new();
+        File::open(path).and_then(|mut file| file.read_to_string(&mut buf))
+                        .map_err(|e| Diagnostic::new(None, format!("{}: {}", path.display(), e)))?;
+
+        self.include_stack.push(canonical);
+        let result = self.process_source(&path.display().to_string(), path.parent(), &buf);
+        self.include_stack.pop();
+
+        result
+    }
+
+    /// Processes the source `buf` read from the file `name`, collecting macro
+    /// definitions and expanding all invocations and includes.
+    ///
+    /// Included files are searched for in `dir` before the include paths.
+    pub fn process_source(&mut self, name: &str, dir: Option<&Path>, buf: &str) -> Result<(), Diagnostic> {
+        let mut definition: Option<(usize, String, Macro)> = None;
+
+        for (num, line) in buf.lines().enumerate() {
+            let location = Location { file: name.to_owned(), line: num + 1, expansions: Vec::new() };
+            let code = strip_comment(line).trim();
+            let keyword = code.split_whitespace().next().unwrap_or("").to_lowercase();
+
+            if keyword == ".macro" {
+                if definition.is_some() {
+                    return Err(Diagnostic::new(Some(location), "Nested macro definition"));
+                }
+
+                let mut words = code[6..].split(|c: char| c == ',' || c.is_whitespace()).filter(|w| !w.is_empty());
+                let macro_name = match words.next() {
+                    Some(macro_name) => macro_name,
+                    None => return Err(Diagnostic::new(Some(location), "Missing macro name"))
+                };
+
+                definition = Some((num + 1, macro_name.to_owned(), Macro {
+                    file: name.to_owned(),
+                    params: words.map(|w| w.to_owned()).collect(),
+                    body: Vec::new()
+                }));
+            } else if keyword == ".endm" {
+                match definition.take() {
+                    Some((_, macro_name, mac)) => { self.macros.insert(macro_name, mac); },
+                    None => return Err(Diagnostic::new(Some(location), ".endm without .macro"))
+                }
+            } else if let Some((_, _, ref mut mac)) = definition {
+                mac.body.push((num + 1, line.to_owned()));
+            } else if keyword == ".include" {
+                let path = code[8..].trim();
+
+                if path.len() < 2 || !path.starts_with('"') || !path.ends_with('"') {
+                    return Err(Diagnostic::new(Some(location), "Expected quoted path after .include"));
+                }
+
+                let path = match self.resolve_include(&path[1..path.len() - 1], dir) {
+                    Some(path) => path,
+                    None => return Err(Diagnostic::new(Some(location), format!("Included file not found: {}", path)))
+                };
+
+                // Report errors in the included file against the `.include`
+                self.process_file(&path).map_err(|e| Diagnostic::new(Some(location), e.to_string()))?;
+            } else {
+                self.expand_line(line, location)?;
+            }
+        }
+
+        if let Some((line, macro_name, _)) = definition {
+            let location = Location { file: name.to_owned(), line: line, expansions: Vec::new() };
+            return Err(Diagnostic::new(Some(location), format!("Unterminated macro `{}`", macro_name)));
+        }
+
+        Ok(())
+    }
+
+    /// Searches for the included file `name`, first in `dir` and then in each
+    /// of the include paths.
+    fn resolve_include(&self, name: &str, dir: Option<&Path>) -> Option<PathBuf> {
+        dir.into_iter()
+           .chain(self.include_paths.iter().map(|p| p.as_path()))
+           .map(|dir| dir.join(name))
+           .find(|path| path.is_file())
+    }
+
+    fn expand_line(&mut self, line: &str, location: Location) -> Result<(), Diagnostic> {
+        let (label, rest) = split_label(strip_comment(line));
+        let rest = rest.trim();
+        let name_len = rest.find(char::is_whitespace).unwrap_or(rest.len());
+
+        if !self.macros.contains_key(&rest[..name_len]) {
+            self.lines.push(SourceLine { text: line.to_owned(), location: location });
+            return Ok(());
+        }
+
+        if location.expansions.len() >= MAX_MACRO_DEPTH {
+            return Err(Diagnostic::new(Some(location), "Macro recursion limit exceeded"));
+        }
+
+        if let Some(label) = label {
+            self.lines.push(SourceLine { text: label.to_owned(), location: location.clone() });
+        }
+
+        let name = &rest[..name_len];
+        let args: Vec<&str> = match rest[name_len..].trim() {
+            "" => Vec::new(),
+            args => args.split(',').map(|a| a.trim()).collect()
+        };
+
+        let id = self.expansions;
+        self.expansions += 1;
+
+        // Substitute the whole body up front, so that `self` isn't borrowed
+        // while we recursively expand each line
+        let (file, body): (String, Vec<(usize, String)>) = {
+            let mac = &self.macros[name];
+
+            if args.len() != mac.params.len() {
+                let message = format!("Macro `{}` expects {} arguments, found {}", name, mac.params.len(), args.len());
+                return Err(Diagnostic::new(Some(location), message));
+            }
+
+            (mac.file.clone(),
+             mac.body.iter().map(|&(num, ref text)| (num, substitute(text, &mac.params, &args, id))).collect())
+        };
+
+        for (num, text) in body {
+            let mut expansions = vec![(name.to_owned(), location.file.clone(), location.line)];
+            expansions.extend(location.expansions.iter().cloned());
+
+            self.expand_line(&text, Location { file: file.clone(), line: num, expansions: expansions })?;
+        }
+
+        Ok(())
+    }
+
+    /// Consumes the preprocessor, returning the processed lines.
+    pub fn into_lines(self) -> Vec<SourceLine> {
+        self.lines
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Preprocessor;
+
+    fn expand(source: &str) -> Result<Vec<String>, String> {
+        let mut preprocessor = Preprocessor::new(Vec::new());
+        preprocessor.process_source("test.sasm", None, source).map_err(|e| e.to_string())?;
+
+        Ok(preprocessor.into_lines().into_iter().map(|l| l.text).collect())
+    }
+
+    #[test]
+    fn substitute() {
+        let params = vec!["reg".to_owned(), "r".to_owned()];
+
+        assert_eq!(super::substitute("add \\reg, \\r, r0", &params, &["r4", "r5"], 0), "add r4, r5, r0");
+        assert_eq!(super::substitute("loop_\\@: bytes \"\\n\\\\r\"", &params, &["r4", "r5"], 3),
+            "loop_3: bytes \"\\n\\\\r\"");
+    }
+
+    #[test]
+    fn expand_macros() {
+        assert_eq!(expand(".macro skip reg\n beq \\reg, r0, $done\\@\n done\\@:\n.endm\nstart: skip r4\n skip r5 # again"),
+            Ok(vec!["start:".to_owned(), " beq r4, r0, $done0".to_owned(), " done0:".to_owned(),
+                    " beq r5, r0, $done1".to_owned(), " done1:".to_owned()]));
+
+        assert_eq!(expand(".macro outer\n inner 1\n.endm\n.macro inner n\n c.li r4, \\n\n.endm\n outer"),
+            Ok(vec![" c.li r4, 1".to_owned()]));
+
+        assert_eq!(expand(".macro forever\n forever\n.endm\n forever").unwrap_err()
+                                                                      .ends_with("Macro recursion limit exceeded"), true);
+        assert_eq!(expand(".macro two a, b\n.endm\n two 1"),
+            Err("test.sasm:3: Macro `two` expects 2 arguments, found 1".to_owned()));
+        assert_eq!(expand(".macro open\n c.li r4, 1"), Err("test.sasm:1: Unterminated macro `open`".to_owned()));
+        assert_eq!(expand(".endm"), Err("test.sasm:1: .endm without .macro".to_owned()));
+    }
+}
@@ -1,19 +1,18 @@
 #![feature(stmt_expr_attributes)]
 
 extern crate clap;
-#[macro_use]
-extern crate nom;
 extern crate svm;
 
-mod parser;
-
 use std::fs::File;
-use std::io::{self, Read, Write};
+use std::io::{self, BufWriter, Write};
 use std::path::Path;
 use std::process;
 
 use clap::{App, Arg};
 
+use svm::{write_ihex, write_srec, SymbolMap, MAX_RAW_SIZE};
+use svm::asm::{parse_address, Assembler, Diagnostics, Program};
+
 macro_rules! exit {
     ($($arg: tt)*) => {
         #[allow(unused_must_use)]
@@ -24,16 +23,44 @@ macro_rules! exit {
     }
 }
 
-fn process_file(path: &Path) -> Result<Vec<u8>, io::Error> {
-    let mut buf = String::new();
-    File::open(path)?.read_to_string(&mut buf)?;
-    parser::parse(buf).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+/// Assembles the files, returning the object file to output if compiling
+/// only, or otherwise the linked program.
+fn process_files(assembler: &Assembler, compile_only: bool) -> Result<(Vec<u8>, Option<Program>), Diagnostics> {
+    if compile_only {
+        assembler.assemble_object().map(|object| {
+            let mut bytes = Vec::new();
+            object.write_to(&mut bytes).unwrap();
+            (bytes, None)
+        })
+    } else {
+        assembler.assemble().map(|program| (Vec::new(), Some(program)))
+    }
 }
 
 fn save_bytes(path: &Path, bytes: Vec<u8>) -> Result<(), io::Error> {
     File::create(path)?.write_all(&bytes)
 }
 
+/// Writes the program's sections to `path` as Intel HEX or S-records, with
+/// execution starting at `.text`, if there is one.
+fn save_hex(path: &Path, program: &Program, format: &str) -> Result<(), io::Error> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    let start = program.section(".text");
+
+    match format {
+        "ihex" => write_ihex(&mut writer, &program.segments, start),
+        _ => write_srec(&mut writer, &program.segments, start)
+    }
+}
+
+fn save_listing(path: &Path, program: &Program) -> Result<(), io::Error> {
+    program.write_listing(&mut BufWriter::new(File::create(path)?))
+}
+
+fn save_symbols(path: &Path, program: &Program) -> Result<(), io::Error> {
+    SymbolMap::new(program.symbols.clone()).write_to(&mut BufWriter::new(File::create(path)?))
+}
+
 fn main() {
     let matches = App::new("Simple Virtual Machine Assembler")
                           .version("0.1.0")
@@ -45,22 +72,109 @@ fn main() {
                               .value_name("FILE")
                               .help("Set an output file name")
                               .takes_value(true))
+                          .arg(Arg::with_name("compile")
+                              .short("c")
+                              .long("compile")
+                              .help("Produce a relocatable object file for use with slink"))
+                          .arg(Arg::with_name("format")
+                              .short("f")
+                              .long("format")
+                              .value_name("FORMAT")
+                              .help("Set the format of the program, placing each section at its address in hex formats")
+                              .takes_value(true)
+                              .possible_values(&["binary", "ihex", "srec"])
+                              .conflicts_with("compile"))
+                          .arg(Arg::with_name("listing")
+                              .short("l")
+                              .long("listing")
+                              .value_name("FILE")
+                              .help("Write a listing of the program with its symbol table")
+                              .takes_value(true)
+                              .conflicts_with("compile"))
+                          .arg(Arg::with_name("symbols")
+                              .long("symbols")
+                              .value_name("FILE")
+                              .help("Write the address of every label to <FILE> for use with svm --symbols")
+                              .takes_value(true)
+                              .conflicts_with("compile"))
+                          .arg(Arg::with_name("include")
+                              .short("I")
+                              .long("include")
+                              .value_name("DIR")
+                              .help("Add a directory to search for included files")
+                              .takes_value(true)
+                              .multiple(true)
+                              .number_of_values(1))
+                          .arg(Arg::with_name("section")
+                              .short("s")
+                              .long("section")
+                              .value_name("NAME=ADDR")
+                              .help("Place a section at an address, e.g. .data=0x8000")
+                              .takes_value(true)
+                              .multiple(true)
+                              .number_of_values(1))
                           .arg(Arg::with_name("FILE")
-                              .help("The assembly file to process")
-                              .required(true))
+                              .help("The assembly files to process")
+                              .required(true)
+                              .multiple(true))
                           .get_matches();
 
-    let input_filename = matches.value_of("FILE").unwrap();
-    let input = Path::new(input_filename);
+    let input_filenames: Vec<&str> = matches.values_of("FILE").unwrap().collect();
+    let compile_only = matches.is_present("compile");
+    let format = matches.value_of("format").unwrap_or("binary");
 
-    process_file(&input).and_then(|bytes| {
-        let output_filename = matches.value_of("output")
-                                     .unwrap_or_else(|| match &input_filename[input_filename.len() - 5..] {
-                                         ".sasm" => &input_filename[..input_filename.len() - 5],
-                                         _ => &input_filename[..]
-                                     });
-        let output = Path::new(output_filename);
+    let mut assembler = input_filenames.iter().fold(Assembler::new(), |assembler, filename| assembler.file(filename));
 
-        save_bytes(output, bytes)
-    }).unwrap_or_else(|error| exit!("sasm: {}", error));
+    for dir in matches.values_of("include").into_iter().flat_map(|v| v) {
+        assembler = assembler.include_path(dir);
+    }
+
+    for section in matches.values_of("section").into_iter().flat_map(|v| v) {
+        let (name, addr) = match section.find('=') {
+            Some(i) => (&section[..i], &section[i + 1..]),
+            None => exit!("sasm: invalid section: {}", section)
+        };
+
+        assembler = assembler.section_address(name, parse_address(addr).unwrap_or_else(|| exit!("sasm: invalid address: {}", addr)));
+    }
+
+    let (bytes, program) = process_files(&assembler, compile_only).unwrap_or_else(|diagnostics| {
+        let messages: Vec<String> = diagnostics.errors.iter().map(|d| format!("sasm: {}", d)).collect();
+        exit!("{}", messages.join("\n"))
+    });
+
+    // Name the output after the first input unless told otherwise
+    let input_filename = input_filenames[0];
+    let default_filename = {
+        let stem = match &input_filename[input_filename.len().saturating_sub(5)..] {
+            ".sasm" => &input_filename[..input_filename.len() - 5],
+            _ => &input_filename[..]
+        };
+
+        match format {
+            _ if compile_only => format!("{}.o", stem),
+            "ihex" => format!("{}.hex", stem),
+            "srec" => format!("{}.srec", stem),
+            _ => stem.to_owned()
+        }
+    };
+    let output = Path::new(matches.value_of("output").unwrap_or(&default_filename[..]));
+
+    match program {
+        Some(ref program) if format != "binary" => save_hex(output, program, format),
+        Some(ref program) if program.raw_size() > MAX_RAW_SIZE =>
+            exit!("sasm: program is {} bytes as a raw binary, use --format ihex or srec", program.raw_size()),
+        Some(ref program) => save_bytes(output, program.bytes()),
+        None => save_bytes(output, bytes)
+    }.unwrap_or_else(|error| exit!("sasm: {}", error));
+
+    if let Some(program) = program {
+        if let Some(path) = matches.value_of("listing") {
+            save_listing(Path::new(path), &program).unwrap_or_else(|error| exit!("sasm: {}: {}", path, error));
+        }
+
+        if let Some(path) = matches.value_of("symbols") {
+            save_symbols(Path::new(path), &program).unwrap_or_else(|error| exit!("sasm: {}: {}", path, error));
+        }
+    }
 }
@@ -0,0 +1,104 @@
+#![feature(stmt_expr_attributes)]
+
+extern crate clap;
+extern crate svm;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::process;
+
+use clap::{App, Arg};
+
+use svm::{Linker, Object, SymbolMap, MAX_RAW_SIZE};
+use svm::asm::parse_address;
+
+macro_rules! exit {
+    ($($arg: tt)*) => {
+        #[allow(unused_must_use)]
+        {
+            writeln!(io::stderr(), $($arg)*);
+            process::exit(1);
+        }
+    }
+}
+
+fn read_object(path: &Path) -> Result<Object, io::Error> {
+    Object::read_from(&mut File::open(path)?)
+}
+
+fn write_symbols(path: &Path, symbols: &SymbolMap) -> Result<(), io::Error> {
+    symbols.write_to(&mut BufWriter::new(File::create(path)?))
+}
+
+fn main() {
+    let matches = App::new("Simple Virtual Machine Linker")
+                          .version("0.1.0")
+                          .author("James Chapman <james.chapman2@mail.bcu.ac.uk>")
+                          .about("Linker for Simple Virtual Machine object files")
+                          .arg(Arg::with_name("output")
+                              .short("o")
+                              .long("output")
+                              .value_name("FILE")
+                              .help("Set an output file name")
+                              .takes_value(true))
+                          .arg(Arg::with_name("base-address")
+                              .short("b")
+                              .long("base-address")
+                              .value_name("ADDR")
+                              .help("Set the address the first section is placed at")
+                              .takes_value(true))
+                          .arg(Arg::with_name("section")
+                              .short("s")
+                              .long("section")
+                              .value_name("NAME=ADDR")
+                              .help("Place a section at an address, e.g. .data=0x8000")
+                              .takes_value(true)
+                              .multiple(true)
+                              .number_of_values(1))
+                          .arg(Arg::with_name("symbols")
+                              .long("symbols")
+                              .value_name("FILE")
+                              .help("Write the address of every label to <FILE> for use with svm --symbols")
+                              .takes_value(true))
+                          .arg(Arg::with_name("FILE")
+                              .help("The object files to link")
+                              .required(true)
+                              .multiple(true))
+                          .get_matches();
+
+    let mut linker = Linker::new();
+
+    if let Some(addr) = matches.value_of("base-address") {
+        linker.set_base_address(parse_address(addr).unwrap_or_else(|| exit!("slink: invalid address: {}", addr)));
+    }
+
+    for section in matches.values_of("section").into_iter().flat_map(|v| v) {
+        let (name, addr) = match section.find('=') {
+            Some(i) => (&section[..i], &section[i + 1..]),
+            None => exit!("slink: invalid section: {}", section)
+        };
+
+        linker.set_section_address(name, parse_address(addr).unwrap_or_else(|| exit!("slink: invalid address: {}", addr)));
+    }
+
+    for filename in matches.values_of("FILE").unwrap() {
+        let object = read_object(Path::new(filename)).unwrap_or_else(|error| exit!("slink: {}: {}", filename, error));
+        linker.add_object(object);
+    }
+
+    let image = linker.link().unwrap_or_else(|error| exit!("slink: {}", error));
+    let output = Path::new(matches.value_of("output").unwrap_or("a.out"));
+
+    if image.raw_size() > MAX_RAW_SIZE {
+        exit!("slink: image is {} bytes as a raw binary, more than the limit of {}", image.raw_size(), MAX_RAW_SIZE);
+    }
+
+    File::create(output).and_then(|mut file| file.write_all(&image.bytes()))
+                        .unwrap_or_else(|error| exit!("slink: {}: {}", output.display(), error));
+
+    if let Some(path) = matches.value_of("symbols") {
+        write_symbols(Path::new(path), &SymbolMap::new(image.symbols))
+            .unwrap_or_else(|error| exit!("slink: {}: {}", path, error));
+    }
+}
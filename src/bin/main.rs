@@ -3,14 +3,16 @@
 extern crate clap;
 extern crate svm;
 
+use std::env;
 use std::fs::File;
-use std::io::{self, Read, Write};
+use std::io::{self, BufReader, BufWriter, Read, Write};
 use std::path::Path;
 use std::process;
 
 use clap::{App, Arg};
 
-use svm::VirtualMachine;
+use svm::{read_ihex, read_srec, Clock, DumpFormat, Engine, MemoryDump, Random, Scheduler, SymbolMap, VirtualMachine};
+use svm::asm::parse_address;
 
 macro_rules! exit {
     ($($arg: tt)*) => {
@@ -22,6 +24,11 @@ macro_rules! exit {
     }
 }
 
+#[cfg(feature = "jit")]
+const ENGINES: &'static [&'static str] = &["interpreter", "blocks", "jit"];
+#[cfg(not(feature = "jit"))]
+const ENGINES: &'static [&'static str] = &["interpreter", "blocks"];
+
 fn read_file(path: &Path) -> Result<Vec<u8>, io::Error> {
     let mut file = File::open(path)?;
 
@@ -35,14 +42,27 @@ fn read_file(path: &Path) -> Result<Vec<u8>, io::Error> {
     Ok(vec)
 }
 
-fn write_to_file(path: &Path, vm: &VirtualMachine) -> Result<(), io::Error> {
-    let mut file = File::create(path)?;
+fn read_symbols(path: &Path) -> Result<SymbolMap, io::Error> {
+    SymbolMap::read_from(&mut BufReader::new(File::open(path)?))
+}
 
-    for page in (0..vm.memory.pages.len()).flat_map(|i| vm.memory.pages.get(i)) {
-        file.write_all(page)?;
-    }
+/// Returns the format of the program in `path`, from its extension if
+/// `format` isn't given.
+fn program_format<'a>(path: &Path, format: Option<&'a str>) -> &'a str {
+    let extension = path.extension().and_then(|extension| extension.to_str()).map(|e| e.to_lowercase());
 
-    Ok(())
+    format.unwrap_or_else(|| match extension.as_ref().map(|e| &e[..]) {
+        Some("hex") | Some("ihex") => "ihex",
+        Some("srec") | Some("s19") | Some("s28") | Some("s37") | Some("mot") => "srec",
+        _ => "binary"
+    })
+}
+
+fn write_to_file(path: &Path, vm: &VirtualMachine, format: DumpFormat) -> Result<(), io::Error> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    vm.dump().write_to(&mut writer, format)?;
+
+    writer.flush()
 }
 
 fn main() {
@@ -56,14 +76,75 @@ fn main() {
                               .value_name("SIZE")
                               .help("Set a custom page size")
                               .takes_value(true))
+                          .arg(Arg::with_name("max-pages")
+                              .long("max-pages")
+                              .value_name("COUNT")
+                              .help("Limit the number of pages of memory the program may allocate")
+                              .takes_value(true))
+                          .arg(Arg::with_name("strict")
+                              .long("strict")
+                              .help("Fault on accesses to memory which isn't part of an image, the stack, \
+                                     the heap or a mapping"))
                           .arg(Arg::with_name("memory-dump")
                               .short("m")
                               .long("memory-dump")
                               .value_name("FILE")
                               .help("Dump memory to <FILE> on exit"))
+                          .arg(Arg::with_name("memory-dump-format")
+                              .long("memory-dump-format")
+                              .value_name("FORMAT")
+                              .help("Set the format of memory dumps")
+                              .takes_value(true)
+                              .possible_values(&["sparse", "ihex", "raw-range"])
+                              .default_value("sparse"))
+                          .arg(Arg::with_name("restore")
+                              .long("restore")
+                              .help("Resume from a sparse memory dump in <FILE> rather than running a program"))
+                          .arg(Arg::with_name("format")
+                              .short("f")
+                              .long("format")
+                              .value_name("FORMAT")
+                              .help("Set the format of the program, rather than going by its extension")
+                              .takes_value(true)
+                              .possible_values(&["binary", "ihex", "srec"])
+                              .conflicts_with("restore"))
+                          .arg(Arg::with_name("load-address")
+                              .long("load-address")
+                              .value_name("ADDR")
+                              .help("Load a raw program at <ADDR> rather than 0, also starting execution there")
+                              .takes_value(true)
+                              .conflicts_with("restore"))
+                          .arg(Arg::with_name("entry")
+                              .long("entry")
+                              .value_name("ADDR")
+                              .help("Start execution at <ADDR>")
+                              .takes_value(true)
+                              .conflicts_with("restore"))
+                          .arg(Arg::with_name("image")
+                              .long("image")
+                              .value_name("FILE=ADDR")
+                              .help("Also load the raw image in <FILE> at <ADDR>, e.g. firmware.bin=0x10000")
+                              .takes_value(true)
+                              .multiple(true)
+                              .number_of_values(1)
+                              .conflicts_with("restore"))
                           .arg(Arg::with_name("FILE")
                               .help("The program to execute")
                               .required(true))
+                          .arg(Arg::with_name("ARGS")
+                              .help("Arguments to pass to the program")
+                              .multiple(true)
+                              .last(true))
+                          .arg(Arg::with_name("env")
+                              .long("env")
+                              .value_name("NAME=VALUE")
+                              .help("Set an environment variable for the program")
+                              .takes_value(true)
+                              .multiple(true)
+                              .number_of_values(1))
+                          .arg(Arg::with_name("inherit-env")
+                              .long("inherit-env")
+                              .help("Pass svm's own environment variables to the program"))
                           .arg(Arg::with_name("verbose")
                               .short("v")
                               .long("verbose")
@@ -72,35 +153,176 @@ fn main() {
                               .short("b")
                               .long("enable-breakpoints")
                               .help("Enable triggering of breakpoints during execution"))
+                          .arg(Arg::with_name("engine")
+                              .short("e")
+                              .long("engine")
+                              .value_name("ENGINE")
+                              .help("Set how instructions are executed")
+                              .takes_value(true)
+                              .possible_values(ENGINES))
+                          .arg(Arg::with_name("symbols")
+                              .long("symbols")
+                              .value_name("FILE")
+                              .help("Read symbols from <FILE> to show alongside addresses")
+                              .takes_value(true))
+                          .arg(Arg::with_name("virtual-clock")
+                              .long("virtual-clock")
+                              .value_name("NS")
+                              .help("Make time advance by <NS> nanoseconds per instruction from the Unix epoch, \
+                                     so that runs are reproducible")
+                              .takes_value(true))
+                          .arg(Arg::with_name("seed")
+                              .long("seed")
+                              .value_name("SEED")
+                              .help("Seed the random number generator with <SEED>, so that the program gets \
+                                     the same random numbers on every run")
+                              .takes_value(true))
                           .get_matches();
 
     let path = Path::new(matches.value_of("FILE").unwrap());
     let program = read_file(&path).unwrap_or_else(|error| exit!("svm: {}: {}", path.display(), error));
 
-    let vm = match matches.value_of("page-size") {
-        Some(page_size) => {
-            let page_size = page_size.parse().unwrap_or_else(|_| exit!("svm: invalid integer: {}", page_size));
-            VirtualMachine::with_page_size(page_size, program)
-        },
-        None => VirtualMachine::new(program)
+    let dump = if matches.is_present("restore") {
+        let dump = MemoryDump::read_from(&mut &program[..]);
+        Some(dump.unwrap_or_else(|error| exit!("svm: {}: {}", path.display(), error)))
+    } else {
+        None
     };
 
+    // Hex files say where their bytes go, so aren't loaded at --load-address
+    let image = match program_format(path, matches.value_of("format")) {
+        _ if dump.is_some() => None,
+        "ihex" => Some(read_ihex(&mut &program[..])),
+        "srec" => Some(read_srec(&mut &program[..])),
+        _ => None
+    };
+    let image = image.map(|image| image.unwrap_or_else(|error| exit!("svm: {}: {}", path.display(), error)));
+
+    // Use the page size the dump was taken with, unless told otherwise
+    let page_size = match matches.value_of("page-size") {
+        Some(page_size) => Some(page_size.parse().unwrap_or_else(|_| exit!("svm: invalid integer: {}", page_size))),
+        None => dump.as_ref().map(|dump| dump.page_size as usize)
+    };
+
+    let vm = match page_size {
+        Some(page_size) => VirtualMachine::with_page_size(page_size, Vec::new()),
+        None => VirtualMachine::new(Vec::new())
+    };
+
+    let mut vm = vm.unwrap_or_else(|error| exit!("svm: {}", error));
+    let parse = |addr: &str| parse_address(addr).unwrap_or_else(|| exit!("svm: invalid address: {}", addr));
+
+    if let Some(ref dump) = dump {
+        vm.restore(dump).unwrap_or_else(|error| exit!("svm: {}: {}", path.display(), error));
+    } else if let Some(image) = image {
+        for segment in &image.segments {
+            vm.load_at(segment.addr, &segment.data).unwrap_or_else(|error| exit!("svm: {}: {}", path.display(), error));
+        }
+
+        vm.set_entry(image.start.unwrap_or(0));
+    } else {
+        let addr = matches.value_of("load-address").map_or(0, &parse);
+
+        vm.load_at(addr, &program).unwrap_or_else(|error| exit!("svm: {}: {}", path.display(), error));
+        vm.set_entry(addr);
+    }
+
+    for image in matches.values_of("image").into_iter().flat_map(|v| v) {
+        let (image_path, addr) = match image.rfind('=') {
+            Some(i) => (Path::new(&image[..i]), parse(&image[i + 1..])),
+            None => exit!("svm: invalid image: {}", image)
+        };
+        let bytes = read_file(image_path).unwrap_or_else(|error| exit!("svm: {}: {}", image_path.display(), error));
+
+        vm.load_at(addr, &bytes).unwrap_or_else(|error| exit!("svm: {}: {}", image_path.display(), error));
+    }
+
+    if let Some(entry) = matches.value_of("entry") {
+        vm.set_entry(parse(entry));
+    }
+
+    // The program is named after its file, as a C program would be
+    vm.args = Some(path.display().to_string()).into_iter()
+                  .chain(matches.values_of("ARGS").into_iter().flat_map(|v| v).map(|arg| arg.to_owned()))
+                  .collect();
+
+    if matches.is_present("inherit-env") {
+        vm.env.extend(env::vars());
+    }
+
+    for var in matches.values_of("env").into_iter().flat_map(|v| v) {
+        match var.find('=') {
+            Some(i) => vm.env.push((var[..i].to_owned(), var[i + 1..].to_owned())),
+            None => exit!("svm: invalid environment variable: {}", var)
+        }
+    }
+
+    // A restored program has already started, so its stack is left alone
+    if dump.is_none() {
+        vm.push_args().unwrap_or_else(|error| exit!("svm: {}", error));
+    }
+
     let verbose = matches.is_present("verbose");
 
-    vm.and_then(|mut vm| {
-        vm.verbose_output = verbose;
-        vm.breakpoints_enabled = matches.is_present("breakpoints");
+    if let Some(max_pages) = matches.value_of("max-pages") {
+        let max_pages = max_pages.parse().unwrap_or_else(|_| exit!("svm: invalid integer: {}", max_pages));
+        vm.memory.set_max_pages(Some(max_pages));
+    }
+
+    vm.memory.set_strict(matches.is_present("strict"));
 
-        vm.run().and_then(|exit_code| {
-            if verbose {
-                println!("svm: exiting with code {}", exit_code);
-            }
+    vm.verbose_output = verbose;
+    vm.breakpoints_enabled = matches.is_present("breakpoints");
+    vm.engine = match matches.value_of("engine") {
+        Some("blocks") => Engine::Blocks,
+        #[cfg(feature = "jit")]
+        Some("jit") => Engine::Jit,
+        _ => Engine::Interpreter
+    };
+
+    if let Some(ns) = matches.value_of("virtual-clock") {
+        let ns = ns.parse().unwrap_or_else(|_| exit!("svm: invalid integer: {}", ns));
+        vm.clock = Clock::Virtual { start: 0, ns_per_instruction: ns };
+    }
 
-            if let Some(path) = matches.value_of("memory-dump") {
-                write_to_file(Path::new(path), &vm).unwrap_or_else(|error| exit!("svm: {}", error));
-            }
+    if let Some(seed) = matches.value_of("seed") {
+        let seed = seed.parse().unwrap_or_else(|_| exit!("svm: invalid integer: {}", seed));
+        vm.random = Random::new(seed);
+    }
+
+    if let Some(path) = matches.value_of("symbols") {
+        vm.symbols = read_symbols(Path::new(path)).unwrap_or_else(|error| exit!("svm: {}: {}", path, error));
+    }
+
+    // Run in a scheduler, so that the program can spawn others
+    let mut scheduler = Scheduler::new(vm);
+    let result = scheduler.run();
+    let mut vm = scheduler.into_root();
+
+    // Shared file mappings the program didn't unmap are written back as it exits
+    vm.sync_files().unwrap_or_else(|error| exit!("svm: {}", error));
+
+    if verbose {
+        let stats = vm.memory.stats();
+        println!("svm: {} pages allocated, {} resident at peak, {} bytes written",
+                 stats.pages_allocated, stats.peak_pages, stats.bytes_written);
+    }
+
+    let exit_code = result.unwrap_or_else(|error| exit!("svm: {} at {}", error, vm.symbols.describe(vm.program_ctr())));
+
+    if verbose {
+        println!("svm: exiting with code {}", exit_code);
+    }
+
+    if let Some(path) = matches.value_of("memory-dump") {
+        let format = match matches.value_of("memory-dump-format") {
+            Some("ihex") => DumpFormat::IntelHex,
+            Some("raw-range") => DumpFormat::RawRange,
+            _ => DumpFormat::Sparse
+        };
+
+        write_to_file(Path::new(path), &vm, format).unwrap_or_else(|error| exit!("svm: {}", error));
+    }
 
-            process::exit(exit_code);
-        })
-    }).unwrap_or_else(|error| exit!("svm: {}", error));
+    process::exit(exit_code);
 }
@@ -0,0 +1,69 @@
+#![feature(stmt_expr_attributes)]
+
+extern crate clap;
+extern crate svm;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::process;
+
+use clap::{App, Arg};
+
+use svm::aot;
+
+macro_rules! exit {
+    ($($arg: tt)*) => {
+        #[allow(unused_must_use)]
+        {
+            writeln!(io::stderr(), $($arg)*);
+            process::exit(1);
+        }
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, io::Error> {
+    let mut vec = Vec::new();
+    File::open(path)?.read_to_end(&mut vec)?;
+
+    Ok(vec)
+}
+
+fn write_source(path: &Path, image: &[u8]) -> Result<(), io::Error> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    aot::translate(image, &mut writer)?;
+
+    writer.flush()
+}
+
+fn main() {
+    let matches = App::new("Simple Virtual Machine Translator")
+                          .version("0.1.0")
+                          .author("James Chapman <james.chapman2@mail.bcu.ac.uk>")
+                          .about("Translates Simple Virtual Machine programs into C")
+                          .arg(Arg::with_name("output")
+                              .short("o")
+                              .long("output")
+                              .value_name("FILE")
+                              .help("Set an output file name")
+                              .takes_value(true))
+                          .arg(Arg::with_name("FILE")
+                              .help("The program to translate")
+                              .required(true))
+                          .get_matches();
+
+    let input = matches.value_of("FILE").unwrap();
+    let image = read_file(Path::new(input)).unwrap_or_else(|error| exit!("svm2c: {}: {}", input, error));
+
+    if image.len() as u64 > u32::max_value() as u64 + 1 {
+        exit!("svm2c: {}: program is larger than memory", input);
+    }
+
+    aot::check(&image).unwrap_or_else(|error| exit!("svm2c: {}: {}", input, error));
+
+    // Name the output after the input unless told otherwise
+    let default_filename = format!("{}.c", input);
+    let output = Path::new(matches.value_of("output").unwrap_or(&default_filename[..]));
+
+    write_source(output, &image).unwrap_or_else(|error| exit!("svm2c: {}: {}", output.display(), error));
+}
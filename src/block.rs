@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hasher};
+
+use {Error, Instruction, MemoryBackend, OpCode};
+
+/// A pre-translated instruction, which returns `false` if the rest of the
+/// block must be abandoned because it overwrote code, or an error if it
+/// accessed memory out of bounds.
+pub type Step<M> = Box<Fn(&mut [u32; 32], &mut M) -> Result<bool, Error>>;
+
+/// A run of instructions which can be executed without dispatching on each
+/// one, ending at the first instruction which may change the program counter.
+pub struct Block<M> {
+    /// Each translated instruction, along with the address of the instruction
+    /// following it.
+    pub steps: Vec<(u32, Step<M>)>,
+    /// Address of the instruction following the last step.
+    pub end: u32,
+    /// The instruction at `end` which ended the block, to be executed by the
+    /// interpreter. `None` if the block ended at an invalid instruction.
+    pub terminator: Option<Instruction>,
+    /// Number of bytes of code the block was translated from, including the
+    /// terminator.
+    pub size: u32,
+    /// Value of `MemoryBackend::code_generation` for the code the block was
+    /// translated from, when it was translated.
+    pub generation: u64
+}
+
+/// Hasher for block addresses. Blocks are looked up on every dispatch, and
+/// the default hasher is designed to resist collision attacks rather than
+/// to be fast.
+#[derive(Default)]
+pub struct AddressHasher(u64);
+
+impl Hasher for AddressHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 << 8 | byte as u64).wrapping_mul(0x9e3779b97f4a7c15);
+        }
+    }
+
+    fn write_u32(&mut self, value: u32) {
+        self.0 = (value as u64).wrapping_mul(0x9e3779b97f4a7c15);
+    }
+}
+
+/// Translated blocks, by their start address.
+pub type Blocks<M> = HashMap<u32, Block<M>, BuildHasherDefault<AddressHasher>>;
+
+/// Returns the operation performed by an arithmetic or logic instruction on
+/// its two operands.
+pub fn operation(op: OpCode) -> Option<fn(u32, u32) -> u32> {
+    use OpCode::*;
+
+    fn add(a: u32, b: u32) -> u32 { a.wrapping_add(b) }
+    fn sub(a: u32, b: u32) -> u32 { a.wrapping_sub(b) }
+    fn and(a: u32, b: u32) -> u32 { a & b }
+    fn or(a: u32, b: u32) -> u32 { a | b }
+    fn xor(a: u32, b: u32) -> u32 { a ^ b }
+    fn sll(a: u32, b: u32) -> u32 { a << (b & 0x1f) }
+    fn srl(a: u32, b: u32) -> u32 { a >> (b & 0x1f) }
+    fn sra(a: u32, b: u32) -> u32 { ((a as i32) >> (b & 0x1f)) as u32 }
+    fn second(_: u32, b: u32) -> u32 { b }
+
+    Some(match op {
+        ADD | C_ADD | ADDI | C_ADDI => add,
+        SUB | C_SUB => sub,
+        AND | C_AND | ANDI | C_ANDI => and,
+        OR  | C_OR  | ORI  | C_ORI  => or,
+        XOR | C_XOR | XORI | C_XORI => xor,
+        SLL | C_SLL | SLLI | C_SLLI => sll,
+        SRL | C_SRL | SRLI | C_SRLI => srl,
+        SRA | C_SRA | SRAI | C_SRAI => sra,
+        MV | LI | C_LI | LUI | C_LUI => second,
+        _ => return None
+    })
+}
+
+fn step<M, F: Fn(&mut [u32; 32], &mut M) -> Result<bool, Error> + 'static>(f: F) -> Step<M> {
+    Box::new(f)
+}
+
+/// Translates `instr` into a step, or returns `None` if it must end the block.
+/// `next` is the address of the following instruction, which is the value
+/// the program counter has while `instr` executes.
+fn translate<M: MemoryBackend + 'static>(instr: Instruction, next: u32) -> Option<Step<M>> {
+    use Instruction::*;
+    use OpCode::*;
+
+    let (inner, reads_pc): (Step<M>, bool) = match instr {
+        Register { dst: 0, .. } | Immediate { dst: 0, .. } | Upper { dst: 0, .. } => return None,
+        Register { op, dst, src1, src2 } => {
+            let f = match operation(op) { Some(f) => f, None => return None };
+            (step(move |regs, _| { regs[dst] = f(regs[src1], regs[src2]); Ok(true) }), src1 == 0 || src2 == 0)
+        },
+        Immediate { op: LOAD, dst, src1, imm } | Immediate { op: C_LOAD, dst, src1, imm } => {
+            (step(move |regs, mem: &mut M| {
+                regs[dst] = mem.read_u32(regs[src1].wrapping_add(imm))?;
+                Ok(true)
+            }), src1 == 0)
+        },
+        Immediate { op, dst, src1, imm } => {
+            let f = match operation(op) { Some(f) => f, None => return None };
+            (step(move |regs, _| { regs[dst] = f(regs[src1], imm); Ok(true) }), src1 == 0)
+        },
+        Store { op: STORE, src1, src2, imm } | Store { op: C_STORE, src1, src2, imm } => {
+            (step(move |regs, mem: &mut M| {
+                let addr = regs[src1].wrapping_add(imm);
+                let generation = mem.code_generation(addr, 4);
+                mem.write_u32(addr, regs[src2])?;
+
+                // The write may have changed instructions later in the block
+                Ok(mem.code_generation(addr, 4) == generation)
+            }), src1 == 0 || src2 == 0)
+        },
+        Store { .. } => return None,
+        Upper { dst, imm, .. } => (step(move |regs, _| { regs[dst] = imm; Ok(true) }), false)
+    };
+
+    if reads_pc {
+        Some(step(move |regs, mem| {
+            regs[0] = next;
+            inner(regs, mem)
+        }))
+    } else {
+        Some(inner)
+    }
+}
+
+impl<M: MemoryBackend + 'static> Block<M> {
+    /// Decodes and translates the instructions starting at `start`.
+    ///
+    /// Returns an error only if the first instruction is invalid, otherwise
+    /// the block ends before the invalid instruction.
+    pub fn translate(memory: &mut M, start: u32) -> Result<Self, Error> {
+        let mut steps = Vec::new();
+        let mut addr = start;
+        let mut terminator = None;
+
+        loop {
+            let instr = match memory.fetch(addr) {
+                Ok(instr) => instr,
+                Err(e) => if steps.is_empty() { return Err(e) } else { break }
+            };
+            let next = addr.wrapping_add(instr.size());
+
+            match translate(instr, next) {
+                Some(step) => steps.push((next, step)),
+                None => {
+                    terminator = Some(instr);
+                    break;
+                }
+            }
+
+            addr = next;
+        }
+
+        let size = addr.wrapping_sub(start) + terminator.map_or(0, |instr| instr.size());
+
+        Ok(Self {
+            steps: steps,
+            end: addr,
+            terminator: terminator,
+            size: size,
+            generation: memory.code_generation(start, size)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {Error, FlatMemory, Instruction, Memory, MemoryBackend, OpCode};
+
+    use super::Block;
+
+    #[test]
+    fn translate() {
+        let mut memory = Memory::new();
+
+        // c.li r4, 1; add r5, r0, r4; c.addi r0, -3
+        memory.write(0, &[0x31, 0x03, 0x42, 0x01, 0x04, 0x00, 0x13, 0xfa]).unwrap();
+
+        let block = Block::translate(&mut memory, 0).unwrap();
+        assert_eq!((block.steps.len(), block.end), (2, 6));
+        assert_eq!(block.terminator, Some(Instruction::Immediate { op: OpCode::C_ADDI, dst: 0, src1: 0, imm: -3i32 as u32 }));
+
+        let mut regs = [0; 32];
+
+        for &(_, ref step) in &block.steps {
+            assert_eq!(step(&mut regs, &mut memory), Ok(true));
+        }
+
+        assert_eq!(regs[..6], [6, 0, 0, 0, 1, 7]);
+    }
+
+    #[test]
+    fn invalid() {
+        let mut memory = Memory::new();
+        memory.write(0, &[0x31, 0x03, 0x01, 0x00]).unwrap();
+
+        let block = Block::translate(&mut memory, 0).unwrap();
+        assert_eq!((block.steps.len(), block.end, block.terminator), (1, 2, None));
+        assert_eq!(Block::translate(&mut memory, 2).is_err(), true);
+    }
+
+    #[test]
+    fn store_code() {
+        let mut memory = Memory::new();
+
+        // store r4, r5, 0; c.li r3, 1
+        memory.write(0, &[0x36, 0x20, 0x05, 0x00, 0xf1, 0x02]).unwrap();
+
+        let block = Block::translate(&mut memory, 0).unwrap();
+        let mut regs = [0; 32];
+        regs[4] = 0x2000;
+
+        assert_eq!(block.steps[0].1(&mut regs, &mut memory), Ok(true));
+        regs[4] = 4;
+        assert_eq!(block.steps[0].1(&mut regs, &mut memory), Ok(false));
+    }
+
+    #[test]
+    fn out_of_bounds() {
+        let mut memory = FlatMemory::new(16);
+
+        // load r5, r4, 0
+        memory.write(0, &[0x74, 0x21, 0x00, 0x00]).unwrap();
+
+        let block = Block::translate(&mut memory, 0).unwrap();
+        let mut regs = [0; 32];
+        regs[4] = 16;
+
+        assert_eq!(block.steps[0].1(&mut regs, &mut memory), Err(Error::OutOfBounds(16)));
+    }
+}
@@ -0,0 +1,258 @@
+use std::io::{self, Read, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use {Memory, Segment};
+use hex::write_ihex;
+
+/// Magic number identifying sparse memory dumps.
+const MAGIC: &'static [u8; 4] = b"SVMD";
+
+/// The way a `MemoryDump` is written out.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DumpFormat {
+    /// The bytes from the start of the lowest segment to the end of the
+    /// highest, with any gaps between them filled with zeros. Nothing records
+    /// where the range starts, and a program using both the bottom and top of
+    /// memory produces a dump of nearly 4 GiB.
+    RawRange,
+    /// Intel HEX, with the program counter as the start address. See
+    /// `write_ihex`.
+    IntelHex,
+    /// The sparse format described by `MemoryDump`, which is the only one
+    /// which keeps every register and can be read back with
+    /// `MemoryDump::read_from`.
+    Sparse
+}
+
+/// A snapshot of the registers and allocated memory of a machine.
+///
+/// In the sparse format, all values are little endian and a dump consists of:
+///
+/// | Field | Size |
+/// | ----- | ---- |
+/// | Magic number `SVMD` | 4 bytes |
+/// | Page size | 4 bytes |
+/// | Registers `r0`-`r31` | 128 bytes |
+/// | Start of the heap | 4 bytes |
+/// | Program break | 4 bytes |
+/// | Number of segments | 4 bytes |
+///
+/// Followed by each segment as its address, its length and then its bytes.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MemoryDump {
+    pub page_size: u32,
+    pub registers: [u32; 32],
+    /// Lowest address the program break can be moved to with `sys_brk`.
+    pub heap_start: u32,
+    /// Program break, the end of the heap.
+    pub brk: u32,
+    /// Contents of memory, ordered by address.
+    pub segments: Vec<Segment>
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl MemoryDump {
+    /// Constructs a dump of `memory`, `registers` and the heap from
+    /// `heap_start` to `brk`, with a segment for each allocated page.
+    pub fn new(memory: &Memory, registers: [u32; 32], heap_start: u32, brk: u32) -> Self {
+        let page_size = memory.page_size();
+        let segments = memory.pages.iter().map(|(index, page)| Segment {
+            addr: (index * page_size) as u32,
+            data: page.to_vec()
+        });
+
+        Self {
+            page_size: page_size as u32,
+            registers: registers,
+            heap_start: heap_start,
+            brk: brk,
+            segments: segments.collect()
+        }
+    }
+
+    /// Reads a dump in the sparse format from `reader`.
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self, io::Error> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+
+        if &magic != MAGIC {
+            return Err(invalid_data("not an SVM memory dump"));
+        }
+
+        let page_size = reader.read_u32::<LittleEndian>()?;
+        let mut registers = [0; 32];
+
+        for register in registers.iter_mut() {
+            *register = reader.read_u32::<LittleEndian>()?;
+        }
+
+        let heap_start = reader.read_u32::<LittleEndian>()?;
+        let brk = reader.read_u32::<LittleEndian>()?;
+
+        if brk < heap_start {
+            return Err(invalid_data("program break is below the start of the heap"));
+        }
+
+        let mut segments = Vec::new();
+
+        for _ in 0..reader.read_u32::<LittleEndian>()? {
+            let addr = reader.read_u32::<LittleEndian>()?;
+            let len = reader.read_u32::<LittleEndian>()?;
+
+            if addr as u64 + len as u64 > u32::max_value() as u64 + 1 {
+                return Err(invalid_data("segment extends beyond the end of memory"));
+            }
+
+            // The length isn't trusted until that many bytes have been read
+            let mut data = Vec::new();
+            reader.by_ref().take(len as u64).read_to_end(&mut data)?;
+
+            if data.len() != len as usize {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "segment is shorter than its length"));
+            }
+
+            segments.push(Segment { addr: addr, data: data });
+        }
+
+        Ok(Self { page_size, registers, heap_start, brk, segments })
+    }
+
+    /// Writes this dump to `writer` in `format`.
+    pub fn write_to<W: Write>(&self, writer: &mut W, format: DumpFormat) -> Result<(), io::Error> {
+        match format {
+            DumpFormat::RawRange => self.write_raw_range(writer),
+            DumpFormat::IntelHex => write_ihex(writer, &self.segments, Some(self.registers[0])),
+            DumpFormat::Sparse => self.write_sparse(writer)
+        }
+    }
+
+    fn write_raw_range<W: Write>(&self, writer: &mut W) -> Result<(), io::Error> {
+        let mut end = match self.segments.first() {
+            Some(segment) => segment.addr as u64,
+            None => return Ok(())
+        };
+
+        for segment in &self.segments {
+            if (segment.addr as u64) < end {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "segments overlap or are out of order"));
+            }
+
+            io::copy(&mut io::repeat(0).take(segment.addr as u64 - end), writer)?;
+            writer.write_all(&segment.data)?;
+
+            end = segment.addr as u64 + segment.data.len() as u64;
+        }
+
+        Ok(())
+    }
+
+    fn write_sparse<W: Write>(&self, writer: &mut W) -> Result<(), io::Error> {
+        writer.write_all(MAGIC)?;
+        writer.write_u32::<LittleEndian>(self.page_size)?;
+
+        for &register in &self.registers {
+            writer.write_u32::<LittleEndian>(register)?;
+        }
+
+        writer.write_u32::<LittleEndian>(self.heap_start)?;
+        writer.write_u32::<LittleEndian>(self.brk)?;
+
+        writer.write_u32::<LittleEndian>(self.segments.len() as u32)?;
+
+        for segment in &self.segments {
+            writer.write_u32::<LittleEndian>(segment.addr)?;
+            writer.write_u32::<LittleEndian>(segment.data.len() as u32)?;
+            writer.write_all(&segment.data)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Cursor};
+
+    use {Memory, Segment};
+
+    use super::{DumpFormat, MemoryDump};
+
+    fn dump() -> MemoryDump {
+        let mut memory = Memory::with_page_size(16);
+        memory.write(0xfffffffc, &[1, 2, 3, 4]).unwrap();
+        memory.write(0x22, &[5, 6]).unwrap();
+
+        let mut registers = [0; 32];
+        registers[0] = 0x20;
+        registers[1] = 0xfffffffc;
+
+        MemoryDump::new(&memory, registers, 0x24, 0x30)
+    }
+
+    #[test]
+    fn new() {
+        let dump = dump();
+
+        assert_eq!(dump.page_size, 16);
+        assert_eq!((dump.heap_start, dump.brk), (0x24, 0x30));
+        assert_eq!(dump.segments.len(), 2);
+        assert_eq!(dump.segments[0].addr, 0x20);
+        assert_eq!(dump.segments[1], Segment { addr: 0xfffffff0, data: vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                                                                            1, 2, 3, 4] });
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut buf = Vec::new();
+        dump().write_to(&mut buf, DumpFormat::Sparse).unwrap();
+
+        assert_eq!(&buf[..8], b"SVMD\x10\0\0\0");
+        assert_eq!(MemoryDump::read_from(&mut Cursor::new(buf)).unwrap(), dump());
+    }
+
+    #[test]
+    fn raw_range() {
+        let mut dump = dump();
+        dump.segments[1].addr = 0x38;
+
+        let mut buf = Vec::new();
+        dump.write_to(&mut buf, DumpFormat::RawRange).unwrap();
+
+        assert_eq!(buf.len(), 0x38 + 16 - 0x20);
+        assert_eq!(&buf[..4], &[0, 0, 5, 6]);
+        assert_eq!(&buf[buf.len() - 4..], &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(MemoryDump::read_from(&mut Cursor::new(b"SVMO".to_vec())).is_err(), true);
+
+        let mut buf = Vec::new();
+        dump().write_to(&mut buf, DumpFormat::Sparse).unwrap();
+        buf.pop();
+
+        assert_eq!(MemoryDump::read_from(&mut Cursor::new(buf)).is_err(), true);
+
+        // A segment claiming to be 4 GiB is only read as far as the data goes
+        let mut buf = Vec::new();
+        dump().write_to(&mut buf, DumpFormat::Sparse).unwrap();
+        buf[144..152].copy_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0]);
+        buf[152..156].copy_from_slice(&[0xff; 4]);
+        buf.truncate(180);
+
+        let error = MemoryDump::read_from(&mut Cursor::new(buf)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+
+        // The break can't be below the start of the heap
+        let mut dump = dump();
+        dump.brk = 0x20;
+
+        let mut buf = Vec::new();
+        dump.write_to(&mut buf, DumpFormat::Sparse).unwrap();
+        assert_eq!(MemoryDump::read_from(&mut Cursor::new(buf)).is_err(), true);
+    }
+}
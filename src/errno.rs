@@ -0,0 +1,164 @@
+use std::io;
+
+/// The reason a syscall failed, which is returned to the program negated in
+/// `r3`.
+///
+/// Values are those of the corresponding POSIX error numbers on Linux, which
+/// most other Unix systems share.
+#[repr(u32)]
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ErrorCode {
+    /// Operation not permitted
+    EPERM = 1,
+    /// No such file or directory
+    ENOENT = 2,
+    /// No such process
+    ESRCH = 3,
+    /// Interrupted system call
+    EINTR = 4,
+    /// I/O error, also used for any error without a more specific code
+    EIO = 5,
+    /// Argument list too long
+    E2BIG = 7,
+    /// Bad file handle
+    EBADF = 9,
+    /// No child process with the given ID
+    ECHILD = 10,
+    /// Resource temporarily unavailable
+    EAGAIN = 11,
+    /// Out of memory
+    ENOMEM = 12,
+    /// Permission denied
+    EACCES = 13,
+    /// Bad address, when a buffer isn't within memory
+    EFAULT = 14,
+    /// Device or resource busy
+    EBUSY = 16,
+    /// File exists
+    EEXIST = 17,
+    /// Cross-device link
+    EXDEV = 18,
+    /// Not a directory
+    ENOTDIR = 20,
+    /// Is a directory
+    EISDIR = 21,
+    /// Invalid argument
+    EINVAL = 22,
+    /// Too many open files
+    EMFILE = 24,
+    /// File too large
+    EFBIG = 27,
+    /// No space left on device
+    ENOSPC = 28,
+    /// Illegal seek
+    ESPIPE = 29,
+    /// Read-only file system
+    EROFS = 30,
+    /// Broken pipe
+    EPIPE = 32,
+    /// Syscall not supported where the program is running
+    ENOSYS = 38
+}
+
+impl ErrorCode {
+    /// Returns the code with the value `code`, if there is one.
+    pub fn from_raw(code: u32) -> Option<Self> {
+        use self::ErrorCode::*;
+
+        Some(match code {
+            1 => EPERM,
+            2 => ENOENT,
+            3 => ESRCH,
+            4 => EINTR,
+            5 => EIO,
+            7 => E2BIG,
+            9 => EBADF,
+            10 => ECHILD,
+            11 => EAGAIN,
+            12 => ENOMEM,
+            13 => EACCES,
+            14 => EFAULT,
+            16 => EBUSY,
+            17 => EEXIST,
+            18 => EXDEV,
+            20 => ENOTDIR,
+            21 => EISDIR,
+            22 => EINVAL,
+            24 => EMFILE,
+            27 => EFBIG,
+            28 => ENOSPC,
+            29 => ESPIPE,
+            30 => EROFS,
+            32 => EPIPE,
+            38 => ENOSYS,
+            _ => return None
+        })
+    }
+
+    /// Returns the value a syscall failing with this code returns, which is
+    /// the code negated.
+    pub fn to_return_value(self) -> u32 {
+        (self as u32).wrapping_neg()
+    }
+}
+
+impl From<io::Error> for ErrorCode {
+    /// Converts a host error by its kind. On Unix, the host's own error
+    /// number is used instead if it has a code, as those below 35 are the
+    /// same across Unix systems and distinguish more errors than `ErrorKind`.
+    fn from(error: io::Error) -> Self {
+        use std::io::ErrorKind::*;
+
+        if cfg!(unix) {
+            let code = error.raw_os_error().and_then(|code| if code < 35 { ErrorCode::from_raw(code as u32) } else { None });
+
+            if let Some(code) = code {
+                return code;
+            }
+        }
+
+        match error.kind() {
+            NotFound => ErrorCode::ENOENT,
+            PermissionDenied => ErrorCode::EACCES,
+            AlreadyExists => ErrorCode::EEXIST,
+            WouldBlock => ErrorCode::EAGAIN,
+            InvalidInput | InvalidData => ErrorCode::EINVAL,
+            BrokenPipe => ErrorCode::EPIPE,
+            Interrupted => ErrorCode::EINTR,
+            _ => ErrorCode::EIO
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use super::ErrorCode;
+
+    #[test]
+    fn from_io_error() {
+        let code = |kind| ErrorCode::from(io::Error::new(kind, "test"));
+
+        assert_eq!(code(io::ErrorKind::NotFound), ErrorCode::ENOENT);
+        assert_eq!(code(io::ErrorKind::PermissionDenied), ErrorCode::EACCES);
+        assert_eq!(code(io::ErrorKind::AlreadyExists), ErrorCode::EEXIST);
+        assert_eq!(code(io::ErrorKind::InvalidInput), ErrorCode::EINVAL);
+        assert_eq!(code(io::ErrorKind::Other), ErrorCode::EIO);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn from_os_error() {
+        assert_eq!(ErrorCode::from(io::Error::from_raw_os_error(20)), ErrorCode::ENOTDIR);
+        assert_eq!(ErrorCode::from(io::Error::from_raw_os_error(2)), ErrorCode::ENOENT);
+    }
+
+    #[test]
+    fn to_return_value() {
+        assert_eq!(ErrorCode::EBADF.to_return_value(), -9i32 as u32);
+        assert_eq!(ErrorCode::from_raw(ErrorCode::EPIPE as u32), Some(ErrorCode::EPIPE));
+        assert_eq!(ErrorCode::from_raw(6), None);
+    }
+}
@@ -5,7 +5,9 @@ use std::fmt;
 pub enum Error {
     ProgramTooLarge,
     InvalidOpCode(u32),
-    InvalidSysCall(u16)
+    InvalidSysCall(u16),
+    OutOfBounds(u32),
+    OutOfMemory
 }
 
 impl fmt::Display for Error {
@@ -17,6 +19,7 @@ impl fmt::Display for Error {
         match *self {
             Error::InvalidOpCode(op) => write!(f, " (0b{:06b})", op),
             Error::InvalidSysCall(call) => write!(f, " (0x{:04x})", call),
+            Error::OutOfBounds(addr) => write!(f, " (0x{:08x})", addr),
             _ => Ok(())
         }
     }
@@ -27,7 +30,9 @@ impl error::Error for Error {
         match *self {
             Error::ProgramTooLarge => "length of program exceeds 2^32 bytes",
             Error::InvalidOpCode(_) => "invalid opcode encountered",
-            Error::InvalidSysCall(_) => "invalid syscall encountered"
+            Error::InvalidSysCall(_) => "invalid syscall encountered",
+            Error::OutOfBounds(_) => "memory access out of bounds",
+            Error::OutOfMemory => "memory limit exceeded"
         }
     }
 }
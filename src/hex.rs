@@ -0,0 +1,409 @@
+use std::io::{self, BufRead, Write};
+
+/// Number of data bytes written per record.
+const BYTES_PER_RECORD: usize = 16;
+
+/// A run of bytes to be placed at an address.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Segment {
+    pub addr: u32,
+    pub data: Vec<u8>
+}
+
+/// Largest raw binary the tools write, as segments placed far apart would
+/// otherwise be separated by gigabytes of zeros.
+pub const MAX_RAW_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Returns the length of the run of bytes `flatten_segments` would return.
+pub fn flattened_size(address: u32, segments: &[Segment]) -> u64 {
+    let end = segments.iter().map(|s| s.addr as u64 + s.data.len() as u64).max().unwrap_or(address as u64);
+    end - address as u64
+}
+
+/// Returns the contents of `segments` as one run of bytes starting at
+/// `address`, with the gaps between them filled with zeros. Every segment
+/// must start at or after `address`.
+pub fn flatten_segments(address: u32, segments: &[Segment]) -> Vec<u8> {
+    let mut bytes = vec![0; flattened_size(address, segments) as usize];
+
+    for segment in segments {
+        let offset = (segment.addr - address) as usize;
+        bytes[offset..offset + segment.data.len()].copy_from_slice(&segment.data);
+    }
+
+    bytes
+}
+
+/// The contents of a hex file: segments of memory, ordered as they appeared
+/// in the file, and the address execution starts from if the file gives one.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct HexImage {
+    pub segments: Vec<Segment>,
+    pub start: Option<u32>
+}
+
+impl HexImage {
+    /// Adds `data` at `addr`, extending the last segment if it ends at `addr`.
+    fn push(&mut self, addr: u32, data: &[u8]) {
+        if let Some(last) = self.segments.last_mut() {
+            if last.addr as u64 + last.data.len() as u64 == addr as u64 {
+                last.data.extend_from_slice(data);
+                return;
+            }
+        }
+
+        self.segments.push(Segment { addr: addr, data: data.to_vec() });
+    }
+}
+
+fn invalid_line(num: usize, msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", num + 1, msg))
+}
+
+/// Decodes a record of hexadecimal digit pairs following the start code.
+fn decode_record(num: usize, digits: &str) -> Result<Vec<u8>, io::Error> {
+    if digits.len() % 2 != 0 || !digits.is_char_boundary(digits.len()) {
+        return Err(invalid_line(num, "odd number of hexadecimal digits"));
+    }
+
+    (0..digits.len() / 2)
+        .map(|i| digits.get(i * 2 .. i * 2 + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                       .ok_or_else(|| invalid_line(num, "invalid hexadecimal digit")))
+        .collect()
+}
+
+/// Writes an Intel HEX record of type `kind` with 16 bit address `addr`.
+fn write_ihex_record<W: Write>(writer: &mut W, kind: u8, addr: u16, data: &[u8]) -> Result<(), io::Error> {
+    let mut sum = data.len() as u8;
+    sum = sum.wrapping_add((addr >> 8) as u8).wrapping_add(addr as u8).wrapping_add(kind);
+
+    write!(writer, ":{:02X}{:04X}{:02X}", data.len(), addr, kind)?;
+
+    for &byte in data {
+        write!(writer, "{:02X}", byte)?;
+        sum = sum.wrapping_add(byte);
+    }
+
+    writeln!(writer, "{:02X}", sum.wrapping_neg())
+}
+
+/// Writes `segments` to `writer` as Intel HEX, along with a start linear
+/// address record for `start` if there is one.
+///
+/// Addresses above 64 KiB are given with extended linear address records,
+/// and data records never cross a 64 KiB boundary.
+pub fn write_ihex<W: Write>(writer: &mut W, segments: &[Segment], start: Option<u32>) -> Result<(), io::Error> {
+    // Upper half of the address data records are currently relative to
+    let mut base = 0;
+
+    for segment in segments {
+        let mut addr = segment.addr;
+        let mut data = &segment.data[..];
+
+        while !data.is_empty() {
+            if addr >> 16 != base {
+                base = addr >> 16;
+                write_ihex_record(writer, 0x04, 0, &[(base >> 8) as u8, base as u8])?;
+            }
+
+            let len = data.len().min(BYTES_PER_RECORD).min(0x10000 - (addr & 0xffff) as usize);
+
+            write_ihex_record(writer, 0x00, addr as u16, &data[..len])?;
+
+            addr = addr.wrapping_add(len as u32);
+            data = &data[len..];
+        }
+    }
+
+    if let Some(start) = start {
+        write_ihex_record(writer, 0x05, 0, &[(start >> 24) as u8, (start >> 16) as u8, (start >> 8) as u8,
+                                             start as u8])?;
+    }
+
+    write_ihex_record(writer, 0x01, 0, &[])
+}
+
+/// Reads an Intel HEX file from `reader`.
+///
+/// Every record's checksum is validated, and errors give the line number of
+/// the record at fault. Blank lines are ignored, as is anything after the end
+/// of file record.
+pub fn read_ihex<R: BufRead>(reader: &mut R) -> Result<HexImage, io::Error> {
+    let mut image = HexImage::default();
+    // Added to the address of data records, set by extended address records
+    let mut base = 0u32;
+
+    for (num, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        if !line.starts_with(':') {
+            return Err(invalid_line(num, "expected a record starting with ':'"));
+        }
+
+        let bytes = decode_record(num, &line[1..])?;
+
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(invalid_line(num, "record length does not match its byte count"));
+        }
+
+        if bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0 {
+            return Err(invalid_line(num, "invalid checksum"));
+        }
+
+        let addr = (bytes[1] as u32) << 8 | bytes[2] as u32;
+        let data = &bytes[4 .. bytes.len() - 1];
+
+        let value = |len: usize| -> Result<u32, io::Error> {
+            if data.len() != len {
+                return Err(invalid_line(num, "invalid length for record type"));
+            }
+
+            Ok(data.iter().fold(0, |value, &byte| value << 8 | byte as u32))
+        };
+
+        match bytes[3] {
+            0x00 => image.push(base.wrapping_add(addr), data),
+            0x01 => return Ok(image),
+            0x02 => base = value(2)? << 4,
+            0x03 => {
+                let value = value(4)?;
+                image.start = Some((value >> 16 << 4).wrapping_add(value & 0xffff));
+            },
+            0x04 => base = value(2)? << 16,
+            0x05 => image.start = Some(value(4)?),
+            _ => return Err(invalid_line(num, "unknown record type"))
+        }
+    }
+
+    Err(io::Error::new(io::ErrorKind::InvalidData, "missing end of file record"))
+}
+
+/// Writes an S-record of type `kind`, with an address of `addr_len` bytes.
+fn write_srec_record<W: Write>(writer: &mut W, kind: u8, addr: u32, addr_len: usize, data: &[u8])
+                               -> Result<(), io::Error> {
+    let count = (addr_len + data.len() + 1) as u8;
+    let mut sum = count;
+
+    write!(writer, "S{}{:02X}", kind, count)?;
+
+    for i in (0..addr_len).rev() {
+        let byte = (addr >> (i * 8)) as u8;
+        write!(writer, "{:02X}", byte)?;
+        sum = sum.wrapping_add(byte);
+    }
+
+    for &byte in data {
+        write!(writer, "{:02X}", byte)?;
+        sum = sum.wrapping_add(byte);
+    }
+
+    writeln!(writer, "{:02X}", !sum)
+}
+
+/// Writes `segments` to `writer` as Motorola S-records, along with a
+/// termination record giving `start`, or 0 if there isn't one.
+///
+/// The shortest addresses which fit every segment are used, so the data
+/// records are all `S1`, `S2` or `S3` records. A header record comes first,
+/// and a record count follows the data.
+pub fn write_srec<W: Write>(writer: &mut W, segments: &[Segment], start: Option<u32>) -> Result<(), io::Error> {
+    let start = start.unwrap_or(0);
+    let end = segments.iter().map(|s| s.addr as u64 + s.data.len() as u64).chain(Some(start as u64 + 1)).max();
+
+    // Data record type and address length, by the highest address used
+    let (kind, addr_len) = match end.unwrap() {
+        0 ... 0x10000 => (1, 2),
+        0x10001 ... 0x1000000 => (2, 3),
+        _ => (3, 4)
+    };
+
+    write_srec_record(writer, 0, 0, 2, b"svm")?;
+
+    let mut count = 0u32;
+
+    for segment in segments {
+        for (i, data) in segment.data.chunks(BYTES_PER_RECORD).enumerate() {
+            let addr = segment.addr.wrapping_add((i * BYTES_PER_RECORD) as u32);
+
+            write_srec_record(writer, kind, addr, addr_len, data)?;
+            count += 1;
+        }
+    }
+
+    if count <= 0xffff {
+        write_srec_record(writer, 5, count, 2, &[])?;
+    } else if count <= 0xffffff {
+        write_srec_record(writer, 6, count, 3, &[])?;
+    }
+
+    write_srec_record(writer, 10 - kind, start, addr_len, &[])
+}
+
+/// Reads a Motorola S-record file from `reader`.
+///
+/// Every record's checksum is validated, as is the record count if the file
+/// has one, and errors give the line number of the record at fault. Blank
+/// lines are ignored, as is anything after the termination record.
+pub fn read_srec<R: BufRead>(reader: &mut R) -> Result<HexImage, io::Error> {
+    let mut image = HexImage::default();
+    let mut count = 0u32;
+
+    for (num, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        if !line.starts_with('S') || line.len() < 2 {
+            return Err(invalid_line(num, "expected a record starting with 'S'"));
+        }
+
+        let kind = match line.as_bytes()[1] {
+            digit @ b'0' ... b'9' if digit != b'4' => digit - b'0',
+            _ => return Err(invalid_line(num, "unknown record type"))
+        };
+        let addr_len = match kind {
+            0 | 1 | 5 | 9 => 2,
+            2 | 6 | 8 => 3,
+            _ => 4
+        };
+
+        let bytes = decode_record(num, &line[2..])?;
+
+        if bytes.len() < addr_len + 2 || bytes.len() != bytes[0] as usize + 1 {
+            return Err(invalid_line(num, "record length does not match its byte count"));
+        }
+
+        if bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0xff {
+            return Err(invalid_line(num, "invalid checksum"));
+        }
+
+        let addr = bytes[1 .. addr_len + 1].iter().fold(0, |addr, &byte| addr << 8 | byte as u32);
+        let data = &bytes[addr_len + 1 .. bytes.len() - 1];
+
+        match kind {
+            0 => {},
+            1 | 2 | 3 => {
+                image.push(addr, data);
+                count += 1;
+            },
+            5 | 6 => if addr != count {
+                return Err(invalid_line(num, "record count does not match the number of data records"));
+            },
+            _ => {
+                image.start = Some(addr);
+                return Ok(image);
+            }
+        }
+    }
+
+    Err(io::Error::new(io::ErrorKind::InvalidData, "missing termination record"))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::{read_ihex, read_srec, write_ihex, write_srec, HexImage, Segment};
+
+    fn image() -> HexImage {
+        HexImage {
+            segments: vec![
+                Segment { addr: 0, data: (0..20).collect() },
+                Segment { addr: 0x1fffe, data: vec![0xaa, 0xbb, 0xcc, 0xdd] }
+            ],
+            start: Some(0x12345678)
+        }
+    }
+
+    #[test]
+    fn write() {
+        let mut buf = Vec::new();
+        write_ihex(&mut buf, &image().segments, image().start).unwrap();
+
+        assert_eq!(String::from_utf8(buf).unwrap(),
+                   ":10000000000102030405060708090A0B0C0D0E0F78\n\
+                    :0400100010111213A6\n\
+                    :020000040001F9\n\
+                    :02FFFE00AABB9C\n\
+                    :020000040002F8\n\
+                    :02000000CCDD55\n\
+                    :0400000512345678E3\n\
+                    :00000001FF\n");
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut buf = Vec::new();
+        write_ihex(&mut buf, &image().segments, image().start).unwrap();
+
+        assert_eq!(read_ihex(&mut Cursor::new(buf)).unwrap(), image());
+    }
+
+    #[test]
+    fn segment_address() {
+        let source = ":020000021000EC\n:0100040042B9\n:0400000300100020C9\n:00000001FF\n";
+        let image = read_ihex(&mut Cursor::new(source)).unwrap();
+
+        assert_eq!(image.segments, vec![Segment { addr: 0x10004, data: vec![0x42] }]);
+        assert_eq!(image.start, Some(0x120));
+    }
+
+    #[test]
+    fn errors() {
+        let error = |source: &str| read_ihex(&mut Cursor::new(source)).unwrap_err().to_string();
+
+        assert_eq!(error(":0100000042BD\n\n:0100000042BE\n"), "line 3: invalid checksum");
+        assert_eq!(error("0100000042BD\n"), "line 1: expected a record starting with ':'");
+        assert_eq!(error(":0200000042BC\n"), "line 1: record length does not match its byte count");
+        assert_eq!(error(":01000000G2BD\n"), "line 1: invalid hexadecimal digit");
+        assert_eq!(error(":0100000042BD\n"), "missing end of file record");
+    }
+
+    #[test]
+    fn write_srec_records() {
+        let segments = [Segment { addr: 0x10, data: (0..20).collect() }];
+
+        let mut buf = Vec::new();
+        write_srec(&mut buf, &segments, None).unwrap();
+
+        assert_eq!(String::from_utf8(buf).unwrap(),
+                   "S006000073766DA3\n\
+                    S1130010000102030405060708090A0B0C0D0E0F64\n\
+                    S10700201011121392\n\
+                    S5030002FA\n\
+                    S9030000FC\n");
+    }
+
+    #[test]
+    fn srec_round_trip() {
+        let mut buf = Vec::new();
+        write_srec(&mut buf, &image().segments, image().start).unwrap();
+
+        let text = String::from_utf8(buf.clone()).unwrap();
+        assert_eq!(text.lines().nth(1).map(|line| &line[..4]), Some("S315"));
+        assert_eq!(text.lines().last(), Some("S70512345678E6"));
+
+        assert_eq!(read_srec(&mut Cursor::new(buf)).unwrap(), image());
+    }
+
+    #[test]
+    fn srec_errors() {
+        let error = |source: &str| read_srec(&mut Cursor::new(source)).unwrap_err().to_string();
+
+        assert_eq!(error("S104000042B9\n\nS104000042BA\n"), "line 3: invalid checksum");
+        assert_eq!(error(":1040000427B\n"), "line 1: expected a record starting with 'S'");
+        assert_eq!(error("S4040000427B\n"), "line 1: unknown record type");
+        assert_eq!(error("S105000042B9\n"), "line 1: record length does not match its byte count");
+        assert_eq!(error("S104000042B9\nS5030002FA\n"),
+                   "line 2: record count does not match the number of data records");
+        assert_eq!(error("S104000042B9\n"), "missing termination record");
+    }
+}
@@ -1,10 +1,9 @@
 use std::convert::TryFrom;
 use std::io;
+use std::str::FromStr;
 
 use byteorder::{LittleEndian, WriteBytesExt};
 
-use enum_traits::Discriminant;
-
 use Error;
 
 const OP_CODE_MASK: u32 = (1 << 6) - 1;
@@ -47,10 +46,41 @@ const C_IMM_STORE_MASK1: u32 = ((1 << 3) - 1) << (C_IMM_STORE_SHIFT1 + 1);
 const C_IMM_STORE_SHIFT2: u32 = 9;
 const C_IMM_STORE_MASK2: u32 = ((1 << 3) - 1) << (C_IMM_STORE_SHIFT2 + 4);
 
-#[repr(u32)]
-#[allow(non_camel_case_types)]
-#[derive(Copy, Clone, Debug, EnumDiscriminant, EnumFromVariantName, Eq, PartialEq)]
-pub enum OpCode {
+/// Defines `OpCode`, along with conversions from its value and its name.
+macro_rules! op_codes {
+    ($($name:ident = $value:tt,)+) => {
+        #[repr(u32)]
+        #[allow(non_camel_case_types)]
+        #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+        pub enum OpCode {
+            $($name = $value,)+
+        }
+
+        impl OpCode {
+            /// Returns the op code with the value `op`, if there is one.
+            pub fn from_u32(op: u32) -> Option<Self> {
+                match op {
+                    $($value => Some(OpCode::$name),)+
+                    _ => None
+                }
+            }
+        }
+
+        impl FromStr for OpCode {
+            type Err = ();
+
+            /// Parses an op code from its name, such as `C_ADDI`.
+            fn from_str(s: &str) -> Result<Self, ()> {
+                match s {
+                    $(stringify!($name) => Ok(OpCode::$name),)+
+                    _ => Err(())
+                }
+            }
+        }
+    }
+}
+
+op_codes! {
     // 0x00 reserved as invalid instruction
 
     ADD = 0x02,
@@ -156,6 +186,17 @@ impl Instruction {
         }
     }
 
+    /// Returns a mutable reference to the immediate of this instruction, or
+    /// `None` if it doesn't have one.
+    pub fn immediate_mut(&mut self) -> Option<&mut u32> {
+        use Instruction::*;
+
+        match *self {
+            Register { .. } => None,
+            Immediate { ref mut imm, .. } | Store { ref mut imm, .. } | Upper { ref mut imm, .. } => Some(imm)
+        }
+    }
+
     pub fn write_bytes(&self, buf: &mut Vec<u8>) -> Result<(), io::Error> {
         use Instruction::*;
 
@@ -240,7 +281,7 @@ impl TryFrom<u32> for Instruction {
 
         let op = {
             let op = instr & OP_CODE_MASK;
-            OpCode::from_discriminant(op).ok_or(Error::InvalidOpCode(op))?
+            OpCode::from_u32(op).ok_or(Error::InvalidOpCode(op))?
         };
 
         Ok(match op {
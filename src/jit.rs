@@ -0,0 +1,562 @@
+use std::collections::HashMap;
+use std::hash::BuildHasherDefault;
+use std::mem;
+use std::ptr;
+
+use libc;
+
+use {Error, Instruction, MemoryBackend, OpCode, VirtualMachine};
+use block::AddressHasher;
+
+/// Size of the executable memory compiled blocks are written to. Once it's
+/// full, every block is discarded and compiled again when next hot.
+const CODE_BUFFER_SIZE: usize = 1 << 20;
+
+/// Most guest instructions compiled into one block, so that the machine code
+/// for a block always fits in the code buffer. Longer runs of instructions
+/// are split into several blocks.
+const MAX_BLOCK_INSTRUCTIONS: usize = 256;
+
+/// State shared between compiled code and the functions it calls back into.
+#[repr(C)]
+pub struct Context<M> {
+    /// Guest registers, which compiled code addresses relative to the context
+    /// so they must come first.
+    pub registers: [u32; 32],
+    /// Set by a memory callback which failed, after which compiled code
+    /// returns `Exit::Stop` straight away. Must follow the registers.
+    pub stopped: bool,
+    pub vm: *mut VirtualMachine<M>,
+    /// Exit status or error of the program, set when compiled code returns
+    /// `Exit::Stop`.
+    pub result: Option<Result<i32, Error>>
+}
+
+/// Functions compiled code calls, each given the context it was called with.
+/// Memory callbacks are also given the address of the instruction calling
+/// them, to leave in the program counter if they fail.
+pub struct Callbacks<M> {
+    /// Reads the word at an address.
+    pub read_u32: extern "C" fn(*mut Context<M>, u32, u32) -> u32,
+    /// Writes a word to an address, returning non-zero if it overwrote code.
+    pub write_u32: extern "C" fn(*mut Context<M>, u32, u32, u32) -> u32,
+    /// Executes a syscall, given its number and the address of the `CALL`
+    /// instruction.
+    pub syscall: extern "C" fn(*mut Context<M>, u32, u32) -> Exit
+}
+
+/// Why compiled code returned, with the program counter set to the address to
+/// continue from.
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Exit {
+    Continue = 0,
+    /// The program exited or failed, see `Context::result`.
+    Stop = 1,
+    /// The instruction at the program counter must be run by the interpreter.
+    Interpret = 2
+}
+
+/// A compiled block, taking the context to run with.
+pub type Code<M> = extern "C" fn(*mut Context<M>) -> Exit;
+
+/// Memory allocated with `mmap`, which is only writable while code is being
+/// copied into it and is otherwise executable.
+struct CodeBuffer {
+    ptr: *mut u8,
+    len: usize
+}
+
+impl CodeBuffer {
+    fn new() -> Self {
+        let ptr = unsafe {
+            libc::mmap(ptr::null_mut(), CODE_BUFFER_SIZE, libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_PRIVATE | libc::MAP_ANON, -1, 0)
+        };
+
+        assert!(ptr != libc::MAP_FAILED, "failed to allocate executable memory");
+
+        Self {
+            ptr: ptr as *mut u8,
+            len: 0
+        }
+    }
+
+    /// Copies `code` into the buffer, or returns `None` if it's full.
+    fn push<M>(&mut self, code: &[u8]) -> Option<Code<M>> {
+        if self.len + code.len() > CODE_BUFFER_SIZE {
+            return None;
+        }
+
+        unsafe {
+            self.protect(libc::PROT_READ | libc::PROT_WRITE);
+
+            let dst = self.ptr.offset(self.len as isize);
+            ptr::copy_nonoverlapping(code.as_ptr(), dst, code.len());
+            self.len += code.len();
+
+            self.protect(libc::PROT_READ | libc::PROT_EXEC);
+
+            Some(mem::transmute(dst))
+        }
+    }
+
+    /// Changes the protection of the whole buffer to `prot`.
+    unsafe fn protect(&self, prot: libc::c_int) {
+        let result = libc::mprotect(self.ptr as *mut libc::c_void, CODE_BUFFER_SIZE, prot);
+        assert!(result == 0, "failed to change the protection of executable memory");
+    }
+}
+
+impl Drop for CodeBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, CODE_BUFFER_SIZE);
+        }
+    }
+}
+
+// x86-64 registers used by compiled code. `rbx` holds the context.
+const EAX: u8 = 0;
+const ECX: u8 = 1;
+const EDX: u8 = 2;
+const ESI: u8 = 6;
+
+/// Writes x86-64 machine code.
+struct Emitter {
+    code: Vec<u8>
+}
+
+impl Emitter {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn imm32(&mut self, imm: u32) {
+        self.bytes(&[imm as u8, (imm >> 8) as u8, (imm >> 16) as u8, (imm >> 24) as u8]);
+    }
+
+    /// `mov reg, imm32`
+    fn mov_imm(&mut self, reg: u8, imm: u32) {
+        self.bytes(&[0xb8 + reg]);
+        self.imm32(imm);
+    }
+
+    /// Loads guest register `src` into `reg`, where reading the program
+    /// counter gives `next`.
+    fn load(&mut self, reg: u8, src: usize, next: u32) {
+        match src {
+            // mov reg, imm32
+            0 => self.mov_imm(reg, next),
+            // mov reg, [rbx + src * 4]
+            _ => self.bytes(&[0x8b, 0x43 | reg << 3, src as u8 * 4])
+        }
+    }
+
+    /// `mov [rbx + dst * 4], reg`
+    fn store(&mut self, dst: usize, reg: u8) {
+        self.bytes(&[0x89, 0x43 | reg << 3, dst as u8 * 4]);
+    }
+
+    /// `mov dword [rbx + dst * 4], imm32`
+    fn store_imm(&mut self, dst: usize, imm: u32) {
+        self.bytes(&[0xc7, 0x43, dst as u8 * 4]);
+        self.imm32(imm);
+    }
+
+    /// Calls `f` with the context and `esi`, `edx` and `ecx` as arguments,
+    /// leaving the result in `eax`.
+    fn call(&mut self, f: usize) {
+        // mov rdi, rbx; mov rax, imm64; call rax
+        self.bytes(&[0x48, 0x89, 0xdf, 0x48, 0xb8]);
+        self.imm32(f as u32);
+        self.imm32((f as u64 >> 32) as u32);
+        self.bytes(&[0xff, 0xd0]);
+    }
+
+    /// Returns `Exit::Stop` if a memory callback set `Context::stopped`.
+    fn check_stopped(&mut self) {
+        // cmp byte [rbx + 128], 0; je over the return
+        self.bytes(&[0x80, 0xbb, 128, 0, 0, 0, 0, 0x74, 7]);
+        self.ret(Exit::Stop);
+    }
+
+    /// Returns `exit` from the compiled code.
+    fn ret(&mut self, exit: Exit) {
+        // mov eax, exit; pop rbx; ret
+        self.mov_imm(EAX, exit as u32);
+        self.bytes(&[0x5b, 0xc3]);
+    }
+
+    /// Sets the program counter to `target` if the flags satisfy the
+    /// condition `cc`, or `next` otherwise, and returns.
+    fn branch(&mut self, cc: u8, target: u32, next: u32) {
+        self.mov_imm(EDX, next);
+        self.mov_imm(ESI, target);
+        // cmovcc edx, esi
+        self.bytes(&[0x0f, 0x40 | cc, 0xd6]);
+        self.store(0, EDX);
+        self.ret(Exit::Continue);
+    }
+}
+
+/// Returns the x86-64 `op eax, ecx` instruction for an arithmetic or logic
+/// operation.
+fn alu(op: OpCode) -> Option<[u8; 2]> {
+    use OpCode::*;
+
+    Some(match op {
+        ADD | C_ADD | ADDI | C_ADDI => [0x01, 0xc8],
+        SUB | C_SUB => [0x29, 0xc8],
+        AND | C_AND | ANDI | C_ANDI => [0x21, 0xc8],
+        OR  | C_OR  | ORI  | C_ORI  => [0x09, 0xc8],
+        XOR | C_XOR | XORI | C_XORI => [0x31, 0xc8],
+        // Shifts by `cl`, which x86 masks to 5 bits as SVM does
+        SLL | C_SLL | SLLI | C_SLLI => [0xd3, 0xe0],
+        SRL | C_SRL | SRLI | C_SRLI => [0xd3, 0xe8],
+        SRA | C_SRA | SRAI | C_SRAI => [0xd3, 0xf8],
+        _ => return None
+    })
+}
+
+/// Compiles `instr`, at `addr`, returning whether it ends the block.
+fn compile_instr<M>(emitter: &mut Emitter, callbacks: &Callbacks<M>, addr: u32, instr: Instruction) -> bool {
+    use Instruction::*;
+    use OpCode::*;
+
+    let next = addr.wrapping_add(instr.size());
+
+    // Leaves the value of the instruction in `eax`, and its destination
+    let dst = match instr {
+        Register { op: MV, dst, src2, .. } => {
+            emitter.load(EAX, src2, next);
+            dst
+        },
+        Register { op, dst, src1, src2 } => {
+            emitter.load(EAX, src1, next);
+            emitter.load(ECX, src2, next);
+            emitter.bytes(&alu(op).unwrap());
+            dst
+        },
+        Immediate { op: LI, dst, imm, .. } | Immediate { op: C_LI, dst, imm, .. } | Upper { dst, imm, .. } => {
+            emitter.mov_imm(EAX, imm);
+            dst
+        },
+        Immediate { op: LOAD, dst, src1, imm } | Immediate { op: C_LOAD, dst, src1, imm } => {
+            emitter.load(ESI, src1, next);
+            // add esi, imm32
+            emitter.bytes(&[0x81, 0xc6]);
+            emitter.imm32(imm);
+            emitter.mov_imm(EDX, addr);
+            emitter.call(callbacks.read_u32 as usize);
+            emitter.check_stopped();
+            dst
+        },
+        Immediate { op: BEZ, src1, imm, .. } | Immediate { op: C_BEZ, src1, imm, .. } |
+        Immediate { op: BNZ, src1, imm, .. } | Immediate { op: C_BNZ, src1, imm, .. } => {
+            emitter.load(EAX, src1, next);
+            // test eax, eax
+            emitter.bytes(&[0x85, 0xc0]);
+
+            let cc = match instr { Immediate { op: BEZ, .. } | Immediate { op: C_BEZ, .. } => 0x4, _ => 0x5 };
+            emitter.branch(cc, next.wrapping_add(imm), next);
+            return true;
+        },
+        Immediate { op: CALL, imm, .. } | Immediate { op: C_CALL, imm, .. } => {
+            emitter.store_imm(0, next);
+            emitter.mov_imm(ESI, imm as u16 as u32);
+            emitter.mov_imm(EDX, addr);
+            emitter.call(callbacks.syscall as usize);
+            // pop rbx; ret
+            emitter.bytes(&[0x5b, 0xc3]);
+            return true;
+        },
+        Immediate { op: BREAK, .. } | Immediate { op: C_BREAK, .. } => {
+            emitter.store_imm(0, addr);
+            emitter.ret(Exit::Interpret);
+            return true;
+        },
+        Immediate { op, dst, src1, imm } => {
+            emitter.load(EAX, src1, next);
+            emitter.mov_imm(ECX, imm);
+            emitter.bytes(&alu(op).unwrap());
+            dst
+        },
+        Store { op: STORE, src1, src2, imm } | Store { op: C_STORE, src1, src2, imm } => {
+            emitter.load(ESI, src1, next);
+            emitter.bytes(&[0x81, 0xc6]);
+            emitter.imm32(imm);
+            emitter.load(EDX, src2, next);
+            emitter.mov_imm(ECX, addr);
+            emitter.call(callbacks.write_u32 as usize);
+            emitter.check_stopped();
+
+            // If the store overwrote code, return so that it's compiled again:
+            // test eax, eax; jz over the return
+            emitter.bytes(&[0x85, 0xc0, 0x74, 14]);
+            emitter.store_imm(0, next);
+            emitter.ret(Exit::Continue);
+            return false;
+        },
+        Store { op, src1, src2, imm } => {
+            emitter.load(EAX, src1, next);
+            emitter.load(ECX, src2, next);
+            // cmp eax, ecx
+            emitter.bytes(&[0x39, 0xc8]);
+
+            let cc = match op {
+                BEQ => 0x4,
+                BNE => 0x5,
+                BLT => 0xc,
+                BGE => 0xd,
+                BLT_U => 0x2,
+                BGE_U => 0x3,
+                _ => unreachable!("{:?}", op)
+            };
+
+            emitter.branch(cc, next.wrapping_add(imm), next);
+            return true;
+        }
+    };
+
+    emitter.store(dst, EAX);
+
+    if dst == 0 {
+        emitter.ret(Exit::Continue);
+    }
+
+    dst == 0
+}
+
+/// Compiles the block starting at `start`, which ends at the first
+/// instruction which may change the program counter or after
+/// `MAX_BLOCK_INSTRUCTIONS`, returning its machine code and the number of
+/// bytes of code it was compiled from.
+fn compile<M: MemoryBackend>(memory: &mut M, callbacks: &Callbacks<M>, start: u32) -> (Vec<u8>, u32) {
+    let mut emitter = Emitter { code: Vec::new() };
+    let mut addr = start;
+
+    // push rbx; mov rbx, rdi
+    emitter.bytes(&[0x53, 0x48, 0x89, 0xfb]);
+
+    for count in 0.. {
+        if count == MAX_BLOCK_INSTRUCTIONS {
+            // Continue with the next block
+            emitter.store_imm(0, addr);
+            emitter.ret(Exit::Continue);
+            break;
+        }
+
+        let instr = match memory.fetch(addr) {
+            Ok(instr) => instr,
+            Err(_) => {
+                // Let the interpreter report the invalid instruction
+                emitter.store_imm(0, addr);
+                emitter.ret(Exit::Interpret);
+                break;
+            }
+        };
+
+        let ends = compile_instr(&mut emitter, callbacks, addr, instr);
+        addr = addr.wrapping_add(instr.size());
+
+        if ends {
+            break;
+        }
+    }
+
+    (emitter.code, addr.wrapping_sub(start))
+}
+
+struct Entry<M> {
+    /// Number of times the block has run since it was last compiled or
+    /// invalidated.
+    runs: u32,
+    code: Option<Code<M>>,
+    /// Number of bytes of code the block was compiled from, or 1 before it
+    /// is compiled.
+    size: u32,
+    /// Value of `MemoryBackend::code_generation` for the code the block
+    /// covers, when it was first run or compiled.
+    generation: u64
+}
+
+impl<M: MemoryBackend> Entry<M> {
+    fn new(memory: &M, start: u32) -> Self {
+        Self { runs: 0, code: None, size: 1, generation: memory.code_generation(start, 1) }
+    }
+
+    /// Returns whether the code the block covers has changed since it was
+    /// first run or compiled.
+    fn stale(&self, memory: &M, start: u32) -> bool {
+        memory.code_generation(start, self.size) != self.generation
+    }
+}
+
+/// Compiles blocks which run often into x86-64 machine code.
+pub struct Jit<M> {
+    callbacks: Callbacks<M>,
+    /// Allocated when the first block is compiled.
+    buffer: Option<CodeBuffer>,
+    blocks: HashMap<u32, Entry<M>, BuildHasherDefault<AddressHasher>>
+}
+
+impl<M: MemoryBackend> Jit<M> {
+    pub fn new(callbacks: Callbacks<M>) -> Self {
+        Self {
+            callbacks: callbacks,
+            buffer: None,
+            blocks: HashMap::default()
+        }
+    }
+
+    /// Returns the compiled block at `start`, if it has been compiled since
+    /// code was last overwritten.
+    pub fn compiled(&self, start: u32, memory: &M) -> Option<Code<M>> {
+        self.blocks.get(&start).and_then(|entry| if entry.stale(memory, start) { None } else { entry.code })
+    }
+
+    /// Records a run of the block at `start`, compiling it once it has run
+    /// `threshold` times, and returns its compiled code if there is any.
+    pub fn run(&mut self, memory: &mut M, start: u32, threshold: u32) -> Option<Code<M>> {
+        {
+            let entry = self.blocks.entry(start).or_insert_with(|| Entry::new(memory, start));
+
+            if entry.stale(memory, start) {
+                *entry = Entry::new(memory, start);
+            }
+
+            if entry.code.is_some() {
+                return entry.code;
+            }
+
+            entry.runs += 1;
+
+            if entry.runs < threshold {
+                return None;
+            }
+        }
+
+        let (code, size) = compile(memory, &self.callbacks, start);
+        let buffer = self.buffer.get_or_insert_with(CodeBuffer::new);
+
+        let compiled = match buffer.push(&code) {
+            Some(compiled) => compiled,
+            None => {
+                // Start again with an empty buffer
+                for entry in self.blocks.values_mut() {
+                    entry.code = None;
+                }
+
+                buffer.len = 0;
+                buffer.push(&code)?
+            }
+        };
+
+        let entry = self.blocks.get_mut(&start).unwrap();
+        entry.code = Some(compiled);
+        entry.size = size;
+        entry.generation = memory.code_generation(start, size);
+
+        entry.code
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ptr;
+
+    use {Memory, VirtualMachine};
+
+    use super::{compile, Callbacks, Code, CodeBuffer, Context, Exit, MAX_BLOCK_INSTRUCTIONS};
+
+    extern "C" fn read_u32(context: *mut Context<Memory>, addr: u32, pc: u32) -> u32 {
+        if addr >= 0x1000 {
+            unsafe {
+                (*context).registers[0] = pc;
+                (*context).stopped = true;
+            }
+        }
+
+        addr * 2
+    }
+
+    extern "C" fn write_u32(context: *mut Context<Memory>, addr: u32, value: u32, _: u32) -> u32 {
+        unsafe { (*context).registers[31] = addr ^ value; }
+        0
+    }
+
+    extern "C" fn syscall(context: *mut Context<Memory>, call: u32, addr: u32) -> Exit {
+        unsafe { (*context).registers[30] = call << 16 | addr; }
+        Exit::Stop
+    }
+
+    fn run(program: &[u8]) -> (Exit, [u32; 32]) {
+        let callbacks = Callbacks { read_u32: read_u32, write_u32: write_u32, syscall: syscall };
+        let mut memory = Memory::new();
+        memory.write(0, program).unwrap();
+
+        let mut buffer = CodeBuffer::new();
+        let code: Code<Memory> = buffer.push(&compile(&mut memory, &callbacks, 0).0).unwrap();
+
+        let mut context = Context {
+            registers: [0; 32],
+            stopped: false,
+            vm: ptr::null_mut::<VirtualMachine>(),
+            result: None
+        };
+        context.registers[5] = 10;
+
+        (code(&mut context), context.registers)
+    }
+
+    #[test]
+    fn arithmetic() {
+        // c.li r4, 1; add r6, r0, r4; sub r7, r4, r5; c.srai r7, 1; c.addi r0, -3
+        let (exit, registers) = run(&[0x31, 0x03, 0x82, 0x01, 0x04, 0x00, 0xc4, 0x21, 0x05, 0x00,
+                                      0xdf, 0x03, 0x13, 0xfa]);
+
+        assert_eq!(exit, Exit::Continue);
+        assert_eq!(registers[..8], [11, 0, 0, 0, 1, 10, 7, -5i32 as u32]);
+    }
+
+    #[test]
+    fn memory() {
+        // load r6, r5, 4; store r5, r6, -2; c.call 3
+        let (exit, registers) = run(&[0xb4, 0x29, 0x04, 0x00, 0xb6, 0x2f, 0xe6, 0xff, 0x3d, 0x06]);
+
+        assert_eq!(exit, Exit::Stop);
+        assert_eq!((registers[0], registers[6], registers[31], registers[30]), (10, 28, 8 ^ 28, 3 << 16 | 8));
+    }
+
+    #[test]
+    fn branch() {
+        // blt r4, r5, 6
+        assert_eq!(run(&[0xa8, 0x21, 0x05, 0x00]).1[0], 10);
+        // bge r4, r5, 6
+        assert_eq!(run(&[0xaa, 0x21, 0x05, 0x00]).1[0], 4);
+
+        // c.break
+        let (exit, registers) = run(&[0x3f, 0x00]);
+        assert_eq!((exit, registers[0]), (Exit::Interpret, 0));
+    }
+
+    #[test]
+    fn long_block() {
+        // c.addi r4, 1, repeated past the end of a block
+        let program: Vec<u8> = [0x13, 0x03].iter().cloned().cycle().take(4 * MAX_BLOCK_INSTRUCTIONS).collect();
+        let (exit, registers) = run(&program);
+
+        assert_eq!(exit, Exit::Continue);
+        assert_eq!((registers[0], registers[4]), (2 * MAX_BLOCK_INSTRUCTIONS as u32, MAX_BLOCK_INSTRUCTIONS as u32));
+    }
+
+    #[test]
+    fn fault() {
+        // load r6, r5, 0x1000; c.li r3, 1
+        let (exit, registers) = run(&[0xb4, 0x29, 0x00, 0x10, 0xf1, 0x02]);
+
+        assert_eq!(exit, Exit::Stop);
+        assert_eq!((registers[0], registers[3], registers[6]), (0, 0, 0));
+    }
+}
@@ -1,17 +1,40 @@
 #![feature(try_from)]
 
 extern crate byteorder;
-extern crate enum_traits;
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+extern crate libc;
 #[macro_use]
-extern crate enum_traits_macros;
+extern crate nom;
 extern crate vec_map;
 
+pub mod aot;
+pub mod asm;
+
+mod block;
+mod dump;
+mod errno;
 mod error;
+mod hex;
 mod instr;
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+mod jit;
+mod link;
 mod mem;
+mod object;
+mod process;
+mod random;
+mod symbols;
 mod vm;
 
+pub use dump::*;
+pub use errno::*;
 pub use error::*;
+pub use hex::*;
 pub use instr::*;
+pub use link::*;
 pub use mem::*;
+pub use object::*;
+pub use process::*;
+pub use random::*;
+pub use symbols::*;
 pub use vm::*;
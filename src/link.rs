@@ -0,0 +1,644 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::error;
+use std::fmt;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use {flatten_segments, flattened_size, Binding, Instruction, Object, OpCode, RelocationKind, Section, Segment, Target, ZERO_SECTION};
+
+/// Minimum alignment in bytes of each section in the linked image.
+const SECTION_ALIGNMENT: u32 = 4;
+
+/// Sections placed first, in this order. Any others follow in the order they
+/// first appear, then `.bss` is placed last so it needn't be in the image.
+const SECTION_ORDER: &'static [&'static str] = &[".text", ".rodata", ".data"];
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LinkError {
+    UndefinedSymbol(String),
+    DuplicateSymbol(String),
+    /// A relocation at the given address doesn't refer to an instruction with
+    /// an immediate, or for `Word` relocations, to a word within its section.
+    InvalidRelocation(u32),
+    /// The section with the given name overlaps another once placed.
+    SectionOverlap(String),
+    /// The value of the symbol with the given name doesn't fit in the
+    /// immediate of the instruction at the given address.
+    RelocationOutOfRange(String, u32),
+    /// The section with the given name extends past the end of the address
+    /// space once placed.
+    SectionOutOfRange(String)
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Link error: {}", error::Error::description(self))?;
+
+        match *self {
+            LinkError::UndefinedSymbol(ref name) |
+            LinkError::DuplicateSymbol(ref name) |
+            LinkError::SectionOverlap(ref name) |
+            LinkError::SectionOutOfRange(ref name) => write!(f, " ({})", name),
+            LinkError::InvalidRelocation(addr) => write!(f, " (0x{:08x})", addr),
+            LinkError::RelocationOutOfRange(ref name, addr) => write!(f, " ({} at 0x{:08x})", name, addr)
+        }
+    }
+}
+
+impl error::Error for LinkError {
+    fn description(&self) -> &str {
+        match *self {
+            LinkError::UndefinedSymbol(_) => "undefined symbol",
+            LinkError::DuplicateSymbol(_) => "symbol defined more than once",
+            LinkError::InvalidRelocation(_) => "relocation does not refer to an instruction with an immediate",
+            LinkError::SectionOverlap(_) => "section overlaps another section",
+            LinkError::RelocationOutOfRange(..) => "relocated value does not fit in the instruction's immediate",
+            LinkError::SectionOutOfRange(_) => "section extends past the end of the address space"
+        }
+    }
+}
+
+/// A linked program, ready to be loaded into memory.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Image {
+    /// Lowest address of any stored section, which `bytes` starts at.
+    pub address: u32,
+    /// Contents of each section which is stored in the image, at the address
+    /// it was placed. `.bss` and empty sections aren't included.
+    pub segments: Vec<Segment>,
+    /// Name and address of every symbol defined by the linked objects.
+    pub symbols: Vec<(String, u32)>,
+    /// Address of each section of each object, in the order the objects were
+    /// added.
+    pub section_addresses: Vec<Vec<u32>>,
+    /// Name, start and end address of each output section, in the order
+    /// they were laid out.
+    pub sections: Vec<(String, u32, u32)>
+}
+
+impl Image {
+    /// Returns the contents of every stored section as a raw binary loaded at
+    /// `address`, with the gaps between sections filled with zeros.
+    pub fn bytes(&self) -> Vec<u8> {
+        flatten_segments(self.address, &self.segments)
+    }
+
+    /// Returns the length of the raw binary `bytes` would return.
+    pub fn raw_size(&self) -> u64 {
+        flattened_size(self.address, &self.segments)
+    }
+}
+
+/// Combines object files into a single program image, resolving symbols
+/// between them and applying their relocations.
+pub struct Linker {
+    base_address: u32,
+    section_addresses: HashMap<String, u32>,
+    objects: Vec<Object>
+}
+
+impl Default for Linker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Rounds `addr` up to a multiple of `section`'s alignment, or returns `None`
+/// if that's past the end of the address space.
+fn align(addr: u32, section: &Section) -> Option<u32> {
+    let align = cmp::max(SECTION_ALIGNMENT, section.align);
+    addr.checked_add(align - 1).map(|addr| addr & !(align - 1))
+}
+
+/// Returns the position of the section named `name` in the image's layout.
+fn section_rank(name: &str) -> usize {
+    match SECTION_ORDER.iter().position(|&s| s == name) {
+        Some(rank) => rank,
+        None if name == ZERO_SECTION => SECTION_ORDER.len() + 1,
+        None => SECTION_ORDER.len()
+    }
+}
+
+impl Linker {
+    /// Constructs a new `Linker` with no objects, placing the image at address 0.
+    pub fn new() -> Self {
+        Self {
+            base_address: 0,
+            section_addresses: HashMap::new(),
+            objects: Vec::new()
+        }
+    }
+
+    /// Sets the address the first section is placed at, unless it's placed
+    /// explicitly with `set_section_address`.
+    pub fn set_base_address(&mut self, addr: u32) {
+        self.base_address = addr;
+    }
+
+    /// Places the section named `name` at `addr`. Sections without an
+    /// explicit address follow on from the previous section.
+    pub fn set_section_address(&mut self, name: &str, addr: u32) {
+        self.section_addresses.insert(name.to_owned(), addr);
+    }
+
+    /// Adds an object to the end of the image.
+    pub fn add_object(&mut self, object: Object) {
+        self.objects.push(object);
+    }
+
+    /// Lays out the objects' sections, grouping sections of the same name in
+    /// the order the objects were added, and links them, returning the
+    /// resulting image.
+    pub fn link(&self) -> Result<Image, LinkError> {
+        let mut names: Vec<&str> = Vec::new();
+
+        for section in self.objects.iter().flat_map(|o| &o.sections) {
+            if !names.contains(&&section.name[..]) {
+                names.push(&section.name);
+            }
+        }
+
+        names.sort_by_key(|name| section_rank(name));
+
+        // Address of each section of each object
+        let mut addrs: Vec<Vec<u32>> = self.objects.iter().map(|o| vec![0; o.sections.len()]).collect();
+        // Name, start and end address of each output section
+        let mut placements = Vec::with_capacity(names.len());
+        let mut next = self.base_address;
+
+        for &name in &names {
+            let out_of_range = || LinkError::SectionOutOfRange(name.to_owned());
+
+            // Placed on the largest alignment of the sections it's made of,
+            // so that none of them need padding before them
+            let start = match self.section_addresses.get(name) {
+                Some(&addr) => addr,
+                None => {
+                    let mut start = next;
+
+                    for section in self.objects.iter().flat_map(|o| &o.sections).filter(|s| s.name == name) {
+                        start = align(start, section).ok_or_else(&out_of_range)?;
+                    }
+
+                    start
+                }
+            };
+            let mut end = start;
+
+            for (object, addrs) in self.objects.iter().zip(addrs.iter_mut()) {
+                for (section, addr) in object.sections.iter().zip(addrs.iter_mut()) {
+                    if section.name == name {
+                        *addr = align(end, section).ok_or_else(&out_of_range)?;
+                        end = addr.checked_add(section.size).ok_or_else(&out_of_range)?;
+                    }
+                }
+            }
+
+            for &(_, other_start, other_end) in &placements {
+                if start < end && start < other_end && other_start < end {
+                    return Err(LinkError::SectionOverlap(name.to_owned()));
+                }
+            }
+
+            placements.push((name, start, end));
+            next = end;
+        }
+
+        // Contents of each output section, which is empty for those that
+        // aren't stored
+        let mut segments: Vec<Segment> = placements.iter().map(|&(name, start, end)| {
+            let len = if name == ZERO_SECTION { 0 } else { end - start };
+            Segment { addr: start, data: vec![0; len as usize] }
+        }).collect();
+
+        for (object, addrs) in self.objects.iter().zip(&addrs) {
+            for (section, &addr) in object.sections.iter().zip(addrs).filter(|&(s, _)| !s.is_zero()) {
+                let segment = &mut segments[names.iter().position(|&n| n == section.name).unwrap()];
+                let offset = (addr - segment.addr) as usize;
+                segment.data[offset..offset + section.data.len()].copy_from_slice(&section.data);
+            }
+        }
+
+        let mut globals = HashMap::new();
+        let mut symbols = Vec::new();
+
+        for (object, addrs) in self.objects.iter().zip(&addrs) {
+            for symbol in object.symbols.iter().filter(|s| s.binding != Binding::Extern) {
+                let addr = addrs[symbol.section as usize].wrapping_add(symbol.value);
+
+                if symbol.binding == Binding::Global && globals.insert(&symbol.name[..], addr).is_some() {
+                    return Err(LinkError::DuplicateSymbol(symbol.name.clone()));
+                }
+
+                symbols.push((symbol.name.clone(), addr));
+            }
+        }
+
+        for (object, addrs) in self.objects.iter().zip(&addrs) {
+            for relocation in &object.relocations {
+                let target = match relocation.target {
+                    Target::Section(index) => addrs[index as usize],
+                    Target::Symbol(ref name) => {
+                        let local = object.symbols.iter()
+                                                  .find(|s| s.name == *name && s.binding != Binding::Extern)
+                                                  .map(|s| addrs[s.section as usize].wrapping_add(s.value));
+
+                        local.or_else(|| globals.get(&name[..]).cloned())
+                             .ok_or(LinkError::UndefinedSymbol(name.clone()))?
+                    }
+                };
+
+                let section_addr = addrs[relocation.section as usize];
+                let target = target.wrapping_add(relocation.addend);
+                let imm = match relocation.kind {
+                    RelocationKind::Relative => target.wrapping_sub(section_addr),
+                    kind => kind.apply(target)
+                };
+
+                // The lower half is added sign extended, so only its width matters
+                let expected = match relocation.kind {
+                    RelocationKind::Low => imm as u16 as i16 as u32,
+                    _ => imm
+                };
+
+                let addr = section_addr.wrapping_add(relocation.offset);
+                let error = LinkError::InvalidRelocation(addr);
+
+                let section = &object.sections[relocation.section as usize];
+
+                if section.is_zero() {
+                    return Err(error);
+                }
+
+                let segment = &mut segments[names.iter().position(|&n| n == section.name).unwrap()];
+                let offset = addr.wrapping_sub(segment.addr);
+
+                if relocation.kind == RelocationKind::Word {
+                    let word = segment.data.get_mut(offset as usize..offset as usize + 4).ok_or(error)?;
+                    LittleEndian::write_u32(word, imm);
+                    continue;
+                }
+
+                let mut instr = patch(&mut segment.data, offset, imm).map_err(|_| error)?;
+
+                // `ORI` sign extends the lower half just as `ADDI` does, but
+                // the upper half given to `LUI` isn't adjusted for it
+                let ori = match instr {
+                    Instruction::Immediate { op: OpCode::ORI, .. } | Instruction::Immediate { op: OpCode::C_ORI, .. } => true,
+                    _ => false
+                };
+
+                if instr.immediate_mut().map(|imm| *imm) != Some(expected) ||
+                   (ori && relocation.kind == RelocationKind::Low && imm & 0x8000 != 0) {
+                    let name = match relocation.target {
+                        Target::Section(index) => object.sections[index as usize].name.clone(),
+                        Target::Symbol(ref name) => name.clone()
+                    };
+
+                    return Err(LinkError::RelocationOutOfRange(name, addr));
+                }
+            }
+        }
+
+        segments.retain(|segment| !segment.data.is_empty());
+
+        let address = segments.iter().map(|segment| segment.addr).min().unwrap_or(self.base_address);
+        let sections = placements.into_iter().map(|(name, start, end)| (name.to_owned(), start, end)).collect();
+
+        Ok(Image { address, segments, symbols, section_addresses: addrs, sections })
+    }
+}
+
+/// Replaces the immediate of the instruction at `offset` in `image` with `imm`,
+/// returning the instruction it now decodes to. Its immediate differs from
+/// `imm` when it doesn't fit in the instruction's immediate field.
+fn patch(image: &mut [u8], offset: u32, imm: u32) -> Result<Instruction, ()> {
+    let offset = offset as usize;
+
+    if offset >= image.len() {
+        return Err(());
+    }
+
+    // Compressed instructions may be at the very end of the image, so pad
+    // with zeros rather than reading past it
+    let mut buf = [0; 4];
+    let len = cmp::min(4, image.len() - offset);
+    buf[..len].copy_from_slice(&image[offset..offset + len]);
+
+    let mut instr = Instruction::try_from(LittleEndian::read_u32(&buf)).map_err(|_| ())?;
+    *instr.immediate_mut().ok_or(())? = imm;
+
+    let mut bytes = Vec::new();
+    instr.write_bytes(&mut bytes).map_err(|_| ())?;
+
+    if offset + bytes.len() > image.len() {
+        return Err(());
+    }
+
+    image[offset..offset + bytes.len()].copy_from_slice(&bytes);
+
+    let mut buf = [0; 4];
+    buf[..bytes.len()].copy_from_slice(&bytes);
+
+    Instruction::try_from(LittleEndian::read_u32(&buf)).map_err(|_| ())
+}
+
+#[cfg(test)]
+mod test {
+    use {Binding, Object, Relocation, RelocationKind, Section, Segment, Symbol, Target};
+
+    use super::{Image, LinkError, Linker};
+
+    fn section(name: &str, data: Vec<u8>) -> Section {
+        Section { name: name.to_owned(), size: data.len() as u32, data, align: 1 }
+    }
+
+    fn symbol(name: &str, binding: Binding, section: u32, value: u32) -> Symbol {
+        Symbol { name: name.to_owned(), binding, section, value }
+    }
+
+    fn relocation(offset: u32, kind: RelocationKind, symbol: Option<&str>, addend: u32) -> Relocation {
+        let target = match symbol {
+            Some(name) => Target::Symbol(name.to_owned()),
+            None => Target::Section(0)
+        };
+
+        Relocation { section: 0, offset, kind, target, addend }
+    }
+
+    #[test]
+    fn link() {
+        // lui r4, hi(%print)
+        // addi r4, r4, lo(%print)
+        // c.addi r0, $print
+        let main = Object {
+            sections: vec![section(".text", vec![0x32, 0x01, 0x00, 0x00, 0x12, 0x21, 0x00, 0x00, 0x13, 0x00])],
+            symbols: vec![symbol("print", Binding::Extern, 0, 0)],
+            relocations: vec![
+                relocation(0, RelocationKind::High, Some("print"), 0),
+                relocation(4, RelocationKind::Low, Some("print"), 0),
+                relocation(8, RelocationKind::Relative, Some("print"), -10i32 as u32)
+            ]
+        };
+
+        // print:
+        //     c.li r4, %print
+        let lib = Object {
+            sections: vec![section(".text", vec![0x31, 0x01])],
+            symbols: vec![symbol("print", Binding::Global, 0, 0)],
+            relocations: vec![relocation(0, RelocationKind::Absolute, None, 0)]
+        };
+
+        let mut linker = Linker::new();
+        linker.add_object(main);
+        linker.add_object(lib);
+
+        assert_eq!(linker.link(), Ok(Image {
+            address: 0,
+            segments: vec![Segment {
+                addr: 0,
+                data: vec![0x32, 0x01, 0x00, 0x00, 0x12, 0x21, 0x0c, 0x00, 0x13, 0x04, 0x00, 0x00, 0x31, 0x19]
+            }],
+            symbols: vec![("print".to_owned(), 12)],
+            section_addresses: vec![vec![0], vec![12]],
+            sections: vec![(".text".to_owned(), 0, 14)]
+        }));
+    }
+
+    #[test]
+    fn base_address() {
+        let object = Object {
+            sections: vec![section(".text", vec![0x12, 0x01, 0x00, 0x00])],
+            symbols: vec![symbol("data", Binding::Local, 0, 2)],
+            relocations: vec![relocation(0, RelocationKind::Absolute, Some("data"), 4)]
+        };
+
+        let mut linker = Linker::new();
+        linker.set_base_address(0x100);
+        linker.add_object(object);
+
+        let image = linker.link().unwrap();
+        assert_eq!(image.address, 0x100);
+        assert_eq!(image.bytes(), vec![0x12, 0x01, 0x06, 0x01]);
+    }
+
+    #[test]
+    fn sections() {
+        // .bss
+        // buffer: .space 16
+        // .data
+        //     .string "hi"
+        // .text
+        //     addi r4, r0, %buffer
+        let object = Object {
+            sections: vec![
+                Section { name: ".bss".to_owned(), data: Vec::new(), size: 16, align: 1 },
+                section(".data", vec![0x68, 0x69]),
+                section(".text", vec![0x12, 0x01, 0x00, 0x00])
+            ],
+            symbols: vec![symbol("buffer", Binding::Local, 0, 0)],
+            relocations: vec![Relocation {
+                section: 2,
+                offset: 0,
+                kind: RelocationKind::Absolute,
+                target: Target::Symbol("buffer".to_owned()),
+                addend: 0
+            }]
+        };
+
+        let mut linker = Linker::new();
+        linker.add_object(object.clone());
+
+        // .text, .data then .bss, which isn't part of the image
+        assert_eq!(linker.link(), Ok(Image {
+            address: 0,
+            segments: vec![
+                Segment { addr: 0, data: vec![0x12, 0x01, 0x08, 0x00] },
+                Segment { addr: 4, data: b"hi".to_vec() }
+            ],
+            symbols: vec![("buffer".to_owned(), 8)],
+            section_addresses: vec![vec![8, 4, 0]],
+            sections: vec![(".text".to_owned(), 0, 4), (".data".to_owned(), 4, 6), (".bss".to_owned(), 8, 24)]
+        }));
+
+        let mut linker = Linker::new();
+        linker.set_section_address(".data", 0x10);
+        linker.add_object(object);
+
+        let image = linker.link().unwrap();
+        let bytes = image.bytes();
+        assert_eq!(bytes.len(), 0x12);
+        assert_eq!(&bytes[..4], &[0x12, 0x01, 0x14, 0x00]);
+        assert_eq!(&bytes[0x10..], b"hi");
+        assert_eq!(image.segments, vec![
+            Segment { addr: 0, data: vec![0x12, 0x01, 0x14, 0x00] },
+            Segment { addr: 0x10, data: b"hi".to_vec() }
+        ]);
+    }
+
+    #[test]
+    fn distant_sections() {
+        let object = Object {
+            sections: vec![section(".text", vec![0; 4]), section(".data", vec![0x68, 0x69])],
+            symbols: Vec::new(),
+            relocations: Vec::new()
+        };
+
+        // Only the sections themselves are stored, not the gap between them
+        let mut linker = Linker::new();
+        linker.set_section_address(".data", 0x80000000);
+        linker.add_object(object.clone());
+
+        let image = linker.link().unwrap();
+        assert_eq!(image.segments, vec![
+            Segment { addr: 0, data: vec![0; 4] },
+            Segment { addr: 0x80000000, data: b"hi".to_vec() }
+        ]);
+
+        let mut linker = Linker::new();
+        linker.set_section_address(".data", 0xffffffff);
+        linker.add_object(object.clone());
+        assert_eq!(linker.link(), Err(LinkError::SectionOutOfRange(".data".to_owned())));
+
+        let mut linker = Linker::new();
+        linker.set_base_address(0xfffffffe);
+        linker.add_object(object);
+        assert_eq!(linker.link(), Err(LinkError::SectionOutOfRange(".text".to_owned())));
+    }
+
+    #[test]
+    fn section_alignment() {
+        let mut data = section(".data", vec![0x68, 0x69]);
+        data.align = 16;
+
+        let mut linker = Linker::new();
+        linker.add_object(Object { sections: vec![section(".text", vec![0; 6])], ..Object::default() });
+        linker.add_object(Object { sections: vec![section(".text", vec![0; 2]), data], ..Object::default() });
+
+        let image = linker.link().unwrap();
+        assert_eq!(image.section_addresses, vec![vec![0], vec![8, 16]]);
+        assert_eq!(image.sections[1], (".data".to_owned(), 16, 18));
+    }
+
+    #[test]
+    fn section_overlap() {
+        let mut linker = Linker::new();
+        linker.set_section_address(".data", 2);
+        linker.add_object(Object {
+            sections: vec![section(".text", vec![0; 4]), section(".data", vec![0; 4])],
+            symbols: Vec::new(),
+            relocations: Vec::new()
+        });
+
+        assert_eq!(linker.link(), Err(LinkError::SectionOverlap(".data".to_owned())));
+    }
+
+    #[test]
+    fn undefined_symbol() {
+        let mut linker = Linker::new();
+        linker.add_object(Object {
+            sections: vec![section(".text", vec![0x12, 0x01, 0x00, 0x00])],
+            symbols: vec![symbol("missing", Binding::Extern, 0, 0)],
+            relocations: vec![relocation(0, RelocationKind::Absolute, Some("missing"), 0)]
+        });
+
+        assert_eq!(linker.link(), Err(LinkError::UndefinedSymbol("missing".to_owned())));
+    }
+
+    #[test]
+    fn duplicate_symbol() {
+        let object = Object {
+            sections: vec![section(".text", Vec::new())],
+            symbols: vec![symbol("start", Binding::Global, 0, 0)],
+            relocations: Vec::new()
+        };
+
+        let mut linker = Linker::new();
+        linker.add_object(object.clone());
+        linker.add_object(object);
+
+        assert_eq!(linker.link(), Err(LinkError::DuplicateSymbol("start".to_owned())));
+    }
+
+    #[test]
+    fn word() {
+        // .word 0, %table + 4
+        // table:
+        let object = Object {
+            sections: vec![section(".data", vec![0; 8])],
+            symbols: vec![symbol("table", Binding::Local, 0, 8)],
+            relocations: vec![relocation(4, RelocationKind::Word, Some("table"), 4)]
+        };
+
+        let mut linker = Linker::new();
+        linker.set_section_address(".data", 0x12345678);
+        linker.add_object(object);
+
+        let image = linker.link().unwrap();
+        assert_eq!(image.segments, vec![Segment { addr: 0x12345678, data: vec![0, 0, 0, 0, 0x84, 0x56, 0x34, 0x12] }]);
+    }
+
+    #[test]
+    fn invalid_relocation() {
+        let mut linker = Linker::new();
+        linker.add_object(Object {
+            sections: vec![section(".text", vec![0x02, 0x00, 0x01, 0x00])],
+            symbols: Vec::new(),
+            relocations: vec![relocation(0, RelocationKind::Absolute, None, 0)]
+        });
+
+        assert_eq!(linker.link(), Err(LinkError::InvalidRelocation(0)));
+
+        let mut linker = Linker::new();
+        linker.add_object(Object {
+            sections: vec![section(".data", vec![0; 6])],
+            symbols: Vec::new(),
+            relocations: vec![relocation(4, RelocationKind::Word, None, 0)]
+        });
+
+        assert_eq!(linker.link(), Err(LinkError::InvalidRelocation(4)));
+    }
+
+    #[test]
+    fn relocation_out_of_range() {
+        // addi r4, r0, %far
+        // c.addi r0, $far
+        let object = |target| Object {
+            sections: vec![section(".text", vec![0x12, 0x01, 0x00, 0x00, 0x13, 0x00])],
+            symbols: vec![symbol("far", Binding::Global, 0, target)],
+            relocations: vec![
+                relocation(0, RelocationKind::Absolute, Some("far"), 0),
+                relocation(4, RelocationKind::Relative, Some("far"), -4i32 as u32)
+            ]
+        };
+
+        let mut linker = Linker::new();
+        linker.add_object(object(0x8000));
+        assert_eq!(linker.link(), Err(LinkError::RelocationOutOfRange("far".to_owned(), 0)));
+
+        let mut linker = Linker::new();
+        linker.add_object(object(0x100));
+        assert_eq!(linker.link(), Err(LinkError::RelocationOutOfRange("far".to_owned(), 4)));
+
+        let mut linker = Linker::new();
+        linker.add_object(object(0x20));
+        assert!(linker.link().is_ok());
+
+        // ori r4, r4, lo(%far)
+        let object = |target| Object {
+            sections: vec![section(".text", vec![0x16, 0x21, 0x00, 0x00])],
+            symbols: vec![symbol("far", Binding::Global, 0, target)],
+            relocations: vec![relocation(0, RelocationKind::Low, Some("far"), 0)]
+        };
+
+        let mut linker = Linker::new();
+        linker.add_object(object(0x12348000));
+        assert_eq!(linker.link(), Err(LinkError::RelocationOutOfRange("far".to_owned(), 0)));
+
+        let mut linker = Linker::new();
+        linker.add_object(object(0x12347000));
+        assert_eq!(linker.link().map(|image| image.bytes()), Ok(vec![0x16, 0x21, 0x00, 0x70]));
+    }
+}
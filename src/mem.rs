@@ -1,15 +1,345 @@
+use std::cmp;
+use std::convert::TryInto;
 use std::io::{Cursor, Read, Write};
-use std::ptr;
+use std::ops::Range;
 
 use byteorder::{ByteOrder, LittleEndian};
 
 use vec_map::VecMap;
 
+use {Error, Instruction};
+
 const DEFAULT_PAGE_SIZE: usize = 4096;
 
+/// Storage for the address space of a [`VirtualMachine`].
+///
+/// Backends also cache the instructions decoded by [`fetch`], so that code
+/// which runs repeatedly is only decoded once. Writing to memory discards any
+/// instructions decoded from the bytes written.
+///
+/// [`VirtualMachine`]: struct.VirtualMachine.html
+/// [`fetch`]: #tymethod.fetch
+pub trait MemoryBackend {
+    /// Returns the number of bytes which can be accessed, starting from
+    /// address 0.
+    fn size(&self) -> u64;
+
+    /// Reads bytes into the specified buffer `buf` starting at byte address `addr`.
+    fn read(&self, addr: u32, buf: &mut [u8]) -> Result<(), Error>;
+
+    /// Writes bytes from the specified buffer `buf` starting at byte address `addr`.
+    fn write(&mut self, addr: u32, buf: &[u8]) -> Result<(), Error>;
+
+    /// Reads an unsigned 32 bit integer starting at byte address `addr`.
+    #[inline]
+    fn read_u32(&self, addr: u32) -> Result<u32, Error> {
+        let mut buf = [0u8; 4];
+
+        self.read(addr, &mut buf)?;
+        Ok(LittleEndian::read_u32(&buf))
+    }
+
+    /// Writes an unsigned 32 bit integer starting at byte address `addr`.
+    #[inline]
+    fn write_u32(&mut self, addr: u32, value: u32) -> Result<(), Error> {
+        let mut buf = [0u8; 4];
+
+        LittleEndian::write_u32(&mut buf, value);
+        self.write(addr, &buf)
+    }
+
+    /// Reads and decodes the instruction starting at byte address `addr`.
+    fn fetch(&mut self, addr: u32) -> Result<Instruction, Error>;
+
+    /// Discards every instruction decoded by [`fetch`].
+    ///
+    /// [`fetch`]: #tymethod.fetch
+    fn clear_decoded(&mut self);
+
+    /// Returns a count which changes whenever instructions decoded by
+    /// [`fetch`] from the `len` bytes starting at byte address `addr` are
+    /// discarded, so that anything derived from them can be checked for
+    /// staleness.
+    ///
+    /// [`fetch`]: #tymethod.fetch
+    fn code_generation(&self, addr: u32, len: u32) -> u64;
+
+    /// Returns the granularity of mappings, which `map` and `unmap` round
+    /// their ranges out to.
+    fn page_size(&self) -> usize;
+
+    /// Marks the pages covering `len` bytes starting at byte address `addr`
+    /// as mapped, so that they may be accessed when memory is strict.
+    fn map(&mut self, addr: u32, len: u64);
+
+    /// Marks the pages covering `len` bytes starting at byte address `addr`
+    /// as unmapped, discarding their contents so that they read as 0 again.
+    fn unmap(&mut self, addr: u32, len: u64);
+
+    /// Marks the mapped pages covering `len` bytes starting at byte address
+    /// `addr` as read only until they're mapped or unmapped again, so that
+    /// writing to them fails, whether or not memory is strict.
+    fn protect(&mut self, addr: u32, len: u64);
+
+    /// Returns the ranges of memory which are mapped.
+    fn mappings(&self) -> &Mappings;
+}
+
+/// The ranges of addresses which have been mapped, which are the only ones a
+/// program may access when memory is strict.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Mappings {
+    /// Start and end address of each range, ordered by address. Ranges never
+    /// overlap or touch, as they're merged when they do.
+    ranges: Vec<(u64, u64)>,
+    /// Start and end address of each mapped range which is read only.
+    read_only: Vec<(u64, u64)>
+}
+
+/// Returns `ranges` without the addresses from `start` to `end`.
+fn cut(ranges: &[(u64, u64)], start: u64, end: u64) -> Vec<(u64, u64)> {
+    let mut kept = Vec::with_capacity(ranges.len() + 1);
+
+    for &(s, e) in ranges {
+        if e <= start || end <= s {
+            kept.push((s, e));
+            continue;
+        }
+
+        if s < start {
+            kept.push((s, start));
+        }
+
+        if end < e {
+            kept.push((end, e));
+        }
+    }
+
+    kept
+}
+
+impl Mappings {
+    /// Returns the start and end address of each mapped range, ordered by
+    /// address.
+    pub fn ranges(&self) -> &[(u64, u64)] {
+        &self.ranges
+    }
+
+    /// Returns whether all of the `len` bytes starting at `addr` are mapped.
+    #[inline]
+    pub fn contains(&self, addr: u32, len: u64) -> bool {
+        let end = addr as u64 + len;
+
+        match self.ranges.binary_search_by(|&(start, _)| start.cmp(&(addr as u64))) {
+            Ok(i) => end <= self.ranges[i].1,
+            Err(0) => len == 0,
+            Err(i) => end <= self.ranges[i - 1].1
+        }
+    }
+
+    /// Returns whether none of the `len` bytes starting at `addr` are read only.
+    #[inline]
+    pub fn is_writable(&self, addr: u32, len: u64) -> bool {
+        let end = addr as u64 + len;
+        self.read_only.iter().all(|&(s, e)| e <= addr as u64 || end <= s)
+    }
+
+    /// Returns whether none of the addresses from `start` to `end` are mapped.
+    pub fn is_free(&self, start: u64, end: u64) -> bool {
+        self.ranges.iter().all(|&(s, e)| e <= start || end <= s)
+    }
+
+    /// Returns the highest start address of a free range of `len` bytes lying
+    /// between `lowest` and `highest`, if there is one.
+    pub fn find_free(&self, len: u64, lowest: u64, highest: u64) -> Option<u64> {
+        let mut end = highest;
+
+        for &(start, range_end) in self.ranges.iter().rev() {
+            if range_end < end && end - range_end >= len && end - len >= lowest {
+                return Some(end - len);
+            }
+
+            end = cmp::min(end, start);
+        }
+
+        if end >= lowest + len { Some(end - len) } else { None }
+    }
+
+    /// Adds the addresses from `start` to `end`.
+    fn insert(&mut self, start: u64, end: u64) {
+        if start >= end {
+            return;
+        }
+
+        self.read_only = cut(&self.read_only, start, end);
+
+        // Merge with every range which overlaps or touches the new one
+        let (mut start, mut end) = (start, end);
+        let first = self.ranges.iter().position(|&(_, e)| e >= start).unwrap_or(self.ranges.len());
+        let last = self.ranges.iter().rposition(|&(s, _)| s <= end).map_or(first, |i| i + 1);
+
+        if first < last {
+            start = cmp::min(start, self.ranges[first].0);
+            end = cmp::max(end, self.ranges[last - 1].1);
+        }
+
+        self.ranges.drain(first..cmp::max(first, last));
+        self.ranges.insert(first, (start, end));
+    }
+
+    /// Removes the addresses from `start` to `end`.
+    fn remove(&mut self, start: u64, end: u64) {
+        self.ranges = cut(&self.ranges, start, end);
+        self.read_only = cut(&self.read_only, start, end);
+    }
+
+    /// Makes the mapped addresses from `start` to `end` read only.
+    fn protect(&mut self, start: u64, end: u64) {
+        let mut read_only = cut(&self.read_only, start, end);
+
+        for &(s, e) in &self.ranges {
+            if s < end && start < e {
+                read_only.push((cmp::max(s, start), cmp::min(e, end)));
+            }
+        }
+
+        self.read_only = read_only;
+    }
+}
+
+/// Returns the range of pages of `page_size` covering `len` bytes starting at
+/// `addr`, as byte addresses clamped to `size`.
+fn page_range(addr: u32, len: u64, page_size: usize, size: u64) -> (u64, u64) {
+    let page_size = page_size as u64;
+    let start = addr as u64 / page_size * page_size;
+    let end = (addr as u64 + len + page_size - 1) / page_size * page_size;
+
+    (cmp::min(start, size), cmp::min(end, size))
+}
+
+/// Instructions decoded from memory, for each page they start in, indexed by
+/// their offset within the page.
+struct DecodeCache {
+    page_size: usize,
+    pages: VecMap<Box<[Option<Instruction>]>>,
+    /// Number of times decoded instructions have been discarded, for each
+    /// page.
+    generations: VecMap<u64>,
+    /// Number of times every decoded instruction has been discarded.
+    cleared: u64
+}
+
+impl DecodeCache {
+    fn new(page_size: usize) -> Self {
+        Self {
+            page_size: page_size,
+            pages: VecMap::new(),
+            generations: VecMap::new(),
+            cleared: 0
+        }
+    }
+
+    #[inline]
+    fn get(&self, addr: u32) -> Option<Instruction> {
+        let index = addr as usize / self.page_size;
+        let offset = addr as usize % self.page_size;
+
+        self.pages.get(index).and_then(|page| page[offset])
+    }
+
+    #[inline]
+    fn insert(&mut self, addr: u32, instr: Instruction) {
+        let index = addr as usize / self.page_size;
+        let offset = addr as usize % self.page_size;
+        let page_size = self.page_size;
+
+        self.pages.entry(index).or_insert_with(|| vec![None; page_size].into_boxed_slice())[offset] = Some(instr);
+    }
+
+    /// Discards instructions decoded from the page at position `index`, out of
+    /// `page_count` pages.
+    ///
+    /// Instructions may start in the last three bytes before this page and end
+    /// in it, so the pages holding those bytes are discarded too. If anything
+    /// was discarded, the generation of each of these pages changes.
+    #[inline]
+    fn invalidate(&mut self, index: usize, page_count: usize) {
+        if self.pages.is_empty() {
+            return;
+        }
+
+        let earlier = (3 + self.page_size - 1) / self.page_size;
+        let pages = (0 .. earlier + 1).map(|i| (index + page_count - i) % page_count);
+        let mut discarded = false;
+
+        for page in pages.clone() {
+            discarded |= self.pages.remove(page).is_some();
+        }
+
+        if discarded {
+            for page in pages {
+                *self.generations.entry(page).or_insert(0) += 1;
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.pages.clear();
+        self.cleared += 1;
+    }
+
+    /// Returns the generation of the `len` bytes starting at byte address
+    /// `addr`, out of `page_count` pages, which changes whenever instructions
+    /// decoded from them are discarded.
+    #[inline]
+    fn generation(&self, addr: u32, len: u32, page_count: usize) -> u64 {
+        let page_size = self.page_size as u64;
+        let first = addr as u64 / page_size;
+        let last = (addr as u64 + cmp::max(len, 1) as u64 - 1) / page_size;
+
+        (first .. last + 1).fold(self.cleared, |generation, page| {
+            generation + self.generations.get((page % page_count as u64) as usize).map_or(0, |&g| g)
+        })
+    }
+}
+
+/// Counts of how a [`Memory`] has been used.
+///
+/// [`Memory`]: struct.Memory.html
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct MemoryStats {
+    /// Number of pages allocated over the lifetime of the memory.
+    pub pages_allocated: u64,
+    /// Largest number of pages resident at once.
+    pub peak_pages: usize,
+    /// Number of bytes written with `write` and `write_u32`.
+    pub bytes_written: u64
+}
+
+/// Sparse memory covering the whole 32 bit address space, allocated a page at
+/// a time as it is written to.
+///
+/// Accesses wrap around from the end of the address space to the start. Reads
+/// never fail, but writes fail with `Error::OutOfMemory` if they would
+/// allocate more pages than the limit set with [`set_max_pages`].
+///
+/// When the memory is made strict with [`set_strict`], accesses through
+/// `MemoryBackend` to anything which isn't mapped fail with
+/// `Error::OutOfBounds` instead. Writes through `MemoryBackend` to pages made
+/// read only with `protect` always fail in the same way. Accesses with
+/// `Memory`'s own methods are never checked, so that the host can still
+/// inspect the whole of memory.
+///
+/// [`set_max_pages`]: #method.set_max_pages
+/// [`set_strict`]: #method.set_strict
 pub struct Memory {
     page_size: usize,
-    pub pages: VecMap<Box<[u8]>>
+    pub pages: VecMap<Box<[u8]>>,
+    decoded: DecodeCache,
+    max_pages: Option<usize>,
+    stats: MemoryStats,
+    mappings: Mappings,
+    strict: bool
 }
 
 impl Default for Memory {
@@ -27,7 +357,12 @@ impl Memory {
     pub fn new() -> Self {
         Self {
             page_size: DEFAULT_PAGE_SIZE,
-            pages: VecMap::new()
+            pages: VecMap::new(),
+            decoded: DecodeCache::new(DEFAULT_PAGE_SIZE),
+            max_pages: None,
+            stats: MemoryStats::default(),
+            mappings: Mappings::default(),
+            strict: false
         }
     }
 
@@ -41,10 +376,66 @@ impl Memory {
 
         Self {
             page_size: page_size,
-            pages: VecMap::new()
+            pages: VecMap::new(),
+            decoded: DecodeCache::new(page_size),
+            max_pages: None,
+            stats: MemoryStats::default(),
+            mappings: Mappings::default(),
+            strict: false
         }
     }
 
+    /// Returns the maximum number of pages which may be resident at once, or
+    /// `None` if there is no limit.
+    pub fn max_pages(&self) -> Option<usize> {
+        self.max_pages
+    }
+
+    /// Limits the number of pages which may be resident at once, or removes
+    /// the limit if `max_pages` is `None`. Pages which are already allocated
+    /// are kept even if there are more than the limit.
+    pub fn set_max_pages(&mut self, max_pages: Option<usize>) {
+        self.max_pages = max_pages;
+    }
+
+    /// Returns statistics on pages allocated and bytes written so far.
+    pub fn stats(&self) -> MemoryStats {
+        self.stats
+    }
+
+    /// Returns whether accesses to pages which aren't mapped fail.
+    pub fn strict(&self) -> bool {
+        self.strict
+    }
+
+    /// Sets whether accesses through `MemoryBackend` to pages which aren't
+    /// mapped fail, rather than allocating them as they're written to.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Checks that `len` bytes starting at `addr` may be accessed.
+    #[inline]
+    fn check(&self, addr: u32, len: u64) -> Result<(), Error> {
+        if self.strict && !self.mappings.contains(addr, len) {
+            return Err(Error::OutOfBounds(addr));
+        }
+
+        Ok(())
+    }
+
+    /// Checks that `len` bytes starting at `addr` may be written to.
+    #[inline]
+    fn check_write(&self, addr: u32, len: u64) -> Result<(), Error> {
+        self.check(addr, len)?;
+
+        if !self.mappings.is_writable(addr, len) {
+            return Err(Error::OutOfBounds(addr));
+        }
+
+        Ok(())
+    }
+
     /// Returns the number of pages the memory consists of.
     pub fn page_count(&self) -> usize {
         ((u32::max_value() as u64 + 1) / self.page_size as u64) as usize
@@ -71,30 +462,93 @@ impl Memory {
 
     /// Returns a mutable reference to the page at position `index`.
     ///
-    /// Allocates the page if it wasn't previously.
+    /// Allocates the page if it wasn't previously, and discards any
+    /// instructions decoded from it. Fails with `Error::OutOfMemory` if the
+    /// page would be allocated beyond the limit set with [`set_max_pages`].
     ///
     /// # Panics
     ///
     /// Panics if `index` is greater than or equal to [`page_count`].
     ///
     /// [`page_count`]: #method.page_count
+    /// [`set_max_pages`]: #method.set_max_pages
     #[inline]
-    pub fn page_mut(&mut self, index: usize) -> &mut [u8] {
+    pub fn page_mut(&mut self, index: usize) -> Result<&mut [u8], Error> {
         assert!(index <= self.page_count(), "`index` out of bounds");
+
+        if !self.pages.contains_key(index) {
+            self.allocate(1)?;
+        }
         
+        let page_count = self.page_count();
+        self.decoded.invalidate(index, page_count);
+
         // Get `page_size` first to avoid current limitations in borrowck
         let page_size = self.page_size();
-        self.pages.entry(index).or_insert(vec![0; page_size].into()).as_mut()
+        Ok(self.pages.entry(index).or_insert(vec![0; page_size].into()).as_mut())
+    }
+
+    /// Checks that `count` more pages can be allocated, and records them as
+    /// allocated.
+    fn allocate(&mut self, count: usize) -> Result<(), Error> {
+        let resident = self.pages.len() + count;
+
+        if self.max_pages.map_or(false, |max_pages| resident > max_pages) {
+            return Err(Error::OutOfMemory);
+        }
+
+        self.stats.pages_allocated += count as u64;
+        self.stats.peak_pages = cmp::max(self.stats.peak_pages, resident);
+
+        Ok(())
+    }
+
+    /// Reads and decodes the instruction starting at byte address `addr`.
+    ///
+    /// Decoded instructions are cached until the page they are in is written
+    /// to, so that running the same code repeatedly only decodes it once.
+    /// Modifying `pages` directly bypasses this, so the cache must be cleared
+    /// with [`clear_decoded`] afterwards.
+    ///
+    /// [`clear_decoded`]: #method.clear_decoded
+    #[inline]
+    pub fn fetch(&mut self, addr: u32) -> Result<Instruction, Error> {
+        if let Some(instr) = self.decoded.get(addr) {
+            return Ok(instr);
+        }
+
+        let instr: Instruction = self.read_u32(addr).try_into()?;
+        self.decoded.insert(addr, instr);
+
+        Ok(instr)
+    }
+
+    /// Discards every instruction decoded by [`fetch`].
+    ///
+    /// [`fetch`]: #method.fetch
+    pub fn clear_decoded(&mut self) {
+        self.decoded.clear();
+    }
+
+    /// Returns a count which changes whenever instructions decoded by
+    /// [`fetch`] from the `len` bytes starting at byte address `addr` are
+    /// discarded, so that anything derived from them can be checked for
+    /// staleness.
+    ///
+    /// [`fetch`]: #method.fetch
+    #[inline]
+    pub fn code_generation(&self, addr: u32, len: u32) -> u64 {
+        self.decoded.generation(addr, len, self.page_count())
     }
 
     /// Reads bytes into the specified buffer `buf` starting at byte address `addr`.
     pub fn read(&self, addr: u32, buf: &mut [u8]) {
-        let end_addr = addr as u64 + (buf.len() as u64).saturating_sub(1);
-
-        if end_addr == addr as u64 {
+        if buf.is_empty() {
             return;
         }
 
+        let end_addr = addr as u64 + buf.len() as u64 - 1;
+
         // Reads may span multiple pages, so we get a range of page indices
         let pages = (addr as u64 / self.page_size as u64) as usize .. (end_addr / self.page_size as u64) as usize + 1;
         let pages_len = pages.len();
@@ -108,28 +562,41 @@ impl Memory {
             if let Some(page) = self.page(page % self.page_count()) {
                 buf.write_all(&page[start .. end]).unwrap();
             } else {
-                // Page not allocated, fill `buf` with 0s
+                // Page not allocated, fill this part of `buf` with 0s
+                let position = buf.position() as usize;
 
-                let count = (end - start) + 1;
-                let position = buf.position();
+                for byte in &mut buf.get_mut()[position .. position + (end - start)] {
+                    *byte = 0;
+                }
 
-                unsafe { ptr::write_bytes(buf.get_mut().as_mut_ptr(), 0, count); }
-                buf.set_position(position + count as u64);
+                buf.set_position((position + (end - start)) as u64);
             }
         }
     }
 
     /// Writes bytes from the specified buffer `buf` starting at byte address `addr`.
-    pub fn write(&mut self, addr: u32, buf: &[u8]) {
-        let end_addr = addr as u64 + (buf.len() as u64).saturating_sub(1);
-
-        if end_addr == addr as u64 {
-            return;
+    ///
+    /// Fails without writing anything if the pages written to can't all be
+    /// allocated.
+    pub fn write(&mut self, addr: u32, buf: &[u8]) -> Result<(), Error> {
+        if buf.is_empty() {
+            return Ok(());
         }
 
+        let end_addr = addr as u64 + buf.len() as u64 - 1;
+
         // Writes may span multiple pages, so we get a range of page indices
         let pages = (addr as u64 / self.page_size as u64) as usize .. (end_addr / self.page_size as u64) as usize + 1;
         let pages_len = pages.len();
+        let page_count = self.page_count();
+
+        if let Some(max_pages) = self.max_pages {
+            let unallocated = pages.clone().filter(|&page| !self.pages.contains_key(page % page_count)).count();
+
+            if self.pages.len() + unallocated > max_pages {
+                return Err(Error::OutOfMemory);
+            }
+        }
 
         let mut buf = Cursor::new(buf);
 
@@ -137,13 +604,13 @@ impl Memory {
             let start = if i > 0 { 0 } else { addr as usize % self.page_size };
             let end = if i < pages_len - 1 { self.page_size } else { (end_addr as usize % self.page_size) + 1 };
 
-            let page = {
-                // Get `page_count` first to avoid current limitations in borrowck
-                let page_count = self.page_count();
-                self.page_mut(page % page_count)
-            };
+            let page = self.page_mut(page % page_count)?;
             buf.read_exact(&mut page[start .. end]).unwrap();
         }
+
+        self.stats.bytes_written += buf.get_ref().len() as u64;
+
+        Ok(())
     }
 
     /// Reads an unsigned 32 bit integer starting at byte address `addr`.
@@ -157,11 +624,11 @@ impl Memory {
 
     /// Writes an unsigned 32 bit integer starting at byte address `addr`.
     #[inline]
-    pub fn write_u32(&mut self, addr: u32, value: u32) {
+    pub fn write_u32(&mut self, addr: u32, value: u32) -> Result<(), Error> {
         let mut buf = [0u8; 4];
 
         LittleEndian::write_u32(&mut buf, value);
-        self.write(addr, &buf);
+        self.write(addr, &buf)
     }
 
     // #[inline]
@@ -179,16 +646,277 @@ impl Memory {
     // }
 }
 
+impl MemoryBackend for Memory {
+    fn size(&self) -> u64 {
+        u32::max_value() as u64 + 1
+    }
+
+    #[inline]
+    fn read(&self, addr: u32, buf: &mut [u8]) -> Result<(), Error> {
+        self.check(addr, buf.len() as u64)?;
+        Ok(Memory::read(self, addr, buf))
+    }
+
+    #[inline]
+    fn write(&mut self, addr: u32, buf: &[u8]) -> Result<(), Error> {
+        self.check_write(addr, buf.len() as u64)?;
+        Memory::write(self, addr, buf)
+    }
+
+    #[inline]
+    fn read_u32(&self, addr: u32) -> Result<u32, Error> {
+        self.check(addr, 4)?;
+        Ok(Memory::read_u32(self, addr))
+    }
+
+    #[inline]
+    fn write_u32(&mut self, addr: u32, value: u32) -> Result<(), Error> {
+        self.check_write(addr, 4)?;
+        Memory::write_u32(self, addr, value)
+    }
+
+    #[inline]
+    fn fetch(&mut self, addr: u32) -> Result<Instruction, Error> {
+        // Compressed instructions are only two bytes long
+        self.check(addr, 2)?;
+        Memory::fetch(self, addr)
+    }
+
+    fn clear_decoded(&mut self) {
+        Memory::clear_decoded(self)
+    }
+
+    #[inline]
+    fn code_generation(&self, addr: u32, len: u32) -> u64 {
+        Memory::code_generation(self, addr, len)
+    }
+
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    fn map(&mut self, addr: u32, len: u64) {
+        let (start, end) = page_range(addr, len, self.page_size, self.size());
+        self.mappings.insert(start, end);
+    }
+
+    fn unmap(&mut self, addr: u32, len: u64) {
+        let (start, end) = page_range(addr, len, self.page_size, self.size());
+        let page_count = self.page_count();
+
+        for index in (start / self.page_size as u64) as usize .. (end / self.page_size as u64) as usize {
+            if self.pages.remove(index).is_some() {
+                self.decoded.invalidate(index, page_count);
+            }
+        }
+
+        self.mappings.remove(start, end);
+    }
+
+    fn protect(&mut self, addr: u32, len: u64) {
+        let (start, end) = page_range(addr, len, self.page_size, self.size());
+        self.mappings.protect(start, end);
+    }
+
+    fn mappings(&self) -> &Mappings {
+        &self.mappings
+    }
+}
+
+/// Memory held in a single contiguous buffer, for programs which only need a
+/// small address space.
+///
+/// Only addresses below the size of the memory can be accessed, and anything
+/// beyond that fails with `Error::OutOfBounds` rather than wrapping around.
+/// This avoids the page lookups of [`Memory`], at the cost of allocating the
+/// whole buffer up front. Mappings are made in pages of 4096 bytes, and when
+/// the memory is strict, accesses to anything which isn't mapped fail too.
+///
+/// [`Memory`]: struct.Memory.html
+pub struct FlatMemory {
+    bytes: Vec<u8>,
+    decoded: DecodeCache,
+    mappings: Mappings,
+    strict: bool
+}
+
+impl FlatMemory {
+    /// Constructs a new `FlatMemory` of `size` bytes, all initially 0.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is greater than 2^32.
+    pub fn new(size: usize) -> Self {
+        assert!(size as u64 <= u32::max_value() as u64 + 1, "`size` is larger than the address space");
+
+        Self {
+            bytes: vec![0; size],
+            decoded: DecodeCache::new(DEFAULT_PAGE_SIZE),
+            mappings: Mappings::default(),
+            strict: false
+        }
+    }
+
+    /// Returns the contents of the memory.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Returns whether accesses to pages which aren't mapped fail.
+    pub fn strict(&self) -> bool {
+        self.strict
+    }
+
+    /// Sets whether accesses to pages which aren't mapped fail.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Returns the range of `len` bytes starting at byte address `addr`, or an
+    /// error if any of them are out of bounds.
+    #[inline]
+    fn range(&self, addr: u32, len: usize) -> Result<Range<usize>, Error> {
+        let end = addr as u64 + len as u64;
+
+        if end > self.bytes.len() as u64 || (self.strict && !self.mappings.contains(addr, len as u64)) {
+            return Err(Error::OutOfBounds(addr));
+        }
+
+        Ok(addr as usize .. end as usize)
+    }
+
+    fn page_count(&self) -> usize {
+        cmp::max((self.bytes.len() + DEFAULT_PAGE_SIZE - 1) / DEFAULT_PAGE_SIZE, 1)
+    }
+}
+
+impl MemoryBackend for FlatMemory {
+    fn size(&self) -> u64 {
+        self.bytes.len() as u64
+    }
+
+    #[inline]
+    fn read(&self, addr: u32, buf: &mut [u8]) -> Result<(), Error> {
+        let range = self.range(addr, buf.len())?;
+        buf.copy_from_slice(&self.bytes[range]);
+
+        Ok(())
+    }
+
+    #[inline]
+    fn write(&mut self, addr: u32, buf: &[u8]) -> Result<(), Error> {
+        let range = self.range(addr, buf.len())?;
+
+        if !self.mappings.is_writable(addr, buf.len() as u64) {
+            return Err(Error::OutOfBounds(addr));
+        }
+
+        if !buf.is_empty() {
+            let page_count = self.page_count();
+
+            for index in range.start / DEFAULT_PAGE_SIZE .. (range.end - 1) / DEFAULT_PAGE_SIZE + 1 {
+                self.decoded.invalidate(index, page_count);
+            }
+        }
+
+        self.bytes[range].copy_from_slice(buf);
+
+        Ok(())
+    }
+
+    #[inline]
+    fn read_u32(&self, addr: u32) -> Result<u32, Error> {
+        let range = self.range(addr, 4)?;
+        Ok(LittleEndian::read_u32(&self.bytes[range]))
+    }
+
+    /// Reads and decodes the instruction starting at byte address `addr`.
+    ///
+    /// Compressed instructions in the last two bytes of memory can still be
+    /// fetched, even though a full word can't be read there.
+    #[inline]
+    fn fetch(&mut self, addr: u32) -> Result<Instruction, Error> {
+        if let Some(instr) = self.decoded.get(addr) {
+            return Ok(instr);
+        }
+
+        let available = cmp::min(self.size().saturating_sub(addr as u64), 4) as usize;
+
+        if available < 2 || (self.strict && !self.mappings.contains(addr, 2)) {
+            return Err(Error::OutOfBounds(addr));
+        }
+
+        let mut buf = [0u8; 4];
+        buf[..available].copy_from_slice(&self.bytes[addr as usize .. addr as usize + available]);
+
+        let instr: Instruction = LittleEndian::read_u32(&buf).try_into()?;
+
+        if instr.size() as usize > available {
+            return Err(Error::OutOfBounds(addr));
+        }
+
+        self.decoded.insert(addr, instr);
+
+        Ok(instr)
+    }
+
+    fn clear_decoded(&mut self) {
+        self.decoded.clear();
+    }
+
+    #[inline]
+    fn code_generation(&self, addr: u32, len: u32) -> u64 {
+        self.decoded.generation(addr, len, self.page_count())
+    }
+
+    fn page_size(&self) -> usize {
+        DEFAULT_PAGE_SIZE
+    }
+
+    fn map(&mut self, addr: u32, len: u64) {
+        let (start, end) = page_range(addr, len, DEFAULT_PAGE_SIZE, self.size());
+        self.mappings.insert(start, end);
+    }
+
+    fn unmap(&mut self, addr: u32, len: u64) {
+        let (start, end) = page_range(addr, len, DEFAULT_PAGE_SIZE, self.size());
+        let page_count = self.page_count();
+
+        if start < end {
+            for index in start as usize / DEFAULT_PAGE_SIZE .. (end as usize - 1) / DEFAULT_PAGE_SIZE + 1 {
+                self.decoded.invalidate(index, page_count);
+            }
+        }
+
+        for byte in &mut self.bytes[start as usize .. end as usize] {
+            *byte = 0;
+        }
+
+        self.mappings.remove(start, end);
+    }
+
+    fn protect(&mut self, addr: u32, len: u64) {
+        let (start, end) = page_range(addr, len, DEFAULT_PAGE_SIZE, self.size());
+        self.mappings.protect(start, end);
+    }
+
+    fn mappings(&self) -> &Mappings {
+        &self.mappings
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::{Memory, DEFAULT_PAGE_SIZE as PAGE_SIZE};
+    use {Error, Instruction, OpCode};
+
+    use super::{FlatMemory, Mappings, Memory, MemoryBackend, MemoryStats, DEFAULT_PAGE_SIZE as PAGE_SIZE};
 
     const PAGE_COUNT: usize = ((1u64 << 32) / PAGE_SIZE as u64) as usize;
 
     #[test]
     fn read() {
         let mut mem = Memory::new();
-        mem.page_mut(0)[..4].copy_from_slice(&[0xff; 4]);
+        mem.page_mut(0).unwrap()[..4].copy_from_slice(&[0xff; 4]);
 
         let mut buf = [0; 4];
 
@@ -199,11 +927,11 @@ mod test {
     #[test]
     fn write() {
         let mut mem = Memory::new();
-        mem.page_mut(0)[..4].copy_from_slice(&[0xff; 4]);
+        mem.page_mut(0).unwrap()[..4].copy_from_slice(&[0xff; 4]);
 
         let buf = [0; 4];
 
-        mem.write(0, &buf);
+        mem.write(0, &buf).unwrap();
         assert_eq!(mem.page(0).unwrap()[..4], [0; 4]);
     }
 
@@ -221,15 +949,15 @@ mod test {
         let mut mem = Memory::new();
         let buf = [0xff; 4];
 
-        mem.write(0, &buf);
+        mem.write(0, &buf).unwrap();
         assert_eq!(mem.page(0).unwrap()[..4], [0xff; 4]);
     }
 
     #[test]
     fn read_boundary() {
         let mut mem = Memory::new();
-        mem.page_mut(0)[(PAGE_SIZE - 2)..].copy_from_slice(&[0xff; 2]);
-        mem.page_mut(1)[..2].copy_from_slice(&[0xff; 2]);
+        mem.page_mut(0).unwrap()[(PAGE_SIZE - 2)..].copy_from_slice(&[0xff; 2]);
+        mem.page_mut(1).unwrap()[..2].copy_from_slice(&[0xff; 2]);
 
         let mut buf = [0; 4];
 
@@ -240,12 +968,12 @@ mod test {
     #[test]
     fn write_boundary() {
         let mut mem = Memory::new();
-        mem.page_mut(0)[(PAGE_SIZE - 2)..].copy_from_slice(&[0xff; 2]);
-        mem.page_mut(1)[..2].copy_from_slice(&[0xff; 2]);
+        mem.page_mut(0).unwrap()[(PAGE_SIZE - 2)..].copy_from_slice(&[0xff; 2]);
+        mem.page_mut(1).unwrap()[..2].copy_from_slice(&[0xff; 2]);
 
         let buf = [0; 4];
 
-        mem.write(PAGE_SIZE as u32 - 2, &buf);
+        mem.write(PAGE_SIZE as u32 - 2, &buf).unwrap();
         assert_eq!(mem.page(0).unwrap()[(PAGE_SIZE - 2)..], [0; 2]);
         assert_eq!(mem.page(1).unwrap()[..2], [0; 2]);
     }
@@ -253,8 +981,8 @@ mod test {
     #[test]
     fn read_wrapping() {
         let mut mem = Memory::new();
-        mem.page_mut(0)[..2].copy_from_slice(&[0xff; 2]);
-        mem.page_mut(PAGE_COUNT - 1)[(PAGE_SIZE - 2)..].copy_from_slice(&[0xff; 2]);
+        mem.page_mut(0).unwrap()[..2].copy_from_slice(&[0xff; 2]);
+        mem.page_mut(PAGE_COUNT - 1).unwrap()[(PAGE_SIZE - 2)..].copy_from_slice(&[0xff; 2]);
 
         let mut buf = [0; 4];
 
@@ -265,13 +993,263 @@ mod test {
     #[test]
     fn write_wrapping() {
         let mut mem = Memory::new();
-        mem.page_mut(0)[..2].copy_from_slice(&[0xff; 2]);
-        mem.page_mut(PAGE_COUNT - 1)[(PAGE_SIZE - 2)..].copy_from_slice(&[0xff; 2]);
+        mem.page_mut(0).unwrap()[..2].copy_from_slice(&[0xff; 2]);
+        mem.page_mut(PAGE_COUNT - 1).unwrap()[(PAGE_SIZE - 2)..].copy_from_slice(&[0xff; 2]);
 
         let buf = [0; 4];
 
-        mem.write(u32::max_value() - 1, &buf);
+        mem.write(u32::max_value() - 1, &buf).unwrap();
         assert_eq!(mem.page(0).unwrap()[..2], [0; 2]);
         assert_eq!(mem.page(PAGE_COUNT - 1).unwrap()[PAGE_SIZE - 2 ..], [0; 2]);
     }
+
+    #[test]
+    fn fetch() {
+        let addi = |imm| Instruction::Immediate { op: OpCode::ADDI, dst: 4, src1: 0, imm: imm };
+
+        // Spans the first two pages
+        let mut mem = Memory::with_page_size(16);
+        mem.write(14, &[0x12, 0x01, 0x08, 0x00]).unwrap();
+
+        assert_eq!(mem.fetch(14), Ok(addi(8)));
+        assert_eq!(mem.fetch(14), Ok(addi(8)));
+
+        mem.write(16, &[0x10, 0x00]).unwrap();
+        assert_eq!(mem.fetch(14), Ok(addi(16)));
+
+        mem.page_mut(0).unwrap()[14] = 0x01;
+        assert_eq!(mem.fetch(14), Err(Error::InvalidOpCode(1)));
+    }
+
+    #[test]
+    fn fetch_small_pages() {
+        let addi = |imm| Instruction::Immediate { op: OpCode::ADDI, dst: 4, src1: 0, imm: imm };
+
+        // Instructions span up to four pages, so writing to the last byte must
+        // discard the instruction decoded from the first
+        for &page_size in &[1, 2] {
+            let mut mem = Memory::with_page_size(page_size);
+            mem.write(8, &[0x12, 0x01, 0x08, 0x00]).unwrap();
+            assert_eq!(mem.fetch(8), Ok(addi(8)));
+
+            mem.write(11, &[0x01, 0x00]).unwrap();
+            assert_eq!(mem.fetch(8), Ok(addi(0x108)));
+        }
+    }
+
+    #[test]
+    fn code_generation() {
+        let mut mem = Memory::with_page_size(16);
+        mem.write(14, &[0x12, 0x01, 0x08, 0x00]).unwrap();
+        mem.fetch(14).unwrap();
+
+        let generation = mem.code_generation(14, 4);
+
+        // Writing to a page without decoded code leaves the generation alone
+        mem.write(64, &[0x12, 0x01, 0x08, 0x00]).unwrap();
+        assert_eq!(mem.code_generation(14, 4), generation);
+
+        mem.fetch(64).unwrap();
+        mem.write(66, &[0x00; 2]).unwrap();
+        assert_eq!(mem.code_generation(14, 4), generation);
+
+        // Writing to either page the instruction spans changes it
+        mem.write(16, &[0x00; 2]).unwrap();
+        assert!(mem.code_generation(14, 4) != generation);
+
+        mem.clear_decoded();
+        assert!(mem.code_generation(64, 1) != 0);
+    }
+
+    #[test]
+    fn max_pages() {
+        let mut mem = Memory::new();
+        mem.set_max_pages(Some(2));
+        mem.write(0, &[0xff; 4]).unwrap();
+
+        // Needs two more pages, so fails without writing to either
+        assert_eq!(mem.write(PAGE_SIZE as u32 * 2 - 2, &[0xff; 4]), Err(Error::OutOfMemory));
+        assert_eq!(mem.pages.len(), 1);
+
+        assert_eq!(mem.write(PAGE_SIZE as u32 - 2, &[0xff; 4]), Ok(()));
+        assert_eq!(mem.write_u32(PAGE_SIZE as u32 * 2, 0), Err(Error::OutOfMemory));
+        assert_eq!(mem.page_mut(2), Err(Error::OutOfMemory));
+    }
+
+    #[test]
+    fn stats() {
+        let mut mem = Memory::new();
+        mem.write(PAGE_SIZE as u32 - 2, &[0xff; 4]).unwrap();
+        mem.write_u32(0, 0).unwrap();
+        mem.pages.remove(1);
+        mem.page_mut(3).unwrap();
+
+        assert_eq!(mem.stats(), MemoryStats { pages_allocated: 3, peak_pages: 2, bytes_written: 8 });
+    }
+
+    #[test]
+    fn flat_read_write() {
+        let mut mem = FlatMemory::new(PAGE_SIZE * 2);
+
+        assert_eq!(mem.write(PAGE_SIZE as u32 - 2, &[0xff; 4]), Ok(()));
+        assert_eq!(mem.read_u32(PAGE_SIZE as u32 - 2), Ok(0xffffffff));
+        assert_eq!(mem.read_u32(PAGE_SIZE as u32 * 2 - 4), Ok(0));
+    }
+
+    #[test]
+    fn flat_matches_sparse() {
+        let mut sparse = Memory::with_page_size(16);
+        let mut flat = FlatMemory::new(64);
+
+        // Single bytes, and runs spanning allocated and unallocated pages
+        let writes: &[(u32, &[u8])] = &[(0, &[1]), (5, &[2]), (15, &[3, 4]), (40, &[5; 9])];
+
+        for &(addr, bytes) in writes {
+            MemoryBackend::write(&mut sparse, addr, bytes).unwrap();
+            flat.write(addr, bytes).unwrap();
+        }
+
+        for addr in 0..64 {
+            for len in 0..64 - addr as usize {
+                let mut sparse_buf = vec![0xee; len + 1];
+                let mut flat_buf = sparse_buf.clone();
+
+                MemoryBackend::read(&sparse, addr, &mut sparse_buf[..len]).unwrap();
+                flat.read(addr, &mut flat_buf[..len]).unwrap();
+
+                assert_eq!(sparse_buf, flat_buf, "reading {} bytes at {}", len, addr);
+            }
+        }
+    }
+
+    #[test]
+    fn flat_out_of_bounds() {
+        let mut mem = FlatMemory::new(16);
+        let mut buf = [0; 4];
+
+        assert_eq!(mem.read(14, &mut buf), Err(Error::OutOfBounds(14)));
+        assert_eq!(mem.write(16, &[0xff]), Err(Error::OutOfBounds(16)));
+        assert_eq!(mem.write_u32(u32::max_value(), 0), Err(Error::OutOfBounds(u32::max_value())));
+        assert_eq!(mem.bytes(), &[0; 16][..]);
+    }
+
+    #[test]
+    fn flat_fetch() {
+        let addi = |imm| Instruction::Immediate { op: OpCode::ADDI, dst: 4, src1: 0, imm: imm };
+
+        let mut mem = FlatMemory::new(PAGE_SIZE + 2);
+        mem.write(PAGE_SIZE as u32 - 2, &[0x12, 0x01, 0x08, 0x00]).unwrap();
+
+        assert_eq!(mem.fetch(PAGE_SIZE as u32 - 2), Ok(addi(8)));
+
+        mem.write(PAGE_SIZE as u32, &[0x10, 0x00]).unwrap();
+        assert_eq!(mem.fetch(PAGE_SIZE as u32 - 2), Ok(addi(16)));
+
+        // Only compressed instructions fit in the last two bytes
+        assert_eq!(mem.fetch(PAGE_SIZE as u32), Err(Error::OutOfBounds(PAGE_SIZE as u32)));
+        mem.write(PAGE_SIZE as u32, &[0x31, 0x03]).unwrap();
+        assert!(mem.fetch(PAGE_SIZE as u32).is_ok());
+    }
+
+    #[test]
+    fn mappings() {
+        let mut mappings = Mappings::default();
+        mappings.insert(0x1000, 0x2000);
+        mappings.insert(0x4000, 0x5000);
+        mappings.insert(0x2000, 0x3000);
+
+        assert_eq!(mappings.ranges(), &[(0x1000, 0x3000), (0x4000, 0x5000)]);
+        assert!(mappings.contains(0x1ffe, 4));
+        assert!(!mappings.contains(0x2ffe, 4));
+        assert!(!mappings.contains(0, 1));
+        assert!(mappings.is_free(0x3000, 0x4000));
+        assert!(!mappings.is_free(0x3000, 0x4001));
+
+        assert_eq!(mappings.find_free(0x1000, 0, 0x6000), Some(0x5000));
+        assert_eq!(mappings.find_free(0x1000, 0, 0x5000), Some(0x3000));
+        assert_eq!(mappings.find_free(0x2000, 0, 0x5000), None);
+        assert_eq!(mappings.find_free(0x1000, 0x3800, 0x5000), None);
+
+        mappings.insert(0x800, 0x4800);
+        assert_eq!(mappings.ranges(), &[(0x800, 0x5000)]);
+
+        mappings.remove(0x1000, 0x2000);
+        mappings.remove(0x4000, 0x6000);
+        assert_eq!(mappings.ranges(), &[(0x800, 0x1000), (0x2000, 0x4000)]);
+    }
+
+    #[test]
+    fn map_unmap() {
+        let mut mem = Memory::new();
+        MemoryBackend::map(&mut mem, PAGE_SIZE as u32 + 1, 2);
+        assert_eq!(mem.mappings().ranges(), &[(PAGE_SIZE as u64, PAGE_SIZE as u64 * 2)]);
+
+        mem.write_u32(PAGE_SIZE as u32, 0xffffffff).unwrap();
+        MemoryBackend::unmap(&mut mem, PAGE_SIZE as u32, 1);
+
+        assert!(mem.mappings().ranges().is_empty());
+        assert!(mem.page(1).is_none());
+        assert_eq!(Memory::read_u32(&mem, PAGE_SIZE as u32), 0);
+    }
+
+    #[test]
+    fn strict() {
+        let mut mem = Memory::new();
+        mem.set_strict(true);
+        MemoryBackend::map(&mut mem, 0, PAGE_SIZE as u64);
+
+        let end = PAGE_SIZE as u32;
+        assert_eq!(MemoryBackend::write_u32(&mut mem, end - 4, 1), Ok(()));
+        assert_eq!(MemoryBackend::write_u32(&mut mem, end - 2, 1), Err(Error::OutOfBounds(end - 2)));
+        assert_eq!(MemoryBackend::read_u32(&mem, end), Err(Error::OutOfBounds(end)));
+        assert_eq!(MemoryBackend::fetch(&mut mem, end), Err(Error::OutOfBounds(end)));
+        assert!(mem.page(1).is_none());
+
+        // The host can still access the whole of memory
+        assert_eq!(Memory::read_u32(&mem, end), 0);
+    }
+
+    #[test]
+    fn read_only() {
+        let mut mem = Memory::new();
+        mem.set_strict(true);
+        MemoryBackend::map(&mut mem, 0, PAGE_SIZE as u64 * 2);
+        MemoryBackend::protect(&mut mem, PAGE_SIZE as u32, 1);
+
+        let page = PAGE_SIZE as u32;
+        assert_eq!(MemoryBackend::write_u32(&mut mem, page - 4, 1), Ok(()));
+        assert_eq!(MemoryBackend::write_u32(&mut mem, page - 2, 1), Err(Error::OutOfBounds(page - 2)));
+        assert_eq!(MemoryBackend::read_u32(&mem, page), Ok(0));
+
+        // Read only pages are enforced even when memory isn't strict
+        mem.set_strict(false);
+        assert_eq!(MemoryBackend::write(&mut mem, page, &[1]), Err(Error::OutOfBounds(page)));
+
+        let mut flat = FlatMemory::new(PAGE_SIZE * 2);
+        flat.map(0, PAGE_SIZE as u64 * 2);
+        flat.protect(page, 1);
+        assert_eq!(flat.write_u32(page, 1), Err(Error::OutOfBounds(page)));
+        assert_eq!(flat.write_u32(0, 1), Ok(()));
+
+        // Mapping the page again makes it writable
+        MemoryBackend::map(&mut mem, page, 1);
+        assert_eq!(MemoryBackend::write_u32(&mut mem, page, 1), Ok(()));
+    }
+
+    #[test]
+    fn flat_strict() {
+        let mut mem = FlatMemory::new(PAGE_SIZE * 2);
+        mem.write_u32(PAGE_SIZE as u32, 1).unwrap();
+        mem.set_strict(true);
+
+        assert_eq!(mem.read_u32(PAGE_SIZE as u32), Err(Error::OutOfBounds(PAGE_SIZE as u32)));
+
+        mem.map(PAGE_SIZE as u32, 1);
+        assert_eq!(mem.read_u32(PAGE_SIZE as u32), Ok(1));
+        assert_eq!(mem.read_u32(0), Err(Error::OutOfBounds(0)));
+
+        mem.unmap(PAGE_SIZE as u32, PAGE_SIZE as u64);
+        mem.set_strict(false);
+        assert_eq!(mem.read_u32(PAGE_SIZE as u32), Ok(0));
+    }
 }
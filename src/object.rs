@@ -0,0 +1,316 @@
+use std::io::{self, Read, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+/// Magic number identifying SVM object files.
+const MAGIC: &'static [u8; 4] = b"SVMO";
+
+/// Visibility of a symbol in an object file.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Binding {
+    /// Defined in this object and only visible within it.
+    Local = 0,
+    /// Defined in this object and visible to all other objects.
+    Global = 1,
+    /// Used by this object but defined in another.
+    Extern = 2
+}
+
+/// The way in which a relocation is applied to an instruction's immediate.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RelocationKind {
+    /// The address of the target.
+    Absolute = 0,
+    /// The offset of the target from the start of the section containing the
+    /// instruction.
+    Relative = 1,
+    /// The upper half of the address of the target, as `hi()` in assembly.
+    /// Rounded for the sign extended `Low` half added by `ADDI`.
+    High = 2,
+    /// The lower half of the address of the target, as `lo()` in assembly.
+    /// Only allowed in `ORI` when bit 15 of the address is clear.
+    Low = 3,
+    /// The address of the target as a 32 bit word of data rather than an
+    /// immediate, as given to `.word` in assembly.
+    Word = 4
+}
+
+impl RelocationKind {
+    /// Returns the immediate for a target at address `value`, ignoring the
+    /// adjustment for the section's address made by `Relative` relocations.
+    pub fn apply(&self, value: u32) -> u32 {
+        match *self {
+            RelocationKind::Absolute | RelocationKind::Relative | RelocationKind::Word => value,
+            // Adjusted so that adding the sign extended lower half yields
+            // the original value again
+            RelocationKind::High => value.wrapping_add(0x8000) & 0xffff0000,
+            RelocationKind::Low => value & 0xffff
+        }
+    }
+}
+
+/// Name of the section whose contents are always zero, and so aren't stored.
+pub const ZERO_SECTION: &'static str = ".bss";
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Section {
+    pub name: String,
+    /// Contents of the section, which is empty for `.bss`.
+    pub data: Vec<u8>,
+    /// Size of the section in bytes.
+    pub size: u32,
+    /// Alignment in bytes the section must be placed at, the largest given
+    /// to `.align` within it.
+    pub align: u32
+}
+
+impl Section {
+    /// Constructs a new, empty `Section` named `name`.
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            data: Vec::new(),
+            size: 0,
+            align: 1
+        }
+    }
+
+    /// Returns whether this section is zero initialised and so has no contents.
+    pub fn is_zero(&self) -> bool {
+        self.name == ZERO_SECTION
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub binding: Binding,
+    /// Index of the section the symbol is defined in. Unused for `Extern` symbols.
+    pub section: u32,
+    /// Offset of the symbol from the start of its section. Unused for `Extern`
+    /// symbols.
+    pub value: u32
+}
+
+/// The address a relocation refers to.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Target {
+    /// The start of the section at this index in the same object.
+    Section(u32),
+    /// The symbol with this name, either in the same object or a global
+    /// symbol in another.
+    Symbol(String)
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Relocation {
+    /// Index of the section containing the instruction to patch.
+    pub section: u32,
+    /// Offset of the instruction to patch from the start of its section.
+    pub offset: u32,
+    pub kind: RelocationKind,
+    pub target: Target,
+    pub addend: u32
+}
+
+/// A relocatable object file, as produced by `sasm -c` and consumed by `slink`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Object {
+    pub sections: Vec<Section>,
+    pub symbols: Vec<Symbol>,
+    pub relocations: Vec<Relocation>
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_string<R: Read>(reader: &mut R) -> Result<String, io::Error> {
+    let mut buf = vec![0; reader.read_u16::<LittleEndian>()? as usize];
+    reader.read_exact(&mut buf)?;
+
+    String::from_utf8(buf).map_err(|_| invalid_data("name is not valid UTF-8"))
+}
+
+fn write_string<W: Write>(writer: &mut W, string: &str) -> Result<(), io::Error> {
+    writer.write_u16::<LittleEndian>(string.len() as u16)?;
+    writer.write_all(string.as_bytes())
+}
+
+impl Object {
+    /// Reads an object file from `reader`.
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self, io::Error> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+
+        if &magic != MAGIC {
+            return Err(invalid_data("not an SVM object file"));
+        }
+
+        let mut sections = Vec::new();
+
+        for _ in 0..reader.read_u32::<LittleEndian>()? {
+            let mut section = Section::new(&read_string(reader)?);
+            section.size = reader.read_u32::<LittleEndian>()?;
+            section.align = reader.read_u32::<LittleEndian>()?;
+
+            if !section.align.is_power_of_two() {
+                return Err(invalid_data("section alignment is not a power of two"));
+            }
+
+            if !section.is_zero() {
+                section.data = vec![0; section.size as usize];
+                reader.read_exact(&mut section.data)?;
+            }
+
+            sections.push(section);
+        }
+
+        let mut symbols = Vec::new();
+
+        for _ in 0..reader.read_u32::<LittleEndian>()? {
+            let binding = match reader.read_u8()? {
+                0 => Binding::Local,
+                1 => Binding::Global,
+                2 => Binding::Extern,
+                _ => return Err(invalid_data("invalid symbol binding"))
+            };
+            let section = reader.read_u32::<LittleEndian>()?;
+            let value = reader.read_u32::<LittleEndian>()?;
+
+            symbols.push(Symbol { name: read_string(reader)?, binding, section, value });
+        }
+
+        let mut relocations = Vec::new();
+
+        for _ in 0..reader.read_u32::<LittleEndian>()? {
+            let section = reader.read_u32::<LittleEndian>()?;
+            let offset = reader.read_u32::<LittleEndian>()?;
+            let kind = match reader.read_u8()? {
+                0 => RelocationKind::Absolute,
+                1 => RelocationKind::Relative,
+                2 => RelocationKind::High,
+                3 => RelocationKind::Low,
+                4 => RelocationKind::Word,
+                _ => return Err(invalid_data("invalid relocation kind"))
+            };
+            let addend = reader.read_u32::<LittleEndian>()?;
+            let target = match reader.read_u8()? {
+                0 => Target::Section(reader.read_u32::<LittleEndian>()?),
+                _ => Target::Symbol(read_string(reader)?)
+            };
+
+            relocations.push(Relocation { section, offset, kind, target, addend });
+        }
+
+        {
+            let valid = |index: u32| (index as usize) < sections.len();
+
+            if symbols.iter().any(|s| s.binding != Binding::Extern && !valid(s.section)) ||
+               relocations.iter().any(|r| !valid(r.section) || match r.target {
+                   Target::Section(index) => !valid(index),
+                   Target::Symbol(_) => false
+               }) {
+                return Err(invalid_data("invalid section index"));
+            }
+        }
+
+        Ok(Self { sections, symbols, relocations })
+    }
+
+    /// Writes this object file to `writer`.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), io::Error> {
+        writer.write_all(MAGIC)?;
+
+        writer.write_u32::<LittleEndian>(self.sections.len() as u32)?;
+
+        for section in &self.sections {
+            write_string(writer, &section.name)?;
+            writer.write_u32::<LittleEndian>(section.size)?;
+            writer.write_u32::<LittleEndian>(section.align)?;
+
+            if !section.is_zero() {
+                writer.write_all(&section.data)?;
+            }
+        }
+
+        writer.write_u32::<LittleEndian>(self.symbols.len() as u32)?;
+
+        for symbol in &self.symbols {
+            writer.write_u8(symbol.binding as u8)?;
+            writer.write_u32::<LittleEndian>(symbol.section)?;
+            writer.write_u32::<LittleEndian>(symbol.value)?;
+            write_string(writer, &symbol.name)?;
+        }
+
+        writer.write_u32::<LittleEndian>(self.relocations.len() as u32)?;
+
+        for relocation in &self.relocations {
+            writer.write_u32::<LittleEndian>(relocation.section)?;
+            writer.write_u32::<LittleEndian>(relocation.offset)?;
+            writer.write_u8(relocation.kind as u8)?;
+            writer.write_u32::<LittleEndian>(relocation.addend)?;
+
+            match relocation.target {
+                Target::Section(index) => {
+                    writer.write_u8(0)?;
+                    writer.write_u32::<LittleEndian>(index)?;
+                },
+                Target::Symbol(ref name) => {
+                    writer.write_u8(1)?;
+                    write_string(writer, name)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::{Binding, Object, Relocation, RelocationKind, Section, Symbol, Target};
+
+    #[test]
+    fn round_trip() {
+        let object = Object {
+            sections: vec![
+                Section { name: ".text".to_owned(), data: vec![0x32, 0x01, 0x00, 0x00, 0x12, 0x21, 0x08, 0x00], size: 8,
+                          align: 1 },
+                Section { name: ".bss".to_owned(), data: Vec::new(), size: 64, align: 16 }
+            ],
+            symbols: vec![
+                Symbol { name: "start".to_owned(), binding: Binding::Global, section: 0, value: 0 },
+                Symbol { name: "buffer".to_owned(), binding: Binding::Local, section: 1, value: 0 },
+                Symbol { name: "print".to_owned(), binding: Binding::Extern, section: 0, value: 0 }
+            ],
+            relocations: vec![
+                Relocation { section: 0, offset: 0, kind: RelocationKind::High,
+                             target: Target::Symbol("print".to_owned()), addend: 0 },
+                Relocation { section: 0, offset: 4, kind: RelocationKind::Low, target: Target::Section(1), addend: 8 }
+            ]
+        };
+
+        let mut buf = Vec::new();
+        object.write_to(&mut buf).unwrap();
+
+        assert_eq!(&buf[..4], b"SVMO");
+        assert_eq!(Object::read_from(&mut Cursor::new(buf)).unwrap(), object);
+    }
+
+    #[test]
+    fn invalid_magic() {
+        assert_eq!(Object::read_from(&mut Cursor::new(b"SVMX\0\0\0\0".to_vec())).is_err(), true);
+    }
+
+    #[test]
+    fn apply() {
+        assert_eq!(RelocationKind::Absolute.apply(0x12348765), 0x12348765);
+        assert_eq!(RelocationKind::High.apply(0x12348765), 0x12350000);
+        assert_eq!(RelocationKind::Low.apply(0x12348765), 0x8765);
+    }
+}
@@ -0,0 +1,624 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use {Error, ErrorCode, Random, VirtualMachine};
+
+/// Number of blocks a process runs for by default before the next one gets a
+/// turn.
+const DEFAULT_SLICE: u32 = 64;
+
+/// A request a program makes of the `Scheduler` running it, which stops
+/// `VirtualMachine::run_slice` so that the scheduler can carry it out and set
+/// the syscall's return value.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ProcessCall {
+    /// `sys_spawn`, running the image at `path` with the arguments `args`.
+    Spawn { path: String, args: Vec<String>, inherit_files: bool },
+    /// `sys_wait`, waiting for the child with the given ID to end.
+    Wait(u32),
+    /// `sys_kill`, ending the child with the given ID.
+    Kill(u32),
+    /// `sys_yield`, giving up the rest of the slice.
+    Yield,
+    /// `sys_sleep` with `Clock::Host`, pausing for the given time.
+    Sleep(Duration)
+}
+
+/// Why `VirtualMachine::run_slice` returned.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Slice {
+    /// The program exited with the given status.
+    Exited(i32),
+    /// The program ran for the whole slice.
+    Expired,
+    /// The program made a call which only a scheduler can carry out.
+    Call(ProcessCall)
+}
+
+/// How a process ended, which `sys_wait` returns to its parent.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ProcessExit {
+    /// The program called `sys_exit` with the given status.
+    Exited(i32),
+    /// The process was ended by its parent with `sys_kill`.
+    Killed,
+    /// The machine stopped with an error.
+    Failed(Error)
+}
+
+impl ProcessExit {
+    /// Returns the values `sys_wait` returns in `r3` and `r4`: the exit
+    /// status, and 0 if the program exited, 1 if it was killed or 2 if it
+    /// failed.
+    fn to_return_values(self) -> (u32, u32) {
+        match self {
+            ProcessExit::Exited(status) => (status as u32, 0),
+            ProcessExit::Killed => (0, 1),
+            ProcessExit::Failed(_) => (0, 2)
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum State {
+    Ready,
+    /// Blocked in `sys_wait` for the child with the given ID.
+    Waiting(u32),
+    /// Blocked in `sys_sleep` until the given time.
+    Sleeping(Instant),
+    Ended(ProcessExit)
+}
+
+struct Process {
+    vm: VirtualMachine,
+    /// ID of the process which spawned this one, or 0 for the first.
+    parent: u32,
+    state: State
+}
+
+/// Runs a program along with any it spawns, each in a machine of its own,
+/// taking turns on the host thread.
+///
+/// Processes are given IDs counting up from 1, the first program's. They take
+/// turns in order of ID, each running until it exits, blocks in `sys_wait` or
+/// `sys_sleep`, gives up its turn with `sys_yield` or has run for `slice`
+/// blocks. Children are set up like their parent, with its page size, engine,
+/// clock and environment, and a generator seeded from their parent's.
+///
+/// A process is removed once it has been waited for, or as soon as it ends if
+/// its parent has ended, as then nothing can wait for it. Killing a process
+/// kills its descendants too, while those of a process which exits or fails
+/// carry on running.
+pub struct Scheduler {
+    /// Live processes by ID, so that turns are taken in order of ID.
+    processes: BTreeMap<u32, Process>,
+    next_pid: u32,
+    /// Number of blocks, or instructions with the interpreter, each process
+    /// runs for before the next gets a turn. Defaults to 64.
+    pub slice: u32
+}
+
+impl Scheduler {
+    /// Constructs a scheduler whose first process runs in `vm`.
+    pub fn new(vm: VirtualMachine) -> Self {
+        let mut processes = BTreeMap::new();
+        processes.insert(1, Process { vm: vm, parent: 0, state: State::Ready });
+
+        Self { processes: processes, next_pid: 2, slice: DEFAULT_SLICE }
+    }
+
+    /// Returns the machine of the first process.
+    pub fn root(&self) -> &VirtualMachine {
+        &self.processes[&1].vm
+    }
+
+    /// Returns the machine of the first process, consuming the scheduler.
+    pub fn into_root(mut self) -> VirtualMachine {
+        self.processes.remove(&1).unwrap().vm
+    }
+
+    /// Runs processes until the first one exits, returning its exit status.
+    /// Any processes still running then are dropped.
+    ///
+    /// If the first process fails, its program counter is left at the
+    /// address of the instruction which caused it, as with
+    /// `VirtualMachine::run`.
+    pub fn run(&mut self) -> Result<i32, Error> {
+        loop {
+            // Processes spawned during the round get their first turn in it
+            let mut next = self.processes.keys().next().cloned();
+
+            while let Some(pid) = next {
+                self.turn(pid);
+
+                match self.processes[&1].state {
+                    State::Ended(ProcessExit::Exited(status)) => return Ok(status),
+                    State::Ended(ProcessExit::Failed(error)) => return Err(error),
+                    _ => {}
+                }
+
+                next = self.processes.range(pid + 1 ..).next().map(|(&pid, _)| pid);
+            }
+
+            self.idle();
+        }
+    }
+
+    /// Pauses the host thread until the first sleeping process wakes, if
+    /// none are ready to run.
+    fn idle(&self) {
+        let mut wake = None;
+
+        for process in self.processes.values() {
+            match process.state {
+                State::Ready => return,
+                State::Sleeping(until) => wake = Some(wake.map_or(until, |wake| cmp::min(wake, until))),
+                _ => {}
+            }
+        }
+
+        if let Some(until) = wake {
+            let now = Instant::now();
+
+            if until > now {
+                thread::sleep(until - now);
+            }
+        }
+    }
+
+    /// Gives the process `pid` a turn, if it's ready to run or it's time for
+    /// it to wake.
+    fn turn(&mut self, pid: u32) {
+        let slice = match self.processes.get_mut(&pid) {
+            Some(process) => {
+                if let State::Sleeping(until) = process.state {
+                    if Instant::now() >= until {
+                        process.state = State::Ready;
+                    }
+                }
+
+                if process.state != State::Ready {
+                    return;
+                }
+
+                process.vm.run_slice(self.slice)
+            },
+            None => return
+        };
+
+        let call = match slice {
+            Ok(Slice::Exited(status)) => return self.end(pid, ProcessExit::Exited(status)),
+            Ok(Slice::Expired) => return,
+            Ok(Slice::Call(call)) => call,
+            Err(error) => return self.end(pid, ProcessExit::Failed(error))
+        };
+
+        let result = match call {
+            ProcessCall::Spawn { path, args, inherit_files } => self.spawn(pid, &path, args, inherit_files),
+            ProcessCall::Wait(child) => {
+                if let Err(code) = self.child(pid, child, ErrorCode::ECHILD) {
+                    Err(code)
+                } else {
+                    // The wait finishes once the child has ended, which it may have already
+                    self.process(pid).state = State::Waiting(child);
+                    return self.reap(pid, child);
+                }
+            },
+            ProcessCall::Kill(child) => self.kill(pid, child),
+            ProcessCall::Yield => Ok(0),
+            ProcessCall::Sleep(duration) => {
+                self.process(pid).state = State::Sleeping(Instant::now() + duration);
+                Ok(0)
+            }
+        };
+
+        self.process(pid).vm.registers[3] = result.unwrap_or_else(ErrorCode::to_return_value);
+    }
+
+    /// Returns the live process `pid`.
+    fn process(&mut self, pid: u32) -> &mut Process {
+        self.processes.get_mut(&pid).expect("process has been removed")
+    }
+
+    /// Ends `child`, a child of `pid`, unless it has already ended.
+    fn kill(&mut self, pid: u32, child: u32) -> Result<u32, ErrorCode> {
+        self.child(pid, child, ErrorCode::ESRCH)?;
+
+        match self.processes[&child].state {
+            State::Ended(_) => {},
+            _ => self.end(child, ProcessExit::Killed)
+        }
+
+        Ok(0)
+    }
+
+    /// Checks that `child` is a child of `pid`, failing with `code` if not.
+    fn child(&self, pid: u32, child: u32, code: ErrorCode) -> Result<(), ErrorCode> {
+        match self.processes.get(&child) {
+            Some(process) if process.parent == pid => Ok(()),
+            _ => Err(code)
+        }
+    }
+
+    /// Ends the process `pid`, then finishes its parent's `sys_wait` if it's
+    /// waiting for it. Children which have already ended are removed, and if
+    /// `pid` was killed, the rest are killed too.
+    fn end(&mut self, pid: u32, exit: ProcessExit) {
+        let parent = {
+            let process = self.process(pid);
+            process.state = State::Ended(exit);
+
+            // There's no one left to tell if this fails
+            let _ = process.vm.sync_files();
+
+            process.parent
+        };
+
+        let children: Vec<u32> = self.processes.iter().filter(|&(_, process)| process.parent == pid)
+                                               .map(|(&child, _)| child).collect();
+
+        for child in children {
+            match self.processes[&child].state {
+                State::Ended(_) => { self.processes.remove(&child); },
+                _ if exit == ProcessExit::Killed => self.end(child, ProcessExit::Killed),
+                _ => {}
+            }
+        }
+
+        // An orphan is removed straight away, as nothing can wait for it
+        let orphan = match self.processes.get(&parent) {
+            Some(process) => match process.state {
+                State::Ended(_) => true,
+                _ => false
+            },
+            None => parent != 0
+        };
+
+        if orphan {
+            self.processes.remove(&pid);
+        } else if parent != 0 {
+            self.reap(parent, pid);
+        }
+    }
+
+    /// Finishes the `sys_wait` of `pid` for `child` if `child` has ended,
+    /// removing it and returning how it ended. `pid` may have been removed
+    /// already, if it ended before `child` and was waited for itself.
+    fn reap(&mut self, pid: u32, child: u32) {
+        let exit = match self.processes[&child].state {
+            State::Ended(exit) => exit,
+            _ => return
+        };
+
+        let parent = match self.processes.get_mut(&pid) {
+            Some(parent) => parent,
+            None => return
+        };
+
+        if parent.state == State::Waiting(child) {
+            let (status, kind) = exit.to_return_values();
+            parent.vm.registers[3] = status;
+            parent.vm.registers[4] = kind;
+            parent.state = State::Ready;
+
+            self.processes.remove(&child);
+        }
+    }
+
+    /// Starts a child of `pid` running the image at `path`, returning its ID.
+    fn spawn(&mut self, pid: u32, path: &str, args: Vec<String>, inherit_files: bool) -> Result<u32, ErrorCode> {
+        let mut program = Vec::new();
+        File::open(path)?.read_to_end(&mut program)?;
+
+        let mut vm = {
+            let parent = &mut self.process(pid).vm;
+            let mut vm = VirtualMachine::with_page_size(parent.memory.page_size(), program).map_err(|_| ErrorCode::ENOMEM)?;
+
+            vm.breakpoints_enabled = parent.breakpoints_enabled;
+            vm.verbose_output = parent.verbose_output;
+            vm.decode_cache = parent.decode_cache;
+            vm.engine = parent.engine;
+            #[cfg(feature = "jit")]
+            {
+                vm.jit_threshold = parent.jit_threshold;
+            }
+            vm.env = parent.env.clone();
+            vm.clock = parent.clock;
+            vm.random = Random::new(parent.random.next_u64());
+            vm.memory.set_max_pages(parent.memory.max_pages());
+            vm.memory.set_strict(parent.memory.strict());
+
+            if inherit_files {
+                vm.inherit_files(parent)?;
+            }
+
+            vm
+        };
+
+        vm.args = if args.is_empty() { vec![path.to_owned()] } else { args };
+        vm.push_args().map_err(|_| ErrorCode::ENOMEM)?;
+
+        let child = self.next_pid;
+        self.next_pid += 1;
+        self.processes.insert(child, Process { vm: vm, parent: pid, state: State::Ready });
+
+        Ok(child)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, process};
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::time::{Duration, Instant};
+
+    use {Engine, ErrorCode, VirtualMachine};
+    use asm::Assembler;
+
+    use super::{Scheduler, State};
+
+    fn assemble(source: &str) -> Vec<u8> {
+        Assembler::new().source("test.sasm", source).assemble().unwrap().bytes()
+    }
+
+    /// Directory holding the programs spawned by a test, which is removed when
+    /// the test ends, even if it fails.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = env::temp_dir().join(format!("svm_process_{}_{}", name, process::id()));
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        /// Assembles `source` into a program called `name`, returning its path.
+        fn program(&self, name: &str, source: &str) -> String {
+            let path = self.0.join(name);
+            File::create(&path).unwrap().write_all(&assemble(source)).unwrap();
+            path.to_str().unwrap().to_owned()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Runs `parent`, which spawns the programs saved by the test.
+    fn run(engine: Engine, parent: &str) -> Result<i32, ::Error> {
+        let mut vm = VirtualMachine::new(assemble(parent)).unwrap();
+        vm.engine = engine;
+        vm.push_args().unwrap();
+
+        Scheduler::new(vm).run()
+    }
+
+    #[test]
+    fn spawn_wait() {
+        // Exits with argc
+        let dir = TempDir::new("spawn_wait");
+        let path = dir.program("child", "call 0");
+
+        // Spawns the child with three arguments, then exits with its status
+        let parent = format!("
+            li r8, %arg
+            c.li r9, 0
+            c.addi r1, -16
+            store r1, r8, 0
+            store r1, r8, 4
+            store r1, r8, 8
+            store r1, r9, 12
+            li r4, %path
+            li r5, path_end - path
+            mv r6, r1
+            c.li r7, 0
+            call 25
+            mv r4, r3
+            call 26
+            mv r4, r3
+            call 0
+        path:
+            bytes \"{}\"
+        path_end:
+        arg:
+            bytes \"arg\"
+            .word 0
+        ", path);
+
+        assert_eq!(run(Engine::Interpreter, &parent), Ok(3));
+    }
+
+    #[test]
+    fn kill() {
+        // Never exits
+        let dir = TempDir::new("kill");
+        let path = dir.program("child", "
+        loop:
+            c.addi r4, 1
+            beq r0, r0, $loop
+        ");
+
+        // Lets the child run, then kills it and exits with how it ended
+        let parent = format!("
+            li r4, %path
+            li r5, path_end - path
+            c.li r6, 0
+            c.li r7, 0
+            call 25
+            mv r8, r3
+            call 28
+            call 28
+            mv r4, r8
+            call 27
+            mv r4, r8
+            call 26
+            call 0
+        path:
+            bytes \"{}\"
+        path_end:
+        ", path);
+
+        #[cfg(not(feature = "jit"))]
+        let engines = [Engine::Interpreter, Engine::Blocks];
+        #[cfg(feature = "jit")]
+        let engines = [Engine::Interpreter, Engine::Blocks, Engine::Jit];
+
+        for &engine in &engines {
+            assert_eq!(run(engine, &parent), Ok(1));
+        }
+    }
+
+    #[test]
+    fn kill_descendants() {
+        let dir = TempDir::new("kill_descendants");
+        let grandchild = dir.program("grandchild", "loop:\n beq r0, r0, $loop");
+
+        // Spawns the grandchild, then never exits
+        let child = dir.program("child", &format!("
+            li r4, %path
+            li r5, path_end - path
+            c.li r6, 0
+            c.li r7, 0
+            call 25
+        loop:
+            beq r0, r0, $loop
+        path:
+            bytes \"{}\"
+        path_end:
+        ", grandchild));
+
+        // Kills the child once it has spawned the grandchild, then waits for it
+        let parent = format!("
+            li r4, %path
+            li r5, path_end - path
+            c.li r6, 0
+            c.li r7, 0
+            call 25
+            mv r8, r3
+            call 28
+            call 28
+            mv r4, r8
+            call 27
+            mv r4, r8
+            call 26
+            c.li r4, 0
+            call 0
+        path:
+            bytes \"{}\"
+        path_end:
+        ", child);
+
+        let mut scheduler = Scheduler::new(VirtualMachine::new(assemble(&parent)).unwrap());
+        let result = scheduler.run();
+
+        // The grandchild was killed with the child, and neither is left behind
+        assert_eq!(result, Ok(0));
+        assert_eq!(scheduler.next_pid, 4);
+        assert_eq!(scheduler.processes.len(), 1);
+    }
+
+    #[test]
+    fn sleep() {
+        // Sleeps for 200ms, then exits with 7
+        let dir = TempDir::new("sleep");
+        let path = dir.program("child", "
+            li r4, 200
+            call 17
+            c.li r4, 7
+            call 0
+        ");
+
+        let parent = |wait: &str| format!("
+            li r4, %path
+            li r5, path_end - path
+            c.li r6, 0
+            c.li r7, 0
+            call 25
+            mv r4, r3
+            {}
+            call 0
+        path:
+            bytes \"{}\"
+        path_end:
+        ", wait, path);
+
+        // Other processes run while the child sleeps, so exiting doesn't wait for it
+        let mut scheduler = Scheduler::new(VirtualMachine::new(assemble(&parent("c.li r4, 0"))).unwrap());
+        assert_eq!(scheduler.run(), Ok(0));
+
+        match scheduler.processes[&2].state {
+            State::Sleeping(_) => {},
+            state => panic!("child should still be sleeping, but is {:?}", state)
+        }
+
+        let start = Instant::now();
+        assert_eq!(run(Engine::Interpreter, &parent("call 26\n mv r4, r3")), Ok(7));
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn large_ids() {
+        let dir = TempDir::new("large_ids");
+        let path = dir.program("child", "c.li r4, 5\n call 0");
+
+        // Spawns a child and exits with its status
+        let parent = format!("
+            li r4, %path
+            li r5, path_end - path
+            c.li r6, 0
+            c.li r7, 0
+            call 25
+            mv r4, r3
+            call 26
+            mv r4, r3
+            call 0
+        path:
+            bytes \"{}\"
+        path_end:
+        ", path);
+
+        // Only live processes are stored and visited, however high their IDs
+        let mut scheduler = Scheduler::new(VirtualMachine::new(assemble(&parent)).unwrap());
+        scheduler.next_pid = 1 << 31;
+        let result = scheduler.run();
+
+        assert_eq!(result, Ok(5));
+        assert_eq!(scheduler.processes.keys().collect::<Vec<_>>(), [&1]);
+    }
+
+    #[test]
+    fn errors() {
+        // Waits for itself, which isn't its child
+        let mut vm = VirtualMachine::new(assemble("c.li r4, 1\n call 26\n mv r4, r3\n call 0")).unwrap();
+        assert_eq!(Scheduler::new(vm).run(), Ok(ErrorCode::ECHILD.to_return_value() as i32));
+
+        vm = VirtualMachine::new(assemble("li r4, 99\n call 27\n mv r4, r3\n call 0")).unwrap();
+        assert_eq!(Scheduler::new(vm).run(), Ok(ErrorCode::ESRCH.to_return_value() as i32));
+
+        let parent = "
+            li r4, %path
+            c.li r5, 2
+            c.li r6, 0
+            c.li r7, 0
+            call 25
+            mv r4, r3
+            call 0
+        path:
+            bytes \"??\"
+        ";
+        vm = VirtualMachine::new(assemble(parent)).unwrap();
+        assert_eq!(Scheduler::new(vm).run(), Ok(ErrorCode::ENOENT.to_return_value() as i32));
+
+        // The first process failing stops the scheduler
+        vm = VirtualMachine::new(assemble("call 99")).unwrap();
+        assert_eq!(Scheduler::new(vm).run(), Err(::Error::InvalidSysCall(99)));
+    }
+}
@@ -0,0 +1,107 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+/// SplitMix64 generator, the source of `sys_random` and `sys_random_u32`.
+///
+/// Each output is computed from a 64 bit state, which is first advanced by
+/// `0x9e3779b97f4a7c15`:
+///
+/// ```text
+/// z = state
+/// z = (z ^ (z >> 30)) * 0xbf58476d1ce4e5b9
+/// z = (z ^ (z >> 27)) * 0x94d049bb133111eb
+/// output = z ^ (z >> 31)
+/// ```
+///
+/// with all arithmetic wrapping. Every seed is valid, and the same seed
+/// always gives the same outputs. It isn't suitable for cryptography.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Random {
+    state: u64
+}
+
+impl Random {
+    /// Constructs a generator whose state starts as `seed`.
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// Constructs a generator seeded by the host, so that it gives different
+    /// outputs on every run.
+    pub fn from_host() -> Self {
+        // The standard library seeds the keys of `RandomState` from the OS
+        Self::new(RandomState::new().build_hasher().finish())
+    }
+
+    /// Returns the next 64 bit output.
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns the low 32 bits of the next output.
+    pub fn next_u32(&mut self) -> u32 {
+        self.next_u64() as u32
+    }
+
+    /// Fills `buf` with the bytes of successive outputs, each little endian,
+    /// discarding whatever of the last output isn't needed.
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        let mut bytes = [0; 8];
+
+        for chunk in buf.chunks_mut(8) {
+            LittleEndian::write_u64(&mut bytes, self.next_u64());
+            let len = chunk.len();
+            chunk.copy_from_slice(&bytes[..len]);
+        }
+    }
+}
+
+impl Default for Random {
+    fn default() -> Self {
+        Self::from_host()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Random;
+
+    #[test]
+    fn next_u64() {
+        // Reference outputs of SplitMix64 seeded with 1234567
+        let mut random = Random::new(1234567);
+
+        assert_eq!(random.next_u64(), 6457827717110365317);
+        assert_eq!(random.next_u64(), 3203168211198807973);
+        assert_eq!(random.next_u32(), 9817491932198370423u64 as u32);
+    }
+
+    #[test]
+    fn fill() {
+        let mut random = Random::new(0);
+        let mut expected = Random::new(0);
+        let mut buf = [0; 11];
+        random.fill(&mut buf);
+
+        let first = expected.next_u64();
+        let second = expected.next_u64();
+
+        assert_eq!(buf[..8], [first as u8, (first >> 8) as u8, (first >> 16) as u8, (first >> 24) as u8,
+                              (first >> 32) as u8, (first >> 40) as u8, (first >> 48) as u8, (first >> 56) as u8]);
+        assert_eq!(buf[8..], [second as u8, (second >> 8) as u8, (second >> 16) as u8]);
+        assert_eq!(random, expected);
+    }
+
+    #[test]
+    fn seeds() {
+        assert_eq!(Random::new(42).next_u64(), Random::new(42).next_u64());
+        assert!(Random::new(42).next_u64() != Random::new(43).next_u64());
+    }
+}
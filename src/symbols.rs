@@ -0,0 +1,146 @@
+use std::cmp::Ordering;
+use std::io::{self, BufRead, Write};
+
+/// Map of label names to addresses, used to show addresses symbolically.
+///
+/// Symbol files are text, with one symbol per line as its address in
+/// hexadecimal followed by its name:
+///
+/// ```text
+/// # Comments and blank lines are ignored
+/// 00000000 start
+/// 0000002c loop
+/// ```
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SymbolMap {
+    /// Address and name of each symbol, ordered by address.
+    symbols: Vec<(u32, String)>
+}
+
+impl SymbolMap {
+    /// Constructs a new `SymbolMap` from the names and addresses in `symbols`.
+    pub fn new(symbols: Vec<(String, u32)>) -> Self {
+        let mut symbols: Vec<(u32, String)> = symbols.into_iter().map(|(name, addr)| (addr, name)).collect();
+        symbols.sort();
+
+        Self { symbols: symbols }
+    }
+
+    /// Returns whether the map contains no symbols.
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// Returns the address of the symbol `name`, if there is one.
+    pub fn address(&self, name: &str) -> Option<u32> {
+        self.symbols.iter().find(|&&(_, ref n)| n == name).map(|&(addr, _)| addr)
+    }
+
+    /// Returns the closest symbol at or before `addr`, along with the offset
+    /// of `addr` from it.
+    pub fn lookup(&self, addr: u32) -> Option<(&str, u32)> {
+        // Treat every symbol at or before `addr` as less than it, so the
+        // search finds the position after the last of them
+        let ordering = |&(a, _): &(u32, String)| if a <= addr { Ordering::Less } else { Ordering::Greater };
+
+        let index = match self.symbols.binary_search_by(ordering) {
+            Ok(_) | Err(0) => return None,
+            Err(i) => i - 1
+        };
+
+        let (symbol_addr, ref name) = self.symbols[index];
+        Some((name, addr - symbol_addr))
+    }
+
+    /// Formats `addr` in hexadecimal followed by the symbol it falls within,
+    /// such as `0x0000002e <loop+0x2>`.
+    pub fn describe(&self, addr: u32) -> String {
+        match self.lookup(addr) {
+            Some((name, 0)) => format!("0x{:08x} <{}>", addr, name),
+            Some((name, offset)) => format!("0x{:08x} <{}+0x{:x}>", addr, name, offset),
+            None => format!("0x{:08x}", addr)
+        }
+    }
+
+    /// Reads a symbol file from `reader`.
+    pub fn read_from<R: BufRead>(reader: &mut R) -> Result<Self, io::Error> {
+        let mut symbols = Vec::new();
+
+        for (num, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            let addr = fields.next().and_then(|addr| u32::from_str_radix(addr, 16).ok());
+
+            match (addr, fields.next(), fields.next()) {
+                (Some(addr), Some(name), None) => symbols.push((name.to_owned(), addr)),
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                               format!("line {}: expected an address and a name", num + 1)))
+            }
+        }
+
+        Ok(Self::new(symbols))
+    }
+
+    /// Writes this map to `writer` as a symbol file.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), io::Error> {
+        for &(addr, ref name) in &self.symbols {
+            writeln!(writer, "{:08x} {}", addr, name)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::SymbolMap;
+
+    fn symbols() -> SymbolMap {
+        SymbolMap::new(vec![("loop".to_owned(), 0x2c), ("start".to_owned(), 0), ("end".to_owned(), 0x40),
+                            ("buffer".to_owned(), 0x40)])
+    }
+
+    #[test]
+    fn lookup() {
+        let symbols = symbols();
+
+        assert_eq!(symbols.lookup(0), Some(("start", 0)));
+        assert_eq!(symbols.lookup(0x2b), Some(("start", 0x2b)));
+        assert_eq!(symbols.lookup(0x2e), Some(("loop", 2)));
+        assert_eq!(symbols.lookup(0x44), Some(("end", 4)));
+        assert_eq!(SymbolMap::default().lookup(0), None);
+        assert_eq!(symbols.address("loop"), Some(0x2c));
+
+        assert_eq!(symbols.describe(0x2c), "0x0000002c <loop>");
+        assert_eq!(symbols.describe(0x2e), "0x0000002e <loop+0x2>");
+        assert_eq!(SymbolMap::new(vec![("loop".to_owned(), 8)]).describe(4), "0x00000004");
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut buf = Vec::new();
+        symbols().write_to(&mut buf).unwrap();
+
+        assert_eq!(String::from_utf8(buf.clone()).unwrap(),
+                   "00000000 start\n0000002c loop\n00000040 buffer\n00000040 end\n");
+        assert_eq!(SymbolMap::read_from(&mut Cursor::new(buf)).unwrap(), symbols());
+    }
+
+    #[test]
+    fn read_from() {
+        let source = "# Symbols\n\n  2c loop\n0 start";
+        assert_eq!(SymbolMap::read_from(&mut Cursor::new(source)).unwrap(),
+                   SymbolMap::new(vec![("start".to_owned(), 0), ("loop".to_owned(), 0x2c)]));
+
+        let error = SymbolMap::read_from(&mut Cursor::new("0 start\nstart")).unwrap_err();
+        assert_eq!(error.to_string(), "line 2: expected an address and a name");
+    }
+}
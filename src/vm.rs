@@ -1,21 +1,152 @@
+use std::cmp;
 use std::convert::TryInto;
-use std::fs::{File, OpenOptions};
-use std::io::{self, Read, Write};
-use std::mem;
+use std::fs::{self, File, Metadata, OpenOptions, ReadDir};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::ops::{BitAnd, BitOr};
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use enum_traits::BitPattern;
+use byteorder::{ByteOrder, LittleEndian};
 
 use vec_map::VecMap;
 
-use {Error, Instruction, Memory};
+use {Error, ErrorCode, Instruction, Memory, MemoryBackend, MemoryDump, ProcessCall, Random, Slice, SymbolMap};
+use block::{Block, Blocks};
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+use jit::{Callbacks, Context, Exit, Jit};
+
+/// Number of bytes at the top of memory mapped for the stack when a machine is
+/// constructed.
+pub const STACK_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Number of random bytes `sys_random` writes at a time, which is a whole
+/// number of the generator's 8 byte outputs.
+const RANDOM_CHUNK: usize = 4096;
+
+/// Largest number of bytes `sys_read` and `sys_write` copy through the host
+/// at a time.
+const IO_CHUNK: usize = 64 * 1024;
+
+/// Longest path read from memory. No host path is longer, so syscalls given
+/// one fail with `ENOENT` without reading it.
+const MAX_PATH: u32 = 4096;
+
+/// Most arguments `sys_spawn` passes to a child, each of which may be up to
+/// `MAX_PATH` bytes long.
+const MAX_ARGS: usize = 1024;
+
+/// The way in which a `VirtualMachine` executes instructions.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Engine {
+    /// Decodes and executes one instruction at a time. This is the reference
+    /// implementation the other engines are tested against.
+    Interpreter,
+    /// Translates straight-line runs of instructions into blocks of
+    /// pre-decoded steps, executing a whole block per dispatch. Blocks end at
+    /// branches, `CALL`, `BREAK` and writes to `r0`, which are executed by
+    /// the interpreter.
+    ///
+    /// Verbose output traces every instruction, so uses the interpreter.
+    Blocks,
+    /// Runs blocks like `Blocks`, compiling each into x86-64 machine code once
+    /// it has run `jit_threshold` times. Compiled code keeps the registers in
+    /// a context of its own, calling back into the machine for memory and
+    /// syscalls, and is discarded when code is overwritten.
+    ///
+    /// Only available with the `jit` feature. Uses the interpreter on hosts
+    /// other than x86-64 Unix, and for verbose output.
+    #[cfg(feature = "jit")]
+    Jit
+}
+
+/// Where the time syscalls get the time from.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Clock {
+    /// The host's clocks, with `sys_sleep` pausing the program. `run` pauses
+    /// the host thread, while `run_slice` leaves it to the caller.
+    Host,
+    /// A clock which advances by `ns_per_instruction` for each instruction
+    /// executed, and by the duration of each `sys_sleep` without pausing, so
+    /// that the times a program sees are the same on every run. The wall
+    /// clock starts at `start` seconds since the Unix epoch.
+    ///
+    /// Compiled code doesn't count instructions, so the `Jit` engine runs
+    /// blocks like `Blocks` instead.
+    Virtual { start: u64, ns_per_instruction: u64 }
+}
 
-pub struct VirtualMachine {
-    pub memory: Memory,
+/// How changes a program makes to a file mapped by `map_file` are treated.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MapMode {
+    /// Changes are private to the machine, so the file is never written to.
+    Private,
+    /// Changes are written back to the file by `sys_msync`, `sys_munmap` and
+    /// `sync_files`.
+    Shared,
+    /// The memory is read only, so writing to it fails and it's never written
+    /// back to the file.
+    ReadOnly
+}
+
+/// A machine running an SVM program, with its memory stored in a
+/// [`MemoryBackend`], the sparse paged `Memory` by default.
+///
+/// [`MemoryBackend`]: trait.MemoryBackend.html
+pub struct VirtualMachine<M = Memory> {
+    pub memory: M,
     pub registers: [u32; 32],
     pub breakpoints_enabled: bool,
     pub verbose_output: bool,
-    file_handles: VecMap<File>
+    /// Whether to reuse decoded instructions rather than decoding each one
+    /// every time it's run. See `Memory::fetch`.
+    pub decode_cache: bool,
+    pub engine: Engine,
+    /// Number of times a block must run before the `Jit` engine compiles it.
+    /// Defaults to 16.
+    #[cfg(feature = "jit")]
+    pub jit_threshold: u32,
+    /// Symbols used to show addresses in breakpoints and verbose output.
+    pub symbols: SymbolMap,
+    /// Arguments given to the program by `push_args` and `sys_args`,
+    /// conventionally starting with its name.
+    pub args: Vec<String>,
+    /// Environment variables given to the program by `push_args` and
+    /// `sys_getenv`, as names and values.
+    pub env: Vec<(String, String)>,
+    /// Where the time syscalls get the time from. Defaults to `Clock::Host`.
+    pub clock: Clock,
+    /// Generator `sys_random` and `sys_random_u32` take numbers from, seeded
+    /// by the host by default.
+    pub random: Random,
+    /// Address `reset` sets the program counter to.
+    entry: u32,
+    /// Number of instructions executed, which drives `Clock::Virtual`.
+    instructions: u64,
+    /// When the machine was constructed, which `sys_monotonic` counts from
+    /// with `Clock::Host`.
+    started: Instant,
+    /// Nanoseconds slept by `sys_sleep` with `Clock::Virtual`.
+    slept: u64,
+    /// End of the highest image loaded, which the program break can't be
+    /// moved below.
+    heap_start: u32,
+    /// Current program break, the end of the heap grown with `sys_brk`.
+    brk: u32,
+    file_handles: VecMap<Handle>,
+    /// Ranges of memory read from files by `map_file`, ordered by address.
+    file_mappings: Vec<FileMapping>,
+    /// Process syscall made by the program, for `run_slice` to return.
+    process_call: Option<ProcessCall>,
+    /// Number of compiled blocks the `Jit` engine may chain before returning
+    /// to `run_slice`.
+    slice: u32,
+    /// Blocks translated by the `Blocks` engine, by their start address.
+    blocks: Blocks<M>,
+    #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+    jit: Jit<M>
 }
 
 impl Default for VirtualMachine {
@@ -26,39 +157,226 @@ impl Default for VirtualMachine {
 
 impl VirtualMachine {
     pub fn new(program: Vec<u8>) -> Result<Self, Error> {
-        if program.len() as u64 > u32::max_value() as u64 + 1 {
+        Self::with_memory(Memory::default(), program)
+    }
+
+    pub fn with_page_size(page_size: usize, program: Vec<u8>) -> Result<Self, Error> {
+        Self::with_memory(Memory::with_page_size(page_size), program)
+    }
+
+    /// Returns a dump of the registers, heap and allocated memory, which
+    /// `restore` can read back.
+    pub fn dump(&self) -> MemoryDump {
+        MemoryDump::new(&self.memory, self.registers, self.heap_start, self.brk)
+    }
+}
+
+impl<M: MemoryBackend + 'static> VirtualMachine<M> {
+    /// Constructs a machine using `memory`, with `program` loaded at address
+    /// 0 and the top `STACK_SIZE` bytes mapped for the stack.
+    pub fn with_memory(mut memory: M, program: Vec<u8>) -> Result<Self, Error> {
+        if program.len() as u64 > memory.size() {
             return Err(Error::ProgramTooLarge);
         }
 
+        let stack_size = cmp::min(STACK_SIZE, memory.size());
+        let stack_bottom = memory.size() - stack_size;
+        memory.map(stack_bottom as u32, stack_size);
+
         let mut vm = Self {
-            memory: Memory::default(),
+            memory: memory,
             registers: [0; 32],
             breakpoints_enabled: false,
             verbose_output: false,
-            file_handles: VecMap::new()
+            decode_cache: true,
+            engine: Engine::Interpreter,
+            #[cfg(feature = "jit")]
+            jit_threshold: 16,
+            symbols: SymbolMap::default(),
+            args: Vec::new(),
+            env: Vec::new(),
+            clock: Clock::Host,
+            random: Random::from_host(),
+            entry: 0,
+            instructions: 0,
+            started: Instant::now(),
+            slept: 0,
+            heap_start: 0,
+            brk: 0,
+            file_handles: VecMap::new(),
+            file_mappings: Vec::new(),
+            process_call: None,
+            slice: 0,
+            blocks: Blocks::default(),
+            #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+            jit: Jit::new(Callbacks {
+                read_u32: callbacks::read_u32::<M>,
+                write_u32: callbacks::write_u32::<M>,
+                syscall: callbacks::syscall::<M>
+            })
         };
         vm.reset();
-        vm.memory.write(0, &program);
+        vm.load_at(0, &program)?;
 
         Ok(vm)
     }
 
-    pub fn with_page_size(page_size: usize, program: Vec<u8>) -> Result<Self, Error> {
-        if program.len() as u64 > u32::max_value() as u64 + 1 {
+    /// Writes `bytes` into memory at `addr`, leaving the rest of memory as it
+    /// was, so that several images can be loaded into the same machine.
+    ///
+    /// The image is mapped, and the program break moved past its end if it is
+    /// the highest image loaded so far.
+    pub fn load_at(&mut self, addr: u32, bytes: &[u8]) -> Result<(), Error> {
+        let end = addr as u64 + bytes.len() as u64;
+
+        if end > self.memory.size() {
             return Err(Error::ProgramTooLarge);
         }
 
-        let mut vm = Self {
-            memory: Memory::with_page_size(page_size),
-            registers: [0; 32],
-            breakpoints_enabled: false,
-            verbose_output: false,
-            file_handles: VecMap::new()
-        };
-        vm.reset();
-        vm.memory.write(0, &program);
+        if bytes.is_empty() {
+            return Ok(());
+        }
 
-        Ok(vm)
+        self.memory.map(addr, bytes.len() as u64);
+        self.memory.write(addr, bytes)?;
+
+        // The break is kept below the address space's end so that it fits in a register
+        let end = cmp::min(end, u32::max_value() as u64) as u32;
+        self.heap_start = cmp::max(self.heap_start, end);
+        self.brk = cmp::max(self.brk, end);
+
+        Ok(())
+    }
+
+    /// Returns the current program break.
+    #[inline]
+    pub fn brk(&self) -> u32 {
+        self.brk
+    }
+
+    /// Maps `len` bytes of `file` from `offset` into memory at `addr`, which
+    /// must be the start of a page. Memory past the end of the file reads as
+    /// 0, and anything previously at `addr` is replaced.
+    ///
+    /// The file's position is left as it was. With `MapMode::Shared`, changes
+    /// to the mapped memory are written back to the file by `sync_files`, or
+    /// by the program with `sys_msync` and `sys_munmap`. With
+    /// `MapMode::ReadOnly`, the pages are made read only after the file is
+    /// read into them.
+    pub fn map_file(&mut self, file: File, offset: u64, len: u32, addr: u32, mode: MapMode) -> io::Result<()> {
+        if len == 0 || addr as u64 % self.memory.page_size() as u64 != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "mapping must start at a page"));
+        }
+
+        if addr as u64 + len as u64 > self.memory.size() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "mapping extends outside of memory"));
+        }
+
+        let mut data = Vec::new();
+        at_offset(&file, offset, |file| file.take(len as u64).read_to_end(&mut data))?;
+
+        self.release_files(addr as u64, addr as u64 + len as u64)?;
+        self.memory.unmap(addr, len as u64);
+        self.memory.map(addr, len as u64);
+        self.memory.write(addr, &data).map_err(|error| io::Error::new(io::ErrorKind::Other, error))?;
+
+        if mode == MapMode::ReadOnly {
+            self.memory.protect(addr, len as u64);
+        }
+
+        let mapping = FileMapping { addr: addr, len: data.len() as u32, offset: offset, file: Arc::new(file), mode: mode };
+        let index = self.file_mappings.iter().position(|m| m.addr > addr).unwrap_or(self.file_mappings.len());
+        self.file_mappings.insert(index, mapping);
+
+        Ok(())
+    }
+
+    /// Gives the program duplicates of the files `parent` has open, with the
+    /// same handles. Directories being listed aren't inherited.
+    pub fn inherit_files<N>(&mut self, parent: &VirtualMachine<N>) -> io::Result<()> {
+        for (index, handle) in &parent.file_handles {
+            if let Handle::File(ref file) = *handle {
+                self.file_handles.insert(index, Handle::File(file.try_clone()?));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes the contents of every shared file mapping back to its file.
+    pub fn sync_files(&mut self) -> io::Result<()> {
+        self.sync_range(0, self.memory.size())
+    }
+
+    /// Writes the parts of shared file mappings from address `start` to `end`
+    /// back to their files.
+    fn sync_range(&mut self, start: u64, end: u64) -> io::Result<()> {
+        for mapping in &self.file_mappings {
+            let (from, to) = mapping.overlap(start, end);
+
+            if mapping.mode != MapMode::Shared || from >= to {
+                continue;
+            }
+
+            let mut data = vec![0; (to - from) as usize];
+            self.memory.read(from as u32, &mut data).map_err(|error| io::Error::new(io::ErrorKind::Other, error))?;
+
+            let offset = mapping.offset + (from - mapping.addr as u64);
+            at_offset(&mapping.file, offset, |mut file| file.write_all(&data))?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes file mappings from address `start` to `end`, writing back the
+    /// parts of shared ones being removed first. Mappings which only partly
+    /// overlap are cut down to what's left.
+    fn release_files(&mut self, start: u64, end: u64) -> io::Result<()> {
+        self.sync_range(start, end)?;
+
+        let mut mappings = Vec::with_capacity(self.file_mappings.len() + 1);
+
+        for mapping in self.file_mappings.drain(..) {
+            let (from, to) = mapping.overlap(start, end);
+
+            if from >= to {
+                mappings.push(mapping);
+                continue;
+            }
+
+            let mapping_end = mapping.addr as u64 + mapping.len as u64;
+
+            if (mapping.addr as u64) < from {
+                mappings.push(FileMapping { len: (from - mapping.addr as u64) as u32, file: mapping.file.clone(), ..mapping });
+            }
+
+            if to < mapping_end {
+                let skipped = to - mapping.addr as u64;
+                mappings.push(FileMapping {
+                    addr: to as u32,
+                    len: (mapping_end - to) as u32,
+                    offset: mapping.offset + skipped,
+                    ..mapping
+                });
+            }
+        }
+
+        self.file_mappings = mappings;
+
+        Ok(())
+    }
+
+    /// Returns the address execution starts from.
+    #[inline]
+    pub fn entry(&self) -> u32 {
+        self.entry
+    }
+
+    /// Sets the address execution starts from, moving the program counter
+    /// there now and on every `reset`.
+    pub fn set_entry(&mut self, pc: u32) {
+        self.entry = pc;
+        *self.program_ctr_mut() = pc;
     }
 
     #[inline]
@@ -81,28 +399,270 @@ impl VirtualMachine {
         &mut self.registers[1]
     }
 
+    /// Resets the program counter to the entry point and the stack pointer to
+    /// the last word of memory.
     #[inline]
     pub fn reset(&mut self) {
-        *self.program_ctr_mut() = 0;
-        *self.stack_ptr_mut() = 0xfffffffc;
+        *self.program_ctr_mut() = self.entry;
+        *self.stack_ptr_mut() = self.memory.size().saturating_sub(4) as u32 & !3;
     }
 
+    /// Places `args` and `env` on the stack in the layout given by the
+    /// specification, moving the stack pointer below them and setting `r4`,
+    /// `r5` and `r6` to the argument count, `argv` and `envp`. Should be
+    /// called after `reset`, which moves the stack pointer back to the top.
+    pub fn push_args(&mut self) -> Result<(), Error> {
+        let mut strings = Vec::new();
+        let mut offsets = Vec::with_capacity(self.args.len() + self.env.len());
+
+        for arg in &self.args {
+            offsets.push(strings.len() as u32);
+            strings.extend_from_slice(arg.as_bytes());
+            strings.push(0);
+        }
+
+        for &(ref name, ref value) in &self.env {
+            offsets.push(strings.len() as u32);
+            strings.extend_from_slice(format!("{}={}", name, value).as_bytes());
+            strings.push(0);
+        }
+
+        // argc, argv and envp with their null terminators, then the strings
+        let table_len = 4 * (offsets.len() as u64 + 3);
+        let top = self.stack_ptr() as u64;
+
+        if table_len + strings.len() as u64 + 3 > top {
+            return Err(Error::OutOfMemory);
+        }
+
+        let strings_addr = (top - strings.len() as u64) as u32 & !3;
+        let sp = strings_addr - table_len as u32;
+        let argc = self.args.len() as u32;
+
+        self.memory.write(strings_addr, &strings)?;
+        self.memory.write_u32(sp, argc)?;
+
+        let (argv, envp) = offsets.split_at(argc as usize);
+        let mut addr = sp + 4;
+
+        for table in &[argv, envp] {
+            for &offset in table.iter() {
+                self.memory.write_u32(addr, strings_addr + offset)?;
+                addr += 4;
+            }
+
+            self.memory.write_u32(addr, 0)?;
+            addr += 4;
+        }
+
+        *self.stack_ptr_mut() = sp;
+        self.registers[4] = argc;
+        self.registers[5] = sp + 4;
+        self.registers[6] = sp + 4 * (argc + 2);
+
+        Ok(())
+    }
+
+    /// Restores the registers, heap and memory saved in `dump`, mapping its
+    /// segments. Memory outside them is left as it was.
+    pub fn restore(&mut self, dump: &MemoryDump) -> Result<(), Error> {
+        for segment in &dump.segments {
+            self.memory.map(segment.addr, segment.data.len() as u64);
+            self.memory.write(segment.addr, &segment.data)?;
+        }
+
+        self.registers = dump.registers;
+        self.heap_start = dump.heap_start;
+        self.brk = dump.brk;
+
+        Ok(())
+    }
+
+    /// Runs the program until it exits, returning its exit status.
+    ///
+    /// If an error occurs, the program counter is left at the address of the
+    /// instruction which caused it.
+    ///
+    /// There are no other processes, so `sys_spawn`, `sys_wait` and
+    /// `sys_kill` fail with ENOSYS. Use a `Scheduler` to run programs which
+    /// use them.
     pub fn run(&mut self) -> Result<i32, Error> {
         loop {
-            let instr = self.memory.read_u32(self.program_ctr()).try_into()?;
-            
-            match self.exec_instr(instr)? {
-                Some(status) => return Ok(status),
-                _ => {}
+            self.slice = u32::max_value();
+
+            if let Some(status) = self.dispatch()? {
+                return Ok(status);
+            }
+
+            if let Some(call) = self.process_call.take() {
+                self.registers[3] = match call {
+                    ProcessCall::Sleep(duration) => {
+                        thread::sleep(duration);
+                        0
+                    },
+                    ProcessCall::Yield => 0,
+                    _ => ErrorCode::ENOSYS.to_return_value()
+                };
+            }
+        }
+    }
+
+    /// Runs the program for up to `count` blocks, or instructions with the
+    /// interpreter, returning early if it exits or makes a process syscall.
+    ///
+    /// A process syscall is left for the caller to carry out, with its
+    /// return value still to be set.
+    pub fn run_slice(&mut self, count: u32) -> Result<Slice, Error> {
+        self.slice = count;
+
+        while self.slice > 0 {
+            self.slice -= 1;
+
+            if let Some(status) = self.dispatch()? {
+                return Ok(Slice::Exited(status));
+            }
+
+            if let Some(call) = self.process_call.take() {
+                return Ok(Slice::Call(call));
+            }
+        }
+
+        Ok(Slice::Expired)
+    }
+
+    /// Executes an instruction, block or run of compiled blocks with the
+    /// engine in use, returning the exit status if the program exits.
+    fn dispatch(&mut self) -> Result<Option<i32>, Error> {
+        match self.engine {
+            Engine::Blocks if !self.verbose_output => self.exec_block(),
+            #[cfg(feature = "jit")]
+            Engine::Jit if !self.verbose_output => match self.clock {
+                Clock::Host => self.exec_jit(),
+                Clock::Virtual { .. } => self.exec_block()
+            },
+            _ => self.step()
+        }
+    }
+
+    /// Executes the instruction at the program counter.
+    fn step(&mut self) -> Result<Option<i32>, Error> {
+        let program_ctr = self.program_ctr();
+        let instr = if self.decode_cache {
+            self.memory.fetch(program_ctr)?
+        } else {
+            self.memory.read_u32(program_ctr)?.try_into()?
+        };
+
+        self.exec_instr(instr).map_err(|error| {
+            *self.program_ctr_mut() = program_ctr;
+            error
+        })
+    }
+
+    /// Executes the block starting at the program counter, translating it
+    /// first if it hasn't been already or the code it came from has changed.
+    fn exec_block(&mut self) -> Result<Option<i32>, Error> {
+        let start = self.program_ctr();
+        let memory = &self.memory;
+
+        let translated = self.blocks.get(&start).map_or(false, |block| {
+            block.generation == memory.code_generation(start, block.size)
+        });
+
+        if !translated {
+            let block = Block::translate(&mut self.memory, start)?;
+            self.blocks.insert(start, block);
+        }
+
+        let terminator = {
+            let block = &self.blocks[&start];
+            let mut addr = start;
+
+            for &(next, ref step) in &block.steps {
+                self.instructions += 1;
+
+                match step(&mut self.registers, &mut self.memory) {
+                    Ok(true) => addr = next,
+                    Ok(false) => {
+                        // Code later in the block was overwritten, so carry on
+                        // from the next instruction with a new block
+                        self.registers[0] = next;
+                        return Ok(None);
+                    },
+                    Err(error) => {
+                        self.registers[0] = addr;
+                        return Err(error);
+                    }
+                }
+            }
+
+            self.registers[0] = block.end;
+            block.terminator
+        };
+
+        match terminator {
+            Some(instr) => {
+                let program_ctr = self.program_ctr();
+
+                self.exec_instr(instr).map_err(|error| {
+                    *self.program_ctr_mut() = program_ctr;
+                    error
+                })
+            },
+            None => Ok(None)
+        }
+    }
+
+    /// Runs compiled code from the program counter for as long as each block
+    /// leads to another which has been compiled, otherwise executes a block
+    /// with the `Blocks` engine.
+    #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+    fn exec_jit(&mut self) -> Result<Option<i32>, Error> {
+        let start = self.program_ctr();
+
+        let mut code = match self.jit.run(&mut self.memory, start, self.jit_threshold) {
+            Some(code) => code,
+            None => return self.exec_block()
+        };
+
+        let mut context = Context { registers: self.registers, stopped: false, vm: self, result: None };
+
+        loop {
+            let exit = code(&mut context);
+            self.registers = context.registers;
+
+            match exit {
+                Exit::Continue => {},
+                Exit::Stop => return context.result.take().unwrap().map(Some),
+                Exit::Interpret => return self.step()
+            }
+
+            // Return to `run_slice` after process syscalls and once the slice is used up
+            if self.process_call.is_some() || self.slice == 0 {
+                return Ok(None);
+            }
+
+            self.slice -= 1;
+
+            match self.jit.compiled(self.program_ctr(), &self.memory) {
+                Some(next) => code = next,
+                None => return Ok(None)
             }
         }
     }
 
+    #[cfg(all(feature = "jit", not(all(target_arch = "x86_64", unix))))]
+    fn exec_jit(&mut self) -> Result<Option<i32>, Error> {
+        self.step()
+    }
+
     fn exec_instr(&mut self, instr: Instruction) -> Result<Option<i32>, Error> {
         use OpCode::*;
 
+        self.instructions += 1;
+
         if self.verbose_output {
-            println!("{:?}", instr);
+            println!("{}: {:?}", self.symbols.describe(self.program_ctr()), instr);
         }
 
         *self.program_ctr_mut() += instr.size();
@@ -149,12 +709,13 @@ impl VirtualMachine {
                         }
                         return Ok(None);
                     },
-                    LOAD | C_LOAD => self.memory.read_u32((src1 as i32).wrapping_add(imm as i32) as u32),
+                    LOAD | C_LOAD => self.memory.read_u32((src1 as i32).wrapping_add(imm as i32) as u32)?,
                     CALL | C_CALL => return self.exec_syscall(imm as u16),
                     BREAK | C_BREAK => {
                         if self.breakpoints_enabled {
-                            println!("breakpoint:\n\tr0 (pc): {}, r1 (sp): 0x{:x}, r2 (lr): {}, r3 (rv): {}",
-                                self.registers[0], self.registers[1], self.registers[2], self.registers[3]);
+                            println!("breakpoint:\n\tr0 (pc): {}, r1 (sp): 0x{:x}\n\tr2 (lr): {}, r3 (rv): {}",
+                                self.symbols.describe(self.registers[0]), self.registers[1],
+                                self.symbols.describe(self.registers[2]), self.registers[3]);
                             println!("\tr4: {}, r5: {}, r6: {}, r7: {}\nPress enter to continue...",
                                 self.registers[4], self.registers[5], self.registers[6], self.registers[7]);
 
@@ -174,7 +735,7 @@ impl VirtualMachine {
                 let program_ctr = self.registers[0] as i32;
 
                 match op {
-                    STORE | C_STORE => self.memory.write_u32((src1 as i32).wrapping_add(imm as i32) as u32, src2),
+                    STORE | C_STORE => self.memory.write_u32((src1 as i32).wrapping_add(imm as i32) as u32, src2)?,
                     BEQ => if src1 == src2 { self.registers[0] = program_ctr.wrapping_add(imm as i32) as u32 },
                     BNE => if src1 != src2 { self.registers[0] = program_ctr.wrapping_add(imm as i32) as u32 },
                     BLT => if (src1 as i32) < (src2 as i32) {
@@ -213,35 +774,181 @@ impl VirtualMachine {
                 let ptr = self.registers[5];
                 let len = self.registers[6];
 
-                self.registers[3] = self.read_file(handle, ptr, len)
-                                        .unwrap_or_else(|e| { println!("{}", e); -1i32 as u32 });
+                self.registers[3] = self.read_file(handle, ptr, len).unwrap_or_else(ErrorCode::to_return_value);
             },
             2 => { // sys_write
                 let handle = self.registers[4];
                 let ptr = self.registers[5];
                 let len = self.registers[6];
 
-                self.registers[3] = self.write_file(handle, ptr, len)
-                                        .unwrap_or_else(|e| { println!("{}", e); -1i32 as u32 });
+                self.registers[3] = self.write_file(handle, ptr, len).unwrap_or_else(ErrorCode::to_return_value);
             },
             3 => { // sys_open
                 let ptr = self.registers[4];
                 let len = self.registers[5];
                 let flags = self.registers[6];
 
-                self.registers[3] = self.open_file(ptr, len, flags)
-                                        .unwrap_or_else(|e| { println!("{}", e); -1i32 as u32 });
+                self.registers[3] = self.open_file(ptr, len, flags).unwrap_or_else(ErrorCode::to_return_value);
             },
             4 => { // sys_close
                 let handle = self.registers[4];
 
-                self.registers[3] = self.close_file(handle).unwrap_or_else(|e| { println!("{}", e); -1i32 as u32 });
+                self.registers[3] = self.close_file(handle).unwrap_or_else(ErrorCode::to_return_value);
             },
             5 => { // sys_create
                 let ptr = self.registers[4];
                 let len = self.registers[5];
 
-                self.registers[3] = self.create_file(ptr, len).unwrap_or_else(|e| { println!("{}", e); -1i32 as u32 });
+                self.registers[3] = self.create_file(ptr, len).unwrap_or_else(ErrorCode::to_return_value);
+            },
+            6 => { // sys_args
+                let index = self.registers[4];
+                let ptr = self.registers[5];
+                let len = self.registers[6];
+
+                self.registers[3] = self.get_arg(index, ptr, len).unwrap_or_else(ErrorCode::to_return_value);
+            },
+            7 => { // sys_getenv
+                let name_ptr = self.registers[4];
+                let name_len = self.registers[5];
+                let ptr = self.registers[6];
+                let len = self.registers[7];
+
+                self.registers[3] = self.get_env(name_ptr, name_len, ptr, len).unwrap_or_else(ErrorCode::to_return_value);
+            },
+            8 => { // sys_seek
+                let handle = self.registers[4];
+                let offset = self.registers[5];
+                let whence = self.registers[6];
+
+                self.registers[3] = self.seek_file(handle, offset, whence).unwrap_or_else(ErrorCode::to_return_value);
+            },
+            9 => { // sys_stat
+                let ptr = self.registers[4];
+                let len = self.registers[5];
+                let stat_ptr = self.registers[6];
+
+                self.registers[3] = self.stat_file(ptr, len, stat_ptr).unwrap_or_else(ErrorCode::to_return_value);
+            },
+            10 => { // sys_unlink
+                let ptr = self.registers[4];
+                let len = self.registers[5];
+
+                self.registers[3] = self.unlink_file(ptr, len).unwrap_or_else(ErrorCode::to_return_value);
+            },
+            11 => { // sys_rename
+                let from_ptr = self.registers[4];
+                let from_len = self.registers[5];
+                let to_ptr = self.registers[6];
+                let to_len = self.registers[7];
+
+                self.registers[3] = self.rename_file(from_ptr, from_len, to_ptr, to_len).unwrap_or_else(ErrorCode::to_return_value);
+            },
+            12 => { // sys_mkdir
+                let ptr = self.registers[4];
+                let len = self.registers[5];
+
+                self.registers[3] = self.make_dir(ptr, len).unwrap_or_else(ErrorCode::to_return_value);
+            },
+            13 => { // sys_opendir
+                let ptr = self.registers[4];
+                let len = self.registers[5];
+
+                self.registers[3] = self.open_dir(ptr, len).unwrap_or_else(ErrorCode::to_return_value);
+            },
+            14 => { // sys_readdir
+                let handle = self.registers[4];
+                let ptr = self.registers[5];
+                let len = self.registers[6];
+
+                self.registers[3] = self.read_dir(handle, ptr, len).unwrap_or_else(ErrorCode::to_return_value);
+            },
+            15 => { // sys_time
+                let time = match self.clock {
+                    Clock::Host => SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs()),
+                    Clock::Virtual { start, .. } => start + self.monotonic() / 1_000_000_000
+                };
+
+                self.registers[3] = time as u32;
+                self.registers[4] = (time >> 32) as u32;
+            },
+            16 => { // sys_monotonic
+                let time = self.monotonic();
+
+                self.registers[3] = time as u32;
+                self.registers[4] = (time >> 32) as u32;
+            },
+            17 => { // sys_sleep
+                let ms = self.registers[4];
+
+                // With the host clock, whatever runs the program does the
+                // sleeping, so that a scheduler can run other processes meanwhile
+                match self.clock {
+                    Clock::Host => self.process_call = Some(ProcessCall::Sleep(Duration::from_millis(ms as u64))),
+                    Clock::Virtual { .. } => self.slept += ms as u64 * 1_000_000
+                }
+
+                self.registers[3] = 0;
+            },
+            18 => { // sys_brk
+                let addr = self.registers[4];
+
+                self.registers[3] = self.set_brk(addr).unwrap_or_else(ErrorCode::to_return_value);
+            },
+            19 => { // sys_mmap_anon
+                let hint = self.registers[4];
+                let len = self.registers[5];
+
+                self.registers[3] = self.map_anon(hint, len).unwrap_or_else(ErrorCode::to_return_value);
+            },
+            20 => { // sys_munmap
+                let addr = self.registers[4];
+                let len = self.registers[5];
+
+                self.registers[3] = self.unmap_region(addr, len).unwrap_or_else(ErrorCode::to_return_value);
+            },
+            21 => { // sys_mmap_file
+                let handle = self.registers[4];
+                let offset = self.registers[5];
+                let len = self.registers[6];
+                let hint = self.registers[7];
+
+                self.registers[3] = self.map_handle(handle, offset, len, hint).unwrap_or_else(ErrorCode::to_return_value);
+            },
+            22 => { // sys_msync
+                let addr = self.registers[4];
+                let len = self.registers[5];
+
+                self.registers[3] = self.sync_region(addr, len).unwrap_or_else(ErrorCode::to_return_value);
+            },
+            23 => { // sys_random
+                let ptr = self.registers[4];
+                let len = self.registers[5];
+
+                self.registers[3] = self.fill_random(ptr, len).unwrap_or_else(ErrorCode::to_return_value);
+            },
+            24 => { // sys_random_u32
+                self.registers[3] = self.random.next_u32();
+            },
+            25 => { // sys_spawn
+                let path_ptr = self.registers[4];
+                let path_len = self.registers[5];
+                let argv_ptr = self.registers[6];
+                let flags = self.registers[7];
+
+                match self.read_spawn(path_ptr, path_len, argv_ptr, flags) {
+                    Ok(call) => self.process_call = Some(call),
+                    Err(code) => self.registers[3] = code.to_return_value()
+                }
+            },
+            26 => { // sys_wait
+                self.process_call = Some(ProcessCall::Wait(self.registers[4]));
+            },
+            27 => { // sys_kill
+                self.process_call = Some(ProcessCall::Kill(self.registers[4]));
+            },
+            28 => { // sys_yield
+                self.process_call = Some(ProcessCall::Yield);
             },
             _ => return Err(Error::InvalidSysCall(call))
         }
@@ -249,44 +956,109 @@ impl VirtualMachine {
         Ok(None)
     }
 
-    fn read_file(&mut self, handle: u32, ptr: u32, len: u32) -> Result<u32, io::Error> {
-        let mut buf = vec![0; len as usize];
-        
+    /// Returns the number of nanoseconds since the machine was constructed.
+    fn monotonic(&self) -> u64 {
+        match self.clock {
+            Clock::Host => {
+                let elapsed = self.started.elapsed();
+                elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64
+            },
+            Clock::Virtual { ns_per_instruction, .. } => self.instructions * ns_per_instruction + self.slept
+        }
+    }
+
+    fn read_file(&mut self, handle: u32, ptr: u32, len: u32) -> Result<u32, ErrorCode> {
+        self.check_range(ptr, len)?;
+
+        // Reads may be short, so at most a chunk is read at once
+        let mut buf = vec![0; cmp::min(len as usize, IO_CHUNK)];
+
         let i = match handle {
             0 => io::stdin().read(&mut buf)?,
-            1 | 2 => return Ok(-1i32 as u32),
-            d @ _ => match self.file_handles.get_mut(d as usize - 3) {
-                Some(ref mut file) => file.read(&mut buf)?,
-                None => return Ok(-1i32 as u32)
+            d @ _ => match self.file_handles.get_mut((d as usize).wrapping_sub(3)) {
+                Some(&mut Handle::File(ref mut file)) => file.read(&mut buf)?,
+                _ => return Err(ErrorCode::EBADF)
             }
         };
 
-        self.memory.write(ptr, &buf[..i]);
+        self.memory.write(ptr, &buf[..i]).map_err(fault)?;
         Ok(i as u32)
     }
 
-    fn write_file(&mut self, handle: u32, ptr: u32, len: u32) -> Result<u32, io::Error> {
-        let mut buf = vec![0; len as usize];
-        self.memory.read(ptr, &mut buf);
+    fn write_file(&mut self, handle: u32, ptr: u32, len: u32) -> Result<u32, ErrorCode> {
+        self.check_range(ptr, len)?;
 
-        let i = match handle {
-            0 => return Ok(-1i32 as u32),
-            1 => io::stdout().write(&buf)?,
-            2 => io::stderr().write(&buf)?,
-            d @ _ => match self.file_handles.get_mut(d as usize - 3) {
-                Some(ref mut file) => file.write(&buf)?,
-                None => return Ok(-1i32 as u32)
+        let mut buf = vec![0; cmp::min(len as usize, IO_CHUNK)];
+        let mut written = 0;
+
+        // Write a chunk at a time until all of it is written or a write is short
+        loop {
+            let chunk = &mut buf[..cmp::min(len as usize - written, IO_CHUNK)];
+            self.memory.read(ptr.wrapping_add(written as u32), chunk).map_err(fault)?;
+
+            let i = match self.write_handle(handle, chunk) {
+                Ok(i) => i,
+                Err(error) => if written == 0 { return Err(error) } else { break }
+            };
+            written += i;
+
+            if i < chunk.len() || written == len as usize {
+                break;
             }
-        };
+        }
 
-        Ok(i as u32)
+        Ok(written as u32)
     }
 
-    fn open_file(&mut self, ptr: u32, len: u32, flags: u32) -> Result<u32, io::Error> {
+    fn write_handle(&mut self, handle: u32, buf: &[u8]) -> Result<usize, ErrorCode> {
+        Ok(match handle {
+            1 => io::stdout().write(buf)?,
+            2 => io::stderr().write(buf)?,
+            d @ _ => match self.file_handles.get_mut((d as usize).wrapping_sub(3)) {
+                Some(&mut Handle::File(ref mut file)) => file.write(buf)?,
+                _ => return Err(ErrorCode::EBADF)
+            }
+        })
+    }
+
+    /// Fails with `EFAULT` if the `len` bytes at `ptr` extend outside of memory.
+    fn check_range(&self, ptr: u32, len: u32) -> Result<(), ErrorCode> {
+        if ptr as u64 + len as u64 > self.memory.size() {
+            return Err(ErrorCode::EFAULT);
+        }
+
+        Ok(())
+    }
+
+    /// Reads a path of `len` bytes at `ptr` from memory.
+    fn read_path(&self, ptr: u32, len: u32) -> Result<String, ErrorCode> {
+        self.check_range(ptr, len)?;
+
+        if len > MAX_PATH {
+            return Err(ErrorCode::ENOENT);
+        }
+
         let mut buf = vec![0; len as usize];
-        self.memory.read(ptr, &mut buf);
+        self.memory.read(ptr, &mut buf).map_err(fault)?;
+
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
 
-        let path = String::from_utf8_lossy(&buf);
+    /// Adds `handle` to the first free slot in `file_handles`, returning the
+    /// handle the program refers to it by.
+    fn insert_handle(&mut self, handle: Handle) -> Result<u32, ErrorCode> {
+        for index in 0..(u32::max_value() as usize - 2) {
+            if !self.file_handles.contains_key(index) {
+                self.file_handles.insert(index, handle);
+                return Ok(index as u32 + 3);
+            }
+        }
+
+        Err(ErrorCode::EMFILE)
+    }
+
+    fn open_file(&mut self, ptr: u32, len: u32, flags: u32) -> Result<u32, ErrorCode> {
+        let path = self.read_path(ptr, len)?;
         let mut options = OpenOptions::new();
 
         macro_rules! apply_flags {
@@ -304,38 +1076,454 @@ impl VirtualMachine {
             APPEND, append
         );
 
-        let file = options.open(&path.as_ref())?;
+        let file = options.open(&path)?;
+
+        self.insert_handle(Handle::File(file))
+    }
+
+    fn close_file(&mut self, handle: u32) -> Result<u32, ErrorCode> {
+        match self.file_handles.remove((handle as usize).wrapping_sub(3)) {
+            Some(Handle::File(file)) => file.sync_all()?,
+            Some(Handle::Dir(_)) => {},
+            None => return Err(ErrorCode::EBADF)
+        }
+
+        // Since the handle has been moved out of the VecMap, it will get
+        // closed once it's dropped when we return
+        Ok(0)
+    }
+
+    fn create_file(&mut self, ptr: u32, len: u32) -> Result<u32, ErrorCode> {
+        self.open_file(ptr, len, FileFlags::CREATE | FileFlags::WRITE | FileFlags::TRUNCATE)
+    }
+
+    fn seek_file(&mut self, handle: u32, offset: u32, whence: u32) -> Result<u32, ErrorCode> {
+        let pos = match whence {
+            0 => SeekFrom::Start(offset as u64),
+            1 => SeekFrom::Current(offset as i32 as i64),
+            2 => SeekFrom::End(offset as i32 as i64),
+            _ => return Err(ErrorCode::EINVAL)
+        };
+
+        match self.file_handles.get_mut((handle as usize).wrapping_sub(3)) {
+            Some(&mut Handle::File(ref mut file)) => Ok(file.seek(pos)? as u32),
+            _ => Err(ErrorCode::EBADF)
+        }
+    }
+
+    fn stat_file(&mut self, ptr: u32, len: u32, stat_ptr: u32) -> Result<u32, ErrorCode> {
+        let metadata = fs::metadata(self.read_path(ptr, len)?)?;
+        let modified = metadata.modified().ok()
+                               .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                               .map_or(0, |duration| duration.as_secs());
+
+        // Size, type, permissions and modification time, as described by the
+        // specification
+        let mut stat = [0; 24];
+        LittleEndian::write_u64(&mut stat[0..8], metadata.len());
+        LittleEndian::write_u32(&mut stat[8..12], if metadata.is_file() { 0 } else if metadata.is_dir() { 1 } else { 2 });
+        LittleEndian::write_u32(&mut stat[12..16], permissions(&metadata));
+        LittleEndian::write_u64(&mut stat[16..24], modified);
+
+        self.memory.write(stat_ptr, &stat).map_err(fault)?;
+        Ok(0)
+    }
+
+    fn unlink_file(&mut self, ptr: u32, len: u32) -> Result<u32, ErrorCode> {
+        fs::remove_file(self.read_path(ptr, len)?)?;
+        Ok(0)
+    }
+
+    fn rename_file(&mut self, from_ptr: u32, from_len: u32, to_ptr: u32, to_len: u32) -> Result<u32, ErrorCode> {
+        let from = self.read_path(from_ptr, from_len)?;
+        let to = self.read_path(to_ptr, to_len)?;
+
+        fs::rename(from, to)?;
+        Ok(0)
+    }
+
+    fn make_dir(&mut self, ptr: u32, len: u32) -> Result<u32, ErrorCode> {
+        fs::create_dir(self.read_path(ptr, len)?)?;
+        Ok(0)
+    }
+
+    fn open_dir(&mut self, ptr: u32, len: u32) -> Result<u32, ErrorCode> {
+        let dir = fs::read_dir(self.read_path(ptr, len)?)?;
+        self.insert_handle(Handle::Dir(dir))
+    }
+
+    fn read_dir(&mut self, handle: u32, ptr: u32, len: u32) -> Result<u32, ErrorCode> {
+        let entry = match self.file_handles.get_mut((handle as usize).wrapping_sub(3)) {
+            Some(&mut Handle::Dir(ref mut dir)) => match dir.next() {
+                Some(entry) => entry?,
+                None => return Ok(0)
+            },
+            _ => return Err(ErrorCode::EBADF)
+        };
+
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let name = name.as_bytes();
+
+        self.memory.write(ptr, &name[..cmp::min(name.len(), len as usize)]).map_err(fault)?;
+        Ok(name.len() as u32)
+    }
+
+    fn get_arg(&mut self, index: u32, ptr: u32, len: u32) -> Result<u32, ErrorCode> {
+        let arg = match self.args.get(index as usize) {
+            Some(arg) => arg.as_bytes(),
+            None => return Err(ErrorCode::EINVAL)
+        };
+
+        self.memory.write(ptr, &arg[..cmp::min(arg.len(), len as usize)]).map_err(fault)?;
+        Ok(arg.len() as u32)
+    }
+
+    fn get_env(&mut self, name_ptr: u32, name_len: u32, ptr: u32, len: u32) -> Result<u32, ErrorCode> {
+        self.check_range(name_ptr, name_len)?;
+
+        // No variable can have a longer name, so don't read it
+        if self.env.iter().all(|&(ref n, _)| n.len() < name_len as usize) {
+            return Err(ErrorCode::ENOENT);
+        }
+
+        let mut name = vec![0; name_len as usize];
+        self.memory.read(name_ptr, &mut name).map_err(fault)?;
+
+        let value = match self.env.iter().find(|&&(ref n, _)| n.as_bytes() == &name[..]) {
+            Some(&(_, ref value)) => value.as_bytes(),
+            None => return Err(ErrorCode::ENOENT)
+        };
+
+        self.memory.write(ptr, &value[..cmp::min(value.len(), len as usize)]).map_err(fault)?;
+        Ok(value.len() as u32)
+    }
+
+    fn fill_random(&mut self, ptr: u32, len: u32) -> Result<u32, ErrorCode> {
+        self.check_range(ptr, len)?;
+
+        // Filling a chunk at a time bounds what's allocated for a large
+        // `len`, and the generator only advances once a chunk is written
+        let mut buf = [0; RANDOM_CHUNK];
+        let mut addr = ptr;
+        let mut left = len as usize;
+
+        while left > 0 {
+            let chunk = &mut buf[..cmp::min(left, RANDOM_CHUNK)];
+            let mut random = self.random.clone();
+            random.fill(chunk);
+
+            self.memory.write(addr, chunk).map_err(fault)?;
+            self.random = random;
+
+            addr = addr.wrapping_add(chunk.len() as u32);
+            left -= chunk.len();
+        }
+
+        Ok(len)
+    }
+
+    /// Reads the null terminated string at `addr`, which may be up to
+    /// `MAX_PATH` bytes long.
+    fn read_c_string(&self, addr: u32) -> Result<String, ErrorCode> {
+        let mut bytes = Vec::new();
+        let mut word_addr = addr & !3;
+        let mut skip = (addr & 3) as usize;
+
+        // Read a word at a time, as strict memory allows any aligned word of a mapped string
+        loop {
+            let mut word = [0; 4];
+            LittleEndian::write_u32(&mut word, self.memory.read_u32(word_addr).map_err(fault)?);
+
+            for &byte in &word[skip..] {
+                if byte == 0 {
+                    return String::from_utf8(bytes).map_err(|_| ErrorCode::EINVAL);
+                }
+
+                if bytes.len() == MAX_PATH as usize {
+                    return Err(ErrorCode::E2BIG);
+                }
+
+                bytes.push(byte);
+            }
+
+            skip = 0;
+            word_addr = word_addr.checked_add(4).ok_or(ErrorCode::EFAULT)?;
+        }
+    }
+
+    fn read_spawn(&self, path_ptr: u32, path_len: u32, argv_ptr: u32, flags: u32) -> Result<ProcessCall, ErrorCode> {
+        let path = self.read_path(path_ptr, path_len)?;
+        let mut args = Vec::new();
+
+        if argv_ptr != 0 {
+            let mut addr = argv_ptr;
+
+            loop {
+                match self.memory.read_u32(addr).map_err(fault)? {
+                    0 => break,
+                    _ if args.len() == MAX_ARGS => return Err(ErrorCode::E2BIG),
+                    ptr => args.push(self.read_c_string(ptr)?)
+                }
+
+                addr = addr.checked_add(4).ok_or(ErrorCode::EFAULT)?;
+            }
+        }
+
+        Ok(ProcessCall::Spawn { path: path, args: args, inherit_files: flags & 1 != 0 })
+    }
+
+    fn set_brk(&mut self, addr: u32) -> Result<u32, ErrorCode> {
+        if addr == 0 {
+            return Ok(self.brk);
+        }
+
+        if addr < self.heap_start {
+            return Err(ErrorCode::EINVAL);
+        }
+
+        let page_size = self.memory.page_size();
+        let old_end = page_align(self.brk as u64, page_size);
+        let new_end = page_align(addr as u64, page_size);
+
+        if new_end > old_end {
+            if new_end > self.memory.size() || !self.memory.mappings().is_free(old_end, new_end) {
+                return Err(ErrorCode::ENOMEM);
+            }
+
+            self.memory.map(old_end as u32, new_end - old_end);
+        } else if new_end < old_end {
+            self.memory.unmap(new_end as u32, old_end - new_end);
+        }
+
+        self.brk = addr;
+
+        Ok(addr)
+    }
+
+    /// Finds where to map `len` bytes, at `hint` if the pages there are free
+    /// or otherwise the highest free pages above the program break.
+    fn find_region(&self, hint: u32, len: u32) -> Result<u32, ErrorCode> {
+        if len == 0 {
+            return Err(ErrorCode::EINVAL);
+        }
+
+        let page_size = self.memory.page_size();
+        let len = page_align(len as u64, page_size);
+        let size = self.memory.size();
+        let hint = hint as u64;
+
+        if hint != 0 && hint % page_size as u64 == 0 && hint + len <= size && self.memory.mappings().is_free(hint, hint + len) {
+            return Ok(hint as u32);
+        }
+
+        let lowest = page_align(self.brk as u64, page_size);
+        self.memory.mappings().find_free(len, lowest, size).map(|addr| addr as u32).ok_or(ErrorCode::ENOMEM)
+    }
+
+    fn map_anon(&mut self, hint: u32, len: u32) -> Result<u32, ErrorCode> {
+        let addr = self.find_region(hint, len)?;
+
+        // Discard anything written there while it was unmapped, so that it reads as 0
+        self.memory.unmap(addr, len as u64);
+        self.memory.map(addr, len as u64);
+
+        Ok(addr)
+    }
+
+    fn map_handle(&mut self, handle: u32, offset: u32, len: u32, hint: u32) -> Result<u32, ErrorCode> {
+        // The fifth argument is passed on the stack
+        let prot = self.memory.read_u32(self.stack_ptr()).map_err(fault)?;
+
+        if prot & PROT_READ == 0 || prot & !(PROT_READ | PROT_WRITE | PROT_SHARED) != 0 {
+            return Err(ErrorCode::EINVAL);
+        }
+
+        let mode = if prot & PROT_WRITE == 0 {
+            MapMode::ReadOnly
+        } else if prot & PROT_SHARED != 0 {
+            MapMode::Shared
+        } else {
+            MapMode::Private
+        };
+
+        let file = match self.file_handles.get((handle as usize).wrapping_sub(3)) {
+            Some(&Handle::File(ref file)) => file.try_clone()?,
+            _ => return Err(ErrorCode::EBADF)
+        };
+
+        let addr = self.find_region(hint, len)?;
+        self.map_file(file, offset as u64, len, addr, mode)?;
+
+        Ok(addr)
+    }
+
+    /// Checks that `len` bytes from `addr` are a valid range of pages for
+    /// `sys_msync` and `sys_munmap`, returning the end of the range.
+    fn check_region(&self, addr: u32, len: u32) -> Result<u64, ErrorCode> {
+        let end = addr as u64 + len as u64;
+
+        if len == 0 || addr as u64 % self.memory.page_size() as u64 != 0 || end > self.memory.size() {
+            return Err(ErrorCode::EINVAL);
+        }
+
+        Ok(end)
+    }
+
+    fn sync_region(&mut self, addr: u32, len: u32) -> Result<u32, ErrorCode> {
+        let end = self.check_region(addr, len)?;
+        self.sync_range(addr as u64, end)?;
+
+        Ok(0)
+    }
+
+    fn unmap_region(&mut self, addr: u32, len: u32) -> Result<u32, ErrorCode> {
+        let end = self.check_region(addr, len)?;
+
+        // Whole pages are unmapped, so the rest of the last one is released too
+        let end = cmp::min(page_align(end, self.memory.page_size()), self.memory.size());
+        self.release_files(addr as u64, end)?;
+        self.memory.unmap(addr, len as u64);
+
+        Ok(0)
+    }
+}
+
+/// Returns `addr` rounded up to a multiple of `page_size`.
+fn page_align(addr: u64, page_size: usize) -> u64 {
+    let page_size = page_size as u64;
+    (addr + page_size - 1) / page_size * page_size
+}
+
+/// Converts a fault accessing memory on behalf of a syscall into an error
+/// code, so that the syscall fails rather than the machine.
+fn fault(_: Error) -> ErrorCode {
+    ErrorCode::EFAULT
+}
+
+/// Functions compiled code calls back into, which run on the machine in the
+/// context they're given.
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+mod callbacks {
+    use {Error, MemoryBackend};
+    use jit::{Context, Exit};
+
+    /// Records a fault by the instruction at `pc`, stopping compiled code.
+    fn stop<M>(context: &mut Context<M>, pc: u32, error: Error) {
+        context.registers[0] = pc;
+        context.result = Some(Err(error));
+        context.stopped = true;
+    }
+
+    pub extern "C" fn read_u32<M: MemoryBackend>(context: *mut Context<M>, addr: u32, pc: u32) -> u32 {
+        let context = unsafe { &mut *context };
+
+        match unsafe { (*context.vm).memory.read_u32(addr) } {
+            Ok(value) => value,
+            Err(error) => {
+                stop(context, pc, error);
+                0
+            }
+        }
+    }
+
+    pub extern "C" fn write_u32<M: MemoryBackend>(context: *mut Context<M>, addr: u32, value: u32, pc: u32) -> u32 {
+        let context = unsafe { &mut *context };
+        let memory = unsafe { &mut (*context.vm).memory };
+        let generation = memory.code_generation(addr, 4);
+
+        match memory.write_u32(addr, value) {
+            Ok(()) => (memory.code_generation(addr, 4) != generation) as u32,
+            Err(error) => {
+                stop(context, pc, error);
+                0
+            }
+        }
+    }
+
+    pub extern "C" fn syscall<M: MemoryBackend + 'static>(context: *mut Context<M>, call: u32, addr: u32) -> Exit {
+        let context = unsafe { &mut *context };
+        let vm = unsafe { &mut *context.vm };
+
+        vm.registers = context.registers;
+        let result = vm.exec_syscall(call as u16);
+        context.registers = vm.registers;
+
+        match result {
+            Ok(None) => return Exit::Continue,
+            Ok(Some(status)) => context.result = Some(Ok(status)),
+            Err(error) => {
+                // Leave the program counter at the `CALL`, as the interpreter does
+                context.registers[0] = addr;
+                context.result = Some(Err(error));
+            }
+        }
+
+        Exit::Stop
+    }
+}
+
+/// Permission bits reported by `sys_stat`, in the form of a Unix mode.
+#[cfg(unix)]
+fn permissions(metadata: &Metadata) -> u32 {
+    metadata.permissions().mode() & 0o7777
+}
+
+/// Permission bits reported by `sys_stat`, in the form of a Unix mode.
+#[cfg(not(unix))]
+fn permissions(metadata: &Metadata) -> u32 {
+    if metadata.permissions().readonly() { 0o444 } else { 0o666 }
+}
 
-        for handle in 0..(u32::max_value() as usize - 2) {
-            if !self.file_handles.contains_key(handle) {
-                self.file_handles.insert(handle, file);
-                return Ok(handle as u32 + 3);
-            }
-        }
+/// A range of memory read from a file by `map_file`.
+struct FileMapping {
+    addr: u32,
+    /// Number of bytes read from the file, which are all that's written back.
+    len: u32,
+    /// Position in the file `addr` was read from.
+    offset: u64,
+    /// The file, shared by the parts of a mapping split by `sys_munmap`.
+    file: Arc<File>,
+    mode: MapMode
+}
 
-        Ok(-1i32 as u32)
+impl FileMapping {
+    /// Returns the part of the addresses from `start` to `end` which the
+    /// mapping covers, which is empty if they don't overlap.
+    fn overlap(&self, start: u64, end: u64) -> (u64, u64) {
+        (cmp::max(start, self.addr as u64), cmp::min(end, self.addr as u64 + self.len as u64))
     }
+}
 
-    fn close_file(&mut self, handle: u32) -> Result<u32, io::Error> {
-        if let Some(file) = self.file_handles.remove(handle as usize - 3) {
-            file.sync_all()?;
+/// Runs `f` with `file` at `offset`, then moves it back to where it was, so
+/// that mapping a file doesn't disturb a handle it's open with.
+fn at_offset<T, F>(file: &File, offset: u64, f: F) -> io::Result<T>
+    where F: FnOnce(&File) -> io::Result<T>
+{
+    let mut file = file;
+    let position = file.seek(SeekFrom::Current(0))?;
 
-            // Since the file has been moved out of the VecMap, it will get
-            // closed once `file` is dropped when we return
-            return Ok(0);
-        }
+    file.seek(SeekFrom::Start(offset))?;
+    let result = f(file);
+    file.seek(SeekFrom::Start(position))?;
 
-        Ok(-1i32 as u32)
-    }
+    result
+}
 
-    fn create_file(&mut self, ptr: u32, len: u32) -> Result<u32, io::Error> {
-        self.open_file(ptr, len, FileFlags::CREATE | FileFlags::WRITE | FileFlags::TRUNCATE)
-    }
+/// Something a program has opened, referred to by its handle.
+enum Handle {
+    File(File),
+    /// A directory being listed by `sys_readdir`.
+    Dir(ReadDir)
 }
 
+/// Bits of the `prot` argument to `sys_mmap_file`.
+const PROT_READ: u32 = 1;
+const PROT_WRITE: u32 = 2;
+const PROT_SHARED: u32 = 4;
+
 #[repr(u32)]
 #[allow(non_camel_case_types)]
-#[derive(Copy, Clone, Debug, EnumBitPattern)]
+#[derive(Copy, Clone, Debug)]
 enum FileFlags {
     READ,
     WRITE,
@@ -345,16 +1533,18 @@ enum FileFlags {
     APPEND
 }
 
+impl FileFlags {
+    /// Returns the bit of `flags` given by this flag, its index.
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
 impl BitAnd<FileFlags> for u32 {
     type Output = u32;
 
     fn bitand(self, rhs: FileFlags) -> Self::Output {
-        // `#[derive(EnumBitPattern)]` sets the return type of `bit_pattern()`
-        // to the shortest byte array that will fit all the enum variants,
-        // which in this case is `[u8; 1]`, hence the transmute to `u8`.
-        let rhs: u8 = unsafe { mem::transmute(rhs.bit_pattern()) };
-        
-        self & rhs as u32
+        self & rhs.bit()
     }
 }
 
@@ -362,11 +1552,7 @@ impl BitOr for FileFlags {
     type Output = u32;
 
     fn bitor(self, rhs: Self) -> Self::Output {
-        // See comment in `bitand()` above
-        let lhs: u8 = unsafe { mem::transmute(self.bit_pattern()) };
-        let rhs: u8 = unsafe { mem::transmute(rhs.bit_pattern()) };
-
-        (lhs | rhs) as u32
+        self.bit() | rhs.bit()
     }
 }
 
@@ -374,23 +1560,21 @@ impl BitOr<FileFlags> for u32 {
     type Output = u32;
 
     fn bitor(self, rhs: FileFlags) -> Self::Output {
-        // See comment in `bitand()` above
-        let rhs: u8 = unsafe { mem::transmute(rhs.bit_pattern()) };
-
-        self | rhs as u32
+        self | rhs.bit()
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::fs::{self, File};
-    use std::io::{Read, Write};
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{self, Read, Seek, SeekFrom, Write};
     use std::path::Path;
 
+    use {Error, ErrorCode, FlatMemory, MemoryBackend, ProcessCall, Random, Slice};
     use Instruction::*;
     use OpCode::*;
 
-    use super::VirtualMachine;
+    use super::{Clock, Engine, Handle, MapMode, VirtualMachine, MAX_ARGS, MAX_PATH, STACK_SIZE};
 
     #[test]
     fn add() {
@@ -802,7 +1986,7 @@ mod test {
         let instr = Immediate { op: CALL, dst: 0, src1: 0, imm: 1 };
         let mut vm = VirtualMachine::default();
         vm.registers[4..7].copy_from_slice(&[3, 0, 13]);
-        vm.file_handles.insert(0, file);
+        vm.file_handles.insert(0, Handle::File(file));
 
         assert_eq!(vm.exec_instr(instr), Ok(None));
 
@@ -827,8 +2011,8 @@ mod test {
         let instr = Immediate { op: CALL, dst: 0, src1: 0, imm: 2 };
         let mut vm = VirtualMachine::default();
         vm.registers[4..7].copy_from_slice(&[3, 0, 13]);
-        vm.memory.write(0, b"Hello, World!");
-        vm.file_handles.insert(0, file);
+        vm.memory.write(0, b"Hello, World!").unwrap();
+        vm.file_handles.insert(0, Handle::File(file));
 
         assert_eq!(vm.exec_instr(instr), Ok(None));
 
@@ -837,7 +2021,10 @@ mod test {
         assert_eq!(vm.registers[2..7], [0, 13, 3, 0, 13]);
         assert_eq!(vm.registers[7..], [0; 25]);
         
-        vm.file_handles.remove(0).unwrap().flush().unwrap();
+        match vm.file_handles.remove(0) {
+            Some(Handle::File(mut file)) => file.flush().unwrap(),
+            _ => panic!("expected a file")
+        }
         let mut file = File::open(&path).unwrap();
         let mut buf = [0; 13];
         file.read(&mut buf).unwrap();
@@ -857,7 +2044,7 @@ mod test {
         let instr = Immediate { op: CALL, dst: 0, src1: 0, imm: 3 };
         let mut vm = VirtualMachine::default();
         vm.registers[4..7].copy_from_slice(&[0, 14, 1]);
-        vm.memory.write(0, b".sys_open_test");
+        vm.memory.write(0, b".sys_open_test").unwrap();
 
         assert_eq!(vm.exec_instr(instr), Ok(None));
 
@@ -878,7 +2065,7 @@ mod test {
         let instr = Immediate { op: CALL, dst: 0, src1: 0, imm: 4 };
         let mut vm = VirtualMachine::default();
         vm.registers[4] = 3;
-        vm.file_handles.insert(0, file);
+        vm.file_handles.insert(0, Handle::File(file));
 
         assert_eq!(vm.exec_instr(instr), Ok(None));
 
@@ -898,7 +2085,7 @@ mod test {
         let instr = Immediate { op: CALL, dst: 0, src1: 0, imm: 5 };
         let mut vm = VirtualMachine::default();
         vm.registers[4..6].copy_from_slice(&[0, 16]);
-        vm.memory.write(0, b".sys_create_test");
+        vm.memory.write(0, b".sys_create_test").unwrap();
 
         assert_eq!(vm.exec_instr(instr), Ok(None));
 
@@ -910,4 +2097,724 @@ mod test {
 
         fs::remove_file(&path).unwrap();
     }
+
+    #[test]
+    fn sys_seek() {
+        let path = Path::new(".sys_seek_test");
+        {
+            File::create(&path).unwrap().write_all(b"Hello, World!").unwrap();
+        }
+
+        let instr = Immediate { op: CALL, dst: 0, src1: 0, imm: 8 };
+        let mut vm = VirtualMachine::default();
+        vm.registers[4..7].copy_from_slice(&[3, -6i32 as u32, 2]);
+        vm.file_handles.insert(0, Handle::File(File::open(&path).unwrap()));
+
+        assert_eq!(vm.exec_instr(instr), Ok(None));
+        assert_eq!(vm.registers[3], 7);
+
+        vm.registers[4..7].copy_from_slice(&[3, 2, 1]);
+        assert_eq!(vm.exec_instr(instr), Ok(None));
+        assert_eq!(vm.registers[3], 9);
+
+        vm.registers[4..7].copy_from_slice(&[3, 0, 3]);
+        assert_eq!(vm.exec_instr(instr), Ok(None));
+        assert_eq!(vm.registers[3], ErrorCode::EINVAL.to_return_value());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn sys_stat() {
+        let path = Path::new(".sys_stat_test");
+        {
+            File::create(&path).unwrap().write_all(b"Hello, World!").unwrap();
+        }
+
+        let instr = Immediate { op: CALL, dst: 0, src1: 0, imm: 9 };
+        let mut vm = VirtualMachine::default();
+        vm.registers[4..7].copy_from_slice(&[0, 14, 0x100]);
+        vm.memory.write(0, b".sys_stat_test").unwrap();
+
+        assert_eq!(vm.exec_instr(instr), Ok(None));
+        assert_eq!(vm.registers[3], 0);
+        assert_eq!((vm.memory.read_u32(0x100), vm.memory.read_u32(0x104), vm.memory.read_u32(0x108)), (13, 0, 0));
+        assert_eq!(vm.memory.read_u32(0x10c) & 0o400, 0o400);
+        assert_ne!(vm.memory.read_u32(0x110), 0);
+
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(vm.exec_instr(instr), Ok(None));
+        assert_eq!(vm.registers[3], ErrorCode::ENOENT.to_return_value());
+    }
+
+    #[test]
+    fn sys_mkdir_rename_unlink() {
+        let dir = Path::new(".sys_mkdir_test");
+        let mut vm = VirtualMachine::default();
+        vm.memory.write(0, b".sys_mkdir_test/a.sys_mkdir_test/b").unwrap();
+
+        vm.registers[4..6].copy_from_slice(&[0, 15]);
+        assert_eq!(vm.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: 12 }), Ok(None));
+        assert_eq!((vm.registers[3], dir.is_dir()), (0, true));
+
+        File::create(dir.join("a")).unwrap();
+
+        vm.registers[4..8].copy_from_slice(&[0, 17, 17, 17]);
+        assert_eq!(vm.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: 11 }), Ok(None));
+        assert_eq!((vm.registers[3], dir.join("a").exists(), dir.join("b").exists()), (0, false, true));
+
+        vm.registers[4..6].copy_from_slice(&[17, 17]);
+        assert_eq!(vm.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: 10 }), Ok(None));
+        assert_eq!((vm.registers[3], dir.join("b").exists()), (0, false));
+
+        assert_eq!(vm.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: 10 }), Ok(None));
+        assert_eq!(vm.registers[3], ErrorCode::ENOENT.to_return_value());
+
+        fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn sys_readdir() {
+        let dir = Path::new(".sys_readdir_test");
+        fs::create_dir_all(&dir).unwrap();
+        File::create(dir.join("entry")).unwrap();
+
+        let mut vm = VirtualMachine::default();
+        vm.registers[4..6].copy_from_slice(&[0, 17]);
+        vm.memory.write(0, b".sys_readdir_test").unwrap();
+
+        assert_eq!(vm.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: 13 }), Ok(None));
+        assert_eq!(vm.registers[3], 3);
+
+        let instr = Immediate { op: CALL, dst: 0, src1: 0, imm: 14 };
+        vm.registers[4..7].copy_from_slice(&[3, 0x100, 16]);
+
+        assert_eq!(vm.exec_instr(instr), Ok(None));
+        assert_eq!(vm.registers[3], 5);
+
+        let mut name = [0; 5];
+        vm.memory.read(0x100, &mut name);
+        assert_eq!(&name, b"entry");
+
+        assert_eq!(vm.exec_instr(instr), Ok(None));
+        assert_eq!(vm.registers[3], 0);
+
+        // Directories can't be read like files
+        vm.registers[4..7].copy_from_slice(&[3, 0x100, 16]);
+        assert_eq!(vm.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: 1 }), Ok(None));
+        assert_eq!(vm.registers[3], ErrorCode::EBADF.to_return_value());
+
+        vm.registers[4] = 3;
+        assert_eq!(vm.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: 4 }), Ok(None));
+        assert_eq!(vm.file_handles.is_empty(), true);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sys_errors() {
+        let mut vm = VirtualMachine::with_memory(FlatMemory::new(64), Vec::new()).unwrap();
+
+        // Writing to a closed handle, then from a buffer outside memory
+        vm.registers[4..7].copy_from_slice(&[3, 0, 8]);
+        assert_eq!(vm.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: 2 }), Ok(None));
+        assert_eq!(vm.registers[3], -9i32 as u32);
+
+        vm.registers[4..7].copy_from_slice(&[1, 60, 8]);
+        assert_eq!(vm.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: 2 }), Ok(None));
+        assert_eq!(vm.registers[3], ErrorCode::EFAULT.to_return_value());
+
+        vm.registers[4] = 0;
+        assert_eq!(vm.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: 4 }), Ok(None));
+        assert_eq!(vm.registers[3], ErrorCode::EBADF.to_return_value());
+    }
+
+    #[test]
+    fn sys_time() {
+        let mut vm = VirtualMachine::default();
+        vm.clock = Clock::Virtual { start: 0x123456789, ns_per_instruction: 500_000_000 };
+
+        assert_eq!(vm.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: 15 }), Ok(None));
+        assert_eq!(vm.registers[3..5], [0x23456789, 0x1]);
+
+        vm.clock = Clock::Host;
+        assert_eq!(vm.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: 15 }), Ok(None));
+        assert!(vm.registers[3] > 1_500_000_000 || vm.registers[4] > 0);
+    }
+
+    #[test]
+    fn virtual_clock() {
+        // c.li r4, 5; call 17; c.call 16; mv r4, r3; c.call 0
+        let program = vec![0x31, 0x0b, 0x3c, 0x00, 0x11, 0x00, 0x3d, 0x20, 0x39, 0x19, 0x3d, 0x00];
+
+        #[cfg(not(feature = "jit"))]
+        let engines = [Engine::Interpreter, Engine::Blocks];
+        #[cfg(feature = "jit")]
+        let engines = [Engine::Interpreter, Engine::Blocks, Engine::Jit];
+
+        for &engine in &engines {
+            let mut vm = VirtualMachine::new(program.clone()).unwrap();
+            vm.engine = engine;
+            vm.clock = Clock::Virtual { start: 0, ns_per_instruction: 10 };
+
+            #[cfg(feature = "jit")]
+            {
+                vm.jit_threshold = 1;
+            }
+
+            // Three instructions and 5ms of sleep by the time it's read
+            assert_eq!(vm.run(), Ok(5_000_030));
+        }
+    }
+
+    #[test]
+    fn sys_args() {
+        let instr = Immediate { op: CALL, dst: 0, src1: 0, imm: 6 };
+        let mut vm = VirtualMachine::default();
+        vm.args = vec!["prog".to_owned(), "argument".to_owned()];
+        vm.registers[4..7].copy_from_slice(&[1, 0x100, 3]);
+
+        assert_eq!(vm.exec_instr(instr), Ok(None));
+        assert_eq!(vm.registers[3], 8);
+        assert_eq!((vm.memory.read_u32(0x100), vm.memory.read_u32(0x104)), (0x00677261, 0));
+
+        vm.registers[4] = 2;
+        assert_eq!(vm.exec_instr(instr), Ok(None));
+        assert_eq!(vm.registers[3], ErrorCode::EINVAL.to_return_value());
+    }
+
+    #[test]
+    fn sys_getenv() {
+        let instr = Immediate { op: CALL, dst: 0, src1: 0, imm: 7 };
+        let mut vm = VirtualMachine::default();
+        vm.env = vec![("HOME".to_owned(), "/home/svm".to_owned())];
+        vm.memory.write(0x100, b"HOME").unwrap();
+        vm.registers[4..8].copy_from_slice(&[0x100, 4, 0x200, 16]);
+
+        assert_eq!(vm.exec_instr(instr), Ok(None));
+        assert_eq!(vm.registers[3], 9);
+
+        let mut value = [0; 9];
+        vm.memory.read(0x200, &mut value);
+        assert_eq!(&value, b"/home/svm");
+
+        vm.registers[5] = 3;
+        assert_eq!(vm.exec_instr(instr), Ok(None));
+        assert_eq!(vm.registers[3], ErrorCode::ENOENT.to_return_value());
+    }
+
+    #[test]
+    fn large_lengths() {
+        fn call<M: MemoryBackend + 'static>(vm: &mut VirtualMachine<M>, call: u32, args: &[u32]) -> u32 {
+            vm.registers[4..4 + args.len()].copy_from_slice(args);
+            vm.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: call }).unwrap();
+            vm.registers[3]
+        }
+
+        let path = Path::new(".large_lengths_test");
+        File::create(&path).unwrap();
+
+        // Lengths covering most of memory are only read as far as needed
+        let mut vm = VirtualMachine::default();
+        vm.env = vec![("HOME".to_owned(), "/home/svm".to_owned())];
+        vm.file_handles.insert(0, Handle::File(OpenOptions::new().read(true).write(true).open(&path).unwrap()));
+
+        assert_eq!(call(&mut vm, 7, &[0x100, 0xffff0000, 0x200, 16]), ErrorCode::ENOENT.to_return_value());
+        assert_eq!(call(&mut vm, 9, &[0x100, 0xffff0000, 0x200]), ErrorCode::ENOENT.to_return_value());
+
+        // Writes larger than a chunk are written in full
+        vm.memory.write(0x100, &[0xaa; 0x20000]).unwrap();
+        assert_eq!(call(&mut vm, 2, &[3, 0x100, 0x20000]), 0x20000);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0x20000);
+
+        if let Some(&mut Handle::File(ref mut file)) = vm.file_handles.get_mut(0) {
+            file.seek(SeekFrom::Start(0x1fff0)).unwrap();
+        }
+
+        assert_eq!(call(&mut vm, 1, &[3, 0x100, 0xffff0000]), 0x10);
+
+        // Buffers extending outside of memory fail
+        let mut vm = VirtualMachine::with_memory(FlatMemory::new(0x10000), Vec::new()).unwrap();
+        vm.env = vec![("HOME".to_owned(), "/home/svm".to_owned())];
+        vm.file_handles.insert(0, Handle::File(File::open(&path).unwrap()));
+
+        assert_eq!(call(&mut vm, 1, &[3, 0x100, 0x10000]), ErrorCode::EFAULT.to_return_value());
+        assert_eq!(call(&mut vm, 2, &[3, 0x100, 0x10000]), ErrorCode::EFAULT.to_return_value());
+        assert_eq!(call(&mut vm, 7, &[0xfffe, 4, 0x200, 16]), ErrorCode::EFAULT.to_return_value());
+        assert_eq!(call(&mut vm, 9, &[0xfffe, 4, 0x200]), ErrorCode::EFAULT.to_return_value());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn push_args() {
+        let mut vm = VirtualMachine::default();
+        vm.args = vec!["prog".to_owned(), "-v".to_owned()];
+        vm.env = vec![("A".to_owned(), "1".to_owned())];
+        vm.push_args().unwrap();
+
+        // "prog\0-v\0A=1\0" takes 12 bytes, below which are argc, two
+        // arguments, a null, one variable and another null
+        let strings = 0xfffffffc - 12;
+        let sp = strings - 24;
+
+        assert_eq!(vm.stack_ptr(), sp);
+        assert_eq!(vm.registers[4..7], [2, sp + 4, sp + 16]);
+
+        let table: Vec<u32> = (0..6).map(|i| vm.memory.read_u32(sp + i * 4)).collect();
+        assert_eq!(table, vec![2, strings, strings + 5, 0, strings + 8, 0]);
+
+        let mut bytes = [0; 12];
+        vm.memory.read(strings, &mut bytes);
+        assert_eq!(&bytes, b"prog\0-v\0A=1\0");
+
+        let mut vm = VirtualMachine::with_memory(FlatMemory::new(32), Vec::new()).unwrap();
+        vm.args = vec!["a very long argument indeed".to_owned()];
+        assert_eq!(vm.push_args(), Err(Error::OutOfMemory));
+    }
+
+    #[test]
+    fn run_error() {
+        let mut vm = VirtualMachine::new(vec![0x31, 0x03, 0x3c, 0x00, 0x63, 0x00]).unwrap();
+
+        assert_eq!(vm.run(), Err(Error::InvalidSysCall(99)));
+        assert_eq!(vm.program_ctr(), 2);
+
+        let mut vm = VirtualMachine::new(vec![0x31, 0x03, 0x01, 0x00]).unwrap();
+
+        assert_eq!(vm.run(), Err(Error::InvalidOpCode(1)));
+        assert_eq!(vm.program_ctr(), 2);
+    }
+
+    #[test]
+    fn restore() {
+        // c.li r4, 1; store r0, r4, 12; c.call 0
+        let mut vm = VirtualMachine::new(vec![0x31, 0x03, 0x36, 0x03, 0x04, 0x00, 0x3d, 0x00]).unwrap();
+        vm.registers[8] = 0x1234;
+        vm.set_brk(0x2000).unwrap();
+
+        let dump = vm.dump();
+        assert_eq!(vm.run(), Ok(1));
+
+        let mut restored = VirtualMachine::new(Vec::new()).unwrap();
+        restored.restore(&dump).unwrap();
+
+        assert_eq!(restored.registers, dump.registers);
+        assert_eq!((restored.heap_start, restored.brk), (8, 0x2000));
+        assert_eq!(restored.run(), Ok(1));
+        assert_eq!((restored.memory.read_u32(18), vm.memory.read_u32(18)), (1, 1));
+    }
+
+    #[test]
+    fn out_of_memory() {
+        // li r4, 0x2000; store r4, r4, 0
+        let mut vm = VirtualMachine::new(vec![0x30, 0x01, 0x00, 0x20, 0x36, 0x20, 0x04, 0x00]).unwrap();
+        vm.memory.set_max_pages(Some(1));
+
+        assert_eq!(vm.run(), Err(Error::OutOfMemory));
+        assert_eq!(vm.program_ctr(), 4);
+    }
+
+    #[test]
+    fn flat_memory() {
+        let vm = VirtualMachine::with_memory(FlatMemory::new(64), vec![]).unwrap();
+        assert_eq!(vm.stack_ptr(), 60);
+
+        // li r4, 64; load r3, r4, 0
+        let program = vec![0x30, 0x01, 0x40, 0x00, 0xf4, 0x20, 0x00, 0x00];
+
+        #[cfg(not(feature = "jit"))]
+        let engines = [Engine::Interpreter, Engine::Blocks];
+        #[cfg(feature = "jit")]
+        let engines = [Engine::Interpreter, Engine::Blocks, Engine::Jit];
+
+        for &engine in &engines {
+            let mut vm = VirtualMachine::with_memory(FlatMemory::new(64), program.clone()).unwrap();
+            vm.engine = engine;
+
+            #[cfg(feature = "jit")]
+            {
+                vm.jit_threshold = 1;
+            }
+
+            assert_eq!(vm.run(), Err(Error::OutOfBounds(64)));
+            assert_eq!(vm.program_ctr(), 4);
+        }
+
+        assert_eq!(VirtualMachine::with_memory(FlatMemory::new(4), program).err(), Some(Error::ProgramTooLarge));
+    }
+
+    #[test]
+    fn load_at() {
+        let mut vm = VirtualMachine::new(Vec::new()).unwrap();
+
+        // c.li r4, 1 followed by a separate image with c.call 0
+        vm.load_at(0x102, &[0x3d, 0x00]).unwrap();
+        vm.load_at(0x100, &[0x31, 0x03]).unwrap();
+        vm.set_entry(0x100);
+
+        assert_eq!((vm.entry(), vm.program_ctr()), (0x100, 0x100));
+        assert_eq!(vm.run(), Ok(1));
+
+        vm.reset();
+        assert_eq!(vm.program_ctr(), 0x100);
+        assert_eq!(vm.run(), Ok(1));
+
+        assert_eq!(vm.brk(), 0x104);
+
+        let mut vm = VirtualMachine::with_memory(FlatMemory::new(16), Vec::new()).unwrap();
+        assert_eq!(vm.load_at(14, &[0; 4]), Err(Error::ProgramTooLarge));
+    }
+
+    #[test]
+    fn sys_brk() {
+        let mut vm = VirtualMachine::new(vec![0; 0x10]).unwrap();
+        let brk = |vm: &mut VirtualMachine, addr| {
+            vm.registers[4] = addr;
+            vm.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: 18 }).unwrap();
+            vm.registers[3]
+        };
+
+        assert_eq!(brk(&mut vm, 0), 0x10);
+        assert_eq!(brk(&mut vm, 0x2010), 0x2010);
+        assert_eq!(vm.memory.mappings().ranges(), &[(0, 0x3000), ((1 << 32) - STACK_SIZE, 1 << 32)]);
+
+        assert_eq!(brk(&mut vm, 0x8), ErrorCode::EINVAL.to_return_value());
+        assert_eq!(brk(&mut vm, 0xfffffff0), ErrorCode::ENOMEM.to_return_value());
+
+        assert_eq!(brk(&mut vm, 0x20), 0x20);
+        assert_eq!(brk(&mut vm, 0), 0x20);
+        assert_eq!(vm.memory.mappings().ranges()[0], (0, 0x1000));
+    }
+
+    #[test]
+    fn sys_mmap_anon() {
+        let mut vm = VirtualMachine::with_memory(FlatMemory::new(0x10000), vec![0; 0x10]).unwrap();
+        let call = |vm: &mut VirtualMachine<FlatMemory>, call, args: &[u32]| {
+            vm.registers[4..4 + args.len()].copy_from_slice(args);
+            vm.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: call }).unwrap();
+            vm.registers[3]
+        };
+
+        // The whole of a memory this small is the stack, so make room in it
+        assert_eq!(call(&mut vm, 20, &[0x1000, 0xf000]), 0);
+
+        vm.memory.write_u32(0xf000, 0xff).unwrap();
+        assert_eq!(call(&mut vm, 19, &[0, 0x1800]), 0xe000);
+        assert_eq!(vm.memory.read_u32(0xf000), Ok(0));
+
+        assert_eq!(call(&mut vm, 19, &[0x2000, 1]), 0x2000);
+        assert_eq!(call(&mut vm, 19, &[0x2000, 1]), 0xd000);
+        assert_eq!(call(&mut vm, 19, &[0, 0]), ErrorCode::EINVAL.to_return_value());
+
+        assert_eq!(call(&mut vm, 20, &[0xe000, 0x2000]), 0);
+        assert_eq!(call(&mut vm, 20, &[0xe001, 0x1000]), ErrorCode::EINVAL.to_return_value());
+        assert_eq!(call(&mut vm, 19, &[0, 0x10000]), ErrorCode::ENOMEM.to_return_value());
+
+        // The heap can't grow into mapped regions
+        assert_eq!(call(&mut vm, 18, &[0x2001]), ErrorCode::ENOMEM.to_return_value());
+        assert_eq!(call(&mut vm, 18, &[0x2000]), 0x2000);
+    }
+
+    #[test]
+    fn strict_memory() {
+        // c.li r4, 1; c.call 0
+        let mut vm = VirtualMachine::new(vec![0x31, 0x03, 0x3d, 0x00]).unwrap();
+        vm.memory.set_strict(true);
+
+        assert_eq!(vm.run(), Ok(1));
+
+        let store = Store { op: STORE, src1: 8, src2: 4, imm: 0 };
+        vm.registers[8] = 0x2000;
+        assert_eq!(vm.exec_instr(store), Err(Error::OutOfBounds(0x2000)));
+
+        vm.registers[4..6].copy_from_slice(&[0x2000, 4]);
+        assert_eq!(vm.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: 19 }), Ok(None));
+        assert_eq!(vm.registers[3], 0x2000);
+        assert_eq!(vm.exec_instr(store), Ok(None));
+
+        // Syscalls can't access unmapped memory either
+        vm.registers[4..7].copy_from_slice(&[1, 0x4000, 4]);
+        assert_eq!(vm.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: 2 }), Ok(None));
+        assert_eq!(vm.registers[3], ErrorCode::EFAULT.to_return_value());
+    }
+
+    #[test]
+    fn sys_mmap_file() {
+        let path = Path::new(".sys_mmap_file_test");
+        {
+            File::create(&path).unwrap().write_all(b"Hello, World!").unwrap();
+        }
+
+        let contents = || {
+            let mut contents = String::new();
+            File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+            contents
+        };
+
+        let mut vm = VirtualMachine::default();
+        let file = OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        vm.file_handles.insert(0, Handle::File(file));
+
+        // Map "World!" readable, writable and shared, with `prot` passed on the stack
+        let sp = vm.stack_ptr();
+        vm.memory.write_u32(sp, 7).unwrap();
+        vm.registers[4..8].copy_from_slice(&[3, 7, 6, 0x10000]);
+        assert_eq!(vm.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: 21 }), Ok(None));
+        assert_eq!(vm.registers[3], 0x10000);
+
+        let mut buf = [0; 8];
+        vm.memory.read(0x10000, &mut buf);
+        assert_eq!(&buf, b"World!\0\0");
+
+        vm.memory.write(0x10000, b"wo").unwrap();
+        vm.registers[4..6].copy_from_slice(&[0x10000, 6]);
+        assert_eq!(vm.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: 22 }), Ok(None));
+        assert_eq!(vm.registers[3], 0);
+        assert_eq!(contents(), "Hello, world!");
+
+        // Unmapping writes back too, and the handle's position is untouched
+        vm.memory.write(0x10004, b"D?").unwrap();
+        vm.registers[4..6].copy_from_slice(&[0x10000, 0x1000]);
+        assert_eq!(vm.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: 20 }), Ok(None));
+        assert_eq!(contents(), "Hello, worlD?");
+
+        match vm.file_handles.get_mut(0) {
+            Some(&mut Handle::File(ref mut file)) => assert_eq!(file.seek(SeekFrom::Current(0)).unwrap(), 0),
+            _ => unreachable!()
+        }
+
+        // A private mapping is never written back
+        vm.memory.write_u32(sp, 3).unwrap();
+        vm.registers[4..8].copy_from_slice(&[3, 0, 5, 0]);
+        assert_eq!(vm.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: 21 }), Ok(None));
+
+        let addr = vm.registers[3];
+        assert_eq!(addr % 4096, 0);
+        vm.memory.write(addr, b"Jello").unwrap();
+        vm.sync_files().unwrap();
+        assert_eq!(contents(), "Hello, worlD?");
+
+        // Mappings must be readable, and unknown bits are refused
+        for &prot in &[2, 9] {
+            vm.memory.write_u32(sp, prot).unwrap();
+            assert_eq!(vm.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: 21 }), Ok(None));
+            assert_eq!(vm.registers[3], ErrorCode::EINVAL.to_return_value());
+        }
+
+        // A read only mapping can't be written to, whether or not memory is strict
+        vm.memory.write_u32(sp, 1).unwrap();
+        assert_eq!(vm.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: 21 }), Ok(None));
+
+        let addr = vm.registers[3];
+        assert_eq!(MemoryBackend::write(&mut vm.memory, addr, b"J"), Err(Error::OutOfBounds(addr)));
+        vm.memory.set_strict(true);
+        assert_eq!(MemoryBackend::write(&mut vm.memory, addr, b"J"), Err(Error::OutOfBounds(addr)));
+        vm.memory.set_strict(false);
+        vm.sync_files().unwrap();
+        assert_eq!(contents(), "Hello, worlD?");
+
+        vm.registers[4] = 4;
+        assert_eq!(vm.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: 21 }), Ok(None));
+        assert_eq!(vm.registers[3], ErrorCode::EBADF.to_return_value());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn map_file() {
+        let path = Path::new(".map_file_test");
+        {
+            File::create(&path).unwrap().write_all(&[1; 48]).unwrap();
+        }
+
+        let mut vm = VirtualMachine::with_page_size(16, Vec::new()).unwrap();
+        let file = OpenOptions::new().read(true).write(true).open(&path).unwrap();
+
+        let error = vm.map_file(file.try_clone().unwrap(), 0, 48, 0x108, MapMode::Shared).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+        vm.map_file(file, 0, 48, 0x100, MapMode::Shared).unwrap();
+        vm.memory.write(0x100, &[2; 48]).unwrap();
+
+        // Unmapping the middle page leaves the rest mapped from the same file
+        vm.registers[4..6].copy_from_slice(&[0x110, 16]);
+        assert_eq!(vm.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: 20 }), Ok(None));
+        assert_eq!(vm.memory.mappings().ranges()[0], (0x100, 0x110));
+
+        vm.memory.write(0x120, &[3; 16]).unwrap();
+        vm.sync_files().unwrap();
+
+        let mut contents = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, [&[2; 32][..], &[3; 16][..]].concat());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn sys_random() {
+        let mut vm = VirtualMachine::default();
+        vm.random = Random::new(7);
+        let mut expected = Random::new(7);
+
+        vm.registers[4..6].copy_from_slice(&[0x100, 6]);
+        assert_eq!(vm.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: 23 }), Ok(None));
+        assert_eq!(vm.registers[3], 6);
+
+        let mut buf = [0; 8];
+        let mut expected_buf = [0; 6];
+        expected.fill(&mut expected_buf);
+        vm.memory.read(0x100, &mut buf);
+        assert_eq!(buf[..6], expected_buf);
+        assert_eq!(buf[6..], [0, 0]);
+
+        assert_eq!(vm.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: 24 }), Ok(None));
+        assert_eq!(vm.registers[3], expected.next_u32());
+
+        let mut vm = VirtualMachine::with_memory(FlatMemory::new(16), Vec::new()).unwrap();
+        vm.registers[4..6].copy_from_slice(&[14, 4]);
+        assert_eq!(vm.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: 23 }), Ok(None));
+        assert_eq!(vm.registers[3], ErrorCode::EFAULT.to_return_value());
+
+        // Only the bytes written before a fault are drawn from the generator
+        let mut vm = VirtualMachine::with_memory(FlatMemory::new(0x2000), Vec::new()).unwrap();
+        vm.memory.set_strict(true);
+        vm.memory.unmap(0x1000, 0x1000);
+        vm.random = Random::new(7);
+
+        let mut expected = Random::new(7);
+        let mut expected_buf = [0; 0x1000];
+        expected.fill(&mut expected_buf);
+
+        vm.registers[4..6].copy_from_slice(&[0, 0x2000]);
+        assert_eq!(vm.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: 23 }), Ok(None));
+        assert_eq!(vm.registers[3], ErrorCode::EFAULT.to_return_value());
+        assert_eq!(&vm.memory.bytes()[..0x1000], &expected_buf[..]);
+        assert_eq!(vm.random, expected);
+    }
+
+    /// Runs syscall `call` with `args` on a machine using `memory`, returning
+    /// its result and the first three pages of memory.
+    fn syscall_memory<M: MemoryBackend + 'static>(memory: M, file: &Path, call: u32, args: &[u32]) -> (u32, Vec<u8>) {
+        let mut vm = VirtualMachine::with_memory(memory, Vec::new()).unwrap();
+        vm.args = vec!["prog".to_owned(), "x".to_owned()];
+        vm.random = Random::new(7);
+        vm.file_handles.insert(0, Handle::File(File::open(file).unwrap()));
+
+        // Fill memory, so that anything written in the wrong place shows
+        vm.memory.write(0, &[0xee; 0x3000]).unwrap();
+        vm.registers[4..4 + args.len()].copy_from_slice(args);
+        vm.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: call }).unwrap();
+
+        let mut bytes = vec![0; 0x3000];
+        vm.memory.read(0, &mut bytes).unwrap();
+
+        (vm.registers[3], bytes)
+    }
+
+    #[test]
+    fn memory_backends_agree() {
+        use Memory;
+        use super::RANDOM_CHUNK;
+
+        let path = Path::new(".memory_backends_test");
+        File::create(&path).unwrap().write_all(b"Hello").unwrap();
+
+        // A 1 byte sys_read, a sys_random ending with a 1 byte chunk, and
+        // sys_args with a 1 character argument
+        let chunk = RANDOM_CHUNK as u32;
+        let calls: &[(u32, &[u32], u32)] = &[(1, &[3, 0x101, 1], 1), (23, &[0x101, chunk + 1], chunk + 1), (6, &[1, 0x101, 4], 1)];
+
+        for &(call, args, result) in calls {
+            let sparse = syscall_memory(Memory::new(), &path, call, args);
+            let flat = syscall_memory(FlatMemory::new(0x10000), &path, call, args);
+
+            assert_eq!(sparse.0, result);
+            assert_eq!(sparse, flat);
+        }
+
+        let (_, bytes) = syscall_memory(Memory::new(), &path, 1, &[3, 0x101, 1]);
+        assert_eq!(bytes[0x100..0x103], [0xee, b'H', 0xee]);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn run_slice() {
+        // c.li r4, 1; call 28; call 26; c.li r4, 0; c.call 0
+        let program = vec![0x31, 0x03, 0x3c, 0x00, 0x1c, 0x00, 0x3c, 0x00, 0x1a, 0x00, 0x31, 0x01, 0x3d, 0x00];
+        let mut vm = VirtualMachine::new(program.clone()).unwrap();
+
+        assert_eq!(vm.run_slice(1), Ok(Slice::Expired));
+        assert_eq!(vm.run_slice(16), Ok(Slice::Call(ProcessCall::Yield)));
+        assert_eq!(vm.run_slice(16), Ok(Slice::Call(ProcessCall::Wait(1))));
+        assert_eq!(vm.run_slice(16), Ok(Slice::Exited(0)));
+
+        // Without a scheduler, only yielding succeeds
+        let mut vm = VirtualMachine::new(program).unwrap();
+        vm.memory.write(10, &[0x39, 0x19]).unwrap();
+        assert_eq!(vm.run(), Ok(ErrorCode::ENOSYS.to_return_value() as i32));
+    }
+
+    #[test]
+    fn sys_spawn() {
+        let mut vm = VirtualMachine::default();
+        vm.memory.write(0x100, b"child\0arg\0").unwrap();
+        vm.memory.write_u32(0x200, 0x100).unwrap();
+        vm.memory.write_u32(0x204, 0x106).unwrap();
+
+        vm.registers[4..8].copy_from_slice(&[0x100, 5, 0x200, 1]);
+        assert_eq!(vm.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: 25 }), Ok(None));
+
+        let args = vec!["child".to_owned(), "arg".to_owned()];
+        assert_eq!(vm.process_call, Some(ProcessCall::Spawn { path: "child".to_owned(), args: args, inherit_files: true }));
+
+        vm.process_call = None;
+        vm.registers[6] = 0xfffffffc;
+        vm.memory.write_u32(0xfffffffc, 0x101).unwrap();
+        assert_eq!(vm.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: 25 }), Ok(None));
+        assert_eq!(vm.registers[3], ErrorCode::EFAULT.to_return_value());
+        assert_eq!(vm.process_call, None);
+
+        // Too long an argument, then too many
+        vm.memory.write(0x1000, &[b'a'; MAX_PATH as usize + 1]).unwrap();
+        vm.memory.write_u32(0x200, 0x1000).unwrap();
+        vm.registers[6] = 0x200;
+        assert_eq!(vm.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: 25 }), Ok(None));
+        assert_eq!(vm.registers[3], ErrorCode::E2BIG.to_return_value());
+
+        for i in 0..MAX_ARGS as u32 + 1 {
+            vm.memory.write_u32(0x4000 + i * 4, 0x106).unwrap();
+        }
+
+        vm.registers[6] = 0x4000;
+        assert_eq!(vm.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: 25 }), Ok(None));
+        assert_eq!(vm.registers[3], ErrorCode::E2BIG.to_return_value());
+        assert_eq!(vm.process_call, None);
+    }
+
+    #[test]
+    fn inherit_files() {
+        let path = Path::new(".inherit_files_test");
+        let mut parent = VirtualMachine::default();
+        parent.file_handles.insert(1, Handle::File(File::create(&path).unwrap()));
+        parent.file_handles.insert(2, Handle::Dir(fs::read_dir(".").unwrap()));
+
+        let mut child = VirtualMachine::default();
+        child.inherit_files(&parent).unwrap();
+        assert_eq!(child.file_handles.keys().collect::<Vec<_>>(), vec![1]);
+
+        child.memory.write(0, b"child").unwrap();
+        child.registers[4..7].copy_from_slice(&[4, 0, 5]);
+        assert_eq!(child.exec_instr(Immediate { op: CALL, dst: 0, src1: 0, imm: 2 }), Ok(None));
+        assert_eq!(child.registers[3], 5);
+
+        let mut contents = String::new();
+        File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "child");
+
+        fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,209 @@
+extern crate svm;
+
+use svm::{Engine, Error, Instruction, OpCode, VirtualMachine};
+use svm::asm::Assembler;
+
+/// Final state of a program: its result, registers and allocated pages.
+type State = (Result<i32, Error>, [u32; 32], Vec<(usize, Vec<u8>)>);
+
+fn run(program: &[u8], engine: Engine) -> State {
+    let mut vm = VirtualMachine::new(program.to_vec()).unwrap();
+    vm.engine = engine;
+
+    #[cfg(feature = "jit")]
+    {
+        // Compile every block, so that the random programs are compiled
+        vm.jit_threshold = 1;
+    }
+
+    let result = vm.run();
+    let pages = vm.memory.pages.iter().map(|(i, page)| (i, page.to_vec())).collect();
+
+    (result, vm.registers, pages)
+}
+
+/// Runs `program` with each engine, checking that they agree with the
+/// interpreter, and returns the interpreter's final state.
+fn run_all(program: &[u8]) -> State {
+    let expected = run(program, Engine::Interpreter);
+    assert_eq!(run(program, Engine::Blocks), expected);
+
+    #[cfg(feature = "jit")]
+    assert_eq!(run(program, Engine::Jit), expected);
+
+    expected
+}
+
+fn assemble(source: &str) -> Vec<u8> {
+    Assembler::new().source("test.sasm", source).assemble().unwrap().bytes()
+}
+
+#[test]
+fn examples() {
+    for &(name, result) in &[("fibonacci", 2971215073), ("factorial", 479001600), ("linear_search", 5)] {
+        let program = Assembler::new().file(format!("examples/{}.sasm", name)).assemble().unwrap();
+        let (status, registers, _) = run_all(&program.bytes());
+
+        assert_eq!((status, registers[3]), (Ok(0), result));
+    }
+}
+
+#[test]
+fn program_counter() {
+    // Reading r0 gives the address of the next instruction, wherever it is in
+    // a block, and writing it ends the block
+    let (_, registers, _) = run_all(&assemble("c.li r4, 0\n add r5, r0, r0\n mv r6, r0\n addi r0, r0, 2\n \
+                                               c.li r4, 1\n c.li r4, 2\n c.call 0"));
+
+    assert_eq!(registers[4..7], [2, 12, 8]);
+}
+
+#[test]
+fn self_modifying() {
+    // Overwrites code later in the same block, then code in a block which
+    // has already run
+    let source = "start:\n li r5, %target\n li r7, %replacement\n load r6, r7, 0\n store r5, r6, 0\ntarget:\n \
+                  c.li r3, 1\n c.li r4, 1\n addi r8, r8, 1\n li r9, 2\n blt r8, r9, $start\n c.li r4, 0\n c.call 0\n\
+                  replacement:\n c.li r3, 7\n c.addi r3, 1";
+    let (status, registers, _) = run_all(&assemble(source));
+
+    assert_eq!((status, registers[3], registers[8]), (Ok(0), 8, 2));
+}
+
+#[test]
+fn split_address() {
+    // hi() is rounded for the sign extended lo() added by ADDI, so ORI only
+    // gives back the value when bit 15 is clear
+    let source = ".equ VALUE, 0x12348765\n.equ CLEAR, 0x12344765\n \
+                  lui r5, hi(VALUE)\n addi r5, r5, lo(VALUE)\n \
+                  lui r6, hi(CLEAR)\n ori r6, r6, lo(CLEAR)\n c.li r4, 0\n c.call 0";
+    let (status, registers, _) = run_all(&assemble(source));
+
+    assert_eq!(status, Ok(0));
+    assert_eq!(registers[5..7], [0x12348765, 0x12344765]);
+
+    let result = Assembler::new().source("test.sasm", ".equ VALUE, 0x12348765\n lui r7, hi(VALUE)\n ori r7, r7, lo(VALUE)")
+                                 .assemble();
+    assert_eq!(result.map_err(|e| e.to_string()),
+        Err("test.sasm:3: lo() cannot be used with ORI when bit 15 is set, use ADDI instead".to_owned()));
+
+    // The value is checked whatever its syntax, including through constants
+    for source in &[".equ L, lo(0x12348765)\n ori r7, r7, L", "ori r7, r7, (lo(0x12348765))"] {
+        let result = Assembler::new().source("test.sasm", source).assemble();
+        assert_eq!(result.map_err(|e| e.to_string()),
+            Err(format!("test.sasm:{}: lo() cannot be used with ORI when bit 15 is set, use ADDI instead",
+                        source.lines().count())));
+    }
+
+    let source = ".equ L, lo(0x12344765)\n.equ M, lo(0x12348765) & 0x7fff\n ori r5, r5, L\n ori r6, r6, M\n \
+                  c.li r4, 0\n c.call 0";
+    let (status, registers, _) = run_all(&assemble(source));
+    assert_eq!((status, registers[5], registers[6]), (Ok(0), 0x4765, 0x765));
+}
+
+#[test]
+fn errors() {
+    assert_eq!(run_all(&assemble("c.li r4, 1\n call 99")).0, Err(Error::InvalidSysCall(99)));
+    assert_eq!(run_all(&[0x31, 0x03, 0x01, 0x00]).0, Err(Error::InvalidOpCode(1)));
+}
+
+/// Xorshift generator, so that the random programs are the same every run.
+struct Random(u32);
+
+impl Random {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+
+    fn below(&mut self, n: u32) -> u32 {
+        self.next() % n
+    }
+
+    /// Returns a register other than `r0`, `r1` (so that stores stay on the
+    /// stack) and `r2`.
+    fn dst(&mut self) -> usize {
+        3 + self.below(29) as usize
+    }
+
+    fn src(&mut self) -> usize {
+        self.below(32) as usize
+    }
+
+    /// Returns a signed immediate of `bits` bits.
+    fn imm(&mut self, bits: u32) -> u32 {
+        ((self.next() << (32 - bits)) as i32 >> (32 - bits)) as u32
+    }
+}
+
+/// Generates a random program of arithmetic, memory and forward branch
+/// instructions which exits with `r4` as its status.
+fn random_program(random: &mut Random) -> Vec<u8> {
+    use Instruction::*;
+    use OpCode::*;
+
+    const REGISTER: &'static [OpCode] = &[ADD, SUB, AND, OR, XOR, SLL, SRL, SRA];
+    const COMPRESSED: &'static [OpCode] = &[C_ADD, C_SUB, C_AND, C_OR, C_XOR, C_SLL, C_SRL, C_SRA];
+    const IMMEDIATE: &'static [OpCode] = &[ADDI, ANDI, ORI, XORI, SLLI, SRLI, SRAI, LI];
+    const BRANCH: &'static [OpCode] = &[BEQ, BNE, BLT, BGE, BLT_U, BGE_U];
+
+    let len = 16 + random.below(64) as usize;
+    let mut instrs = Vec::with_capacity(len + 1);
+    // Indices of branches and the instructions they jump to
+    let mut branches = Vec::new();
+
+    for i in 0..len {
+        let pick = |ops: &[OpCode], random: &mut Random| ops[random.below(ops.len() as u32) as usize];
+
+        instrs.push(match random.below(9) {
+            0 => Register { op: pick(REGISTER, random), dst: random.dst(), src1: random.src(), src2: random.src() },
+            1 => {
+                let dst = random.dst();
+                Register { op: pick(COMPRESSED, random), dst: dst, src1: dst, src2: random.src() }
+            },
+            2 => Register { op: MV, dst: random.dst(), src1: 0, src2: random.src() },
+            3 | 4 => Immediate { op: pick(IMMEDIATE, random), dst: random.dst(), src1: random.src(), imm: random.imm(16) },
+            5 => {
+                // Compressed immediate instructions only encode `r0` to `r7`
+                let dst = 3 + random.below(5) as usize;
+                Immediate { op: C_ADDI, dst: dst, src1: dst, imm: random.imm(7) }
+            },
+            6 => Immediate { op: LOAD, dst: random.dst(), src1: random.src(), imm: random.imm(16) },
+            7 => Store { op: STORE, src1: 1, src2: random.src(), imm: (-4 - 4 * random.below(64) as i32) as u32 },
+            _ => {
+                branches.push((i, i + 1 + random.below((len - i) as u32) as usize));
+                Store { op: pick(BRANCH, random), src1: random.src(), src2: random.src(), imm: 0 }
+            }
+        });
+    }
+
+    instrs.push(Immediate { op: CALL, dst: 0, src1: 0, imm: 0 });
+
+    let addrs: Vec<u32> = instrs.iter().scan(0, |addr, instr| {
+        *addr += instr.size();
+        Some(*addr - instr.size())
+    }).collect();
+
+    for (branch, target) in branches {
+        *instrs[branch].immediate_mut().unwrap() = addrs[target].wrapping_sub(addrs[branch] + 4);
+    }
+
+    let mut bytes = Vec::new();
+
+    for instr in instrs {
+        instr.write_bytes(&mut bytes).unwrap();
+    }
+
+    bytes
+}
+
+#[test]
+fn random() {
+    let mut random = Random(0x5eed);
+
+    for _ in 0..100 {
+        run_all(&random_program(&mut random));
+    }
+}
@@ -0,0 +1,155 @@
+extern crate svm;
+
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+
+use svm::{aot, VirtualMachine};
+use svm::asm::Assembler;
+
+/// Translates `image`, compiles it with the system C compiler and runs it
+/// with `input` on stdin, returning its exit status, stdout and stderr.
+fn run_translated(name: &str, image: &[u8], input: &[u8]) -> (i32, String, String) {
+    run_translated_with(name, image, input, &[], &[])
+}
+
+/// As `run_translated`, but passing `args` and setting the variables in `env`.
+fn run_translated_with(name: &str, image: &[u8], input: &[u8], args: &[&str], env: &[(&str, &str)])
+                       -> (i32, String, String) {
+    let source = format!(".svm2c_{}.c", name);
+    let binary = format!("./.svm2c_{}", name);
+
+    aot::translate(image, &mut File::create(&source).unwrap()).unwrap();
+
+    let status = Command::new("cc").args(&["-O1", "-o", &binary, &source]).status().expect("failed to run cc");
+    assert!(status.success(), "failed to compile {}", source);
+
+    let mut command = Command::new(&binary);
+    command.args(args).stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped());
+
+    for &(name, value) in env {
+        command.env(name, value);
+    }
+
+    let mut child = command.spawn().unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+
+    let output = child.wait_with_output().unwrap();
+
+    fs::remove_file(&source).unwrap();
+    fs::remove_file(&binary).unwrap();
+
+    (output.status.code().unwrap(),
+     String::from_utf8(output.stdout).unwrap(),
+     String::from_utf8(output.stderr).unwrap())
+}
+
+/// Runs `image` with the interpreter, returning its exit status as seen by
+/// the host and the message `svm` would print if it failed.
+fn run_interpreted(image: &[u8]) -> (i32, String) {
+    let mut vm = VirtualMachine::new(image.to_vec()).unwrap();
+
+    match vm.run() {
+        Ok(status) => (status & 0xff, String::new()),
+        Err(error) => (1, format!("svm: {} at 0x{:08x}\n", error, vm.program_ctr()))
+    }
+}
+
+fn assemble(source: &str) -> Vec<u8> {
+    Assembler::new().include_path("examples").source("test.sasm", source).assemble().unwrap().bytes()
+}
+
+#[test]
+fn examples() {
+    for name in &["fibonacci", "factorial", "linear_search"] {
+        let mut source = String::new();
+        File::open(format!("examples/{}.sasm", name)).unwrap().read_to_string(&mut source).unwrap();
+
+        // Exit with the result rather than stopping at a breakpoint, so that
+        // it can be compared
+        let image = assemble(&source.replace("c.break", "mv r4, r3\n    c.call 0"));
+        let (status, _) = run_interpreted(&image);
+
+        assert_eq!(run_translated(name, &image, b""), (status, String::new(), String::new()));
+    }
+}
+
+#[test]
+fn greeter() {
+    let image = Assembler::new().file("examples/greeter.sasm").assemble().unwrap().bytes();
+
+    assert_eq!(run_translated("greeter", &image, b"World"),
+               (0, "Type your name: Hello World!\n".to_owned(), String::new()));
+}
+
+#[test]
+fn computed_jump() {
+    // `target` is only reached through a computed jump, so is interpreted,
+    // and returns to translated code at `done`
+    let source = "start:\n li r2, %done\n li r5, 5\n slli r5, r5, 2\n mv r0, r5\n\
+                  done:\n mv r4, r3\n call 0\n\
+                  target:\n c.li r3, 7\n c.addi r3, 3\n mv r0, r2";
+    let image = assemble(source);
+
+    assert_eq!(aot::recover(&image).contains_key(&20), false);
+    assert_eq!(run_interpreted(&image), (10, String::new()));
+    assert_eq!(run_translated("computed_jump", &image, b""), (10, String::new(), String::new()));
+}
+
+#[test]
+fn files() {
+    let source = ".include \"stdlib.sasm\"\n\
+                  start:\n\
+                  \x20   li r4, %path\n li r5, path_end - path\n call SYS_CREATE\n mv r8, r3\n\
+                  \x20   mv r4, r8\n li r5, %text\n li r6, text_end - text\n call SYS_WRITE\n\
+                  \x20   mv r4, r8\n call SYS_CLOSE\n\
+                  \x20   li r4, %path\n li r5, path_end - path\n li r6, 1\n call SYS_OPEN\n\
+                  \x20   mv r4, r3\n li r5, %buffer\n li r6, 64\n call SYS_READ\n\
+                  \x20   mv r6, r3\n li r4, STDOUT\n li r5, %buffer\n call SYS_WRITE\n\
+                  \x20   exit 0\n\
+                  .data\n\
+                  path:\n bytes \".svm2c_files_test\"\n path_end:\n\
+                  text:\n bytes \"Hello, file!\\n\"\n text_end:\n\
+                  .bss\n\
+                  buffer:\n .space 64";
+    let image = assemble(source);
+    let result = run_translated("files", &image, b"");
+
+    let mut contents = String::new();
+    File::open(".svm2c_files_test").unwrap().read_to_string(&mut contents).unwrap();
+    fs::remove_file(".svm2c_files_test").unwrap();
+
+    assert_eq!(result, (0, "Hello, file!\n".to_owned(), String::new()));
+    assert_eq!(contents, "Hello, file!\n");
+}
+
+#[test]
+fn arguments() {
+    // Writes its first argument and the value of SVM2C_TEST, then exits with
+    // the number of arguments, including the program's name
+    let source = ".include \"stdlib.sasm\"\n\
+                  start:\n\
+                  \x20   mv r8, r4\n\
+                  \x20   c.li r4, 1\n li r5, %buffer\n li r6, 64\n call 6\n\
+                  \x20   mv r6, r3\n li r4, STDOUT\n li r5, %buffer\n call SYS_WRITE\n\
+                  \x20   li r4, %name\n li r5, name_end - name\n li r6, %buffer\n li r7, 64\n call 7\n\
+                  \x20   mv r6, r3\n li r4, STDOUT\n li r5, %buffer\n call SYS_WRITE\n\
+                  \x20   mv r4, r8\n call SYS_EXIT\n\
+                  .data\n\
+                  name:\n bytes \"SVM2C_TEST\"\n name_end:\n\
+                  .bss\n\
+                  buffer:\n .space 64";
+    let image = assemble(source);
+
+    assert_eq!(run_translated_with("arguments", &image, b"", &["first", "second"], &[("SVM2C_TEST", "value")]),
+               (3, "firstvalue".to_owned(), String::new()));
+}
+
+#[test]
+fn errors() {
+    for (i, image) in [assemble("c.li r4, 1\n call 99"), vec![0x31, 0x03, 0x01, 0x00]].iter().enumerate() {
+        let (status, message) = run_interpreted(image);
+
+        assert_eq!(run_translated(&format!("errors_{}", i), image, b""), (status, String::new(), message));
+    }
+}